@@ -273,6 +273,15 @@ class SortBy<T> extends Operator<T> {
     }
 }
 
+/**
+ * What happened to the elements of a cursor that is followed with
+ * `ChiselCursor.subscribe()`.
+ */
+export type CursorEvent<T> =
+    | { event: "snapshot"; rows: T[] }
+    | { event: "insert" | "update"; id: string; row: T }
+    | { event: "delete"; id: string };
+
 /** ChiselCursor is a lazy iterator that will be used by ChiselStrike to construct an optimized query. */
 export class ChiselCursor<T> {
     constructor(
@@ -452,10 +461,11 @@ export class ChiselCursor<T> {
             : this.baseConstructor;
         return {
             [Symbol.asyncIterator]: async function* () {
+                const context = await dataRequestContext();
                 const rid = Deno.core.opSync(
                     "op_chisel_relational_query_create",
                     op,
-                    requestContext,
+                    context,
                 );
                 try {
                     while (true) {
@@ -481,6 +491,107 @@ export class ChiselCursor<T> {
             },
         };
     }
+
+    /**
+     * Follows the elements of this cursor as they change, until the request
+     * ends. The first event is a snapshot of all of them, and the next ones
+     * tell that an element entered the cursor, changed, or left it.
+     *
+     * Cursors that filter with a function, take or skip can't be followed.
+     */
+    subscribe(): AsyncIterableIterator<CursorEvent<T>> {
+        if (this.inner.containsType(OpType.PredicateFilter)) {
+            throw new Error(
+                "Cannot subscribe to a cursor that filters with a function",
+            );
+        }
+        const ctor = this.inner.containsType(OpType.ColumnsSelect)
+            ? undefined
+            : this.baseConstructor;
+        return subscriptionEvents("op_chisel_subscribe", this.inner, ctor);
+    }
+}
+
+// Follows the subscription to `query` that `op` creates, building the rows of
+// its events with `ctor`, if there is one.
+function subscriptionEvents<T>(
+    op: string,
+    query: unknown,
+    ctor?: { new (): T },
+): AsyncIterableIterator<CursorEvent<T>> {
+    // Subscribing right away reports the changes made after this call even if
+    // the events are read later. Subscriptions read outside of the transaction
+    // of the request, which would otherwise be held for as long as they last.
+    const rid = Deno.core.opSync(op, query, currentRequestContext());
+    const build = (properties: Record<string, unknown>) => {
+        if (ctor === undefined) {
+            return properties as T;
+        }
+        const result = new ctor();
+        Object.assign(result, properties);
+        return result;
+    };
+    async function* events() {
+        try {
+            while (true) {
+                const e = await Deno.core.opAsync(
+                    "op_chisel_subscription_next",
+                    rid,
+                );
+                if (e.event == "snapshot") {
+                    yield { event: e.event, rows: e.rows.map(build) };
+                } else if (e.event == "delete") {
+                    yield { event: e.event, id: e.id };
+                } else {
+                    yield { event: e.event, id: e.id, row: build(e.row) };
+                }
+            }
+        } finally {
+            Deno.core.tryClose(rid);
+        }
+    }
+    return events();
+}
+
+/**
+ * Sends `events` as Server-Sent Events, named after the kind of each event.
+ * The data of a snapshot is the array of rows, the one of an insert or update
+ * is the row, and the one of a delete is `{ id }`.
+ */
+export function responseFromEvents(
+    events: AsyncIterable<CursorEvent<unknown>>,
+    status = 200,
+) {
+    const iterator = events[Symbol.asyncIterator]();
+    const encoder = new TextEncoder();
+    const body = new ReadableStream<Uint8Array>({
+        async pull(controller: ReadableStreamDefaultController) {
+            const next = await iterator.next();
+            if (next.done) {
+                controller.close();
+                return;
+            }
+            const e = next.value;
+            const data = e.event == "snapshot"
+                ? e.rows
+                : e.event == "delete"
+                ? { id: e.id }
+                : e.row;
+            const json = JSON.stringify(data);
+            const message = `event: ${e.event}\ndata: ${json}\n\n`;
+            controller.enqueue(encoder.encode(message));
+        },
+        cancel() {
+            iterator.return?.();
+        },
+    });
+    return new Response(body, {
+        status,
+        headers: [
+            ["content-type", "text/event-stream"],
+            ["cache-control", "no-cache"],
+        ],
+    });
 }
 
 /** Extends the Request class adding ChiselStrike-specific helpers
@@ -489,6 +600,7 @@ export class ChiselCursor<T> {
  * @property {string} endpoint - The current endpoint being called.
  * @property {string} pathParams - This is essentially the URL's path, but with everything before the endpoint name removed.
  * @property {AuthUser} user - The currently logged in user. `undefined` if there isn't one.
+ * @property {Record<string, string>} params - The values of the parameters of the endpoint's path, like `id` for `endpoints/posts/[id].ts`.
  */
 export class ChiselRequest extends Request {
     constructor(
@@ -498,6 +610,7 @@ export class ChiselRequest extends Request {
         public endpoint: string,
         public pathParams: string,
         public user?: AuthUser | undefined,
+        public params: Record<string, string> = {},
     ) {
         super(input, init);
     }
@@ -517,6 +630,149 @@ export class ChiselRequest extends Request {
     }
 }
 
+type WebSocketListener = (event: Event) => unknown;
+
+/**
+ * The server side of a WebSocket that an endpoint accepted with `upgradeWebSocket()`.
+ *
+ * The connection stays with the worker that handled the handshake. The handlers of each event,
+ * like a message, run in a transaction of their own, which is committed once the promises that
+ * they return settle, or rolled back if they throw. Binary messages are `ArrayBuffer`s.
+ */
+export class ChiselWebSocket {
+    static readonly CONNECTING = 0;
+    static readonly OPEN = 1;
+    static readonly CLOSING = 2;
+    static readonly CLOSED = 3;
+
+    readyState: number = ChiselWebSocket.CONNECTING;
+    onopen: ((event: Event) => unknown) | null = null;
+    onmessage: ((event: MessageEvent) => unknown) | null = null;
+    onclose: ((event: CloseEvent) => unknown) | null = null;
+    onerror: ((event: Event) => unknown) | null = null;
+    /** Resource of the connection, which the worker sets once it is open. */
+    rid?: number;
+
+    private listeners = new Map<string, Set<WebSocketListener>>();
+    // Messages are written in the order they are sent.
+    private sending: Promise<void> = Promise.resolve();
+
+    addEventListener(type: string, listener: WebSocketListener) {
+        let listeners = this.listeners.get(type);
+        if (listeners === undefined) {
+            listeners = new Set();
+            this.listeners.set(type, listeners);
+        }
+        listeners.add(listener);
+    }
+
+    removeEventListener(type: string, listener: WebSocketListener) {
+        this.listeners.get(type)?.delete(listener);
+    }
+
+    send(data: string | ArrayBuffer | ArrayBufferView) {
+        if (this.readyState !== ChiselWebSocket.OPEN) {
+            throw new Error("WebSocket is not open");
+        }
+        if (typeof data === "string") {
+            this.write("op_chisel_websocket_send_text", data);
+        } else if (data instanceof ArrayBuffer) {
+            this.write("op_chisel_websocket_send_binary", new Uint8Array(data));
+        } else {
+            const bytes = new Uint8Array(
+                data.buffer,
+                data.byteOffset,
+                data.byteLength,
+            );
+            this.write("op_chisel_websocket_send_binary", bytes);
+        }
+    }
+
+    /**
+     * Starts the closing handshake. The `close` event is dispatched once the
+     * client answers it.
+     */
+    close(code?: number, reason?: string) {
+        if (
+            code !== undefined && code !== 1000 &&
+            (code < 3000 || code > 4999)
+        ) {
+            throw new RangeError(`Invalid close code ${code}`);
+        }
+        if (this.readyState !== ChiselWebSocket.OPEN) {
+            return;
+        }
+        this.readyState = ChiselWebSocket.CLOSING;
+        this.write("op_chisel_websocket_close", code, reason);
+    }
+
+    /** Waits for the messages that were sent to be written. */
+    flush(): Promise<void> {
+        return this.sending;
+    }
+
+    /**
+     * Runs the handlers of `event`, and waits for the promises that they
+     * return. The worker calls this with the events of the connection.
+     */
+    async dispatch(event: Event) {
+        const property = (this as unknown as Record<string, unknown>)[
+            "on" + event.type
+        ];
+        const listeners = [...(this.listeners.get(event.type) ?? [])];
+        if (typeof property === "function") {
+            listeners.unshift(property as WebSocketListener);
+        }
+        await Promise.all(listeners.map((l) => l.call(this, event)));
+    }
+
+    private write(op: string, ...args: unknown[]) {
+        this.sending = this.sending.then(async () => {
+            try {
+                await Deno.core.opAsync(op, this.rid, ...args);
+            } catch (_) {
+                // A connection that is gone shows up when reading from it,
+                // which closes the WebSocket.
+            }
+        });
+    }
+}
+
+/**
+ * The WebSockets of the responses that `upgradeWebSocket()` returned, for
+ * the worker to accept the handshakes.
+ */
+export const webSocketUpgrades = new WeakMap<Response, ChiselWebSocket>();
+
+/**
+ * Accepts the WebSocket handshake of `req`. The endpoint returns the `response`, and handles
+ * the events of the `socket`.
+ *
+ * Example:
+ * ```typescript
+ * export default async function chisel(req: Request) {
+ *     const { socket, response } = upgradeWebSocket(req);
+ *     socket.onmessage = async (e) => {
+ *         await Message.build({ text: e.data }).save();
+ *         socket.send("saved");
+ *     };
+ *     return response;
+ * }
+ * ```
+ */
+export function upgradeWebSocket(
+    req: Request,
+): { socket: ChiselWebSocket; response: Response } {
+    if (req.headers.get("upgrade")?.toLowerCase() !== "websocket") {
+        throw new TypeError("The request is not a WebSocket handshake");
+    }
+    const socket = new ChiselWebSocket();
+    // Responses can't be made with status 101, which the worker gives it.
+    const response = new Response(null, { status: 200 });
+    webSocketUpgrades.set(response, socket);
+    return { socket, response };
+}
+
 export function chiselIterator<T>(
     type: { new (): T },
 ) {
@@ -574,10 +830,11 @@ export class ChiselEntity {
     /** saves the current object into the backend */
     async save() {
         ensureNotGet();
+        const context = await dataRequestContext();
         const jsonIds = await Deno.core.opAsync("op_chisel_store", {
             name: this.constructor.name,
             value: this,
-        }, requestContext);
+        }, context);
         type IdsJson = Map<string, IdsJson>;
         function backfillIds(this_: ChiselEntity, jsonIds: IdsJson) {
             for (const [fieldName, value] of Object.entries(jsonIds)) {
@@ -707,10 +964,11 @@ export class ChiselEntity {
         restrictions: Partial<T>,
     ): Promise<void> {
         ensureNotGet();
+        const context = await dataRequestContext();
         await Deno.core.opAsync("op_chisel_entity_delete", {
             typeName: this.name,
             filterExpr: restrictionsToFilterExpr(restrictions),
-        }, requestContext);
+        }, context);
     }
 
     /**
@@ -817,6 +1075,43 @@ export class AuthUser extends ChiselEntity {
     image?: string;
 }
 
+/**
+ * Grants a role to the user `userId`. Roles are referred to by the `roles` section of policies, and
+ * are deleted along with their user.
+ */
+export class AuthUserRole extends ChiselEntity {
+    userId: string = "";
+    role: string = "";
+}
+
+/**
+ * An API key of a machine client, which acts as the user `userId`. Only the SHA-256 hash of the key
+ * is stored. `scopes` is a space-separated list checked by the `endpoints` section of policies, and
+ * `expiresAt` is in milliseconds since the epoch.
+ */
+export class AuthApiKey extends ChiselEntity {
+    keyHash: string = "";
+    userId: string = "";
+    scopes?: string;
+    expiresAt?: number;
+}
+
+/**
+ * A change to an entity that policies mark as audited. `before` and `after` are the JSON of the entity
+ * as stored before and after the change; `timestamp` is in milliseconds since the epoch.
+ */
+export class AuditRecord extends ChiselEntity {
+    timestamp: number = 0;
+    userId?: string;
+    path: string = "";
+    apiVersion: string = "";
+    entity: string = "";
+    entityId: string = "";
+    action: string = "";
+    before?: string;
+    after?: string;
+}
+
 /**
  * Gets a secret from the environment
  *
@@ -880,17 +1175,80 @@ function ensureNotGet() {
     }
 }
 
-export const requestContext: {
+export type RequestContext = {
     path: string;
     method: string;
     apiVersion: string;
     userId?: string;
-} = {
+    username?: string;
+    userRoles: string[];
+    requestId?: string;
+    /** Identifies the request within the worker, which handles many at once. */
+    handlerId?: number;
+};
+
+// Context of the code that doesn't run for a request, like the top level of
+// endpoint modules.
+const noRequestContext: RequestContext = {
     path: "",
     method: "",
     apiVersion: "",
+    userRoles: [],
 };
 
+let requestContextSource: () => RequestContext | undefined = () => undefined;
+
+/**
+ * Tells how to find the context of the request whose code is running. The
+ * worker that handles requests sets this up.
+ */
+export function setRequestContextSource(
+    source: () => RequestContext | undefined,
+) {
+    requestContextSource = source;
+}
+
+function currentRequestContext(): RequestContext {
+    return requestContextSource() ?? noRequestContext;
+}
+
+// Requests start their transactions when they first access data, so that the
+// ones that don't access any take none of the database's locks.
+const transactions = new WeakMap<RequestContext, Promise<void>>();
+
+// Returns the context of the request whose code is running, once the request
+// has a transaction for the data that the code accesses.
+async function dataRequestContext(): Promise<RequestContext> {
+    const context = currentRequestContext();
+    const id = context.handlerId;
+    if (id === undefined) {
+        return context;
+    }
+    let started = transactions.get(context);
+    if (started === undefined) {
+        started = Deno.core.opAsync("op_chisel_create_transaction", id);
+        transactions.set(context, started);
+    }
+    await started;
+    return context;
+}
+
+/**
+ * Has the request of `context` start another transaction the next time it
+ * accesses data, once the server committed the one it had. The worker that
+ * handles requests calls this.
+ */
+export function forgetTransaction(context: RequestContext) {
+    transactions.delete(context);
+}
+
+/** The context of the request whose code is running. */
+export const requestContext: RequestContext = new Proxy(noRequestContext, {
+    get: (_target, key) => Reflect.get(currentRequestContext(), key),
+    set: (_target, key, value) =>
+        Reflect.set(currentRequestContext(), key, value),
+});
+
 // TODO: BEGIN: this should be in another file: crud.ts
 
 // TODO: BEGIN: when module import is fixed:
@@ -1063,34 +1421,50 @@ async function fetchEntitiesCrud<T extends ChiselEntity>(
     type: { new (): T },
     url: string,
 ): Promise<T[]> {
+    const context = await dataRequestContext();
     const results = await Deno.core.opAsync(
         "op_chisel_crud_query",
         {
             typeName: type.name,
             url,
         },
-        requestContext,
+        context,
     );
     return results;
 }
 
+/**
+ * Follows the crud data that `url` selects.
+ */
+function subscribeEntitiesCrud<T extends ChiselEntity>(
+    type: { new (): T },
+    url: string,
+): AsyncIterableIterator<CursorEvent<unknown>> {
+    return subscriptionEvents("op_chisel_crud_subscribe", {
+        typeName: type.name,
+        url,
+    });
+}
+
 async function deleteEntitiesCrud<T extends ChiselEntity>(
     type: { new (): T },
     url: string,
 ): Promise<void> {
+    const context = await dataRequestContext();
     await Deno.core.opAsync(
         "op_chisel_crud_delete",
         {
             typeName: type.name,
             url,
         },
-        requestContext,
+        context,
     );
 }
 
 const defaultCrudMethods: CRUDMethods<ChiselEntity, GenericChiselEntityClass> =
     {
         // Returns a specific entity matching params.id (if present) or all entities matching the filter in the `filter` URL parameter.
+        // With `watch=true`, streams the entities matching the filter, and their changes, as Server-Sent Events.
         GET: async (
             entity: GenericChiselEntityClass,
             _req: Request,
@@ -1102,6 +1476,10 @@ const defaultCrudMethods: CRUDMethods<ChiselEntity, GenericChiselEntityClass> =
             if (id) {
                 const u = await entity.findOne({ id });
                 return createResponse(u ?? "Not found", u ? 200 : 404);
+            } else if (url.searchParams.get("watch") === "true") {
+                return responseFromEvents(
+                    subscribeEntitiesCrud(entity, url.href),
+                );
             } else {
                 return createResponse(
                     await fetchEntitiesCrud(entity, url.href),
@@ -4,25 +4,42 @@
 /// <reference lib="dom" />
 /// <reference lib="deno.unstable" />
 
-const endpointWorker = new Worker("file:///worker.js", {
-    type: "module",
-    name: "endpointWorker",
-    deno: {
-        namespace: true,
-    },
-});
+function startWorker() {
+    const worker = new Worker("file:///worker.js", {
+        type: "module",
+        name: "endpointWorker",
+        deno: {
+            namespace: true,
+        },
+    });
+    worker.onmessageerror = function (e) {
+        throw e;
+    };
+    worker.onerror = function (e) {
+        // The server terminates the handlers that time out, which the worker
+        // survives, and the ones that run out of memory, in which case it
+        // restarts the worker.
+        if (e.message.includes("execution terminated")) {
+            e.preventDefault();
+            return;
+        }
+        throw e;
+    };
+    worker.onmessage = onWorkerMessage;
+    return worker;
+}
+
+let endpointWorker = startWorker();
+// Set while the worker is being replaced, until the new one is initialized.
+let restarting = false;
 type Resolver = {
     resolve: (value: unknown) => void;
     reject: (err: Error) => void;
-    msg: unknown;
-};
-const resolvers: Resolver[] = [];
-endpointWorker.onmessageerror = function (e) {
-    throw e;
-};
-endpointWorker.onerror = function (e) {
-    throw e;
 };
+// The worker handles many messages at once, and replies to each with the
+// msgId it was sent with.
+const resolvers = new Map<number, Resolver>();
+let nextMsgId = 0;
 
 type BodyState = {
     parts: { value?: Uint8Array; err?: Error }[];
@@ -31,74 +48,109 @@ type BodyState = {
 };
 
 const bodyParts: Record<number, BodyState> = {};
-let bodyDone = false;
-let resolveBody: (() => void) | undefined = undefined;
-endpointWorker.onmessage = function (event) {
-    const { msg, id, value, err } = event.data;
-    if (msg == "body") {
-        const state = bodyParts[id];
-        if (state?.resolve !== undefined) {
-            state.resolve();
-            state.resolve = undefined;
-        }
-        if ((err !== undefined || value !== undefined) && state !== undefined) {
-            state.parts.push({ value, err });
-        }
-        if (err !== undefined || value === undefined) {
-            if (state !== undefined) {
-                state.done = true;
-            }
-            bodyDone = true;
-            if (resolveBody !== undefined) {
-                resolveBody();
-                resolveBody = undefined;
-            }
-        }
-    } else {
-        const resolver = resolvers[0];
-        if (err) {
-            resolver.reject(err);
-        } else {
-            resolver.resolve(value);
-        }
+// The msgId of the callHandler message of each request that awaits a reply
+// from the worker.
+const pendingCalls = new Map<number, number>();
+// Resolve the promises of abortRequest.
+const resolveAborts = new Map<number, () => void>();
+
+function pushBodyPart(id: number, value?: Uint8Array, err?: Error) {
+    const state = bodyParts[id];
+    if (state === undefined || state.done) {
+        return;
     }
-};
+    if (err !== undefined || value !== undefined) {
+        state.parts.push({ value, err });
+    }
+    if (err !== undefined || value === undefined) {
+        state.done = true;
+    }
+    state.resolve?.();
+    state.resolve = undefined;
+}
 
-function sendMsg(msg: unknown) {
-    const p = new Promise((resolve, reject) => {
-        resolvers.push({ resolve, reject, msg });
-    });
-    // Each worker should handle a single request at a time, so we
-    // only post a message if the worker is not currently
-    // busy. Otherwise we leave it scheduled and know it will be
-    // posted once the preceding messages are answered.
-    if (resolvers.length == 1) {
-        endpointWorker.postMessage(resolvers[0].msg);
+function reply(msgId: number, value: unknown, err?: Error) {
+    const resolver = resolvers.get(msgId);
+    if (resolver === undefined) {
+        return;
+    }
+    resolvers.delete(msgId);
+    if (err) {
+        resolver.reject(err);
+    } else {
+        resolver.resolve(value);
     }
-    return p;
 }
 
-async function toWorker(msg: unknown) {
-    try {
-        return await sendMsg(msg);
-    } finally {
-        endMsgProcessing();
+function onWorkerMessage(event: MessageEvent) {
+    const { msg, msgId, id, value, err, pending } = event.data;
+    if (msg == "aborted") {
+        // If the worker had replied to callHandler, the reply is on its way
+        // and the request ends as usual. Otherwise, there will be no reply.
+        if (pending) {
+            const callMsgId = pendingCalls.get(id);
+            pendingCalls.delete(id);
+            if (callMsgId !== undefined) {
+                resolvers.delete(callMsgId);
+            }
+        }
+        resolveAborts.get(id)?.();
+        resolveAborts.delete(id);
+    } else if (msg == "body") {
+        pushBodyPart(id, value, err);
+    } else {
+        reply(msgId, value, err);
     }
 }
 
-function endMsgProcessing() {
-    resolvers.shift();
-    // If a message was scheduled while the worker was busy, post
-    // it now.
-    if (resolvers.length != 0) {
-        endpointWorker.postMessage(resolvers[0].msg);
+function sendMsg(msg: Record<string, unknown>) {
+    if (restarting) {
+        return {
+            msgId: undefined,
+            reply: Promise.reject(new Error("Endpoint worker is restarting")),
+        };
     }
+    const msgId = nextMsgId++;
+    const reply = new Promise((resolve, reject) => {
+        resolvers.set(msgId, { resolve, reject });
+    });
+    endpointWorker.postMessage({ ...msg, msgId });
+    return { msgId, reply };
+}
+
+function toWorker(msg: Record<string, unknown>) {
+    return sendMsg(msg).reply;
 }
 
 export async function initWorker(id: number) {
+    restarting = false;
     await toWorker({ cmd: "initWorker", id });
 }
 
+// Replaces the worker, whose isolate ran out of memory and was terminated by
+// the server. The messages that the old worker had fail, and so do the ones
+// that are sent before the server initializes the new worker.
+export function restartWorker() {
+    endpointWorker.terminate();
+    endpointWorker = startWorker();
+    restarting = true;
+
+    const err = new Error("Endpoint worker ran out of memory");
+    const pending = [...resolvers.values()];
+    resolvers.clear();
+    pendingCalls.clear();
+    for (const resolver of pending) {
+        resolver.reject(err);
+    }
+    for (const id of Object.keys(bodyParts)) {
+        pushBodyPart(Number(id), undefined, err);
+    }
+    for (const resolve of resolveAborts.values()) {
+        resolve();
+    }
+    resolveAborts.clear();
+}
+
 export async function readWorkerChannel() {
     await toWorker({ cmd: "readWorkerChannel" });
 }
@@ -123,15 +175,40 @@ export async function activateEndpoint(path: string) {
     });
 }
 
-export function endOfRequest(id: number) {
-    endpointWorker.postMessage({ cmd: "endOfRequest", id });
+export async function importTransform(path: string, version: number) {
+    await toWorker({
+        cmd: "importTransform",
+        path,
+        version,
+    });
+}
+
+export async function applyTransform(
+    path: string,
+    typeName: string,
+    row: string,
+) {
+    return await toWorker({
+        cmd: "applyTransform",
+        path,
+        typeName,
+        row,
+    });
+}
+
+// Aborts the request whose handler timed out. The worker stops the request
+// when it gets to the abort; if it is slow to, the server terminates the
+// JavaScript that it runs. The promise of its callHandler never settles.
+export function abortRequest(id: number) {
     delete bodyParts[id];
+    return new Promise<void>((resolve) => {
+        resolveAborts.set(id, resolve);
+    });
 }
 
-function clear() {
-    // Clear for the next request
-    bodyDone = false;
-    endMsgProcessing();
+export function endOfRequest(id: number) {
+    endpointWorker.postMessage({ cmd: "endOfRequest", id });
+    delete bodyParts[id];
 }
 
 export async function callHandler(
@@ -141,29 +218,20 @@ export async function callHandler(
 ) {
     bodyParts[id] = { parts: [], done: false };
 
+    const { msgId, reply } = sendMsg({
+        cmd: "callHandler",
+        path,
+        apiVersion,
+        id,
+    });
+    if (msgId !== undefined) {
+        pendingCalls.set(id, msgId);
+    }
     let res;
     try {
-        res = await sendMsg({
-            cmd: "callHandler",
-            path,
-            apiVersion,
-            id,
-        }) as { status: number; headers: number };
-    } catch (e) {
-        clear();
-        throw e;
-    }
-
-    let bodyDonePromise: Promise<void>;
-    if (!bodyDone) {
-        bodyDonePromise = new Promise<void>((resolve) => {
-            resolveBody = resolve;
-        });
-        // Don't await for the new promise. We want a background task
-        // to run clear once we have received the full body.
-        bodyDonePromise.then(clear);
-    } else {
-        clear();
+        res = await reply as { status: number; headers: number };
+    } finally {
+        pendingCalls.delete(id);
     }
 
     // The read function is called repeatedly until it returns
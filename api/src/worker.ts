@@ -19,6 +19,16 @@ Deno.core.setPromiseRejectCallback((type, _promise, reason) => {
     }
 });
 
+// Ops fail with this error class when a policy doesn't allow a write. See
+// get_error_class_name in deno.rs.
+class PolicyError extends Error {
+    constructor(message?: string) {
+        super(message);
+        this.name = "PolicyError";
+    }
+}
+Deno.core.registerErrorClass("PolicyError", PolicyError);
+
 type requestHandler = (req: Request) => Promise<Response>;
 // Handlers that have been compiled but are not yet serving
 // requests. The function activateEndpoint moves handler from
@@ -27,19 +37,112 @@ const nextHandlers: Record<string, requestHandler> = {};
 // A map from paths to functions that handle requests for that path.
 const handlers: Record<string, requestHandler> = {};
 
+type rowTransform = (
+    typeName: string,
+    row: Record<string, unknown>,
+) => Promise<Record<string, unknown>> | Record<string, unknown>;
+// Transforms used by `chisel populate`, keyed by path.
+const transforms: Record<string, rowTransform> = {};
+
 const requestContext = Chisel.requestContext;
 const ChiselRequest = Chisel.ChiselRequest;
 const loggedInUser = Chisel.loggedInUser;
 
+// A request that the worker is handling. The worker handles many at once, each
+// with its own context and transaction.
+type RequestState = {
+    id: number;
+    context: Chisel.RequestContext;
+    // Set once the handler returned a response.
+    responded: boolean;
+    // Set once the server is done with the response.
+    ended: boolean;
+    // Set if the request was aborted because its handler timed out. Whatever
+    // its handler does afterwards is not sent, nor committed.
+    aborted: boolean;
+    // Reads the body of the response, which is cancelled if the server is done
+    // with the response before the body ends.
+    reader?: ReadableStreamDefaultReader<Uint8Array>;
+};
+const requests = new Map<number, RequestState>();
+
+// The request whose code is running. Promise hooks carry it from the code that
+// creates a promise over to the code that runs once the promise settles, which
+// is how the code of each request sees its own requestContext.
+let runningRequest: RequestState | undefined;
+// The server reads the ID of the running request from here when a handler
+// times out, and only terminates the JavaScript that runs if it is the
+// handler's. It can't call into JavaScript for it, so this is a plain global.
+const runningRequestId = globalThis as unknown as {
+    __chiselRunningRequest: number | undefined;
+};
+function setRunningRequest(request: RequestState | undefined) {
+    runningRequest = request;
+    runningRequestId.__chiselRunningRequest = request?.id;
+}
+const promiseRequests = new WeakMap<Promise<unknown>, RequestState>();
+Deno.core.setPromiseHooks(
+    (promise: Promise<unknown>) => {
+        if (runningRequest !== undefined) {
+            promiseRequests.set(promise, runningRequest);
+        }
+    },
+    (promise: Promise<unknown>) => {
+        setRunningRequest(promiseRequests.get(promise));
+    },
+    () => {
+        setRunningRequest(undefined);
+    },
+    undefined,
+);
+Chisel.setRequestContextSource(() => runningRequest?.context);
+
+function formatLogArg(arg: unknown): string {
+    if (typeof arg === "string") {
+        return arg;
+    }
+    if (arg instanceof Error) {
+        return arg.stack ?? String(arg);
+    }
+    try {
+        return JSON.stringify(arg) ?? String(arg);
+    } catch (_) {
+        return String(arg);
+    }
+}
+
+// Console output of endpoints is written by the server, tagged with the ID of
+// the current request.
+for (const level of ["debug", "info", "log", "warn", "error"] as const) {
+    console[level] = (...args: unknown[]) => {
+        const message = args.map(formatLogArg).join(" ");
+        Deno.core.opSync(
+            "op_chisel_log",
+            level,
+            message,
+            requestContext.requestId,
+        );
+    };
+}
+
 function sendBodyPart(
     value: Uint8Array | undefined,
-    id: number,
+    request: RequestState,
     err?: unknown,
 ) {
-    postMessage({ msg: "body", value, err, id });
+    if (request.aborted) {
+        return;
+    }
+    postMessage({ msg: "body", value, err, id: request.id });
 }
 
-async function handleMsg(func: () => unknown) {
+// Replies to the message `msgId` with the result of `func`. The reply to a
+// request's callHandler message is its response.
+async function handleMsg(
+    func: () => unknown,
+    msgId: number,
+    request?: RequestState,
+) {
     let err = undefined;
     let value = undefined;
     try {
@@ -47,29 +150,58 @@ async function handleMsg(func: () => unknown) {
     } catch (e) {
         err = e;
     }
-    postMessage({ msg: "reply", value, err });
+    if (request !== undefined) {
+        if (request.aborted) {
+            return;
+        }
+        request.responded = true;
+    }
+    postMessage({ msg: "reply", msgId, value, err });
 }
 
-function initWorker(id: number) {
+function initWorker(id: number, msgId: number) {
     handleMsg(() => {
         Deno.core.opSync("op_chisel_init_worker", id);
-    });
+        abortTimedOutRequests();
+    }, msgId);
 }
 
-function readWorkerChannel() {
+function abort(request: RequestState) {
+    request.aborted = true;
+    requests.delete(request.id);
+    Deno.core.opSync("op_chisel_abort_request", request.id);
+}
+
+// Aborts the requests whose handlers time out. If the worker doesn't get to an
+// abort soon because the handler is stuck running, the server terminates its
+// JavaScript.
+async function abortTimedOutRequests() {
+    for (;;) {
+        const id = await Deno.core.opAsync("op_chisel_next_abort");
+        const request = requests.get(id);
+        const pending = request !== undefined && !request.responded;
+        if (pending) {
+            abort(request);
+        }
+        postMessage({ msg: "aborted", id, pending });
+    }
+}
+
+function readWorkerChannel(msgId: number) {
     handleMsg(() => {
         return Deno.core.opAsync("op_chisel_read_worker_channel");
-    });
+    }, msgId);
 }
 
 function importEndpoint(
     path: string,
     apiVersion: string,
     version: number,
+    msgId: number,
 ) {
     handleMsg(() => {
         return importEndpointImpl(path, apiVersion, version);
-    });
+    }, msgId);
 }
 
 async function importEndpointImpl(
@@ -94,19 +226,46 @@ async function importEndpointImpl(
     nextHandlers[path] = handler;
 }
 
-function activateEndpoint(path: string) {
+function activateEndpoint(path: string, msgId: number) {
     handleMsg(() => {
         handlers[path] = nextHandlers[path];
         delete nextHandlers[path];
-    });
+    }, msgId);
+}
+
+function importTransform(path: string, version: number, msgId: number) {
+    handleMsg(async () => {
+        const mod = await import(`file:///${path}.js?ver=${version}`);
+        const transform = mod.default;
+        if (typeof transform !== "function") {
+            throw new Error("populate transform must export a default function");
+        }
+        transforms[path] = transform;
+    }, msgId);
 }
 
-async function rollback_on_failure<T>(func: () => Promise<T>): Promise<T> {
+function applyTransform(
+    path: string,
+    typeName: string,
+    row: string,
+    msgId: number,
+) {
+    handleMsg(async () => {
+        const ret = await transforms[path](typeName, JSON.parse(row));
+        return JSON.stringify(ret);
+    }, msgId);
+}
+
+async function rollback_on_failure<T>(
+    func: () => Promise<T>,
+    request: RequestState,
+): Promise<T> {
     try {
         return await func();
     } catch (e) {
-        closeResources();
-        Deno.core.opSync("op_chisel_rollback_transaction");
+        if (!request.aborted) {
+            Deno.core.opSync("op_chisel_rollback_transaction", request.id);
+        }
         throw e;
     }
 }
@@ -128,18 +287,12 @@ function buildReadableStreamForBody(rid: number) {
     });
 }
 
-function closeResources() {
-    const resources = Deno.core.resources();
-    for (const k in resources) {
-        if (parseInt(k) > 2) {
-            Deno.core.opSync("op_close", k);
-        }
-    }
-}
-
+// The server closes the resources of a request, like its body and the
+// queries it didn't read to the end, when its transaction is committed or
+// rolled back.
 async function sendBody(
     reader: ReadableStreamDefaultReader<Uint8Array> | undefined,
-    id: number,
+    request: RequestState,
 ) {
     try {
         if (reader !== undefined) {
@@ -149,48 +302,171 @@ async function sendBody(
                 if (i % 16 == 0) {
                     await new Promise((resolve) => setTimeout(resolve, 0));
                 }
-                if (v.done || currentRequestId === undefined) {
+                if (v.done || request.ended) {
                     break;
                 }
-                sendBodyPart(v.value, id);
+                sendBodyPart(v.value, request);
             }
         }
-        closeResources();
-        await Deno.core.opAsync("op_chisel_commit_transaction");
+        if (request.aborted) {
+            return;
+        }
+        await Deno.core.opAsync("op_chisel_commit_transaction", request.id);
 
-        sendBodyPart(undefined, id);
+        sendBodyPart(undefined, request);
     } catch (e) {
-        closeResources();
-        Deno.core.opSync("op_chisel_rollback_transaction");
+        if (request.aborted) {
+            return;
+        }
+        Deno.core.opSync("op_chisel_rollback_transaction", request.id);
 
-        sendBodyPart(undefined, id, e);
+        sendBodyPart(undefined, request, e);
     }
 }
 
-let currentRequestId: number | undefined;
-async function callHandlerImpl(
-    path: string,
-    apiVersion: string,
-    id: number,
+// Answers the WebSocket handshake of `request`, which ends like a request
+// without a body, and serves the WebSocket once the connection is upgraded.
+function acceptWebSocket(
+    socket: Chisel.ChiselWebSocket,
+    request: RequestState,
 ) {
-    currentRequestId = id;
-    requestContext.apiVersion = apiVersion;
-    requestContext.path = path;
+    const { rid, accept } = Deno.core.opSync(
+        "op_chisel_websocket_accept",
+        request.id,
+    );
+    sendBody(undefined, request);
+    // The WebSocket outlives the request.
+    const running = runningRequest;
+    setRunningRequest(undefined);
+    try {
+        serveWebSocket(socket, rid, request.context);
+    } finally {
+        setRunningRequest(running);
+    }
+    const headers = [
+        ["upgrade", "websocket"],
+        ["connection", "Upgrade"],
+        ["sec-websocket-accept", accept],
+    ];
+    return { status: 101, headers };
+}
+
+// Dispatches the events of the WebSocket, one at a time.
+async function serveWebSocket(
+    socket: Chisel.ChiselWebSocket,
+    upgradeRid: number,
+    context: Chisel.RequestContext,
+) {
+    try {
+        socket.rid = await Deno.core.opAsync(
+            "op_chisel_websocket_open",
+            upgradeRid,
+        );
+    } catch (_) {
+        Deno.core.tryClose(upgradeRid);
+        socket.readyState = Chisel.ChiselWebSocket.CLOSED;
+        const event = new CloseEvent("close", { code: 1006 });
+        await handleWebSocketEvent(socket, event, context);
+        return;
+    }
+    socket.readyState = Chisel.ChiselWebSocket.OPEN;
+    await handleWebSocketEvent(socket, new Event("open"), context);
+    for (;;) {
+        let message;
+        try {
+            message = await Deno.core.opAsync(
+                "op_chisel_websocket_next",
+                socket.rid,
+            );
+        } catch (_) {
+            message = null;
+        }
+        if (message?.kind == "text" || message?.kind == "binary") {
+            let data = message.data;
+            if (message.kind == "binary") {
+                data = data.buffer.slice(
+                    data.byteOffset,
+                    data.byteOffset + data.byteLength,
+                );
+            }
+            const event = new MessageEvent("message", { data });
+            await handleWebSocketEvent(socket, event, context);
+            continue;
+        }
+        socket.readyState = Chisel.ChiselWebSocket.CLOSED;
+        await socket.flush();
+        Deno.core.tryClose(socket.rid);
+        // Connections that are lost have no close frame, which 1006 stands for.
+        const event = new CloseEvent("close", {
+            code: message?.code ?? 1006,
+            reason: message?.reason ?? "",
+            wasClean: message != null,
+        });
+        await handleWebSocketEvent(socket, event, context);
+        return;
+    }
+}
+
+// Runs the handlers of an event of a WebSocket in a request of their own,
+// with the user of the handshake, which commits their transaction once they
+// are done.
+async function handleWebSocketEvent(
+    socket: Chisel.ChiselWebSocket,
+    event: Event,
+    context: Chisel.RequestContext,
+) {
+    const id = Deno.core.opSync("op_chisel_start_message");
+    const request: RequestState = {
+        id,
+        // Unlike the GET of the handshake, messages may write data.
+        context: { ...context, method: "WEBSOCKET", handlerId: id },
+        responded: true,
+        ended: false,
+        aborted: false,
+    };
+    let handled;
+    const running = runningRequest;
+    setRunningRequest(request);
+    try {
+        handled = rollback_on_failure(async () => {
+            await socket.dispatch(event);
+            await Deno.core.opAsync("op_chisel_commit_transaction", id);
+        }, request);
+    } finally {
+        setRunningRequest(running);
+    }
+    try {
+        await handled;
+    } catch (e) {
+        console.error(`Error handling the ${event.type} of a WebSocket:`, e);
+    }
+}
 
-    const start = await Deno.core.opAsync("op_chisel_start_request");
+async function callHandlerImpl(request: RequestState) {
+    const { id, context } = request;
+    const start = await Deno.core.opAsync("op_chisel_start_request", id);
     if (start.Special) {
-        sendBodyPart(start.Special.body, id);
-        sendBodyPart(undefined, id);
+        sendBodyPart(start.Special.body, request);
+        sendBodyPart(undefined, request);
         return start.Special;
     }
-    const { userid, url, method, headers, body_rid } = start.Js;
-    requestContext.method = method;
-    requestContext.userId = userid;
-
-    // FIXME: maybe defer creating the transaction until we need one, to avoid doing it for
-    // endpoints that don't do any data access. For now, because we always create it above,
-    // it should be safe to unwrap.
-    await Deno.core.opAsync("op_chisel_create_transaction");
+    const {
+        userid,
+        username,
+        user_roles,
+        request_id,
+        url,
+        method,
+        headers,
+        body_rid,
+        params,
+        path_params,
+    } = start.Js;
+    context.method = method;
+    context.userId = userid;
+    context.username = username;
+    context.userRoles = user_roles;
+    context.requestId = request_id;
 
     const init: RequestInit = {
         method,
@@ -201,31 +477,57 @@ async function callHandlerImpl(
         const body = buildReadableStreamForBody(body_rid);
         init.body = body;
     }
+    const { apiVersion, path } = context;
     const fullPath = "/" + apiVersion + path;
-    const pathParams = new URL(url).pathname.replace(
-        /\/+/g,
-        "/",
-    ).replace(/\/$/, "").substring(fullPath.length + 1);
+    const decodedParams: Record<string, string> = {};
+    for (const [name, value] of Object.entries(params)) {
+        try {
+            decodedParams[name] = decodeURIComponent(value);
+        } catch (_) {
+            // Not valid percent-encoding, so it is taken literally.
+            decodedParams[name] = value;
+        }
+    }
     const user = await loggedInUser();
     const req = new ChiselRequest(
         url,
         init,
         apiVersion,
         path,
-        pathParams,
+        path_params,
         user,
+        decodedParams,
     );
 
     const res = await handlers[fullPath](req);
+    const socket = Chisel.webSocketUpgrades.get(res);
+    if (socket !== undefined) {
+        return acceptWebSocket(socket, request);
+    }
     const resHeaders = [];
     for (const h of res.headers) {
         resHeaders.push(h);
     }
+    // Event streams go on until the client disconnects, and get their data
+    // from subscriptions, which don't need the transaction of the request. It
+    // is committed before they start, so that it doesn't hold its locks all
+    // along.
+    const type = res.headers.get("content-type");
+    if (type?.startsWith("text/event-stream")) {
+        const committed = await Deno.core.opAsync(
+            "op_chisel_commit_before_stream",
+            id,
+        );
+        if (committed) {
+            Chisel.forgetTransaction(context);
+        }
+    }
     const reader = res.body?.getReader();
+    request.reader = reader;
 
     // Don't wait on sendBody as we want to send the body as a
     // background job.
-    sendBody(reader, id);
+    sendBody(reader, request);
 
     const status = res.status;
     return { status, headers: resHeaders };
@@ -235,21 +537,56 @@ function callHandler(
     path: string,
     apiVersion: string,
     id: number,
+    msgId: number,
 ) {
-    handleMsg(() => {
-        return rollback_on_failure(() => {
-            return callHandlerImpl(
-                path,
-                apiVersion,
-                id,
-            );
-        });
-    });
+    const request: RequestState = {
+        id,
+        context: {
+            path,
+            method: "",
+            apiVersion,
+            userRoles: [],
+            handlerId: id,
+        },
+        responded: false,
+        ended: false,
+        aborted: false,
+    };
+    requests.set(id, request);
+    // Whatever the handler does, including what it awaits, runs for this
+    // request.
+    setRunningRequest(request);
+    try {
+        handleMsg(async () => {
+            try {
+                return await rollback_on_failure(() => {
+                    return callHandlerImpl(request);
+                }, request);
+            } catch (e) {
+                if (e instanceof PolicyError) {
+                    // The transaction was already rolled back.
+                    sendBodyPart(
+                        new TextEncoder().encode(e.message + "\n"),
+                        request,
+                    );
+                    sendBodyPart(undefined, request);
+                    return { status: 403, headers: [] };
+                }
+                throw e;
+            }
+        }, msgId, request);
+    } finally {
+        setRunningRequest(undefined);
+    }
 }
 
 function endOfRequest(id: number) {
-    if (id == currentRequestId) {
-        currentRequestId = undefined;
+    const request = requests.get(id);
+    if (request !== undefined) {
+        request.ended = true;
+        requests.delete(id);
+        // Bodies that stream events may not end on their own.
+        request.reader?.cancel().catch(() => {});
     }
 }
 
@@ -257,22 +594,29 @@ onmessage = function (e) {
     const d = e.data;
     switch (d.cmd) {
         case "readWorkerChannel":
-            readWorkerChannel();
+            readWorkerChannel(d.msgId);
             break;
         case "initWorker":
-            initWorker(d.id);
+            initWorker(d.id, d.msgId);
             break;
         case "importEndpoint":
-            importEndpoint(d.path, d.apiVersion, d.version);
+            importEndpoint(d.path, d.apiVersion, d.version, d.msgId);
             break;
         case "activateEndpoint":
-            activateEndpoint(d.path);
+            activateEndpoint(d.path, d.msgId);
+            break;
+        case "importTransform":
+            importTransform(d.path, d.version, d.msgId);
+            break;
+        case "applyTransform":
+            applyTransform(d.path, d.typeName, d.row, d.msgId);
             break;
         case "callHandler":
             callHandler(
                 d.path,
                 d.apiVersion,
                 d.id,
+                d.msgId,
             );
             break;
         case "endOfRequest":
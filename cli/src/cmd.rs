@@ -10,3 +10,4 @@ macro_rules! execute {
 
 pub(crate) mod apply;
 pub(crate) mod dev;
+pub(crate) mod policy;
@@ -1,7 +1,9 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::chisel::chisel_rpc_client::ChiselRpcClient;
-use crate::chisel::{ChiselApplyRequest, EndPointCreationRequest, PolicyUpdateRequest};
+use crate::chisel::{
+    ChiselApplyRequest, EndPointCreationRequest, PolicyUpdateRequest, StaticFileRequest,
+};
 use crate::project::{read_manifest, read_to_string, Module, Optimize};
 use anyhow::{anyhow, Context, Result};
 use compile::compile_ts_code as swc_compile;
@@ -13,6 +15,7 @@ use std::process::Stdio;
 use tempfile::Builder;
 use tempfile::NamedTempFile;
 use tokio::task::{spawn_blocking, JoinHandle};
+use tonic::transport::Endpoint;
 use tsc_compile::compile_ts_code;
 use tsc_compile::CompileOptions;
 
@@ -66,7 +69,7 @@ impl From<bool> for TypeChecking {
 }
 
 pub(crate) async fn apply<S: ToString>(
-    server_url: String,
+    server_url: Endpoint,
     version: S,
     allow_type_deletion: AllowTypeDeletion,
     type_check: TypeChecking,
@@ -77,6 +80,7 @@ pub(crate) async fn apply<S: ToString>(
     let models = manifest.models()?;
     let endpoints = manifest.endpoints()?;
     let policies = manifest.policies()?;
+    let static_files = manifest.static_files()?;
 
     let types_req = crate::ts::parse_types(&models)?;
     let mut endpoints_req = vec![];
@@ -249,6 +253,28 @@ pub(crate) async fn apply<S: ToString>(
         });
     }
 
+    let mut static_req = vec![];
+    for f in static_files {
+        // An index file is served as its directory too.
+        let dir = f.name.strip_suffix("/index.html");
+        for route in std::iter::once(f.name.as_str()).chain(dir) {
+            if let Some(endpoint) = endpoints.iter().find(|e| e.name == route) {
+                anyhow::bail!(
+                    "Cannot serve {} as /{}, as it is the route of {}",
+                    f.file_path.display(),
+                    route,
+                    endpoint.file_path.display()
+                );
+            }
+        }
+        let content = std::fs::read(&f.file_path)
+            .with_context(|| format!("Could not read {}", f.file_path.display()))?;
+        static_req.push(StaticFileRequest {
+            path: f.name,
+            content,
+        });
+    }
+
     let package = match read_to_string("./package.json") {
         Ok(x) => {
             let val: serde_json::Result<serde_json::Value> = serde_json::from_str(&x);
@@ -290,6 +316,7 @@ pub(crate) async fn apply<S: ToString>(
                 version,
                 version_tag,
                 app_name,
+                static_files: static_req,
             }))
             .await
     );
@@ -302,6 +329,10 @@ pub(crate) async fn apply<S: ToString>(
         println!("End point defined: {}", end);
     }
 
+    for file in msg.static_files {
+        println!("Static file defined: {}", file);
+    }
+
     for lbl in msg.labels {
         println!("Policy defined for label {}", lbl);
     }
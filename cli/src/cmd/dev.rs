@@ -12,8 +12,9 @@ use std::collections::HashSet;
 use std::path::Path;
 use std::path::PathBuf;
 use std::time::Duration;
+use tonic::transport::Endpoint;
 
-pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()> {
+pub(crate) async fn cmd_dev(server_url: Endpoint, type_check: bool) -> Result<()> {
     let type_check = type_check.into();
     let manifest = read_manifest()?;
     let mut server = start_server()?;
@@ -39,6 +40,10 @@ pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()>
         let policies_dir = Path::new(policies_dir);
         apply_watcher.watch(policies_dir, RecursiveMode::Recursive)?;
     }
+    for static_dir in &manifest.static_dirs {
+        let static_dir = Path::new(static_dir);
+        apply_watcher.watch(static_dir, RecursiveMode::Recursive)?;
+    }
     while let Some(res) = rx.next().await {
         match res {
             Ok(Event {
@@ -65,7 +70,7 @@ pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()>
     Ok(())
 }
 
-async fn apply_from_dev(server_url: String, type_check: TypeChecking) {
+async fn apply_from_dev(server_url: Endpoint, type_check: TypeChecking) {
     if let Err(e) = apply(
         server_url,
         DEFAULT_API_VERSION,
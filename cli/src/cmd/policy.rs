@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::PolicyTestRequest;
+use crate::project::{read_manifest, read_to_string};
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use tonic::transport::Endpoint;
+
+/// Runs the test cases of `cases` against the policies and models of the project, and prints the
+/// outcome of each case. Fails if any case doesn't meet its expectations.
+///
+/// The cases are run by the server, with the policy code that serves requests, but nothing is
+/// applied to it.
+pub(crate) async fn test_policies(server_url: Endpoint, cases: &Path) -> Result<()> {
+    let manifest = read_manifest().with_context(|| "Reading manifest file".to_string())?;
+    let types = crate::ts::parse_types(&manifest.models()?)?;
+    // The server only applies the first policy file, so that's the one tested.
+    let policy_config = match manifest.policies()?.first() {
+        Some(p) => read_to_string(p)?,
+        None => String::new(),
+    };
+    let cases = read_to_string(cases)?;
+
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let response = execute!(
+        client
+            .policy_test(tonic::Request::new(PolicyTestRequest {
+                types,
+                policy_config,
+                cases,
+            }))
+            .await
+    );
+    let results = response.cases;
+    let mut failed = 0;
+    for r in &results {
+        let access = match r.denied {
+            false => "allowed".to_owned(),
+            true => format!("denied with {}", r.status),
+        };
+        let passed = r.failures.is_empty();
+        print!(
+            "{} {}: {}, transformed [{}]",
+            if passed { "ok" } else { "FAILED" },
+            r.name,
+            access,
+            r.transformed.join(", ")
+        );
+        if !passed {
+            failed += 1;
+            print!("; {}", r.failures.join(", "));
+        }
+        println!();
+        if let Some(row) = &r.row {
+            println!("    row: {}", row);
+        }
+    }
+    anyhow::ensure!(
+        failed == 0,
+        "{} of {} policy test cases failed",
+        failed,
+        results.len()
+    );
+    println!("{} policy test cases passed", results.len());
+    Ok(())
+}
@@ -2,18 +2,23 @@
 
 use crate::cmd::apply::apply;
 use crate::cmd::dev::cmd_dev;
-use crate::project::{create_project, CreateProjectOptions};
+use crate::cmd::policy::test_policies;
+use crate::project::{create_project, read_to_string, CreateProjectOptions};
 use crate::server::{start_server, wait};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use chisel::chisel_rpc_client::ChiselRpcClient;
 use chisel::{
     ChiselDeleteRequest, DescribeRequest, PopulateRequest, RestartRequest, StatusRequest,
 };
 use std::env;
+use std::ffi::OsStr;
 use std::fs;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+use tsc_compile::compile_ts_code;
+use tsc_compile::CompileOptions;
 
 mod chisel;
 mod cmd;
@@ -31,9 +36,21 @@ pub(crate) static DEFAULT_API_VERSION: &str = "dev";
 #[derive(StructOpt, Debug)]
 #[structopt(name = "chisel", version = env!("VERGEN_GIT_SEMVER_LIGHTWEIGHT"))]
 struct Opt {
-    /// RPC server address.
+    /// RPC server address. With an https address, the server must present a certificate for its
+    /// host name.
     #[structopt(short, long, default_value = "http://localhost:50051")]
     rpc_addr: String,
+    /// PEM file with the CA that the certificate of an https RPC server is signed by, if it isn't
+    /// a well-known one.
+    #[structopt(long)]
+    rpc_ca_cert: Option<PathBuf>,
+    /// PEM file with the certificate that chisel presents to an https RPC server that requires
+    /// client certificates.
+    #[structopt(long)]
+    rpc_client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the --rpc-client-cert certificate.
+    #[structopt(long)]
+    rpc_client_key: Option<PathBuf>,
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -99,10 +116,56 @@ enum Command {
         version: String,
         #[structopt(long)]
         from: String,
+        /// YAML file describing how types and fields of the source version map to the new one.
+        #[structopt(long)]
+        mapping: Option<PathBuf>,
+        /// TypeScript or JavaScript module whose default export is applied to every row.
+        #[structopt(long)]
+        transform: Option<PathBuf>,
+    },
+    /// Work with the policies of the project.
+    Policy {
+        #[structopt(subcommand)]
+        cmd: PolicyCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum PolicyCommand {
+    /// Check the policies against the cases of a test file, without applying them.
+    Test {
+        /// YAML file of test cases.
+        cases: PathBuf,
     },
 }
 
-async fn delete<S: ToString>(server_url: String, version: S) -> Result<()> {
+impl Opt {
+    /// Returns the RPC server endpoint, which uses TLS if the address is https.
+    fn rpc_endpoint(&self) -> Result<Endpoint> {
+        let endpoint = Endpoint::from_shared(self.rpc_addr.clone())
+            .with_context(|| format!("bad RPC server address {}", self.rpc_addr))?;
+        if endpoint.uri().scheme_str() != Some("https") {
+            return Ok(endpoint);
+        }
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca_cert) = &self.rpc_ca_cert {
+            tls = tls.ca_certificate(Certificate::from_pem(read_to_string(ca_cert)?));
+        }
+        match (&self.rpc_client_cert, &self.rpc_client_key) {
+            (Some(cert), Some(key)) => {
+                tls = tls.identity(Identity::from_pem(
+                    read_to_string(cert)?,
+                    read_to_string(key)?,
+                ));
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("--rpc-client-cert and --rpc-client-key must be given together"),
+        }
+        Ok(endpoint.tls_config(tls)?)
+    }
+}
+
+async fn delete<S: ToString>(server_url: Endpoint, version: S) -> Result<()> {
     let version = version.to_string();
     let mut client = ChiselRpcClient::connect(server_url).await?;
 
@@ -115,7 +178,38 @@ async fn delete<S: ToString>(server_url: String, version: S) -> Result<()> {
     Ok(())
 }
 
-async fn populate(server_url: String, to_version: String, from_version: String) -> Result<()> {
+async fn populate(
+    server_url: Endpoint,
+    to_version: String,
+    from_version: String,
+    mapping: Option<PathBuf>,
+    transform: Option<PathBuf>,
+) -> Result<()> {
+    let mapping = match mapping {
+        Some(mapping) => read_to_string(&mapping)?,
+        None => String::new(),
+    };
+    let transform = match transform {
+        Some(transform) => {
+            let path = transform.to_str().ok_or_else(|| {
+                anyhow!(
+                    "populate transform path {} is not valid UTF-8",
+                    transform.display()
+                )
+            })?;
+            if transform.extension() == Some(OsStr::new("ts")) {
+                let mut code = compile_ts_code(path, CompileOptions::default())
+                    .await
+                    .with_context(|| format!("parsing populate transform {}", path))?;
+                code.remove(path).ok_or_else(|| {
+                    anyhow!("compiling populate transform {} produced no code", path)
+                })?
+            } else {
+                read_to_string(&transform)?
+            }
+        }
+        None => String::new(),
+    };
     let mut client = ChiselRpcClient::connect(server_url).await?;
 
     let msg = execute!(
@@ -123,6 +217,8 @@ async fn populate(server_url: String, to_version: String, from_version: String)
             .populate(tonic::Request::new(PopulateRequest {
                 to_version,
                 from_version,
+                mapping,
+                transform,
             }))
             .await
     );
@@ -133,7 +229,7 @@ async fn populate(server_url: String, to_version: String, from_version: String)
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let server_url = opt.rpc_addr;
+    let server_url = opt.rpc_endpoint()?;
     match opt.cmd {
         Command::Init {
             force,
@@ -274,8 +370,18 @@ async fn main() -> Result<()> {
         Command::Delete { version } => {
             delete(server_url, version).await?;
         }
-        Command::Populate { version, from } => {
-            populate(server_url, version, from).await?;
+        Command::Populate {
+            version,
+            from,
+            mapping,
+            transform,
+        } => {
+            populate(server_url, version, from, mapping, transform).await?;
+        }
+        Command::Policy {
+            cmd: PolicyCommand::Test { cases },
+        } => {
+            test_policies(server_url, &cases).await?;
         }
     }
     Ok(())
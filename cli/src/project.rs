@@ -3,7 +3,7 @@
 use anyhow::{anyhow, Context, Result};
 use handlebars::Handlebars;
 use serde_derive::Deserialize;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fs;
 use std::io::{stdin, Read};
@@ -49,6 +49,13 @@ pub(crate) struct Endpoint {
     pub(crate) file_path: PathBuf,
 }
 
+#[derive(PartialOrd, PartialEq, Eq, Ord)]
+pub(crate) struct StaticFile {
+    /// Path that the file is served at, relative to the API version.
+    pub(crate) name: String,
+    pub(crate) file_path: PathBuf,
+}
+
 /// Manifest defines the files that describe types, endpoints, and policies.
 ///
 /// The manifest is a high-level declaration of application behavior.
@@ -63,6 +70,9 @@ pub(crate) struct Manifest {
     pub(crate) endpoints: Vec<String>,
     /// Vector of directories to scan for policy definitions.
     pub(crate) policies: Vec<String>,
+    /// Vector of directories whose files are served as they are.
+    #[serde(rename = "static", default)]
+    pub(crate) static_dirs: Vec<String>,
     /// Whether to use deno-style or node-style modules
     #[serde(default)]
     pub(crate) modules: Module,
@@ -92,7 +102,9 @@ impl Manifest {
 
                 let name = parent.strip_prefix(&dir)?;
 
-                if let Some(old) = routes.insert(name.to_owned(), file_path.to_owned()) {
+                let route = route_template(name)
+                    .with_context(|| format!("bad route for {}", file_path.display()))?;
+                if let Some(old) = routes.insert(route, file_path.to_owned()) {
                     anyhow::bail!("Cannot add both {} {} as routes. ChiselStrike uses filesystem-based routing, so we don't know what to do. Sorry! 🥺", old.display(), file_path.display());
                 }
 
@@ -107,6 +119,36 @@ impl Manifest {
         Ok(ret)
     }
 
+    pub fn static_files(&self) -> anyhow::Result<Vec<StaticFile>> {
+        let mut ret = vec![];
+        let mut names = BTreeMap::new();
+        for dir in &self.static_dirs {
+            let mut paths = vec![];
+            let dir = Path::new(dir);
+            dir_to_paths(dir, &mut paths)?;
+            for file_path in paths {
+                let name = file_path.strip_prefix(&dir)?;
+                let name = name
+                    .iter()
+                    .map(|c| c.to_str())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow!("filename is not utf8 {:?}", name))?
+                    .join("/");
+                if let Some(old) = names.insert(name.clone(), file_path.to_owned()) {
+                    anyhow::bail!(
+                        "Cannot serve both {} and {} as /{}",
+                        old.display(),
+                        file_path.display(),
+                        name
+                    );
+                }
+                ret.push(StaticFile { name, file_path });
+            }
+        }
+        ret.sort_unstable();
+        Ok(ret)
+    }
+
     pub fn policies(&self) -> anyhow::Result<Vec<PathBuf>> {
         Self::dirs_to_paths(&self.policies)
     }
@@ -121,6 +163,47 @@ impl Manifest {
     }
 }
 
+/// Returns the template of the route of the endpoint `name`, with the names of its parameters left
+/// out, as two routes that differ only in them match the same requests. For example,
+/// `posts/[id]/comments` has the template `posts/[]/comments`.
+fn route_template(name: &Path) -> Result<PathBuf> {
+    let mut template = PathBuf::new();
+    let mut params = HashSet::new();
+    let mut components = name.iter().peekable();
+    while let Some(component) = components.next() {
+        let param = component
+            .to_str()
+            .and_then(|c| c.strip_prefix('['))
+            .and_then(|c| c.strip_suffix(']'));
+        let param = match param {
+            Some(param) => param,
+            None => {
+                template.push(component);
+                continue;
+            }
+        };
+        let (param, segment) = match param.strip_prefix("...") {
+            Some(param) => {
+                anyhow::ensure!(
+                    components.peek().is_none(),
+                    "the catch-all parameter [...{}] must be last",
+                    param
+                );
+                (param, "[...]")
+            }
+            None => (param, "[]"),
+        };
+        anyhow::ensure!(!param.is_empty(), "parameters must have a name");
+        anyhow::ensure!(
+            params.insert(param),
+            "the parameter {} appears more than once",
+            param
+        );
+        template.push(segment);
+    }
+    Ok(template)
+}
+
 fn dir_to_paths(dir: &Path, paths: &mut Vec<PathBuf>) -> anyhow::Result<()> {
     for dentry in read_dir(dir)? {
         let dentry = dentry?;
@@ -7,7 +7,7 @@ use std::future::Future;
 use std::io::ErrorKind;
 use std::thread;
 use std::time::Duration;
-use tonic::transport::Channel;
+use tonic::transport::{Channel, Endpoint};
 
 pub(crate) fn start_server() -> anyhow::Result<std::process::Child> {
     println!("🚀 Thank you for your interest in the ChiselStrike beta! 🚀");
@@ -66,7 +66,7 @@ where
     }
 }
 
-async fn connect_with_retry(server_url: String) -> Result<ChiselRpcClient<Channel>> {
+async fn connect_with_retry(server_url: Endpoint) -> Result<ChiselRpcClient<Channel>> {
     with_retry(TIMEOUT, (), |_| async {
         let c = ChiselRpcClient::connect(server_url.clone()).await;
         c.map_err(|_| ())
@@ -77,7 +77,7 @@ async fn connect_with_retry(server_url: String) -> Result<ChiselRpcClient<Channe
 // Timeout when waiting for connection or server status.
 const TIMEOUT: Duration = Duration::from_secs(10);
 
-pub(crate) async fn wait(server_url: String) -> Result<tonic::Response<StatusResponse>> {
+pub(crate) async fn wait(server_url: Endpoint) -> Result<tonic::Response<StatusResponse>> {
     let client = connect_with_retry(server_url).await?;
     with_retry(TIMEOUT, client, |mut client| async {
         let request = tonic::Request::new(StatusRequest {});
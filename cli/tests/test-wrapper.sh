@@ -31,7 +31,13 @@ else
     DB_URL="sqlite://$TEMPDIR/chiseld.db?mode=rwc"
 fi
 
-$CHISELD --webui --db-uri "$DB_URL" --api-listen-addr "$CHISELD_HOST" --internal-routes-listen-addr "$CHISELD_INTERNAL" --rpc-listen-addr $CHISELD_RPC_HOST &
+# Tests can give chiseld more flags with a `# CHISELD_ARGS: <flags>` line.
+TEST_FILE=$(echo "$2" | awk '{print $NF}')
+CHISELD_ARGS=$(sed -n 's/^# CHISELD_ARGS: //p' "$TEST_FILE")
+
+# The output of chiseld is also kept in chiseld.log, for tests to check it.
+export CHISELD_LOG="$TEMPDIR/chiseld.log"
+$CHISELD --webui --db-uri "$DB_URL" --api-listen-addr "$CHISELD_HOST" --internal-routes-listen-addr "$CHISELD_INTERNAL" --rpc-listen-addr $CHISELD_RPC_HOST $CHISELD_ARGS > >(tee -a "$CHISELD_LOG") 2> >(tee -a "$CHISELD_LOG" >&2) &
 PID=$!
 
 function cleanup() {
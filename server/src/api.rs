@@ -1,28 +1,37 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
-use crate::prefix_map::PrefixMap;
+use crate::compression;
+use crate::cors::{default_preflight, Cors};
+use crate::policies::VersionPolicy;
+use crate::prefix_map::{PathMatch, PrefixMap};
+use crate::rate_limit::{client_id, ClientKey, DeferredRateLimit, RateLimiter, RateLimits};
+use crate::telemetry;
+use crate::tls::{Conn, Tls};
 use anyhow::{Error, Result};
 use futures::future::LocalBoxFuture;
 use futures::ready;
 use futures::stream::Stream;
 use hyper::body::HttpBody;
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderValue, ORIGIN, RETRY_AFTER};
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{HeaderMap, Request, Response, Server, StatusCode};
-use socket2::{Domain, Protocol, Socket, Type};
+use hyper::{HeaderMap, Method, Request, Response, Server, StatusCode};
+use opentelemetry::trace::{FutureExt, TraceContextExt};
+use opentelemetry::KeyValue;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::convert::TryFrom;
 use std::io::Cursor;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-type JsStream = Pin<Box<dyn Stream<Item = Result<Box<[u8]>>>>>;
+pub(crate) type JsStream = Pin<Box<dyn Stream<Item = Result<Box<[u8]>>>>>;
 
 pub(crate) enum Body {
     Const(Option<Box<[u8]>>),
@@ -147,6 +156,19 @@ impl ApiInfo {
 }
 pub(crate) type ApiInfoMap = HashMap<PathBuf, ApiInfo>;
 
+/// The parts of an API version's policies that are enforced before routing requests.
+#[derive(Default)]
+struct ApiPolicy {
+    rate_limits: RateLimits,
+    cors: Option<Cors>,
+    timeouts: PrefixMap<Duration>,
+}
+
+/// How long the endpoint of a request may take to respond. The API service sets it as an
+/// extension of the requests it routes.
+#[derive(Clone, Copy)]
+pub(crate) struct RequestTimeout(pub(crate) Duration);
+
 /// API service for Chisel server.
 pub(crate) struct ApiService {
     // Although we are on a TPC environment, this sync mutex should be fine. It will
@@ -155,23 +177,36 @@ pub(crate) struct ApiService {
     // have to manually implement Send (which is unsafe).
     paths: Mutex<PrefixMap<RouteFn>>,
     info: Mutex<ApiInfoMap>,
+    /// Policies of each API version.
+    policies: Mutex<HashMap<String, ApiPolicy>>,
+    /// Token buckets, shared with the other executor threads.
+    rate_limiter: Arc<RateLimiter>,
+    /// Timeout of the endpoints that policies don't give one.
+    default_timeout: Option<Duration>,
 }
 
 impl ApiService {
-    pub(crate) fn new(mut info: ApiInfoMap) -> Self {
+    pub(crate) fn new(
+        mut info: ApiInfoMap,
+        rate_limiter: Arc<RateLimiter>,
+        default_timeout: Option<Duration>,
+    ) -> Self {
         info.insert("__chiselstrike".into(), ApiInfo::chiselstrike());
         info.insert("".into(), ApiInfo::all_routes());
         Self {
             paths: Default::default(),
             info: Mutex::new(info),
+            policies: Default::default(),
+            rate_limiter,
+            default_timeout,
         }
     }
 
-    /// Finds the right RouteFn for this request.
-    fn find_route_fn<S: AsRef<Path>>(&self, request: S) -> Option<RouteFn> {
-        match self.paths.lock().unwrap().longest_prefix(request.as_ref()) {
+    /// Finds the right RouteFn for this request, and the parameters of its path template.
+    fn find_route_fn<S: AsRef<Path>>(&self, request: S) -> Option<(RouteFn, PathMatch)> {
+        match self.paths.lock().unwrap().find(request.as_ref()) {
             None => None,
-            Some((_, f)) => Some(f.clone()),
+            Some((_, f, path_match)) => Some((f.clone(), path_match)),
         }
     }
 
@@ -203,6 +238,96 @@ impl ApiService {
         self.info.lock().unwrap().get(api_version.as_ref()).cloned()
     }
 
+    /// Sets the policies of an API version, replacing the previous ones.
+    pub(crate) fn set_policy(&self, api_version: &str, policy: &VersionPolicy) {
+        let policy = ApiPolicy {
+            rate_limits: policy.rate_limits.clone(),
+            cors: policy.cors.clone(),
+            timeouts: policy.timeouts.clone(),
+        };
+        self.policies
+            .lock()
+            .unwrap()
+            .insert(api_version.to_owned(), policy);
+    }
+
+    pub(crate) fn remove_policy(&self, api_version: &str) {
+        self.policies.lock().unwrap().remove(api_version);
+    }
+
+    /// The CORS policy of the API version of this request, if it has one.
+    fn find_cors(&self, req: &Request<hyper::Body>) -> Option<Cors> {
+        let rp = RequestPath::try_from(req.uri().path()).ok()?;
+        let policies = self.policies.lock().unwrap();
+        policies.get(rp.api_version())?.cors.clone()
+    }
+
+    /// How long the endpoint of this request may take to respond, if it is limited.
+    fn find_timeout(&self, req: &Request<hyper::Body>) -> Option<Duration> {
+        let rp = RequestPath::try_from(req.uri().path()).ok();
+        let timeout = rp.and_then(|rp| {
+            let policies = self.policies.lock().unwrap();
+            let timeouts = &policies.get(rp.api_version())?.timeouts;
+            timeouts
+                .longest_prefix(rp.path().as_ref())
+                .map(|(_, timeout)| *timeout)
+        });
+        timeout.or(self.default_timeout)
+    }
+
+    /// Takes a token from the client's bucket if the request is rate limited by remote address.
+    /// Returns the response to send instead of running the request when there is none left.
+    /// Limits keyed by user or API key are attached to the request, for the executor to check
+    /// once it has validated the credentials.
+    fn check_rate_limit(
+        &self,
+        req: &mut Request<hyper::Body>,
+        remote_addr: &SocketAddr,
+    ) -> Result<Option<Response<Body>>> {
+        let rp = match RequestPath::try_from(req.uri().path()) {
+            Ok(rp) => rp,
+            Err(_) => return Ok(None),
+        };
+        let (prefix, limit) = {
+            let policies = self.policies.lock().unwrap();
+            let limits = match policies.get(rp.api_version()) {
+                Some(policy) => &policy.rate_limits,
+                None => return Ok(None),
+            };
+            match limits.longest_prefix(rp.path().as_ref()) {
+                Some((prefix, limit)) => (prefix.to_owned(), limit.clone()),
+                None => return Ok(None),
+            }
+        };
+        let prefix = format!("{}:{}", rp.api_version(), prefix.display());
+        if limit.key() != ClientKey::Ip {
+            req.extensions_mut().insert(DeferredRateLimit {
+                limiter: self.rate_limiter.clone(),
+                limit,
+                prefix,
+                remote_addr: *remote_addr,
+            });
+            return Ok(None);
+        }
+        let bucket = format!(
+            "{}:{}",
+            prefix,
+            client_id(limit.key(), None, None, remote_addr)
+        );
+        match self.rate_limiter.acquire(bucket, &limit, Instant::now()) {
+            Ok(()) => Ok(None),
+            Err(wait) => Ok(Some(Self::too_many_requests(wait)?)),
+        }
+    }
+
+    /// Response to a request over its rate limit, which may be retried after `wait`.
+    pub(crate) fn too_many_requests(wait: Duration) -> Result<Response<Body>> {
+        Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, wait.as_secs_f64().ceil() as u64)
+            .body("Too many requests\n".to_string().into())?)
+    }
+
     pub(crate) fn routes(&self) -> Vec<String> {
         let mut result = vec![];
         for (path, _) in self.paths.lock().unwrap().iter() {
@@ -211,18 +336,69 @@ impl ApiService {
         result
     }
 
-    async fn route_impl(&self, req: Request<hyper::Body>) -> Result<Response<Body>> {
-        if let Some(route_fn) = self.find_route_fn(req.uri().path()) {
-            return route_fn(req).await;
+    async fn route_impl(
+        &self,
+        mut req: Request<hyper::Body>,
+        remote_addr: SocketAddr,
+    ) -> Result<Response<Body>> {
+        let (route_fn, path_match) = {
+            let _span = telemetry::span("route lookup");
+            match self.find_route_fn(req.uri().path()) {
+                Some(found) => found,
+                None => return ApiService::not_found(),
+            }
+        };
+        let cors = self.find_cors(&req);
+        // Preflights are sent by browsers on their own, so they aren't charged to the client.
+        if req.method() == Method::OPTIONS {
+            return match cors {
+                Some(cors) => cors.preflight(&req),
+                None => default_preflight(),
+            };
+        }
+        if let Some(response) = self.check_rate_limit(&mut req, &remote_addr)? {
+            return Ok(response);
+        }
+        let origin = req.headers().get(ORIGIN).cloned();
+        if let Some(timeout) = self.find_timeout(&req) {
+            req.extensions_mut().insert(RequestTimeout(timeout));
+        }
+        req.extensions_mut().insert(path_match);
+        let mut response = route_fn(req).await?;
+        if let Some(cors) = cors {
+            cors.apply(origin.as_ref(), response.headers_mut());
         }
-        ApiService::not_found()
+        Ok(response)
     }
 
-    async fn route(&self, req: Request<hyper::Body>) -> hyper::http::Result<Response<Body>> {
-        match self.route_impl(req).await {
+    async fn route(
+        &self,
+        req: Request<hyper::Body>,
+        remote_addr: SocketAddr,
+    ) -> hyper::http::Result<Response<Body>> {
+        let encoding = compression::negotiate(req.headers());
+        let cx = telemetry::request_context(
+            format!("HTTP {}", req.method()),
+            req.headers(),
+            vec![
+                KeyValue::new("http.method", req.method().to_string()),
+                KeyValue::new("http.target", req.uri().to_string()),
+            ],
+        );
+        let response = match self
+            .route_impl(req, remote_addr)
+            .with_context(cx.clone())
+            .await
+        {
             Ok(val) => Ok(val),
             Err(err) => Self::internal_error(err),
+        };
+        if let Ok(response) = &response {
+            let status = response.status().as_u16() as i64;
+            cx.span()
+                .set_attribute(KeyValue::new("http.status_code", status));
         }
+        response.map(|response| compression::compress(encoding, response))
     }
 
     pub(crate) fn not_found() -> Result<Response<Body>> {
@@ -231,15 +407,19 @@ impl ApiService {
             .body(Body::default())?)
     }
 
-    fn internal_error(err: anyhow::Error) -> hyper::http::Result<Response<Body>> {
+    pub(crate) fn internal_error(err: anyhow::Error) -> hyper::http::Result<Response<Body>> {
         Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
             .body(format!("{:?}\n", err).into())
     }
 
     pub(crate) fn forbidden(err: &str) -> Result<Response<Body>> {
+        Self::error_response(StatusCode::FORBIDDEN, err)
+    }
+
+    pub(crate) fn error_response(status: StatusCode, err: &str) -> Result<Response<Body>> {
         Ok(Response::builder()
-            .status(StatusCode::FORBIDDEN)
+            .status(status)
             .body(err.to_string().into())?)
     }
 }
@@ -265,35 +445,30 @@ where
 pub(crate) fn spawn(
     api: Rc<ApiService>,
     listen_addr: String,
+    tls: Option<Arc<Tls>>,
     shutdown: async_channel::Receiver<()>,
 ) -> Result<Vec<tokio::task::JoinHandle<Result<(), hyper::Error>>>> {
     let mut tasks = Vec::new();
     let sock_addrs = listen_addr.to_socket_addrs()?;
+    let acceptor = tls.map(|tls| tls.acceptor(&["h2", "http/1.1"]));
     for addr in sock_addrs {
         debug!("{} has address {:?}", listen_addr, addr);
         let api = api.clone();
         let shutdown = shutdown.clone();
-        let domain = if addr.is_ipv6() {
-            Domain::ipv6()
-        } else {
-            Domain::ipv4()
-        };
-        let sk = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
-        let addr = socket2::SockAddr::from(addr);
-        sk.set_reuse_port(true)?;
-        sk.bind(&addr)?;
-        sk.listen(1024)?;
+        let sk = crate::listeners::listen(addr)?;
+        let incoming = crate::tls::incoming(sk.into_tcp_listener(), acceptor.clone())?;
 
-        let make_svc = make_service_fn(move |_conn| {
+        let make_svc = make_service_fn(move |conn: &Conn| {
             let api = api.clone();
+            let remote_addr = conn.remote_addr();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
                     let api = api.clone();
-                    async move { api.route(req).await }
+                    async move { api.route(req, remote_addr).await }
                 }))
             }
         });
-        let server = Server::from_tcp(sk.into_tcp_listener())?
+        let server = Server::builder(accept::from_stream(incoming))
             .executor(LocalExec)
             .serve(make_svc);
         let task = tokio::task::spawn_local(async move {
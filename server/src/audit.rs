@@ -0,0 +1,178 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Audit log of the changes to the entities that policies mark as audited.
+//!
+//! Each change is recorded as an `AuditRecord` entity, inserted in the transaction of the request
+//! that makes the change, so the record and the change are committed or rolled back together.
+
+use crate::api::ApiService;
+use crate::datastore::changes::Change;
+use crate::datastore::QueryEngine;
+use crate::policies::WritePermissions;
+use crate::types::ObjectType;
+use crate::JsonObject;
+use anyhow::Result;
+use serde_json::json;
+use sqlx::any::Any;
+use sqlx::Transaction;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const AUDIT_RECORD_NAME: &str = "AuditRecord";
+
+/// Adds the endpoint that lists audit records, which can't be changed through it.
+pub(crate) async fn init(api: &mut ApiService) -> Result<()> {
+    crate::server::add_endpoint(
+        "/__chiselstrike/audit",
+        format!(
+            r#"
+import {{ ChiselEntity, responseFromJson }} from "@chiselstrike/api"
+class {AUDIT_RECORD_NAME} extends ChiselEntity {{}}
+const crud = {AUDIT_RECORD_NAME}.crud();
+export default async function chisel(req: Request) {{
+    if (req.method != "GET") {{
+        return responseFromJson("Audit records are read-only", 405);
+    }}
+    return crud(req);
+}}"#
+        ),
+        api,
+    )
+    .await
+}
+
+/// Records the changes that a request makes to entities of one type.
+pub(crate) struct Auditor {
+    /// Type of the audit records.
+    record_type: Arc<ObjectType>,
+    /// Type of the changed entities.
+    ty: Arc<ObjectType>,
+    user_id: Option<String>,
+    path: String,
+    api_version: String,
+}
+
+impl Auditor {
+    pub(crate) fn new(
+        record_type: Arc<ObjectType>,
+        ty: Arc<ObjectType>,
+        user_id: Option<String>,
+        path: String,
+        api_version: String,
+    ) -> Self {
+        Self {
+            record_type,
+            ty,
+            user_id,
+            path,
+            api_version,
+        }
+    }
+
+    /// Records a change to the entity with this `id`. `before` is `None` for a creation, and
+    /// `after` is `None` for a deletion. Returns the changes that writing the record makes.
+    pub(crate) async fn record(
+        &self,
+        query_engine: &QueryEngine,
+        transaction: &mut Transaction<'_, Any>,
+        id: &str,
+        before: Option<&JsonObject>,
+        after: Option<&JsonObject>,
+    ) -> Result<Vec<Change>> {
+        let record = make_record(
+            self,
+            id,
+            before,
+            after,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as f64,
+        );
+        let id_tree = query_engine
+            .add_row(
+                &self.record_type,
+                &record,
+                &WritePermissions::default(),
+                Some(transaction),
+            )
+            .await?;
+        Ok(id_tree.changes(&self.record_type))
+    }
+}
+
+fn make_record(
+    auditor: &Auditor,
+    id: &str,
+    before: Option<&JsonObject>,
+    after: Option<&JsonObject>,
+    timestamp: f64,
+) -> JsonObject {
+    let action = match (before, after) {
+        (None, _) => "create",
+        (Some(_), Some(_)) => "update",
+        (Some(_), None) => "delete",
+    };
+    let mut record = JsonObject::new();
+    record.insert("timestamp".into(), json!(timestamp));
+    if let Some(user_id) = &auditor.user_id {
+        record.insert("userId".into(), json!(user_id));
+    }
+    record.insert("path".into(), json!(auditor.path));
+    record.insert("apiVersion".into(), json!(auditor.api_version));
+    record.insert("entity".into(), json!(auditor.ty.name()));
+    record.insert("entityId".into(), json!(id));
+    record.insert("action".into(), json!(action));
+    if let Some(before) = before {
+        record.insert(
+            "before".into(),
+            json!(serde_json::to_string(before).unwrap()),
+        );
+    }
+    if let Some(after) = after {
+        record.insert("after".into(), json!(serde_json::to_string(after).unwrap()));
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::make_object;
+
+    #[test]
+    fn records() {
+        let auditor = Auditor::new(
+            make_object(AUDIT_RECORD_NAME, vec![]),
+            make_object("Account", vec![]),
+            Some("u1".into()),
+            "/accounts".into(),
+            "dev".into(),
+        );
+        let row = |balance: i64| json!({"id": "a1", "balance": balance}).as_object().cloned();
+        let (before, after) = (row(10), row(20));
+
+        let mut record = make_record(&auditor, "a1", before.as_ref(), after.as_ref(), 5.0);
+        let parse = |v: serde_json::Value| -> serde_json::Value {
+            serde_json::from_str(v.as_str().unwrap()).unwrap()
+        };
+        assert_eq!(parse(record.remove("before").unwrap()), json!(before));
+        assert_eq!(parse(record.remove("after").unwrap()), json!(after));
+        assert_eq!(
+            serde_json::Value::Object(record),
+            json!({
+                "timestamp": 5.0,
+                "userId": "u1",
+                "path": "/accounts",
+                "apiVersion": "dev",
+                "entity": "Account",
+                "entityId": "a1",
+                "action": "update",
+            })
+        );
+
+        let record = make_record(&auditor, "a1", None, after.as_ref(), 5.0);
+        assert_eq!(record["action"], "create");
+        assert!(!record.contains_key("before"));
+        let record = make_record(&auditor, "a1", before.as_ref(), None, 5.0);
+        assert_eq!(record["action"], "delete");
+        assert!(!record.contains_key("after"));
+    }
+}
@@ -8,20 +8,24 @@ use crate::deno::query_engine_arc;
 use crate::types::{ObjectType, Type};
 use anyhow::Result;
 use deno_core::OpState;
+use sha2::{Digest, Sha256};
 use sqlx::Row;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub(crate) const AUTH_USER_NAME: &str = "AuthUser";
 pub(crate) const AUTH_SESSION_NAME: &str = "AuthSession";
 pub(crate) const AUTH_TOKEN_NAME: &str = "AuthToken";
 pub(crate) const AUTH_ACCOUNT_NAME: &str = "AuthAccount";
+pub(crate) const AUTH_USER_ROLE_NAME: &str = "AuthUserRole";
+pub(crate) const AUTH_API_KEY_NAME: &str = "AuthApiKey";
 
-fn get_auth_user_type(state: &OpState) -> Result<Arc<ObjectType>> {
-    match lookup_builtin_type(state, AUTH_USER_NAME) {
+fn get_auth_type(state: &OpState, name: &str) -> Result<Arc<ObjectType>> {
+    match lookup_builtin_type(state, name) {
         Ok(Type::Object(t)) => Ok(t),
-        _ => anyhow::bail!("Internal error: type AuthUser not found"),
+        _ => anyhow::bail!("Internal error: type {} not found", name),
     }
 }
 
@@ -47,7 +51,45 @@ pub(crate) async fn init(api: &mut ApiService) -> Result<()> {
     add_crud_endpoint_for_type(AUTH_USER_NAME, "users", api).await?;
     add_crud_endpoint_for_type(AUTH_SESSION_NAME, "sessions", api).await?;
     add_crud_endpoint_for_type(AUTH_TOKEN_NAME, "tokens", api).await?;
-    add_crud_endpoint_for_type(AUTH_ACCOUNT_NAME, "accounts", api).await
+    add_crud_endpoint_for_type(AUTH_ACCOUNT_NAME, "accounts", api).await?;
+    add_crud_endpoint_for_type(AUTH_USER_ROLE_NAME, "roles", api).await?;
+    add_api_key_endpoint(api).await
+}
+
+/// Like the CRUD endpoints, but POST generates the key, stores its hash and returns the key itself,
+/// which can't be retrieved later.
+async fn add_api_key_endpoint(api: &mut ApiService) -> Result<()> {
+    crate::server::add_endpoint(
+        "/__chiselstrike/auth/apikeys".to_string(),
+        format!(
+            r#"
+import {{ ChiselEntity, responseFromJson }} from "@chiselstrike/api"
+class {AUTH_API_KEY_NAME} extends ChiselEntity {{}}
+const crud = {AUTH_API_KEY_NAME}.crud();
+export default async function chisel(req: Request) {{
+    if (req.method != "POST") {{
+        return crud(req);
+    }}
+    const {{ userId, scopes, expiresAt }} = await req.json();
+    if (typeof userId != "string") {{
+        return responseFromJson("userId is required", 400);
+    }}
+    const bytes = crypto.getRandomValues(new Uint8Array(32));
+    const hex = (b: Uint8Array) => Array.from(b, (x) => x.toString(16).padStart(2, "0")).join("");
+    const key = hex(bytes);
+    const hash = await crypto.subtle.digest("SHA-256", new TextEncoder().encode(key));
+    const apiKey = await {AUTH_API_KEY_NAME}.create({{
+        keyHash: hex(new Uint8Array(hash)),
+        userId,
+        scopes,
+        expiresAt,
+    }});
+    return responseFromJson({{ id: apiKey.id, key }});
+}}"#
+        ),
+        api,
+    )
+    .await
 }
 
 /// Extracts the username of the logged-in user, or None if there was no login.
@@ -58,7 +100,7 @@ pub(crate) async fn get_username_from_id(
     let (qeng, user_type) = {
         let state = state.borrow();
         let qeng = query_engine_arc(&state);
-        let user_type = get_auth_user_type(&state);
+        let user_type = get_auth_type(&state, AUTH_USER_NAME);
         (qeng, user_type)
     };
     match (userid, user_type) {
@@ -87,3 +129,117 @@ pub(crate) async fn get_username_from_id(
         }
     }
 }
+
+/// Extracts the roles of the logged-in user. A user that isn't logged in has no roles.
+pub(crate) async fn get_user_roles(
+    state: Rc<RefCell<OpState>>,
+    userid: &Option<String>,
+) -> Vec<String> {
+    let id = match userid {
+        None => return vec![],
+        Some(id) => id.clone(),
+    };
+    let (qeng, role_type) = {
+        let state = state.borrow();
+        let qeng = query_engine_arc(&state);
+        let role_type = get_auth_type(&state, AUTH_USER_ROLE_NAME);
+        (qeng, role_type)
+    };
+    let role_type = match role_type {
+        Err(e) => {
+            warn!("{:?}", e);
+            return vec![];
+        }
+        Ok(role_type) => role_type,
+    };
+    let rows = qeng
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                "SELECT role FROM \"{}\" WHERE \"userId\"=$1",
+                role_type.backing_table()
+            ),
+            args: vec![SqlValue::String(id)],
+        })
+        .await;
+    match rows {
+        Err(e) => {
+            warn!("User roles query error: {:?}", e);
+            vec![]
+        }
+        Ok(rows) => rows.iter().map(|row| row.get("role")).collect(),
+    }
+}
+
+/// What a valid API key authenticates.
+pub(crate) struct ApiKey {
+    /// The user the key acts as.
+    pub(crate) user_id: String,
+    /// Scopes granted to the key, checked by endpoint policies.
+    pub(crate) scopes: Vec<String>,
+}
+
+fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Looks up an API key. Returns None if it's unknown or expired.
+pub(crate) async fn get_api_key(state: Rc<RefCell<OpState>>, key: &str) -> Option<ApiKey> {
+    let (qeng, key_type) = {
+        let state = state.borrow();
+        let qeng = query_engine_arc(&state);
+        let key_type = get_auth_type(&state, AUTH_API_KEY_NAME);
+        (qeng, key_type)
+    };
+    let key_type = match key_type {
+        Err(e) => {
+            warn!("{:?}", e);
+            return None;
+        }
+        Ok(key_type) => key_type,
+    };
+    let rows = qeng
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                "SELECT \"userId\", scopes, \"expiresAt\" FROM \"{}\" WHERE \"keyHash\"=$1",
+                key_type.backing_table()
+            ),
+            args: vec![SqlValue::String(hash_api_key(key))],
+        })
+        .await;
+    let rows = match rows {
+        Err(e) => {
+            warn!("API key query error: {:?}", e);
+            return None;
+        }
+        Ok(rows) => rows,
+    };
+    let row = rows.first()?;
+    if let Some(expires_at) = row.get::<Option<f64>, _>("expiresAt") {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        if expires_at <= now.as_millis() as f64 {
+            return None;
+        }
+    }
+    let scopes: Option<String> = row.get("scopes");
+    Some(ApiKey {
+        user_id: row.get("userId"),
+        scopes: scopes
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_hash() {
+        assert_eq!(
+            hash_api_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
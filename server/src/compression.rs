@@ -0,0 +1,342 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Compression of the responses of the API server with the encodings that clients accept in
+//! `Accept-Encoding`, brotli if they can take it and gzip otherwise.
+//!
+//! Only textual content types are compressed, and constant bodies only if they are large enough
+//! for it to pay off. Streamed bodies are compressed chunk by chunk, each chunk being flushed so
+//! that clients see it as soon as the endpoint sends it.
+
+use crate::api::{Body, JsStream};
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use futures::ready;
+use futures::stream::Stream;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{HeaderMap, Response, StatusCode};
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Constant bodies smaller than this many bytes are sent as they are.
+const MIN_SIZE: usize = 1024;
+
+/// Content types that are compressed. The ones that end with `/` match all their subtypes.
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Brotli quality, which trades the size of responses for the time it takes to compress them.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn encoder(self) -> Encoder {
+        match self {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_BITS,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(vec![], flate2::Compression::default())),
+        }
+    }
+}
+
+/// Returns the encoding to compress the response with, given the `Accept-Encoding` of the request.
+pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut brotli = false;
+    let mut gzip = false;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            let refused = params.any(|p| {
+                let p = p.trim();
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(false, |q| q <= 0.0)
+            });
+            if refused {
+                continue;
+            }
+            match coding.to_ascii_lowercase().as_str() {
+                "br" => brotli = true,
+                "gzip" | "x-gzip" | "*" => gzip = true,
+                _ => {}
+            }
+        }
+    }
+    if brotli {
+        Some(Encoding::Brotli)
+    } else if gzip {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.ends_with("+json")
+        || COMPRESSIBLE_TYPES.iter().any(|t| {
+            if t.ends_with('/') {
+                essence.starts_with(t)
+            } else {
+                essence == *t
+            }
+        })
+}
+
+/// Compresses `response` with `encoding`, if the client accepts one and the response is worth
+/// compressing.
+pub(crate) fn compress(encoding: Option<Encoding>, response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    if parts.status == StatusCode::NO_CONTENT
+        || parts.status == StatusCode::NOT_MODIFIED
+        || parts.headers.contains_key(CONTENT_RANGE)
+        || parts.headers.contains_key(CONTENT_ENCODING)
+        || !is_compressible(&parts.headers)
+    {
+        return Response::from_parts(parts, body);
+    }
+    // Caches must tell apart the responses to clients that accept other encodings.
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let no_transform = parts
+        .headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .any(|v| v.to_str().map_or(false, |v| v.contains("no-transform")));
+    let encoding = match encoding {
+        Some(encoding) if !no_transform => encoding,
+        _ => return Response::from_parts(parts, body),
+    };
+    let body = match body {
+        Body::Const(Some(data)) if data.len() >= MIN_SIZE => {
+            match encoding.encoder().finish(&data) {
+                Ok(compressed) => Body::Const(Some(compressed)),
+                Err(e) => {
+                    warn!("Could not compress response: {:?}", e);
+                    return Response::from_parts(parts, Body::Const(Some(data)));
+                }
+            }
+        }
+        Body::Stream(stream) => Body::Stream(Box::pin(CompressedStream {
+            stream,
+            encoder: Some(encoding.encoder()),
+        })),
+        body => return Response::from_parts(parts, body),
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    // The compressed body isn't the one that a strong ETag stands for byte by byte.
+    if let Some(etag) = parts.headers.get(ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                parts.headers.insert(ETAG, weak);
+            }
+        }
+    }
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    Response::from_parts(parts, body)
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Compresses `data`, returning what of it can be decompressed already.
+    fn compress(&mut self, data: &[u8]) -> Result<Box<[u8]>> {
+        let out = match self {
+            Encoder::Brotli(w) => {
+                w.write_all(data)?;
+                w.flush()?;
+                w.get_mut()
+            }
+            Encoder::Gzip(w) => {
+                w.write_all(data)?;
+                w.flush()?;
+                w.get_mut()
+            }
+        };
+        Ok(std::mem::take(out).into_boxed_slice())
+    }
+
+    /// Compresses `data` as the last of the body.
+    fn finish(self, data: &[u8]) -> Result<Box<[u8]>> {
+        let out = match self {
+            Encoder::Brotli(mut w) => {
+                w.write_all(data)?;
+                w.into_inner()
+            }
+            Encoder::Gzip(mut w) => {
+                w.write_all(data)?;
+                w.finish()?
+            }
+        };
+        Ok(out.into_boxed_slice())
+    }
+}
+
+struct CompressedStream {
+    stream: JsStream,
+    /// Taken when the stream ends.
+    encoder: Option<Encoder>,
+}
+
+impl Stream for CompressedStream {
+    type Item = Result<Box<[u8]>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let encoder = match &mut this.encoder {
+            Some(encoder) => encoder,
+            None => return Poll::Ready(None),
+        };
+        let r = match ready!(this.stream.as_mut().poll_next(cx)) {
+            Some(Ok(chunk)) => Some(encoder.compress(&chunk)),
+            Some(Err(e)) => Some(Err(e)),
+            None => this.encoder.take().map(|encoder| encoder.finish(&[])),
+        };
+        Poll::Ready(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::io::Read;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn gunzip(data: &[u8]) -> String {
+        let mut out = String::new();
+        flate2::read::GzDecoder::new(data)
+            .read_to_string(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let negotiated = |value| negotiate(&headers(&[("accept-encoding", value)]));
+        assert_eq!(negotiated("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiated("gzip;q=1.0, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("*"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("identity"), None);
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn compress_const_body() {
+        let json = format!("[{}]", vec!["{\"name\":\"x\"}"; 200].join(","));
+        let mut response = Response::new(Body::from(json.clone()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+            .headers_mut()
+            .insert(ETAG, HeaderValue::from_static("\"abc\""));
+        let response = compress(Some(Encoding::Gzip), response);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        assert_eq!(response.headers()[ETAG], "W/\"abc\"");
+        match response.into_body() {
+            Body::Const(Some(data)) => {
+                assert!(data.len() < json.len());
+                assert_eq!(gunzip(&data), json);
+            }
+            _ => panic!("expected a constant body"),
+        }
+    }
+
+    #[test]
+    fn skip_small_and_binary_bodies() {
+        let mut small = Response::new(Body::from("{}".to_string()));
+        small
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let small = compress(Some(Encoding::Gzip), small);
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(small.headers()[VARY], "accept-encoding");
+
+        let mut binary = Response::new(Body::from("x".repeat(MIN_SIZE)));
+        binary
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        let binary = compress(Some(Encoding::Gzip), binary);
+        assert!(!binary.headers().contains_key(CONTENT_ENCODING));
+        assert!(!binary.headers().contains_key(VARY));
+    }
+
+    #[tokio::test]
+    async fn compress_stream_body() {
+        let chunks: Vec<Result<Box<[u8]>>> = vec![
+            Ok(b"first ".to_vec().into_boxed_slice()),
+            Ok(b"second".to_vec().into_boxed_slice()),
+        ];
+        let mut response = Response::new(Body::Stream(Box::pin(futures::stream::iter(chunks))));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain;charset=UTF-8"),
+        );
+        let response = compress(Some(Encoding::Gzip), response);
+        let mut stream = match response.into_body() {
+            Body::Stream(stream) => stream,
+            _ => panic!("expected a streamed body"),
+        };
+        let mut data = vec![];
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(gunzip(&data), "first second");
+    }
+}
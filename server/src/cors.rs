@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Cross-Origin Resource Sharing, configured by the `cors` section of policies.
+
+use crate::api::{response_template, Body};
+use crate::policies::yaml_strings;
+use anyhow::{Context, Result};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use std::collections::HashSet;
+use yaml_rust::Yaml;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Cors {
+    /// Origins allowed to make requests, or `None` to allow any origin.
+    origins: Option<HashSet<String>>,
+    /// Methods allowed in requests, as sent in Access-Control-Allow-Methods.
+    methods: String,
+    /// Headers allowed in requests, as sent in Access-Control-Allow-Headers.
+    headers: String,
+    /// Whether requests can include credentials, like cookies.
+    credentials: bool,
+    /// Seconds that browsers may cache the answer to a preflight request.
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    /// Parses the `cors` section of policies: `allow_origins`, `allow_methods` and
+    /// `allow_headers` lists, `allow_credentials` and `max_age`.
+    pub(crate) fn from_yaml(cors: &Yaml) -> Result<Self> {
+        let list = |name: &str, default: &str| -> Result<String> {
+            let items = yaml_strings(&cors[name])
+                .with_context(|| format!("couldn't parse yaml: bad {} in cors", name))?;
+            if items.is_empty() {
+                Ok(default.to_owned())
+            } else {
+                Ok(items.join(", "))
+            }
+        };
+        let origins = yaml_strings(&cors["allow_origins"])
+            .context("couldn't parse yaml: bad allow_origins in cors")?;
+        let origins = if origins.iter().any(|o| o == "*") {
+            None
+        } else {
+            Some(origins.into_iter().collect())
+        };
+        let credentials = match &cors["allow_credentials"] {
+            Yaml::BadValue => false,
+            Yaml::Boolean(b) => *b,
+            x => anyhow::bail!("couldn't parse yaml: bad allow_credentials {:?} in cors", x),
+        };
+        // Allowing any origin to make requests with the user's credentials would let any page
+        // act on behalf of the user.
+        anyhow::ensure!(
+            origins.is_some() || !credentials,
+            "couldn't parse yaml: allow_credentials can't be used with allow_origins '*' in cors"
+        );
+        let max_age = match &cors["max_age"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) if *n >= 0 => Some(*n as u64),
+            x => anyhow::bail!("couldn't parse yaml: bad max_age {:?} in cors", x),
+        };
+        Ok(Self {
+            origins,
+            methods: list("allow_methods", "POST, PUT, GET, OPTIONS, DELETE")?.to_uppercase(),
+            headers: list("allow_headers", "Content-Type, ChiselUID")?,
+            credentials,
+            max_age,
+        })
+    }
+
+    /// Value of Access-Control-Allow-Origin for a request from `origin`, if it's allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin = origin?;
+        match &self.origins {
+            None => Some(HeaderValue::from_static("*")),
+            Some(origins) => origins
+                .contains(origin.to_str().ok()?)
+                .then(|| origin.clone()),
+        }
+    }
+
+    /// Answers a preflight request. Requests from origins or with methods that aren't allowed
+    /// get no CORS headers, which makes browsers reject them.
+    pub(crate) fn preflight(&self, req: &Request<hyper::Body>) -> Result<Response<Body>> {
+        let mut builder = Response::builder().status(StatusCode::NO_CONTENT);
+        let method_allowed = match req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD) {
+            None => true,
+            Some(m) => m
+                .to_str()
+                .map_or(false, |m| self.methods.split(", ").any(|x| x == m)),
+        };
+        let origin = self.allow_origin(req.headers().get(header::ORIGIN));
+        if let (Some(origin), true) = (origin, method_allowed) {
+            builder = builder
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, &self.methods)
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, &self.headers);
+            if self.credentials {
+                builder = builder.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+            }
+            if let Some(max_age) = self.max_age {
+                builder = builder.header(header::ACCESS_CONTROL_MAX_AGE, max_age);
+            }
+        }
+        if self.origins.is_some() {
+            builder = builder.header(header::VARY, "Origin");
+        }
+        Ok(builder.body(Body::default())?)
+    }
+
+    /// Replaces the CORS headers of the response to a request from `origin`.
+    pub(crate) fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        headers.remove(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+        headers.remove(header::ACCESS_CONTROL_ALLOW_METHODS);
+        headers.remove(header::ACCESS_CONTROL_ALLOW_HEADERS);
+        headers.remove(header::ACCESS_CONTROL_ALLOW_CREDENTIALS);
+        if let Some(origin) = self.allow_origin(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            if self.credentials {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+            }
+        }
+        if self.origins.is_some() {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+}
+
+/// Answers a preflight request to a version without a `cors` policy, allowing everything.
+pub(crate) fn default_preflight() -> Result<Response<Body>> {
+    Ok(response_template().body("ok".to_string().into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn cors(yaml: &str) -> Cors {
+        Cors::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]).unwrap()
+    }
+
+    fn preflight(cors: &Cors, origin: &str, method: &str) -> Response<Body> {
+        let req = Request::builder()
+            .method("OPTIONS")
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", method)
+            .body(hyper::Body::empty())
+            .unwrap();
+        cors.preflight(&req).unwrap()
+    }
+
+    #[test]
+    fn origins() {
+        let c = cors("allow_origins: [https://a.com]\nallow_methods: [get, post]\nmax_age: 60");
+        let res = preflight(&c, "https://a.com", "POST");
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let h = res.headers();
+        assert_eq!(h["access-control-allow-origin"], "https://a.com");
+        assert_eq!(h["access-control-allow-methods"], "GET, POST");
+        assert_eq!(h["access-control-allow-headers"], "Content-Type, ChiselUID");
+        assert_eq!(h["access-control-max-age"], "60");
+        assert_eq!(h["vary"], "Origin");
+        assert!(!h.contains_key("access-control-allow-credentials"));
+
+        let res = preflight(&c, "https://b.com", "POST");
+        assert!(!res.headers().contains_key("access-control-allow-origin"));
+        let res = preflight(&c, "https://a.com", "DELETE");
+        assert!(!res.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn wildcard() {
+        let origin = HeaderValue::from_static("https://a.com");
+        let c = cors("allow_origins: '*'");
+        let mut headers = HeaderMap::new();
+        headers.insert("access-control-allow-origin", HeaderValue::from_static("x"));
+        c.apply(Some(&origin), &mut headers);
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert!(!headers.contains_key("vary"));
+
+        let mut headers = HeaderMap::new();
+        c.apply(None, &mut headers);
+        assert!(!headers.contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn credentials() {
+        let origin = HeaderValue::from_static("https://a.com");
+        let c = cors("allow_origins: [https://a.com]\nallow_credentials: true");
+        let mut headers = HeaderMap::new();
+        c.apply(Some(&origin), &mut headers);
+        assert_eq!(headers["access-control-allow-origin"], "https://a.com");
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+
+        // The endpoint's own header doesn't survive a policy without credentials.
+        let c = cors("allow_origins: [https://a.com]");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "access-control-allow-credentials",
+            HeaderValue::from_static("true"),
+        );
+        c.apply(Some(&origin), &mut headers);
+        assert!(!headers.contains_key("access-control-allow-credentials"));
+    }
+
+    #[test]
+    fn errors() {
+        let parse = |yaml: &str| Cors::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]);
+        assert!(parse("allow_credentials: maybe").is_err());
+        assert!(parse("max_age: -1").is_err());
+        assert!(parse("allow_methods: {GET: true}").is_err());
+        assert!(parse("allow_origins: '*'\nallow_credentials: true").is_err());
+    }
+}
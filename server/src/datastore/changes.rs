@@ -0,0 +1,335 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! # Change Feed
+//!
+//! Writes to entities are announced on a `ChangeFeed` once they are committed, as the table
+//! and the id of each row that was written or deleted. `Subscription`s follow the feed to keep
+//! the result set of a query up to date: they fetch the rows that changed again, through the
+//! query plan, so that the policies of the subscriber apply to what they see.
+//!
+//! With SQLite, the process that commits a transaction is the only one that sees it, and it
+//! publishes the changes itself. With Postgres, the transaction sends them with `NOTIFY`, and
+//! every process, this one included, `LISTEN`s for them and publishes what it hears.
+
+use crate::datastore::engine::{QueryEngine, ResultRow};
+use crate::datastore::query::QueryPlan;
+use anyhow::Result;
+use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use sqlx::any::Any;
+use sqlx::postgres::PgListener;
+use sqlx::Transaction;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// The Postgres channel that carries the changes.
+const CHANNEL: &str = "chisel_changes";
+
+/// How many changes a subscriber may fall behind before it has to start over from a snapshot.
+const CAPACITY: usize = 1024;
+
+/// A committed write to the row `id` of `table`.
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Change {
+    pub(crate) table: String,
+    pub(crate) id: String,
+}
+
+/// Announces the changes committed by this process, and on Postgres by the others too.
+#[derive(Debug, Clone)]
+pub(crate) struct ChangeFeed {
+    sender: broadcast::Sender<Arc<Change>>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl ChangeFeed {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<Change>> {
+        self.sender.subscribe()
+    }
+
+    pub(crate) fn publish(&self, changes: impl IntoIterator<Item = Change>) {
+        for change in changes {
+            // Nobody listening is fine.
+            self.sender.send(Arc::new(change)).ok();
+        }
+    }
+}
+
+/// Has the Postgres `transaction` send `changes` to the listeners when it commits.
+pub(crate) async fn notify(
+    transaction: &mut Transaction<'_, Any>,
+    changes: &[Change],
+) -> Result<()> {
+    for change in changes {
+        let payload = serde_json::to_string(change)?;
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(payload)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Publishes on `feed` the changes that the transactions committed to the Postgres database at
+/// `conn_uri` notify.
+pub(crate) async fn listen(conn_uri: String, feed: ChangeFeed) -> Result<()> {
+    let mut listener = PgListener::connect(&conn_uri).await?;
+    listener.listen(CHANNEL).await?;
+    loop {
+        // The listener connects again if it loses the connection, but the changes it misses
+        // meanwhile are gone.
+        let notification = match listener.recv().await {
+            Ok(notification) => notification,
+            Err(e) => {
+                warn!("Lost changes to the data while listening: {:?}", e);
+                continue;
+            }
+        };
+        match serde_json::from_str::<Change>(notification.payload()) {
+            Ok(change) => feed.publish([change]),
+            Err(e) => warn!(
+                "Bad change notification {}: {:?}",
+                notification.payload(),
+                e
+            ),
+        }
+    }
+}
+
+/// What happened to the result set of a subscription.
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub(crate) enum SubscriptionEvent {
+    /// The whole result set, which is sent first, and again if the subscriber falls behind.
+    Snapshot { rows: Vec<ResultRow> },
+    /// The row `id` joined the result set.
+    Insert { id: String, row: ResultRow },
+    /// The row `id` of the result set changed.
+    Update { id: String, row: ResultRow },
+    /// The row `id` left the result set, or was deleted.
+    Delete { id: String },
+}
+
+/// Follows the result set of a query.
+pub(crate) struct Subscription {
+    query_engine: Arc<QueryEngine>,
+    /// The query, which returns the id of the rows even if its caller didn't ask for it.
+    plan: QueryPlan,
+    /// Whether the caller didn't ask for the id.
+    hide_id: bool,
+    changes: broadcast::Receiver<Arc<Change>>,
+    /// The result set as last sent, by id, once the snapshot was.
+    rows: Option<HashMap<String, ResultRow>>,
+}
+
+impl Subscription {
+    pub(crate) fn new(query_engine: Arc<QueryEngine>, plan: QueryPlan) -> Result<Self> {
+        // Which rows enter or leave a limited result set depends on the rows around them.
+        anyhow::ensure!(
+            !plan.is_limited(),
+            "Cannot subscribe to a query with take or skip"
+        );
+        let hide_id = !plan.returns_field("id");
+        // Changes committed while the snapshot is taken are fetched again after it, so none
+        // is missed.
+        let changes = query_engine.changes().subscribe();
+        Ok(Self {
+            query_engine,
+            plan: plan.returning_field("id"),
+            hide_id,
+            changes,
+            rows: None,
+        })
+    }
+
+    /// Waits for the next change to the result set.
+    pub(crate) async fn next(&mut self) -> Result<SubscriptionEvent> {
+        let rows = match &mut self.rows {
+            Some(rows) => rows,
+            None => return self.snapshot().await,
+        };
+        loop {
+            let change = match self.changes.recv().await {
+                Ok(change) => change,
+                Err(broadcast::error::RecvError::Lagged(_)) => return self.snapshot().await,
+                Err(broadcast::error::RecvError::Closed) => anyhow::bail!("The data changes ended"),
+            };
+            if change.table != self.plan.base_table() {
+                continue;
+            }
+            let id = &change.id;
+            let plan = self.plan.clone().restricted_to_id(id);
+            let row = Self::fetch(&self.query_engine, plan).await?.pop();
+            let event = match (row, rows.remove(id)) {
+                (None, None) => continue,
+                (None, Some(_)) => SubscriptionEvent::Delete { id: id.clone() },
+                (Some(row), old) => {
+                    let changed = old.as_ref() != Some(&row);
+                    rows.insert(id.clone(), row.clone());
+                    if !changed {
+                        continue;
+                    }
+                    let (id, row) = (id.clone(), Self::shown(self.hide_id, row));
+                    match old {
+                        None => SubscriptionEvent::Insert { id, row },
+                        Some(_) => SubscriptionEvent::Update { id, row },
+                    }
+                }
+            };
+            return Ok(event);
+        }
+    }
+
+    async fn snapshot(&mut self) -> Result<SubscriptionEvent> {
+        let fetched = Self::fetch(&self.query_engine, self.plan.clone()).await?;
+        let mut rows = HashMap::new();
+        let mut shown = vec![];
+        for row in fetched {
+            let id = row.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+            rows.insert(id.to_owned(), row.clone());
+            shown.push(Self::shown(self.hide_id, row));
+        }
+        self.rows = Some(rows);
+        Ok(SubscriptionEvent::Snapshot { rows: shown })
+    }
+
+    /// Runs `plan` in a transaction of its own, so that subscriptions hold no locks while they
+    /// wait for changes.
+    async fn fetch(query_engine: &Arc<QueryEngine>, plan: QueryPlan) -> Result<Vec<ResultRow>> {
+        let transaction = query_engine.clone().start_transaction_static().await?;
+        let rows = query_engine.query(transaction, plan)?;
+        rows.collect::<Vec<_>>().await.into_iter().collect()
+    }
+
+    /// What the subscriber gets of `row`.
+    fn shown(hide_id: bool, mut row: ResultRow) -> ResultRow {
+        if hide_id {
+            row.remove("id");
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::expr::BinaryOp;
+    use crate::datastore::query::tests::{
+        binary, make_field, make_object, make_type_system, setup_clear_db, VERSION,
+    };
+    use crate::datastore::query::{Mutation, QueryOp, RequestContext};
+    use crate::policies::{Policies, WritePermissions};
+    use crate::types::Type;
+    use crate::JsonObject;
+    use serde_json::json;
+
+    fn object(value: serde_json::Value) -> JsonObject {
+        value.as_object().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("age", Type::Float),
+            ],
+        );
+        let ts = make_type_system(&[&person]);
+        let policies = Policies::default();
+        let context = RequestContext {
+            policies: &policies,
+            ts: &ts,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            user_roles: vec![],
+            path: "".to_string(),
+            secrets: None,
+        };
+        let (qe, _db_file) = setup_clear_db(&[&person]).await;
+        let qe = Arc::new(qe);
+        let save = |value: serde_json::Value| {
+            let (qe, person) = (qe.clone(), person.clone());
+            async move {
+                let permissions = WritePermissions::default();
+                let value = object(value);
+                qe.add_row(&person, &value, &permissions, None)
+                    .await
+                    .unwrap()
+                    .id
+            }
+        };
+
+        let adults = QueryPlan::from_ops(
+            &context,
+            &person,
+            vec![
+                QueryOp::Filter {
+                    expression: binary(&["age"], BinaryOp::GtEq, (18.).into()),
+                },
+                QueryOp::Projection {
+                    fields: vec!["name".to_owned()],
+                },
+            ],
+        )
+        .unwrap();
+        let mut subscription = Subscription::new(qe.clone(), adults).unwrap();
+        save(json!({"name": "John", "age": 30.})).await;
+        let snapshot = subscription.next().await.unwrap();
+        assert_eq!(
+            snapshot,
+            SubscriptionEvent::Snapshot {
+                rows: vec![object(json!({"name": "John"}))]
+            }
+        );
+
+        // The change to John is older than the snapshot, which has it already.
+        save(json!({"name": "Jake", "age": 10.})).await;
+        let alan = save(json!({"name": "Alan", "age": 20.})).await;
+        let event = subscription.next().await.unwrap();
+        assert_eq!(
+            event,
+            SubscriptionEvent::Insert {
+                id: alan.clone(),
+                row: object(json!({"name": "Alan"}))
+            }
+        );
+
+        save(json!({"id": alan, "name": "Alan", "age": 21.})).await;
+        save(json!({"id": alan, "name": "Alan Turing", "age": 21.})).await;
+        let event = subscription.next().await.unwrap();
+        assert_eq!(
+            event,
+            SubscriptionEvent::Update {
+                id: alan.clone(),
+                row: object(json!({"name": "Alan Turing"}))
+            }
+        );
+
+        save(json!({"id": alan, "name": "Alan Turing", "age": 12.})).await;
+        let event = subscription.next().await.unwrap();
+        assert_eq!(event, SubscriptionEvent::Delete { id: alan.clone() });
+
+        let everyone = binary(&["age"], BinaryOp::GtEq, (0.).into());
+        let mutation =
+            Mutation::delete_from_expr(&context, "Person", &Some(everyone), &Default::default())
+                .unwrap();
+        qe.mutate(mutation).await.unwrap();
+        let event = subscription.next().await.unwrap();
+        assert!(matches!(event, SubscriptionEvent::Delete { .. }));
+
+        let take = QueryPlan::from_ops(&context, &person, vec![QueryOp::Take { count: 1 }]);
+        assert!(Subscription::new(qe.clone(), take.unwrap()).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::datastore::expr::{BinaryExpr, BinaryOp, Expr, Literal, PropertyAccess};
 use crate::datastore::query::{Mutation, QueryOp, QueryPlan, RequestContext, SortBy, SortKey};
+use crate::policies::WritePermissions;
 use crate::types::{ObjectType, Type};
 use anyhow::{Context, Result};
 use std::sync::Arc;
@@ -51,7 +52,12 @@ fn query_str_to_ops(base_type: &Arc<ObjectType>, url: &str) -> Result<Vec<QueryO
 }
 
 /// Constructs Delete Mutation from CRUD url.
-pub(crate) fn delete_from_url(c: &RequestContext, type_name: &str, url: &str) -> Result<Mutation> {
+pub(crate) fn delete_from_url(
+    c: &RequestContext,
+    type_name: &str,
+    url: &str,
+    permissions: &WritePermissions,
+) -> Result<Mutation> {
     let base_entity = match c.ts.lookup_type(type_name, &c.api_version) {
         Ok(Type::Object(ty)) => ty,
         Ok(ty) => anyhow::bail!("Cannot delete scalar type {type_name} ({})", ty.name()),
@@ -68,7 +74,7 @@ pub(crate) fn delete_from_url(c: &RequestContext, type_name: &str, url: &str) ->
             anyhow::bail!("crud delete requires a filter to be set or `all=true` parameter.")
         }
     }
-    Mutation::delete_from_expr(c, type_name, &filter_expr)
+    Mutation::delete_from_expr(c, type_name, &filter_expr, permissions)
 }
 
 fn url_to_filter(base_type: &Arc<ObjectType>, url: &str) -> Result<Option<Expr>> {
@@ -369,7 +375,9 @@ mod tests {
             ts: &make_type_system(&*ENTITIES),
             api_version: VERSION.to_owned(),
             user_id: None,
+            user_roles: vec![],
             path: "".to_string(),
+            secrets: None,
         };
 
         let query_plan = query_plan_from_url(&context, entity_name, &url)?;
@@ -515,10 +523,13 @@ mod tests {
                     ts: &make_type_system(&*ENTITIES),
                     api_version: VERSION.to_owned(),
                     user_id: None,
+                    user_roles: vec![],
                     path: "".to_string(),
+                    secrets: None,
                 },
                 entity_name,
                 url,
+                &WritePermissions::default(),
             )
             .unwrap()
         };
@@ -1,10 +1,13 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::datastore::changes::ChangeFeed;
 use anyhow::Context;
 use anyhow::Result;
+use async_lock::Mutex;
 use sea_query::{PostgresQueryBuilder, SchemaBuilder, SqliteQueryBuilder};
 use sqlx::any::{AnyConnectOptions, AnyKind, AnyPool, AnyPoolOptions};
 use std::str::FromStr;
+use std::sync::Arc;
 
 // FIXME: Sqlite's Anykind does not implement Copy / Clone. It got merged
 // in their cdb40b1f8e5f, but that was not released yet. So temporarily wrap
@@ -38,6 +41,11 @@ pub(crate) struct DbConnection {
     pub(crate) kind: Kind,
     pub(crate) pool: AnyPool,
     pub(crate) conn_uri: String,
+    /// Taken by the request transactions of SQLite, which run one at a time. The local
+    /// connections of SQLite share it.
+    pub(crate) writer: Arc<Mutex<()>>,
+    /// Announces the changes to the data. The local connections share it.
+    pub(crate) changes: ChangeFeed,
 }
 
 impl DbConnection {
@@ -55,12 +63,20 @@ impl DbConnection {
             kind: opts.kind().into(),
             pool,
             conn_uri,
+            writer: Default::default(),
+            changes: Default::default(),
         })
     }
 
     pub(crate) async fn local_connection(&self, nr_conn: usize) -> Result<Self> {
         match self.kind {
-            Kind::Postgres => Self::connect(&self.conn_uri, nr_conn).await,
+            Kind::Postgres => {
+                let local = Self::connect(&self.conn_uri, nr_conn).await?;
+                Ok(Self {
+                    changes: self.changes.clone(),
+                    ..local
+                })
+            }
             Kind::Sqlite => Ok(self.clone()),
         }
     }
@@ -1,9 +1,13 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::datastore::changes::{self, Change, ChangeFeed};
 use crate::datastore::query::{
     Mutation, QueriedEntity, QueryField, QueryPlan, SqlValue, TargetDatabase,
 };
 use crate::datastore::{DbConnection, Kind};
+use crate::metrics;
+use crate::policies::{PolicyError, WritePermissions};
+use crate::telemetry;
 use crate::types::{Field, ObjectDelta, ObjectType, Type};
 use crate::JsonObject;
 use anyhow::{anyhow, Context as AnyhowContext, Result};
@@ -14,7 +18,9 @@ use futures::stream::Stream;
 use futures::FutureExt;
 use futures::StreamExt;
 use itertools::Itertools;
+use opentelemetry::global::BoxedSpan;
 use pin_project::pin_project;
+use prometheus::HistogramTimer;
 use sea_query::{Alias, ColumnDef, Table};
 use serde::Serialize;
 use serde_json::json;
@@ -34,6 +40,9 @@ pub(crate) type QueryResults = BoxStream<'static, Result<ResultRow>>;
 
 pub(crate) type TransactionStatic = Arc<Mutex<Transaction<'static, Any>>>;
 
+/// Held by a request transaction while it is open, on databases that run them one at a time.
+pub(crate) type WriterLock = MutexGuardArc<()>;
+
 pub(crate) fn extract_transaction(transaction: TransactionStatic) -> Transaction<'static, Any> {
     let transaction = Arc::try_unwrap(transaction).expect("Transaction still has references held!");
     transaction.into_inner()
@@ -45,15 +54,33 @@ pub(crate) fn extract_transaction(transaction: TransactionStatic) -> Transaction
 struct RawQueryResults<T> {
     raw_query: String,
     tr: MutexGuardArc<Transaction<'static, Any>>,
+    /// Records the duration of the query when the results are dropped.
+    _sql: SqlObserver,
     #[pin]
     stream: T,
 }
 
+/// Times and traces an SQL statement until dropped.
+struct SqlObserver {
+    _timer: HistogramTimer,
+    _span: BoxedSpan,
+}
+
+/// Logs the SQL `statement` of this `kind`, and starts timing and tracing it.
+fn observe_sql(kind: &'static str, statement: &str) -> SqlObserver {
+    debug!("SQL: {}", statement);
+    SqlObserver {
+        _timer: metrics::sql_timer(kind),
+        _span: telemetry::sql_span(kind, statement),
+    }
+}
+
 async fn make_transactioned_stream(
     tr: TransactionStatic,
     raw_query: String,
 ) -> impl Stream<Item = anyhow::Result<AnyRow>> {
     let mut tr = tr.lock_arc().await;
+    let sql = observe_sql("select", &raw_query);
 
     // The string data and Transaction will not move anymore.
     let raw_query_ptr = raw_query.as_ref() as *const str;
@@ -65,6 +92,7 @@ async fn make_transactioned_stream(
     RawQueryResults {
         tr,
         raw_query,
+        _sql: sql,
         stream,
     }
 }
@@ -153,6 +181,24 @@ pub(crate) struct IdTree {
     children: HashMap<String, IdTree>,
 }
 
+impl IdTree {
+    /// The changes made by writing the objects of this tree, whose root is of type `ty`.
+    pub(crate) fn changes(&self, ty: &ObjectType) -> Vec<Change> {
+        let mut changes = vec![Change {
+            table: ty.backing_table().to_owned(),
+            id: self.id.clone(),
+        }];
+        for field in ty.all_fields() {
+            if let (Type::Object(nested_type), Some(child)) =
+                (&field.type_, self.children.get(&field.name))
+            {
+                changes.extend(child.changes(nested_type));
+            }
+        }
+        changes
+    }
+}
+
 fn column_is_null(row: &AnyRow, column_idx: usize) -> bool {
     row.try_get_raw(column_idx).unwrap().is_null()
 }
@@ -184,19 +230,40 @@ fn id_idx(entity: &QueriedEntity) -> usize {
 pub(crate) struct QueryEngine {
     kind: Kind,
     pool: AnyPool,
+    writer: Arc<Mutex<()>>,
+    changes: ChangeFeed,
 }
 
 impl QueryEngine {
-    fn new(kind: Kind, pool: AnyPool) -> Self {
-        Self { kind, pool }
+    fn new(kind: Kind, pool: AnyPool, writer: Arc<Mutex<()>>, changes: ChangeFeed) -> Self {
+        Self {
+            kind,
+            pool,
+            writer,
+            changes,
+        }
     }
 
     pub(crate) async fn local_connection(conn: &DbConnection, nr_conn: usize) -> Result<Self> {
         let local = conn.local_connection(nr_conn).await?;
-        Ok(Self::new(local.kind, local.pool))
+        Ok(Self::new(
+            local.kind,
+            local.pool,
+            local.writer,
+            local.changes,
+        ))
     }
 
-    fn target_db(&self) -> TargetDatabase {
+    /// Announces the changes that transactions commit.
+    pub(crate) fn changes(&self) -> &ChangeFeed {
+        &self.changes
+    }
+
+    pub(crate) fn pool(&self) -> &AnyPool {
+        &self.pool
+    }
+
+    pub(crate) fn target_db(&self) -> TargetDatabase {
         match self.kind {
             Kind::Postgres => TargetDatabase::Postgres,
             Kind::Sqlite => TargetDatabase::Sqlite,
@@ -218,6 +285,17 @@ impl QueryEngine {
         Ok(())
     }
 
+    /// Waits for the request transactions that are open to end, if the database can't run them
+    /// side by side. A SQLite transaction that writes after another one committed since it first
+    /// read fails with SQLITE_BUSY rather than waiting, so request transactions of SQLite hold this
+    /// lock from their start.
+    pub(crate) async fn lock_writer(&self) -> Option<WriterLock> {
+        match self.kind {
+            Kind::Postgres => None,
+            Kind::Sqlite => Some(self.writer.lock_arc().await),
+        }
+    }
+
     pub(crate) async fn start_transaction_static(self: Arc<Self>) -> Result<TransactionStatic> {
         Ok(Arc::new(Mutex::new(self.pool.begin().await?)))
     }
@@ -227,16 +305,37 @@ impl QueryEngine {
     }
 
     pub(crate) async fn commit_transaction(transaction: Transaction<'static, Any>) -> Result<()> {
+        let _span = telemetry::span("commit");
         transaction.commit().await?;
         Ok(())
     }
 
     pub(crate) async fn commit_transaction_static(transaction: TransactionStatic) -> Result<()> {
         let transaction = extract_transaction(transaction);
+        let _span = telemetry::span("commit");
         transaction.commit().await?;
         Ok(())
     }
 
+    /// Commits `transaction`, and announces the `changes` that it made.
+    pub(crate) async fn commit_transaction_with_changes(
+        &self,
+        mut transaction: Transaction<'static, Any>,
+        changes: Vec<Change>,
+    ) -> Result<()> {
+        match self.kind {
+            Kind::Postgres => {
+                changes::notify(&mut transaction, &changes).await?;
+                Self::commit_transaction(transaction).await?;
+            }
+            Kind::Sqlite => {
+                Self::commit_transaction(transaction).await?;
+                self.changes.publish(changes);
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) async fn create_table(
         &self,
         transaction: &mut Transaction<'_, Any>,
@@ -365,7 +464,7 @@ impl QueryEngine {
                     };
                     if let Some(tr) = transform {
                         // Apply policy transformation
-                        val = tr(val);
+                        val = tr.apply(val);
                     }
                     ret.insert(name.clone(), val);
                 }
@@ -381,7 +480,7 @@ impl QueryEngine {
                     let mut val = json!(Self::row_to_json(db_kind, child_entity, row)?);
                     if let Some(tr) = transform {
                         // Apply policy transformation
-                        val = tr(val);
+                        val = tr.apply(val);
                     }
                     ret.insert(name.clone(), val);
                 }
@@ -428,12 +527,97 @@ impl QueryEngine {
     pub(crate) async fn mutate(&self, mutation: Mutation) -> Result<()> {
         let mut transaction = self.start_transaction().await?;
         let raw_sql = mutation.build_sql(self.target_db())?;
+        let ids_sql = mutation.build_ids_sql(self.target_db())?;
+        let mut changes: Vec<Change> = {
+            let _sql = observe_sql("select", &ids_sql);
+            let rows = sqlx::query(&ids_sql).fetch_all(&mut transaction).await?;
+            let table = mutation.base_entity().backing_table();
+            rows.iter()
+                .map(|row| Change {
+                    table: table.to_owned(),
+                    id: row.get(0),
+                })
+                .collect()
+        };
+        changes.extend(self.delete_dependents(&mutation, &mut transaction).await?);
         let query = sqlx::query(&raw_sql);
-        transaction.execute(query).await?;
-        QueryEngine::commit_transaction(transaction).await?;
+        {
+            let _sql = observe_sql("delete", &raw_sql);
+            transaction.execute(query).await?;
+        }
+        self.commit_transaction_with_changes(transaction, changes)
+            .await?;
         Ok(())
     }
 
+    /// Deletes the entities that depend on the ones `mutation` deletes, before it runs. Returns
+    /// the changes that makes.
+    async fn delete_dependents(
+        &self,
+        mutation: &Mutation,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<Vec<Change>> {
+        let mut changes = vec![];
+        for dependent in mutation.build_dependents_sql(self.target_db())? {
+            let rows = {
+                let _sql = observe_sql("select", &dependent.ids_sql);
+                sqlx::query(&dependent.ids_sql)
+                    .fetch_all(&mut *transaction)
+                    .await?
+            };
+            changes.extend(rows.iter().map(|row| Change {
+                table: dependent.table.clone(),
+                id: row.get(0),
+            }));
+            let _sql = observe_sql("delete", &dependent.delete_sql);
+            sqlx::query(&dependent.delete_sql)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        Ok(changes)
+    }
+
+    /// Executes the given `mutation` in the transaction `tr`, returning the rows it deleted, as
+    /// fetched by `fetch_stored_row`, and the changes to the entities that depend on them.
+    pub(crate) async fn mutate_returning(
+        &self,
+        mutation: Mutation,
+        tr: TransactionStatic,
+    ) -> Result<(Vec<JsonObject>, Vec<Change>)> {
+        let raw_sql = mutation.build_sql(self.target_db())?;
+        let ids_sql = mutation.build_ids_sql(self.target_db())?;
+        let ids: Vec<String> = {
+            let mut transaction = tr.lock().await;
+            let _sql = observe_sql("select", &ids_sql);
+            let rows = sqlx::query(&ids_sql).fetch_all(&mut *transaction).await?;
+            rows.iter().map(|row| row.get(0)).collect()
+        };
+        let mut deleted = vec![];
+        for id in &ids {
+            let row = self
+                .fetch_stored_row(tr.clone(), mutation.base_entity(), id)
+                .await?;
+            deleted.extend(row);
+        }
+        let mut transaction = tr.lock().await;
+        let changes = self.delete_dependents(&mutation, &mut transaction).await?;
+        let _sql = observe_sql("delete", &raw_sql);
+        transaction.execute(sqlx::query(&raw_sql)).await?;
+        Ok((deleted, changes))
+    }
+
+    /// Fetches the row of `ty` with this `id` as it is stored, without applying policies.
+    /// Nested entities are represented by their ids.
+    pub(crate) async fn fetch_stored_row(
+        &self,
+        tr: TransactionStatic,
+        ty: &Arc<ObjectType>,
+        id: &str,
+    ) -> Result<Option<JsonObject>> {
+        let mut rows = self.query(tr, QueryPlan::from_type_and_id(ty, id))?;
+        rows.next().await.transpose()
+    }
+
     /// Inserts object of type `ty` and value `ty_value` into the database.
     /// Returns JSON containing ids of all inserted objects in the format of
     /// IdsJson = {
@@ -443,14 +627,34 @@ impl QueryEngine {
     ///     ...
     /// }
     ///
+    /// Without a `transaction`, the row is written in one of its own, which announces the change.
+    /// Callers that pass one announce the `changes` of the tree when they commit it.
     pub(crate) async fn add_row(
         &self,
         ty: &ObjectType,
         ty_value: &JsonObject,
+        permissions: &WritePermissions,
         transaction: Option<&mut Transaction<'_, Any>>,
     ) -> Result<IdTree> {
-        let (inserts, id_tree) = self.prepare_insertion(ty, ty_value)?;
-        self.run_sql_queries(&inserts, transaction).await?;
+        let (inserts, id_tree) = self.prepare_insertion(ty, ty_value, permissions)?;
+        let inserted = match transaction {
+            Some(transaction) => self.run_sql_queries(&inserts, transaction).await,
+            None => {
+                async {
+                    let mut transaction = self.start_transaction().await?;
+                    self.run_sql_queries(&inserts, &mut transaction).await?;
+                    self.commit_transaction_with_changes(transaction, id_tree.changes(ty))
+                        .await
+                }
+                .await
+            }
+        };
+        inserted.map_err(|e| match e.downcast_ref::<sqlx::Error>() {
+            // Unrestricted inserts always return the row, so a missing one means a policy
+            // prevented the write.
+            Some(sqlx::Error::RowNotFound) => PolicyError::UpdateRejected.into(),
+            _ => e,
+        })?;
         Ok(id_tree)
     }
 
@@ -460,29 +664,30 @@ impl QueryEngine {
         ty_value: &JsonObject,
     ) -> Result<()> {
         let query = self.prepare_insertion_shallow(ty, ty_value)?;
-        self.run_sql_queries(&[query], None).await?;
+        let mut transaction = self.start_transaction().await?;
+        self.run_sql_queries(&[query], &mut transaction).await?;
+        QueryEngine::commit_transaction(transaction).await?;
         Ok(())
     }
 
     pub(crate) async fn fetch_one(&self, q: SqlWithArguments) -> Result<AnyRow> {
+        let _sql = observe_sql("select", &q.sql);
         Ok(q.get_sqlx().fetch_one(&self.pool).await?)
     }
 
+    pub(crate) async fn fetch_all(&self, q: SqlWithArguments) -> Result<Vec<AnyRow>> {
+        let _sql = observe_sql("select", &q.sql);
+        Ok(q.get_sqlx().fetch_all(&self.pool).await?)
+    }
+
     async fn run_sql_queries(
         &self,
         queries: &[SqlWithArguments],
-        transaction: Option<&mut Transaction<'_, Any>>,
+        transaction: &mut Transaction<'_, Any>,
     ) -> Result<()> {
-        if let Some(transaction) = transaction {
-            for q in queries {
-                transaction.fetch_one(q.get_sqlx()).await?;
-            }
-        } else {
-            let mut transaction = self.start_transaction().await?;
-            for q in queries {
-                transaction.fetch_one(q.get_sqlx()).await?;
-            }
-            QueryEngine::commit_transaction(transaction).await?;
+        for q in queries {
+            let _sql = observe_sql("insert", &q.sql);
+            transaction.fetch_one(q.get_sqlx()).await?;
         }
         Ok(())
     }
@@ -496,14 +701,22 @@ impl QueryEngine {
     }
 
     /// Recursively generates insert SQL queries necessary to insert object of type `ty`
-    /// and value `ty_value` into database.
+    /// and value `ty_value` into database, as far as `permissions` allow.
     /// Returns vector of SQL insert queries with corresponding arguments and IdTree of
     /// inserted objects.
     fn prepare_insertion(
         &self,
         ty: &ObjectType,
         ty_value: &JsonObject,
+        permissions: &WritePermissions,
     ) -> Result<(Vec<SqlWithArguments>, IdTree)> {
+        // Objects without an id are new, so they must be created.
+        let is_new = ty_value.get("id").map_or(true, |id| id.is_null());
+        if is_new && !permissions.create {
+            return Err(PolicyError::WriteDenied("create", ty.name().to_owned()).into());
+        }
+        let unrestricted = WritePermissions::default();
+
         let mut child_ids = HashMap::<String, IdTree>::new();
         let mut obj_id = Option::<String>::None;
         let mut query_args = Vec::<SqlValue>::new();
@@ -535,8 +748,10 @@ impl QueryEngine {
                             _ => anyhow::bail!("Cannot save into type {}.", nested_type.name()),
                         }
                     } else {
+                        let nested_permissions =
+                            permissions.nested.get(&field.name).unwrap_or(&unrestricted);
                         let (nested_inserts, nested_ids) =
-                            self.prepare_insertion(nested_type, nested_value)?;
+                            self.prepare_insertion(nested_type, nested_value, nested_permissions)?;
                         inserts.extend(nested_inserts);
                         let nested_id = nested_ids.id.to_owned();
                         child_ids.insert(field.name.to_owned(), nested_ids);
@@ -560,7 +775,7 @@ impl QueryEngine {
         }
 
         inserts.push(SqlWithArguments {
-            sql: self.make_insert_query(ty, ty_value)?,
+            sql: self.make_insert_query(ty, ty_value, permissions)?,
             args: query_args,
         });
         let obj_id = obj_id
@@ -616,12 +831,22 @@ impl QueryEngine {
 
     /// For given object of type `ty` and its value `ty_value` computes a string
     /// representing SQL query which inserts the object into database.
-    fn make_insert_query(&self, ty: &ObjectType, ty_value: &JsonObject) -> Result<String> {
+    ///
+    /// If `permissions` don't allow creating or updating the object, the update would change a
+    /// read-only field, or the object is one that the request may not read, the query returns
+    /// no rows.
+    fn make_insert_query(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+        permissions: &WritePermissions,
+    ) -> Result<String> {
         let mut field_binds = String::new();
         let mut field_names = vec![];
         let mut id_name = String::new();
         let mut update_binds = String::new();
         let mut id_bind = String::new();
+        let mut update_conditions = String::new();
 
         let mut i = 0;
         for f in ty.all_fields() {
@@ -650,9 +875,30 @@ impl QueryEngine {
                 id_bind = bind.clone();
             }
             update_binds.push_str(&std::format!("\"{}\" = {},", &f.name, &bind));
+            if permissions.readonly.contains(&f.name) {
+                let condition = if bind == "NULL" {
+                    "IS NULL".to_string()
+                } else {
+                    format!("= {}", bind)
+                };
+                update_conditions.push_str(&std::format!(
+                    " AND \"{}\".\"{}\" {}",
+                    &ty.backing_table(),
+                    &f.name,
+                    condition
+                ));
+            }
         }
         field_binds.pop();
         update_binds.pop();
+        if let Some(readable) = &permissions.readable {
+            update_conditions.push_str(&std::format!(
+                " AND \"{}\".\"{}\" IN ({})",
+                &ty.backing_table(),
+                &id_name,
+                readable
+            ));
+        }
 
         for v in ty_value.keys() {
             anyhow::ensure!(
@@ -663,17 +909,37 @@ impl QueryEngine {
             );
         }
 
-        Ok(std::format!(
-            "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} WHERE \"{}\".\"{}\" = {} RETURNING *",
-            &ty.backing_table(),
-            field_names.into_iter().map(|f| format!("\"{}\"", f)).join(","),
-            field_binds,
-            id_name,
-            update_binds,
-            &ty.backing_table(),
-            id_name,
-            id_bind,
-        ))
+        let table = ty.backing_table();
+        let field_names = field_names
+            .into_iter()
+            .map(|f| format!("\"{}\"", f))
+            .join(",");
+        let query = match (permissions.create, permissions.update) {
+            (true, true) => std::format!(
+                "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} WHERE \"{}\".\"{}\" = {}{} RETURNING *",
+                table,
+                field_names,
+                field_binds,
+                id_name,
+                update_binds,
+                table,
+                id_name,
+                id_bind,
+                update_conditions,
+            ),
+            (true, false) => std::format!(
+                "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO NOTHING RETURNING *",
+                table, field_names, field_binds, id_name,
+            ),
+            (false, true) => std::format!(
+                "UPDATE \"{}\" SET {} WHERE \"{}\".\"{}\" = {}{} RETURNING *",
+                table, update_binds, table, id_name, id_bind, update_conditions,
+            ),
+            (false, false) => {
+                return Err(PolicyError::WriteDenied("write", ty.name().to_owned()).into())
+            }
+        };
+        Ok(query)
     }
 
     fn prepare_insertion_shallow(
@@ -693,7 +959,7 @@ impl QueryEngine {
         }
 
         Ok(SqlWithArguments {
-            sql: self.make_insert_query(ty, ty_value)?,
+            sql: self.make_insert_query(ty, ty_value, &WritePermissions::default())?,
             args: query_args,
         })
     }
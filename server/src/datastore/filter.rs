@@ -0,0 +1,382 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Parser for the filter expressions written in policy files, like
+//! `published == true || author.id == $user.id`.
+//!
+//! Bare identifiers are properties of the filtered entity (parameter 0) and
+//! `$user` is the logged-in user (parameter 1), which is substituted when the
+//! filter is applied to a request.
+
+use crate::datastore::expr::{BinaryExpr, BinaryOp, Expr, Literal, PropertyAccess};
+use anyhow::Result;
+use std::iter::Peekable;
+
+type Chars<'a> = Peekable<std::str::Chars<'a>>;
+
+/// Position of the filtered entity in the parsed expression.
+pub(crate) const ENTITY_PARAMETER: usize = 0;
+/// Position of the logged-in user in the parsed expression.
+pub(crate) const USER_PARAMETER: usize = 1;
+
+#[derive(Debug, Clone)]
+enum Token {
+    Ident(String),
+    Variable(String),
+    Literal(Literal),
+    Op(BinaryOp),
+    Dot,
+    LParen,
+    RParen,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = match c {
+            '(' => {
+                chars.next();
+                Token::LParen
+            }
+            ')' => {
+                chars.next();
+                Token::RParen
+            }
+            '.' => {
+                chars.next();
+                Token::Dot
+            }
+            '=' => two(&mut chars, '=', BinaryOp::Eq, src)?,
+            '!' => two(&mut chars, '=', BinaryOp::NotEq, src)?,
+            '&' => two(&mut chars, '&', BinaryOp::And, src)?,
+            '|' => two(&mut chars, '|', BinaryOp::Or, src)?,
+            '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if_eq(&'=').is_some();
+                Token::Op(match (c, eq) {
+                    ('<', false) => BinaryOp::Lt,
+                    ('<', true) => BinaryOp::LtEq,
+                    ('>', false) => BinaryOp::Gt,
+                    _ => BinaryOp::GtEq,
+                })
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(x) => s.push(x),
+                            None => anyhow::bail!("unterminated string in filter `{}`", src),
+                        },
+                        Some(x) if x == c => break,
+                        Some(x) => s.push(x),
+                        None => anyhow::bail!("unterminated string in filter `{}`", src),
+                    }
+                }
+                Token::Literal(s.into())
+            }
+            '$' => {
+                chars.next();
+                Token::Variable(take_word(&mut chars))
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = String::new();
+                s.push(c);
+                chars.next();
+                while let Some(x) = chars.next_if(|x| x.is_ascii_digit() || *x == '.') {
+                    s.push(x);
+                }
+                let literal = if let Ok(v) = s.parse::<u64>() {
+                    v.into()
+                } else if let Ok(v) = s.parse::<i64>() {
+                    v.into()
+                } else if let Ok(v) = s.parse::<f64>() {
+                    v.into()
+                } else {
+                    anyhow::bail!("invalid number {} in filter `{}`", s, src);
+                };
+                Token::Literal(literal)
+            }
+            c if c.is_alphabetic() || c == '_' => match take_word(&mut chars).as_str() {
+                "true" => Token::Literal(true.into()),
+                "false" => Token::Literal(false.into()),
+                "null" => Token::Literal(Literal::Null),
+                word => Token::Ident(word.to_owned()),
+            },
+            c => anyhow::bail!("unexpected character '{}' in filter `{}`", c, src),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Reads a two-character operator whose first character is next in `chars`.
+fn two(chars: &mut Chars<'_>, second: char, op: BinaryOp, src: &str) -> Result<Token> {
+    let first = chars.next().unwrap();
+    match chars.next() {
+        Some(x) if x == second => Ok(Token::Op(op)),
+        _ => anyhow::bail!("expected '{}{}' in filter `{}`", first, second, src),
+    }
+}
+
+fn take_word(chars: &mut Chars<'_>) -> String {
+    let mut word = String::new();
+    while let Some(x) = chars.next_if(|x| x.is_alphanumeric() || *x == '_') {
+        word.push(x);
+    }
+    word
+}
+
+/// Recursive descent parser. From lowest to highest precedence: `||`, `&&`,
+/// comparisons, and operands (literals, property chains and parenthesized
+/// expressions).
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while matches!(self.peek(), Some(Token::Op(BinaryOp::Or))) {
+            self.next();
+            lhs = BinaryExpr::or(lhs, self.parse_and()?);
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_comparison()?;
+        while matches!(self.peek(), Some(Token::Op(BinaryOp::And))) {
+            self.next();
+            lhs = BinaryExpr::and(lhs, self.parse_comparison()?);
+        }
+        Ok(lhs)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let lhs = self.parse_operand()?;
+        match self.peek() {
+            Some(Token::Op(op)) if !matches!(op, BinaryOp::And | BinaryOp::Or) => {
+                let op = op.clone();
+                self.next();
+                let rhs = self.parse_operand()?;
+                Ok(BinaryExpr::new(op, lhs, rhs).into())
+            }
+            _ => Ok(lhs),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => anyhow::bail!("missing ')' in filter `{}`", self.src),
+                }
+            }
+            Some(Token::Literal(value)) => Ok(Expr::Literal { value }),
+            Some(Token::Ident(property)) => self.parse_properties(
+                PropertyAccess {
+                    property,
+                    object: Expr::Parameter {
+                        position: ENTITY_PARAMETER,
+                    }
+                    .into(),
+                }
+                .into(),
+            ),
+            Some(Token::Variable(name)) if name == "user" => {
+                self.parse_properties(Expr::Parameter {
+                    position: USER_PARAMETER,
+                })
+            }
+            Some(Token::Variable(name)) => {
+                anyhow::bail!("unknown variable ${} in filter `{}`", name, self.src)
+            }
+            Some(t) => anyhow::bail!("unexpected {:?} in filter `{}`", t, self.src),
+            None => anyhow::bail!("unexpected end of filter `{}`", self.src),
+        }
+    }
+
+    fn parse_properties(&mut self, mut object: Expr) -> Result<Expr> {
+        while matches!(self.peek(), Some(Token::Dot)) {
+            self.next();
+            match self.next() {
+                Some(Token::Ident(property)) => {
+                    object = PropertyAccess {
+                        property,
+                        object: object.into(),
+                    }
+                    .into();
+                }
+                _ => anyhow::bail!(
+                    "expected a property name after '.' in filter `{}`",
+                    self.src
+                ),
+            }
+        }
+        Ok(object)
+    }
+}
+
+/// Parses a policy filter expression.
+pub(crate) fn parse_filter(src: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        src,
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let expr = parser.parse_or()?;
+    if let Some(t) = parser.peek() {
+        anyhow::bail!("unexpected {:?} in filter `{}`", t, src);
+    }
+    check_user_properties(&expr)?;
+    Ok(expr)
+}
+
+/// Only `$user.id` can be resolved without reading from the database.
+fn check_user_properties(expr: &Expr) -> Result<()> {
+    match expr {
+        Expr::Binary(b) => {
+            check_user_properties(&b.left)?;
+            check_user_properties(&b.right)
+        }
+        Expr::Property(p) => match &*p.object {
+            Expr::Parameter {
+                position: USER_PARAMETER,
+            } if p.property == "id" => Ok(()),
+            Expr::Parameter {
+                position: USER_PARAMETER,
+            } => anyhow::bail!("unsupported property $user.{} in filter", p.property),
+            object => check_user_properties(object),
+        },
+        Expr::Parameter {
+            position: USER_PARAMETER,
+        } => anyhow::bail!("$user can only be used as $user.id in filters"),
+        _ => Ok(()),
+    }
+}
+
+/// Instantiates a parsed filter for a request: the filtered entity is replaced
+/// by `entity` and `$user.id` by the id of the logged-in user, if any.
+pub(crate) fn bind_filter(filter: &Expr, entity: &Expr, user_id: &Option<String>) -> Expr {
+    match filter {
+        Expr::Binary(b) => BinaryExpr::new(
+            b.op.clone(),
+            bind_filter(&b.left, entity, user_id),
+            bind_filter(&b.right, entity, user_id),
+        )
+        .into(),
+        Expr::Property(p) => match &*p.object {
+            Expr::Parameter {
+                position: USER_PARAMETER,
+            } => Literal::from(user_id.clone().map(Literal::from)).into(),
+            object => PropertyAccess {
+                property: p.property.clone(),
+                object: bind_filter(object, entity, user_id).into(),
+            }
+            .into(),
+        },
+        Expr::Parameter {
+            position: ENTITY_PARAMETER,
+        } => entity.clone(),
+        e => e.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::binary;
+
+    fn property(object: Expr, fields: &[&str]) -> Expr {
+        let mut expr = object;
+        for field in fields {
+            expr = PropertyAccess {
+                property: field.to_string(),
+                object: expr.into(),
+            }
+            .into();
+        }
+        expr
+    }
+
+    #[test]
+    fn parse() {
+        let expr = parse_filter("published == true || author.id == $user.id").unwrap();
+        let entity = Expr::Parameter {
+            position: ENTITY_PARAMETER,
+        };
+        let user = Expr::Parameter {
+            position: USER_PARAMETER,
+        };
+        assert_eq!(
+            expr,
+            BinaryExpr::or(
+                binary(&["published"], BinaryOp::Eq, true.into()),
+                BinaryExpr::eq(property(entity, &["author", "id"]), property(user, &["id"]))
+            )
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let expr = parse_filter("a == 1 || b != 'x' && (c < -2 || d >= 1.5)").unwrap();
+        let c_or_d = BinaryExpr::or(
+            binary(&["c"], BinaryOp::Lt, (-2i64).into()),
+            binary(&["d"], BinaryOp::GtEq, (1.5).into()),
+        );
+        let b_and = BinaryExpr::and(binary(&["b"], BinaryOp::NotEq, "x".into()), c_or_d);
+        assert_eq!(
+            expr,
+            BinaryExpr::or(binary(&["a"], BinaryOp::Eq, 1u64.into()), b_and)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_filter("a == ").is_err());
+        assert!(parse_filter("a = 1").is_err());
+        assert!(parse_filter("(a == 1").is_err());
+        assert!(parse_filter("a == 1)").is_err());
+        assert!(parse_filter("a == $group.id").is_err());
+        assert!(parse_filter("a == $user.email").is_err());
+        assert!(parse_filter("a == $user").is_err());
+        assert!(parse_filter("a == 'x").is_err());
+    }
+
+    #[test]
+    fn bind() {
+        let expr = parse_filter("author.id == $user.id").unwrap();
+        let entity = Expr::Parameter {
+            position: ENTITY_PARAMETER,
+        };
+        let ceo = property(entity, &["ceo"]);
+        assert_eq!(
+            bind_filter(&expr, &ceo, &Some("42".to_owned())),
+            binary(&["ceo", "author", "id"], BinaryOp::Eq, "42".into())
+        );
+        assert_eq!(
+            bind_filter(&expr, &ceo, &None),
+            binary(&["ceo", "author", "id"], BinaryOp::Eq, Literal::Null)
+        );
+    }
+}
@@ -6,6 +6,7 @@ use crate::api::{ApiInfo, ApiInfoMap};
 use crate::datastore::{DbConnection, Kind};
 use crate::policies::Policies;
 use crate::prefix_map::PrefixMap;
+use crate::static_files::StaticFile;
 use crate::types::AuthOrNot::IsNotAuth;
 use crate::types::{
     ExistingField, ExistingObject, Field, FieldDelta, ObjectDelta, ObjectType, TypeSystem,
@@ -21,7 +22,7 @@ use tokio::fs;
 ///
 /// The meta service is responsible for managing metadata such as object
 /// types and labels persistently.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct MetaService {
     kind: Kind,
     pool: AnyPool,
@@ -495,6 +496,42 @@ impl MetaService {
         Ok(())
     }
 
+    /// Load the static files from the metadata store.
+    pub(crate) async fn load_static_files(&self) -> anyhow::Result<PrefixMap<Arc<StaticFile>>> {
+        let query = sqlx::query("SELECT path, content FROM static_files");
+        let rows = fetch_all(&self.pool, query).await?;
+
+        let mut files = PrefixMap::default();
+        for row in rows {
+            let path: &str = row.get("path");
+            let content: Vec<u8> = row.get("content");
+            debug!("Loading static file {}", path);
+            let file = StaticFile::new(Path::new(path), content);
+            files.insert(path.into(), Arc::new(file));
+        }
+        Ok(files)
+    }
+
+    pub(crate) async fn persist_static_files(
+        &self,
+        files: &PrefixMap<Arc<StaticFile>>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let drop = sqlx::query("DELETE FROM static_files");
+        execute(&mut transaction, drop).await?;
+
+        for (path, file) in files.iter() {
+            let new_file = sqlx::query("INSERT INTO static_files (path, content) VALUES ($1, $2)")
+                .bind(path.to_str())
+                .bind(file.content.to_vec());
+
+            execute(&mut transaction, new_file).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
     /// Load the type system from metadata store.
     pub(crate) async fn load_type_system<'r>(&self) -> anyhow::Result<TypeSystem> {
         let query = sqlx::query(
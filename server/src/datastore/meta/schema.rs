@@ -68,6 +68,13 @@ enum Endpoints {
     Code,
 }
 
+#[derive(Iden)]
+enum StaticFiles {
+    Table,
+    Path,
+    Content,
+}
+
 #[derive(Iden)]
 enum Policies {
     Table,
@@ -189,6 +196,13 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         .col(ColumnDef::new(Endpoints::Code).text())
         .to_owned();
 
+    let static_files = Table::create()
+        .table(StaticFiles::Table)
+        .if_not_exists()
+        .col(ColumnDef::new(StaticFiles::Path).text().unique_key())
+        .col(ColumnDef::new(StaticFiles::Content).binary())
+        .to_owned();
+
     let policies = Table::create()
         .table(Policies::Table)
         .if_not_exists()
@@ -205,6 +219,7 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         type_fields,
         field_labels,
         endpoints,
+        static_files,
         policies,
     ]
 }
@@ -52,10 +52,12 @@
 //! object instead and returns a `QueryResults` object, which represents a
 //! stream of query results with *policies applied*.
 
+pub(crate) mod changes;
 pub(crate) mod crud;
 mod dbconn;
 pub(crate) mod engine;
 pub(crate) mod expr;
+pub(crate) mod filter;
 pub(crate) mod meta;
 pub(crate) mod query;
 
@@ -1,14 +1,14 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
-use crate::auth::AUTH_USER_NAME;
+use crate::auth::{AUTH_USER_NAME, AUTH_USER_ROLE_NAME};
 use crate::datastore::expr::{BinaryExpr, Expr, Literal, PropertyAccess};
-use crate::policies::{FieldPolicies, Policies};
+use crate::policies::{FieldPolicies, FieldTransform, Policies, PolicyError, WritePermissions};
 use crate::types::{Field, ObjectType, Type, TypeSystem};
+use crate::JsonObject;
 
 use anyhow::{anyhow, Context, Result};
 use enum_as_inner::EnumAsInner;
 use serde_derive::{Deserialize, Serialize};
-use serde_json::value::Value;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
@@ -38,15 +38,29 @@ pub(crate) struct RequestContext<'a> {
     pub api_version: String,
     /// Id of user making the request.
     pub user_id: Option<String>,
+    /// Roles of user making the request.
+    pub user_roles: Vec<String>,
     /// Current URL path from which this request originated.
     pub path: String,
+    /// Secrets available to policies, like the salts of hash transforms.
+    pub secrets: Option<&'a JsonObject>,
 }
 
 impl RequestContext<'_> {
     /// Calculates field policies for the request being processed.
     fn make_field_policies(&self, ty: &ObjectType) -> FieldPolicies {
-        self.policies
-            .make_field_policies(&self.user_id, &self.path, ty)
+        self.policies.make_field_policies(
+            &self.user_id,
+            &self.user_roles,
+            &self.path,
+            self.secrets,
+            ty,
+        )
+    }
+
+    /// Calculates the row filter for entities of type `ty` addressed by `entity`.
+    fn make_read_filter(&self, ty: &ObjectType, entity: &Expr) -> Option<Expr> {
+        self.policies.make_read_filter(&self.user_id, ty, entity)
     }
 }
 
@@ -62,14 +76,14 @@ pub(crate) enum QueryField {
         /// the database.
         column_idx: usize,
         /// Policy transformation to be applied on the resulting JSON value.
-        transform: Option<fn(Value) -> Value>,
+        transform: Option<FieldTransform>,
     },
     Entity {
         /// Name of the original Type field
         name: String,
         is_optional: bool,
         /// Policy transformation to be applied on the resulting JSON value.
-        transform: Option<fn(Value) -> Value>,
+        transform: Option<FieldTransform>,
     },
 }
 
@@ -158,6 +172,7 @@ pub(crate) enum QueryOp {
     SortBy(SortBy),
 }
 
+#[derive(Clone)]
 struct Column {
     /// Column name which is coincidentally also the name of the Entity field
     /// this column corresponds to.
@@ -208,6 +223,7 @@ pub(crate) enum TargetDatabase {
 /// When we are done with that, `build_query` can be called which creates a `Query`
 /// structure that contains raw SQL query string and additional data necessary for
 /// JSON response reconstruction and filtering.
+#[derive(Clone)]
 pub(crate) struct QueryPlan {
     /// Columns that will be retrieved from the database in order defined by this vector.
     columns: Vec<Column>,
@@ -243,6 +259,45 @@ impl QueryPlan {
         &self.entity.ty
     }
 
+    /// The table of the entities that this plan retrieves.
+    pub(crate) fn base_table(&self) -> &str {
+        self.base_type().backing_table()
+    }
+
+    /// Whether the plan takes or skips some of the rows that match its filters.
+    pub(crate) fn is_limited(&self) -> bool {
+        self.operators
+            .iter()
+            .any(|op| matches!(op, QueryOp::Take { .. } | QueryOp::Skip { .. }))
+    }
+
+    /// Whether the rows that this plan returns have the field `name`.
+    pub(crate) fn returns_field(&self, name: &str) -> bool {
+        self.allowed_fields
+            .as_ref()
+            .map_or(true, |fields| fields.contains(name))
+    }
+
+    /// This plan, with the field `name` among the ones that it returns.
+    pub(crate) fn returning_field(mut self, name: &str) -> Self {
+        if let Some(fields) = &mut self.allowed_fields {
+            fields.insert(name.to_owned());
+        }
+        self
+    }
+
+    /// This plan, restricted to the entity with this `id`.
+    pub(crate) fn restricted_to_id(mut self, id: &str) -> Self {
+        let property = PropertyAccess {
+            property: "id".to_owned(),
+            object: Expr::Parameter { position: 0 }.into(),
+        };
+        self.extend_operators(vec![QueryOp::Filter {
+            expression: BinaryExpr::eq(property.into(), Literal::String(id.to_owned()).into()),
+        }]);
+        self
+    }
+
     /// Constructs a query builder ready to build an expression querying all fields of a
     /// given type `ty`. This is done in a shallow manner. Columns representing foreign
     /// key are returned as string, not as the related Entity.
@@ -260,6 +315,11 @@ impl QueryPlan {
         builder
     }
 
+    /// Like `from_type`, restricted to the row with this `id`.
+    pub(crate) fn from_type_and_id(ty: &Arc<ObjectType>, id: &str) -> Self {
+        Self::from_type(ty).restricted_to_id(id)
+    }
+
     fn from_entity_name(c: &RequestContext, entity_name: &str) -> Result<Self> {
         let ty =
             c.ts.lookup_object_type(entity_name, &c.api_version)
@@ -270,6 +330,32 @@ impl QueryPlan {
         Ok(builder)
     }
 
+    /// Builds a query for the IDs of the entities of type `ty` that the request may read, or
+    /// `None` if no login restriction or row filter hides any of them.
+    pub(crate) fn readable_ids_sql(
+        c: &RequestContext,
+        ty: &ObjectType,
+        target: &TargetDatabase,
+    ) -> Result<Option<String>> {
+        let plan = Self::from_entity_name(c, ty.name())?;
+        if plan.operators.is_empty() {
+            return Ok(None);
+        }
+        plan.build_ids_sql(target).map(Some)
+    }
+
+    /// Builds a query for the IDs of the base entities that this plan retrieves.
+    fn build_ids_sql(&self, target: &TargetDatabase) -> Result<String> {
+        let select_sql = self.build_query(target)?.raw_sql;
+        let id_column = ColumnAlias {
+            field_name: "id".to_owned(),
+            table_name: self.entity.ty.backing_table().to_owned(),
+        };
+        Ok(format!(
+            r#"SELECT "{id_column}" FROM ({select_sql}) as subquery"#
+        ))
+    }
+
     /// Constructs QueryPlan from `entity_name` and application of given
     /// `operators.
     pub(crate) fn from_ops(
@@ -315,7 +401,7 @@ impl QueryPlan {
         &mut self,
         field: &Field,
         table_name: &str,
-        transform: Option<fn(Value) -> Value>,
+        transform: Option<FieldTransform>,
     ) -> QueryField {
         let column_idx = self.columns.len();
         let select_field = QueryField::Scalar {
@@ -334,9 +420,10 @@ impl QueryPlan {
     }
 
     /// Prepares the retrieval of Entity of type `ty` from the database and
-    /// ensures login restrictions are respected.
+    /// ensures login restrictions and row filters are respected.
     fn load_entity(&mut self, context: &RequestContext, ty: &Arc<ObjectType>) -> QueriedEntity {
         self.add_login_filters_recursive(context, ty, Expr::Parameter { position: 0 });
+        self.add_read_filters_recursive(context, ty, Expr::Parameter { position: 0 });
         self.load_entity_recursive(context, ty, ty.backing_table())
     }
 
@@ -427,6 +514,29 @@ impl QueryPlan {
         }
     }
 
+    /// Adds the row filters that policies define for type `ty` and for the
+    /// types of its nested entities. A row is left out if it references a
+    /// nested entity that is filtered out.
+    fn add_read_filters_recursive(
+        &mut self,
+        context: &RequestContext,
+        ty: &Arc<ObjectType>,
+        property_chain: Expr,
+    ) {
+        if let Some(expr) = context.make_read_filter(ty, &property_chain) {
+            self.operators.push(QueryOp::Filter { expression: expr });
+        }
+        for field in ty.all_fields() {
+            if let Type::Object(nested_ty) = &field.type_ {
+                let property_access = PropertyAccess {
+                    property: field.name.to_owned(),
+                    object: property_chain.clone().into(),
+                };
+                self.add_read_filters_recursive(context, nested_ty, property_access.into());
+            }
+        }
+    }
+
     fn make_column_string(&self) -> String {
         let mut column_string = String::new();
         for c in &self.columns {
@@ -765,6 +875,19 @@ pub(crate) struct Mutation {
     base_entity: Arc<ObjectType>,
     /// Query plan used to build mutation condition.
     filter_query_plan: QueryPlan,
+    /// Whether policies allow the request to delete entities of this type.
+    delete_allowed: bool,
+    /// Types whose entities refer to a deleted entity by the field of this name, and are deleted
+    /// along with it.
+    dependents: Vec<(Arc<ObjectType>, String)>,
+}
+
+/// Deletes the entities of one type that depend on the ones a `Mutation` deletes.
+pub(crate) struct DependentSql {
+    pub(crate) table: String,
+    /// Query for the ids of the entities that are deleted.
+    pub(crate) ids_sql: String,
+    pub(crate) delete_sql: String,
 }
 
 impl Mutation {
@@ -773,6 +896,7 @@ impl Mutation {
         c: &RequestContext,
         type_name: &str,
         filter_expr: &Option<Expr>,
+        permissions: &WritePermissions,
     ) -> Result<Self> {
         let base_entity = match c.ts.lookup_type(type_name, &c.api_version) {
             Ok(Type::Object(ty)) => ty,
@@ -786,23 +910,56 @@ impl Mutation {
                 expression: expr.clone(),
             }]);
         }
+        // Roles are granted by entities of their own, which mustn't outlive their user.
+        let mut dependents = vec![];
+        if base_entity.name() == AUTH_USER_NAME {
+            if let Ok(Type::Object(role)) = c.ts.lookup_builtin_type(AUTH_USER_ROLE_NAME) {
+                dependents.push((role, "userId".to_owned()));
+            }
+        }
         Ok(Self {
             base_entity,
             filter_query_plan: query_plan,
+            delete_allowed: permissions.delete,
+            dependents,
         })
     }
 
+    pub(crate) fn base_entity(&self) -> &Arc<ObjectType> {
+        &self.base_entity
+    }
+
+    /// Builds a query for the ids of the rows this mutation deletes.
+    pub(crate) fn build_ids_sql(&self, target: TargetDatabase) -> Result<String> {
+        self.filter_query_plan.build_ids_sql(&target)
+    }
+
+    /// Builds the SQL that deletes the dependent entities, for each of their types. It has to run
+    /// before the mutation, as it looks up the rows that the mutation deletes.
+    pub(crate) fn build_dependents_sql(&self, target: TargetDatabase) -> Result<Vec<DependentSql>> {
+        let ids_sql = self.filter_query_plan.build_ids_sql(&target)?;
+        let dependents = self.dependents.iter().map(|(ty, field)| {
+            let table = ty.backing_table().to_owned();
+            let condition = format!(r#""{field}" IN ({ids_sql})"#);
+            DependentSql {
+                ids_sql: format!(r#"SELECT "id" FROM "{table}" WHERE {condition}"#),
+                delete_sql: format!(r#"DELETE FROM "{table}" WHERE {condition}"#),
+                table,
+            }
+        });
+        Ok(dependents.collect())
+    }
+
     pub(crate) fn build_sql(&self, target: TargetDatabase) -> Result<String> {
-        let select_sql = self.filter_query_plan.build_query(&target)?.raw_sql;
-        let id_column = ColumnAlias {
-            field_name: "id".to_owned(),
-            table_name: self.base_entity.backing_table().to_owned(),
-        };
+        if !self.delete_allowed {
+            return Err(
+                PolicyError::WriteDenied("delete", self.base_entity.name().to_owned()).into(),
+            );
+        }
+        let ids_sql = self.filter_query_plan.build_ids_sql(&target)?;
         let raw_sql = format!(
             r#"DELETE FROM "{base_table}"
-                WHERE "id" IN (
-                    SELECT "{id_column}" FROM ({select_sql}) as subquery
-                )"#,
+                WHERE "id" IN ({ids_sql})"#,
             base_table = &self.base_entity.backing_table(),
         );
         Ok(raw_sql)
@@ -884,7 +1041,10 @@ pub(crate) mod tests {
         values: &serde_json::Value,
     ) {
         let ins_row = values.as_object().unwrap();
-        query_engine.add_row(entity, ins_row, None).await.unwrap();
+        query_engine
+            .add_row(entity, ins_row, &WritePermissions::default(), None)
+            .await
+            .unwrap();
         let rows = fetch_rows(query_engine, entity).await;
         assert!(rows.iter().any(|row| {
             ins_row.iter().all(|(key, value)| {
@@ -941,7 +1101,9 @@ pub(crate) mod tests {
                     ts: &make_type_system(&*ENTITIES),
                     api_version: VERSION.to_owned(),
                     user_id: None,
+                    user_roles: vec![],
                     path: "".to_string(),
+                    secrets: None,
                 },
                 op_chain,
             )
@@ -1008,10 +1170,13 @@ pub(crate) mod tests {
                     ts: &make_type_system(&*ENTITIES),
                     api_version: VERSION.to_owned(),
                     user_id: None,
+                    user_roles: vec![],
                     path: "".to_string(),
+                    secrets: None,
                 },
                 entity_name,
                 &Some(expr),
+                &WritePermissions::default(),
             )
             .unwrap()
         };
@@ -1054,4 +1219,160 @@ pub(crate) mod tests {
             assert_eq!(fetch_rows(&qe, &COMPANY_TY).await.len(), 0);
         }
     }
+
+    #[tokio::test]
+    async fn test_read_filter() {
+        let mut policies = Policies::default();
+        policies
+            .add_from_yaml(
+                VERSION,
+                r#"
+entities:
+  - name: Person
+    read: age > 25 || name == $user.id
+"#,
+            )
+            .unwrap();
+        let context = |user_id: Option<&str>| RequestContext {
+            policies: &policies,
+            ts: &TS,
+            api_version: VERSION.to_owned(),
+            user_id: user_id.map(|u| u.to_owned()),
+            user_roles: vec![],
+            path: "".to_string(),
+            secrets: None,
+        };
+        let fetch_names = |qe: QueryEngine, c: RequestContext| async move {
+            let op_chain = QueryOpChain::BaseEntity {
+                name: "Person".to_owned(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&c, op_chain).unwrap();
+            let mut names: Vec<_> = fetch_rows_with_plan(&qe, query_plan)
+                .await
+                .iter()
+                .map(|r| r["name"].as_str().unwrap().to_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let john = json!({"name": "John", "age": json!(20f32)});
+        let alan = json!({"name": "Alan", "age": json!(30f32)});
+        let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+        add_row(&qe, &PERSON_TY, &john).await;
+        add_row(&qe, &PERSON_TY, &alan).await;
+
+        assert_eq!(fetch_names(qe.clone(), context(None)).await, vec!["Alan"]);
+        assert_eq!(
+            fetch_names(qe.clone(), context(Some("John"))).await,
+            vec!["Alan", "John"]
+        );
+
+        // Only the visible rows can be updated.
+        let john_id = fetch_rows(&qe, &PERSON_TY)
+            .await
+            .into_iter()
+            .find(|r| r["name"] == "John")
+            .unwrap()["id"]
+            .clone();
+        let update = json!({"id": john_id, "name": "John", "age": json!(21f32)});
+        let update = update.as_object().unwrap();
+        let mut permissions = WritePermissions::default();
+        permissions.readable =
+            QueryPlan::readable_ids_sql(&context(None), &PERSON_TY, &qe.target_db()).unwrap();
+        assert!(permissions.readable.is_some());
+        let r = qe.add_row(&PERSON_TY, update, &permissions, None).await;
+        assert!(r
+            .err()
+            .map_or(false, |e| e.downcast_ref::<PolicyError>().is_some()));
+        permissions.readable =
+            QueryPlan::readable_ids_sql(&context(Some("John")), &PERSON_TY, &qe.target_db())
+                .unwrap();
+        qe.add_row(&PERSON_TY, update, &permissions, None)
+            .await
+            .unwrap();
+
+        // Deleting everything only deletes the visible rows.
+        let mutation = Mutation::delete_from_expr(
+            &context(None),
+            "Person",
+            &None,
+            &WritePermissions::default(),
+        )
+        .unwrap();
+        qe.mutate(mutation).await.unwrap();
+        let rows = fetch_rows(&qe, &PERSON_TY).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["name"], "John");
+    }
+
+    #[tokio::test]
+    async fn test_write_permissions() {
+        fn is_policy_error<T>(r: Result<T>) -> bool {
+            r.err()
+                .map_or(false, |e| e.downcast_ref::<PolicyError>().is_some())
+        }
+        let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+        add_row(
+            &qe,
+            &PERSON_TY,
+            &json!({"name": "John", "age": json!(20f32)}),
+        )
+        .await;
+        let id = fetch_rows(&qe, &PERSON_TY).await[0]["id"].clone();
+
+        let mut permissions = WritePermissions::default();
+        permissions.readonly.insert("age".to_owned());
+        let update = |name: &str, age: f32| {
+            json!({"id": id, "name": name, "age": json!(age)})
+                .as_object()
+                .unwrap()
+                .clone()
+        };
+        let r = qe
+            .add_row(&PERSON_TY, &update("John", 21.), &permissions, None)
+            .await;
+        assert!(is_policy_error(r));
+        qe.add_row(&PERSON_TY, &update("Johnny", 20.), &permissions, None)
+            .await
+            .unwrap();
+        assert_eq!(fetch_rows(&qe, &PERSON_TY).await[0]["name"], "Johnny");
+
+        permissions.update = false;
+        let r = qe
+            .add_row(&PERSON_TY, &update("John", 20.), &permissions, None)
+            .await;
+        assert!(is_policy_error(r));
+
+        permissions.update = true;
+        permissions.create = false;
+        let alan = json!({"name": "Alan", "age": json!(30f32)});
+        let r = qe
+            .add_row(&PERSON_TY, alan.as_object().unwrap(), &permissions, None)
+            .await;
+        assert!(is_policy_error(r));
+        qe.add_row(&PERSON_TY, &update("John", 20.), &permissions, None)
+            .await
+            .unwrap();
+        assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 1);
+
+        permissions.delete = false;
+        let mutation = Mutation::delete_from_expr(
+            &RequestContext {
+                policies: &Policies::default(),
+                ts: &TS,
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                user_roles: vec![],
+                path: "".to_string(),
+                secrets: None,
+            },
+            "Person",
+            &None,
+            &permissions,
+        )
+        .unwrap();
+        assert!(is_policy_error(qe.mutate(mutation).await));
+        assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 1);
+    }
 }
@@ -1,19 +1,26 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::ApiService;
-use crate::api::{response_template, Body, RequestPath};
-use crate::auth::get_username_from_id;
+use crate::api::{response_template, Body, RequestPath, RequestTimeout};
+use crate::audit::{Auditor, AUDIT_RECORD_NAME};
+use crate::auth::{get_api_key, get_user_roles, get_username_from_id};
+use crate::datastore::changes::{Change, Subscription, SubscriptionEvent};
 use crate::datastore::crud;
 use crate::datastore::engine::extract_transaction;
 use crate::datastore::engine::IdTree;
-use crate::datastore::engine::TransactionStatic;
 use crate::datastore::engine::{QueryResults, ResultRow};
+use crate::datastore::engine::{TransactionStatic, WriterLock};
 use crate::datastore::expr::Expr;
-use crate::datastore::query::{Mutation, QueryOpChain, QueryPlan, RequestContext};
+use crate::datastore::query::{Mutation, QueryOpChain, QueryPlan, RequestContext, TargetDatabase};
 use crate::datastore::MetaService;
 use crate::datastore::QueryEngine;
-use crate::policies::Policies;
+use crate::jwt::{self, JwtConfig, JWT_SECRET};
+use crate::policies::{Policies, PolicyError, WritePermissions};
+use crate::prefix_map::PathMatch;
+use crate::rate_limit::{DeferredRateLimit, API_KEY_HEADER};
 use crate::rcmut::RcMut;
+use crate::telemetry;
+use crate::types::ObjectType;
 use crate::types::Type;
 use crate::types::TypeSystem;
 use crate::types::TypeSystemError;
@@ -25,9 +32,11 @@ use api::endpoint_js;
 use api::worker_js;
 use async_channel::Receiver;
 use async_channel::Sender;
+use async_lock::RwLock;
 use deno_core::error::AnyError;
 use deno_core::op;
 use deno_core::v8;
+use deno_core::AsyncRefCell;
 use deno_core::CancelFuture;
 use deno_core::CancelHandle;
 use deno_core::Extension;
@@ -49,11 +58,16 @@ use deno_runtime::web_worker::WebWorker;
 use deno_runtime::web_worker::WebWorkerOptions;
 use deno_runtime::worker::{MainWorker, WorkerOptions};
 use deno_runtime::BootstrapOptions;
-use futures::stream::{try_unfold, Stream};
+use futures::future::Either;
+use futures::stream::{try_unfold, SplitSink, SplitStream, Stream};
 use futures::task::LocalFutureObj;
 use futures::FutureExt;
-use futures::{future, StreamExt};
+use futures::{future, SinkExt, StreamExt};
 use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderValue, AUTHORIZATION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::upgrade::{OnUpgrade, Upgraded};
 use hyper::Method;
 use hyper::Uri;
 use hyper::{Request, Response, StatusCode};
@@ -62,11 +76,11 @@ use once_cell::unsync::OnceCell;
 use pin_project::pin_project;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
-use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::TryInto;
+use std::ffi::c_void;
 use std::fmt::Debug;
 use std::future::Future;
 use std::io::Read;
@@ -76,10 +90,17 @@ use std::ops::DerefMut;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::rc::Weak;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::Builder;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
 
 // FIXME: This should not be here. The client should download and
 // compile modules, the server should not get code out of the
@@ -96,15 +117,63 @@ struct VersionedCode {
 
 enum WorkerMsg {
     SetMeta(MetaService),
-    HandleRequest(Request<hyper::Body>),
     SetTypeSystem(TypeSystem),
     RemoveTypeVersion(String),
     SetQueryEngine(Arc<QueryEngine>),
+    SetJwtConfig(Arc<JwtConfig>),
     SetPolicies(Policies),
-    MutatePolicies(Box<dyn FnOnce(&mut Policies) + Send>),
     SetCurrentSecrets(JsonObject),
 }
 
+/// The state that the executor gave its worker, for a replacement worker to get it too.
+#[derive(Default)]
+struct WorkerState {
+    meta: Option<MetaService>,
+    type_system: Option<TypeSystem>,
+    query_engine: Option<Arc<QueryEngine>>,
+    jwt_config: Option<Arc<JwtConfig>>,
+    policies: Option<Policies>,
+    secrets: Option<JsonObject>,
+}
+
+impl WorkerState {
+    fn update(&mut self, msg: &WorkerMsg) {
+        match msg {
+            WorkerMsg::SetMeta(meta) => self.meta = Some(meta.clone()),
+            WorkerMsg::SetTypeSystem(type_system) => self.type_system = Some(type_system.clone()),
+            WorkerMsg::RemoveTypeVersion(version) => {
+                if let Some(type_system) = &mut self.type_system {
+                    type_system.versions.remove(version);
+                }
+            }
+            WorkerMsg::SetQueryEngine(query_engine) => {
+                self.query_engine = Some(query_engine.clone())
+            }
+            WorkerMsg::SetJwtConfig(config) => self.jwt_config = Some(config.clone()),
+            WorkerMsg::SetPolicies(policies) => self.policies = Some(policies.clone()),
+            WorkerMsg::SetCurrentSecrets(secrets) => self.secrets = Some(secrets.clone()),
+        }
+    }
+
+    fn check_hash_secrets(&self) {
+        if let Some(policies) = &self.policies {
+            policies.check_hash_secrets(self.secrets.as_ref());
+        }
+    }
+
+    /// The messages that give a new worker this state.
+    fn messages(&self) -> Vec<WorkerMsg> {
+        let mut msgs = vec![];
+        msgs.extend(self.secrets.clone().map(WorkerMsg::SetCurrentSecrets));
+        msgs.extend(self.type_system.clone().map(WorkerMsg::SetTypeSystem));
+        msgs.extend(self.query_engine.clone().map(WorkerMsg::SetQueryEngine));
+        msgs.extend(self.jwt_config.clone().map(WorkerMsg::SetJwtConfig));
+        msgs.extend(self.policies.clone().map(WorkerMsg::SetPolicies));
+        msgs.extend(self.meta.clone().map(WorkerMsg::SetMeta));
+        msgs
+    }
+}
+
 /// A v8 isolate doesn't want to be moved between or used from
 /// multiple threads. A JsRuntime owns an isolate, so we need to use a
 /// thread local storage.
@@ -127,18 +196,38 @@ struct DenoService {
 
     import_endpoint: v8::Global<v8::Function>,
     activate_endpoint: v8::Global<v8::Function>,
+    import_transform: v8::Global<v8::Function>,
+    apply_transform: v8::Global<v8::Function>,
     call_handler: v8::Global<v8::Function>,
     read_worker_channel: v8::Global<v8::Function>,
     end_of_request: v8::Global<v8::Function>,
+    abort_request: v8::Global<v8::Function>,
+    init_worker: v8::Global<v8::Function>,
+    restart_worker: v8::Global<v8::Function>,
 
     to_worker: Sender<WorkerMsg>,
+    /// IDs of the requests whose handlers timed out, for the worker to abort.
+    to_abort: Sender<u32>,
+    /// Handle of the worker's isolate, once the worker is running.
+    worker_isolate: Arc<Mutex<Option<v8::IsolateHandle>>>,
     worker_channel_id: u32,
+    /// What the worker took from the executor, kept to hand it to a replacement worker.
+    channel: Channel,
+    /// Receives a message when the worker's isolate nears its heap limit.
+    out_of_memory: Receiver<()>,
+    worker_state: WorkerState,
+    /// Paths of the endpoints loaded in the worker.
+    endpoints: HashSet<String>,
+    /// Held for reading while talking to the worker, and for writing while replacing it.
+    worker_gate: Rc<RwLock<()>>,
 }
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error["Endpoint didn't produce a response"]]
     NotAResponse,
+    #[error["Endpoint worker is not running"]]
+    NoWorker,
 }
 
 struct ModuleLoaderInner {
@@ -233,16 +322,40 @@ fn build_extensions() -> Vec<Extension> {
             op_chisel_crud_query::decl(),
             op_chisel_relational_query_create::decl(),
             op_chisel_query_next::decl(),
+            op_chisel_subscribe::decl(),
+            op_chisel_crud_subscribe::decl(),
+            op_chisel_subscription_next::decl(),
             op_chisel_commit_transaction::decl(),
+            op_chisel_commit_before_stream::decl(),
             op_chisel_rollback_transaction::decl(),
             op_chisel_create_transaction::decl(),
+            op_chisel_log::decl(),
             op_chisel_init_worker::decl(),
             op_chisel_read_worker_channel::decl(),
             op_chisel_start_request::decl(),
+            op_chisel_next_abort::decl(),
+            op_chisel_abort_request::decl(),
+            op_chisel_websocket_accept::decl(),
+            op_chisel_websocket_open::decl(),
+            op_chisel_websocket_next::decl(),
+            op_chisel_websocket_send_text::decl(),
+            op_chisel_websocket_send_binary::decl(),
+            op_chisel_websocket_close::decl(),
+            op_chisel_start_message::decl(),
         ])
         .build()]
 }
 
+/// Errors from policy violations get their own class in JavaScript, so that the
+/// worker can answer them with 403.
+fn get_error_class_name(e: &AnyError) -> &'static str {
+    if e.downcast_ref::<PolicyError>().is_some() {
+        "PolicyError"
+    } else {
+        "Error"
+    }
+}
+
 fn create_web_worker(
     bootstrap: BootstrapOptions,
     preload_module_cb: Arc<PreloadModuleCb>,
@@ -278,24 +391,62 @@ fn create_web_worker(
             use_deno_namespace: args.use_deno_namespace,
             worker_type: args.worker_type,
             maybe_inspector_server: maybe_inspector_server.clone(),
-            get_error_class_fn: None,
+            get_error_class_fn: Some(&get_error_class_name),
             blob_store: Default::default(),
             broadcast_channel: Default::default(),
             shared_array_buffer_store: None,
             compiled_wasm_module_store: None,
             maybe_exit_code: args.maybe_exit_code,
         };
-        WebWorker::bootstrap_from_options(
+        let (mut worker, handle) = WebWorker::bootstrap_from_options(
             args.name,
             args.permissions,
             args.main_module,
             args.worker_id,
             options,
-        )
+        );
+        // This runs in the thread of the new worker, where op_chisel_init_worker picks it up.
+        let isolate = worker.js_runtime.v8_isolate().thread_safe_handle();
+        WORKER_ISOLATE.with(|i| *i.borrow_mut() = Some(isolate.clone()));
+        let context = worker.js_runtime.global_context();
+        WORKER_CONTEXT.with(|c| *c.borrow_mut() = Some(context));
+        // Rather than letting V8 abort the process, give the isolate room to unwind the
+        // JavaScript that is terminated here, and have the executor replace the worker.
+        let mut out_of_memory = false;
+        worker
+            .js_runtime
+            .add_near_heap_limit_callback(move |current, _initial| {
+                if !out_of_memory {
+                    out_of_memory = true;
+                    isolate.terminate_execution();
+                    WORKER_CHANNEL.with(|d| {
+                        if let Some(channel) = d.get() {
+                            channel.out_of_memory.try_send(()).ok();
+                        }
+                    });
+                }
+                current * 2
+            });
+        (worker, handle)
     })
 }
 
-type Channel = Receiver<WorkerMsg>;
+/// What a web worker takes from its executor when it starts.
+#[derive(Clone)]
+struct Channel {
+    /// Messages from the executor.
+    msgs: Receiver<WorkerMsg>,
+    /// IDs of the requests to abort because their handlers timed out.
+    aborts: Receiver<u32>,
+    /// Where the worker leaves the handle of its isolate, for the executor to terminate the
+    /// handlers that time out.
+    isolate: Arc<Mutex<Option<v8::IsolateHandle>>>,
+    /// Tells the executor that the worker's isolate is nearing its heap limit.
+    out_of_memory: Sender<()>,
+    /// Requests for the worker to handle, by ID, until op_chisel_start_request takes them.
+    requests: Arc<Mutex<HashMap<u32, Request<hyper::Body>>>>,
+}
+
 type GlobalChannels = VecMap<Channel>;
 
 lazy_static! {
@@ -304,10 +455,13 @@ lazy_static! {
 
 thread_local! {
      static WORKER_CHANNEL: OnceCell<Channel> = OnceCell::new();
+     static WORKER_ISOLATE: RefCell<Option<v8::IsolateHandle>> = RefCell::new(None);
+     /// Context of the worker's JavaScript, for terminate_if_running to look into.
+     static WORKER_CONTEXT: RefCell<Option<v8::Global<v8::Context>>> = RefCell::new(None);
 }
 
 impl DenoService {
-    pub(crate) async fn new(inspect_brk: bool) -> (Self, v8::Global<v8::Function>) {
+    pub(crate) async fn new(inspect_brk: bool) -> Self {
         let web_worker_preload_module_cb =
             Arc::new(|worker| LocalFutureObj::new(Box::new(future::ready(Ok(worker)))));
         let inner = Arc::new(std::sync::Mutex::new(ModuleLoaderInner {
@@ -423,10 +577,14 @@ impl DenoService {
         let (
             import_endpoint,
             activate_endpoint,
+            import_transform,
+            apply_transform,
             call_handler,
             init_worker,
             read_worker_channel,
             end_of_request,
+            abort_request,
+            restart_worker,
         ) = {
             let runtime = &mut worker.js_runtime;
             let promise = runtime
@@ -441,6 +599,12 @@ impl DenoService {
             let activate_endpoint: v8::Local<v8::Function> =
                 get_member(module, scope, "activateEndpoint").unwrap();
             let activate_endpoint = v8::Global::new(scope, activate_endpoint);
+            let import_transform: v8::Local<v8::Function> =
+                get_member(module, scope, "importTransform").unwrap();
+            let import_transform = v8::Global::new(scope, import_transform);
+            let apply_transform: v8::Local<v8::Function> =
+                get_member(module, scope, "applyTransform").unwrap();
+            let apply_transform = v8::Global::new(scope, apply_transform);
             let call_handler: v8::Local<v8::Function> =
                 get_member(module, scope, "callHandler").unwrap();
             let call_handler = v8::Global::new(scope, call_handler);
@@ -453,36 +617,87 @@ impl DenoService {
             let end_of_request: v8::Local<v8::Function> =
                 get_member(module, scope, "endOfRequest").unwrap();
             let end_of_request = v8::Global::new(scope, end_of_request);
+            let abort_request: v8::Local<v8::Function> =
+                get_member(module, scope, "abortRequest").unwrap();
+            let abort_request = v8::Global::new(scope, abort_request);
+            let restart_worker: v8::Local<v8::Function> =
+                get_member(module, scope, "restartWorker").unwrap();
+            let restart_worker = v8::Global::new(scope, restart_worker);
 
             (
                 import_endpoint,
                 activate_endpoint,
+                import_transform,
+                apply_transform,
                 call_handler,
                 init_worker,
                 read_worker_channel,
                 end_of_request,
+                abort_request,
+                restart_worker,
             )
         };
 
         let (to_worker_sender, to_worker_receiver) = async_channel::bounded(1);
+        let (to_abort, aborts) = async_channel::unbounded();
+        let (out_of_memory_sender, out_of_memory) = async_channel::unbounded();
+        let worker_isolate = Arc::new(Mutex::new(None));
+        let channel = Channel {
+            msgs: to_worker_receiver,
+            aborts,
+            isolate: worker_isolate.clone(),
+            out_of_memory: out_of_memory_sender,
+            requests: Default::default(),
+        };
         let mut map = GLOBAL_WORKER_CHANNELS.lock().unwrap();
-        let worker_channel_id = map.push(to_worker_receiver) as u32;
+        let worker_channel_id = map.push(channel.clone()) as u32;
 
-        (
-            Self {
-                worker,
-                inspector,
-                module_loader: inner,
-                import_endpoint,
-                activate_endpoint,
-                call_handler,
-                to_worker: to_worker_sender,
-                worker_channel_id,
-                read_worker_channel,
-                end_of_request,
-            },
+        Self {
+            worker,
+            inspector,
+            module_loader: inner,
+            import_endpoint,
+            activate_endpoint,
+            import_transform,
+            apply_transform,
+            call_handler,
+            to_worker: to_worker_sender,
+            to_abort,
+            worker_isolate,
+            worker_channel_id,
+            read_worker_channel,
+            end_of_request,
+            abort_request,
             init_worker,
-        )
+            restart_worker,
+            channel,
+            out_of_memory,
+            worker_state: WorkerState::default(),
+            endpoints: HashSet::new(),
+            worker_gate: Rc::new(RwLock::new(())),
+        }
+    }
+
+    /// Calls initWorker, for the worker to take the channel registered under
+    /// `worker_channel_id`.
+    fn call_init_worker(&mut self) -> v8::Global<v8::Value> {
+        let runtime = &mut self.worker.js_runtime;
+        let scope = &mut runtime.handle_scope();
+        let undefined = v8::undefined(scope).into();
+        let id = v8::Number::new(scope, self.worker_channel_id as f64).into();
+        let promise = self
+            .init_worker
+            .open(scope)
+            .call(scope, undefined, &[id])
+            .unwrap();
+        v8::Global::new(scope, promise)
+    }
+
+    /// Discards the messages, requests and aborts that no worker is going to take.
+    fn drain_channel(&self) {
+        while self.channel.msgs.try_recv().is_ok() {}
+        while self.channel.aborts.try_recv().is_ok() {}
+        self.channel.requests.lock().unwrap().clear();
     }
 }
 
@@ -505,13 +720,17 @@ async fn op_chisel_read_body(
     state: Rc<RefCell<OpState>>,
     body_rid: ResourceId,
 ) -> Result<Option<ZeroCopyBuf>> {
-    let resource: Rc<BodyResource> = state.borrow().resource_table.get(body_rid)?;
-    let cancel = RcRef::map(&resource, |r| &r.cancel);
-    let fut = ReadFuture {
-        resource: resource.clone(),
+    let traceparent = resource_traceparent(&state.borrow(), body_rid);
+    let inner = async move {
+        let resource: Rc<BodyResource> = state.borrow().resource_table.get(body_rid)?;
+        let cancel = RcRef::map(&resource, |r| &r.cancel);
+        let fut = ReadFuture {
+            resource: resource.clone(),
+        };
+        let fut = fut.or_cancel(cancel);
+        Ok(fut.await?.transpose()?.map(|x| x.to_vec().into()))
     };
-    let fut = fut.or_cancel(cancel);
-    Ok(fut.await?.transpose()?.map(|x| x.to_vec().into()))
+    telemetry::in_child_span("op_chisel_read_body", traceparent.as_deref(), inner).await
 }
 
 /// RequestContext corresponds to `requestContext` structure used in chisel.ts.
@@ -528,6 +747,19 @@ struct ChiselRequestContext {
     /// Current user ID.
     #[serde(rename = "userId")]
     user_id: Option<String>,
+    /// Username of the current user, if the credentials of the request gave it.
+    #[serde(default)]
+    username: Option<String>,
+    /// Roles of the current user.
+    #[serde(rename = "userRoles", default)]
+    user_roles: Vec<String>,
+    /// ID that correlates the log lines of the request.
+    #[serde(rename = "requestId", default)]
+    request_id: Option<String>,
+    /// ID of the request within the worker, which handles many at once. Code that doesn't run
+    /// for a request has none.
+    #[serde(rename = "handlerId", default)]
+    handler_id: Option<u32>,
 }
 
 #[derive(Deserialize)]
@@ -545,6 +777,17 @@ async fn op_chisel_store(
     state: Rc<RefCell<OpState>>,
     content: StoreContent,
     c: ChiselRequestContext,
+) -> Result<IdTree> {
+    let request_id = c.request_id.clone();
+    let traceparent = request_traceparent(&state.borrow(), c.handler_id);
+    let inner = crate::logging::scoped(request_id, op_chisel_store_impl(state, content, c));
+    telemetry::in_child_span("op_chisel_store", traceparent.as_deref(), inner).await
+}
+
+async fn op_chisel_store_impl(
+    state: Rc<RefCell<OpState>>,
+    content: StoreContent,
+    c: ChiselRequestContext,
 ) -> Result<IdTree> {
     let type_name = &content.name;
     let value = &content.value;
@@ -562,14 +805,212 @@ async fn op_chisel_store(
         let query_engine = query_engine_arc(&state);
         (query_engine, ty)
     };
+    let auditor = make_auditor(&state.borrow(), &c, &ty)?;
+    let mut permissions = make_write_permissions(state.clone(), &c, &ty).await;
+    limit_updates_to_readable(&state.borrow(), &c, &ty, &mut permissions)?;
     let transaction = {
         let state = state.borrow();
-        current_transaction(&state)
+        current_transaction(&state, c.handler_id)?
     };
+    let auditor = match auditor {
+        None => {
+            let id_tree = {
+                let mut transaction = transaction.lock().await;
+                query_engine
+                    .add_row(&ty, value, &permissions, Some(transaction.deref_mut()))
+                    .await?
+            };
+            add_request_changes(&mut state.borrow_mut(), c.handler_id, id_tree.changes(&ty));
+            return Ok(id_tree);
+        }
+        Some(auditor) => auditor,
+    };
+    let before = match value.get("id").and_then(|id| id.as_str()) {
+        Some(id) => {
+            query_engine
+                .fetch_stored_row(transaction.clone(), &ty, id)
+                .await?
+        }
+        None => None,
+    };
+    let id_tree = {
+        let mut transaction = transaction.lock().await;
+        query_engine
+            .add_row(&ty, value, &permissions, Some(transaction.deref_mut()))
+            .await?
+    };
+    add_request_changes(&mut state.borrow_mut(), c.handler_id, id_tree.changes(&ty));
+    let after = query_engine
+        .fetch_stored_row(transaction.clone(), &ty, &id_tree.id)
+        .await?;
     let mut transaction = transaction.lock().await;
-    query_engine
-        .add_row(&ty, value, Some(transaction.deref_mut()))
-        .await
+    let changes = auditor
+        .record(
+            &query_engine,
+            transaction.deref_mut(),
+            &id_tree.id,
+            before.as_ref(),
+            after.as_ref(),
+        )
+        .await?;
+    add_request_changes(&mut state.borrow_mut(), c.handler_id, changes);
+    Ok(id_tree)
+}
+
+/// Returns what records the changes to entities of type `ty`, if policies mark them as audited.
+fn make_auditor(
+    state: &OpState,
+    c: &ChiselRequestContext,
+    ty: &Arc<ObjectType>,
+) -> Result<Option<Auditor>> {
+    let audited = current_policies(state)
+        .versions
+        .get(&ty.api_version)
+        .map_or(false, |v| v.is_audited(ty));
+    if !audited {
+        return Ok(None);
+    }
+    let record_type = match lookup_builtin_type(state, AUDIT_RECORD_NAME) {
+        Ok(Type::Object(t)) => t,
+        _ => anyhow::bail!("Internal error: type {} not found", AUDIT_RECORD_NAME),
+    };
+    Ok(Some(Auditor::new(
+        record_type,
+        ty.clone(),
+        c.user_id.clone(),
+        c.path.clone(),
+        c.api_version.clone(),
+    )))
+}
+
+/// Like make_auditor, for a type that is looked up by name.
+fn make_auditor_by_name(
+    state: &OpState,
+    c: &ChiselRequestContext,
+    type_name: &str,
+) -> Result<Option<Auditor>> {
+    match current_type_system(state).lookup_object_type(type_name, &c.api_version) {
+        Ok(ty) => make_auditor(state, c, &ty),
+        // Building the mutation will fail and report it.
+        Err(_) => Ok(None),
+    }
+}
+
+/// Runs a delete `mutation`. Deletions of audited entities are recorded, and run in the
+/// transaction of the request so they are committed along with the records.
+async fn run_delete(
+    state: Rc<RefCell<OpState>>,
+    handler_id: Option<u32>,
+    mutation: Mutation,
+    auditor: Option<Auditor>,
+) -> Result<()> {
+    // Endpoints can't erase the audit log.
+    if mutation.base_entity().name() == AUDIT_RECORD_NAME {
+        anyhow::bail!("Cannot delete from type {}.", AUDIT_RECORD_NAME);
+    }
+    let query_engine = query_engine_arc(&state.borrow());
+    let auditor = match auditor {
+        None => return query_engine.mutate(mutation).await,
+        Some(auditor) => auditor,
+    };
+    let transaction = current_transaction(&state.borrow(), handler_id)?;
+    let table = mutation.base_entity().backing_table().to_owned();
+    let (deleted, mut changes) = query_engine
+        .mutate_returning(mutation, transaction.clone())
+        .await?;
+    {
+        let mut transaction = transaction.lock().await;
+        for row in &deleted {
+            let id = row["id"].as_str().unwrap_or_default();
+            changes.push(Change {
+                table: table.clone(),
+                id: id.to_owned(),
+            });
+            let recorded = auditor
+                .record(&query_engine, transaction.deref_mut(), id, Some(row), None)
+                .await?;
+            changes.extend(recorded);
+        }
+    }
+    add_request_changes(&mut state.borrow_mut(), handler_id, changes);
+    Ok(())
+}
+
+/// Computes what the user making the request may write into entities of type `ty`.
+async fn make_write_permissions(
+    state: Rc<RefCell<OpState>>,
+    c: &ChiselRequestContext,
+    ty: &ObjectType,
+) -> WritePermissions {
+    // Looking the username up costs a query, so only do it if there are rules that need it.
+    let has_entity_rules = {
+        let state = state.borrow();
+        current_policies(&state)
+            .versions
+            .get(&c.api_version)
+            .map_or(false, |v| !v.entities.is_empty())
+    };
+    let username = match &c.username {
+        Some(username) => Some(username.clone()),
+        None if has_entity_rules => get_username_from_id(state.clone(), c.user_id.clone()).await,
+        None => None,
+    };
+    let state = state.borrow();
+    current_policies(&state).make_write_permissions(&username, &c.user_roles, &c.path, ty)
+}
+
+/// Limits the updates that `permissions` allow on entities of type `ty`, and on the entities
+/// nested in them, to those that the user making the request may read.
+fn limit_updates_to_readable(
+    state: &OpState,
+    c: &ChiselRequestContext,
+    ty: &ObjectType,
+    permissions: &mut WritePermissions,
+) -> Result<()> {
+    let context = RequestContext {
+        policies: current_policies(state),
+        ts: current_type_system(state),
+        api_version: c.api_version.clone(),
+        user_id: c.user_id.clone(),
+        user_roles: c.user_roles.clone(),
+        path: c.path.clone(),
+        secrets: current_secrets(state),
+    };
+    let target = query_engine_arc(state).target_db();
+    fn limit(
+        context: &RequestContext,
+        target: &TargetDatabase,
+        ty: &ObjectType,
+        permissions: &mut WritePermissions,
+    ) -> Result<()> {
+        permissions.readable = QueryPlan::readable_ids_sql(context, ty, target)?;
+        for fld in ty.user_fields() {
+            if let (Type::Object(nested_ty), Some(nested)) =
+                (&fld.type_, permissions.nested.get_mut(&fld.name))
+            {
+                limit(context, target, nested_ty, nested)?;
+            }
+        }
+        Ok(())
+    }
+    limit(&context, &target, ty, permissions)
+}
+
+/// Like make_write_permissions, for a type that is looked up by name.
+async fn make_write_permissions_by_name(
+    state: Rc<RefCell<OpState>>,
+    c: &ChiselRequestContext,
+    type_name: &str,
+) -> WritePermissions {
+    let ty = {
+        let state = state.borrow();
+        current_type_system(&state).lookup_object_type(type_name, &c.api_version)
+    };
+    match ty {
+        Ok(ty) => make_write_permissions(state, c, &ty).await,
+        // Building the mutation will fail and report it.
+        Err(_) => WritePermissions::default(),
+    }
 }
 
 #[derive(Deserialize)]
@@ -586,6 +1027,24 @@ async fn op_chisel_entity_delete(
     params: DeleteParams,
     context: ChiselRequestContext,
 ) -> Result<()> {
+    let request_id = context.request_id.clone();
+    let traceparent = request_traceparent(&state.borrow(), context.handler_id);
+    let inner = crate::logging::scoped(
+        request_id,
+        op_chisel_entity_delete_impl(state, params, context),
+    );
+    telemetry::in_child_span("op_chisel_entity_delete", traceparent.as_deref(), inner).await
+}
+
+async fn op_chisel_entity_delete_impl(
+    state: Rc<RefCell<OpState>>,
+    params: DeleteParams,
+    context: ChiselRequestContext,
+) -> Result<()> {
+    let handler_id = context.handler_id;
+    let auditor = make_auditor_by_name(&state.borrow(), &context, &params.type_name)?;
+    let permissions =
+        make_write_permissions_by_name(state.clone(), &context, &params.type_name).await;
     let mutation = {
         let state = state.borrow_mut();
         Mutation::delete_from_expr(
@@ -594,17 +1053,19 @@ async fn op_chisel_entity_delete(
                 ts: current_type_system(&state),
                 api_version: context.api_version,
                 user_id: context.user_id,
+                user_roles: context.user_roles,
                 path: context.path,
+                secrets: current_secrets(&state),
             },
             &params.type_name,
             &params.filter_expr,
+            &permissions,
         )
         .context(
             "failed to construct delete expression from JSON passed to `op_chisel_entity_delete`",
         )?
     };
-    let query_engine = query_engine_arc(&state.borrow());
-    query_engine.mutate(mutation).await
+    run_delete(state, handler_id, mutation, auditor).await
 }
 
 #[derive(Deserialize)]
@@ -620,6 +1081,24 @@ async fn op_chisel_crud_delete(
     params: CrudDeleteParams,
     context: ChiselRequestContext,
 ) -> Result<()> {
+    let request_id = context.request_id.clone();
+    let traceparent = request_traceparent(&state.borrow(), context.handler_id);
+    let inner = crate::logging::scoped(
+        request_id,
+        op_chisel_crud_delete_impl(state, params, context),
+    );
+    telemetry::in_child_span("op_chisel_crud_delete", traceparent.as_deref(), inner).await
+}
+
+async fn op_chisel_crud_delete_impl(
+    state: Rc<RefCell<OpState>>,
+    params: CrudDeleteParams,
+    context: ChiselRequestContext,
+) -> Result<()> {
+    let handler_id = context.handler_id;
+    let auditor = make_auditor_by_name(&state.borrow(), &context, &params.type_name)?;
+    let permissions =
+        make_write_permissions_by_name(state.clone(), &context, &params.type_name).await;
     let mutation = {
         let state = state.borrow_mut();
         crud::delete_from_url(
@@ -628,20 +1107,19 @@ async fn op_chisel_crud_delete(
                 ts: current_type_system(&state),
                 api_version: context.api_version,
                 user_id: context.user_id,
+                user_roles: context.user_roles,
                 path: context.path,
+                secrets: current_secrets(&state),
             },
             &params.type_name,
             &params.url,
+            &permissions,
         )
         .context(
             "failed to construct delete expression from JSON passed to `op_chisel_crud_delete`",
         )?
     };
-    let query_engine = {
-        let state = state.borrow();
-        query_engine_arc(&state).clone()
-    };
-    query_engine.mutate(mutation).await
+    run_delete(state, handler_id, mutation, auditor).await
 }
 
 type DbStream = RefCell<QueryResults>;
@@ -680,6 +1158,21 @@ async fn op_chisel_crud_query(
     params: CrudQueryParams,
     context: ChiselRequestContext,
 ) -> Result<Vec<JsonObject>> {
+    let request_id = context.request_id.clone();
+    let traceparent = request_traceparent(&state.borrow(), context.handler_id);
+    let inner = crate::logging::scoped(
+        request_id,
+        op_chisel_crud_query_impl(state, params, context),
+    );
+    telemetry::in_child_span("op_chisel_crud_query", traceparent.as_deref(), inner).await
+}
+
+async fn op_chisel_crud_query_impl(
+    state: Rc<RefCell<OpState>>,
+    params: CrudQueryParams,
+    context: ChiselRequestContext,
+) -> Result<Vec<JsonObject>> {
+    let handler_id = context.handler_id;
     let stream = {
         // Contextualize stream creation to prevent state RC borrow living across await
         let op_state = &state.borrow();
@@ -689,13 +1182,15 @@ async fn op_chisel_crud_query(
                 ts: current_type_system(op_state),
                 api_version: context.api_version,
                 user_id: context.user_id,
+                user_roles: context.user_roles,
                 path: context.path,
+                secrets: current_secrets(&state),
             },
             &params.type_name,
             &params.url,
         )?;
 
-        let transaction = current_transaction(op_state);
+        let transaction = current_transaction(op_state, handler_id)?;
         let query_engine = query_engine_arc(op_state);
         query_engine.query(transaction, query_plan)?
     };
@@ -715,21 +1210,32 @@ fn op_chisel_relational_query_create(
     op_chain: QueryOpChain,
     context: ChiselRequestContext,
 ) -> Result<ResourceId> {
+    let _scope = crate::logging::enter(context.request_id.clone());
+    let traceparent = request_traceparent(op_state, context.handler_id);
+    let _span =
+        telemetry::enter_child_span("op_chisel_relational_query_create", traceparent.as_deref());
+    let handler_id = context.handler_id;
     let query_plan = QueryPlan::from_op_chain(
         &RequestContext {
             policies: current_policies(op_state),
             ts: current_type_system(op_state),
             api_version: context.api_version,
             user_id: context.user_id,
+            user_roles: context.user_roles,
             path: context.path,
+            secrets: current_secrets(op_state),
         },
         op_chain,
     )?;
-    create_query(op_state, query_plan)
+    create_query(op_state, handler_id, query_plan)
 }
 
-fn create_query(op_state: &mut OpState, query_plan: QueryPlan) -> Result<ResourceId> {
-    let transaction = current_transaction(op_state);
+fn create_query(
+    op_state: &mut OpState,
+    handler_id: Option<u32>,
+    query_plan: QueryPlan,
+) -> Result<ResourceId> {
+    let transaction = current_transaction(op_state, handler_id)?;
     let query_engine = query_engine_arc(op_state);
     let stream = query_engine.query(transaction, query_plan)?;
     let resource = QueryStreamResource {
@@ -737,6 +1243,7 @@ fn create_query(op_state: &mut OpState, query_plan: QueryPlan) -> Result<Resourc
         cancel: Default::default(),
     };
     let rid = op_state.resource_table.add(resource);
+    add_request_resource(op_state, handler_id, rid);
     Ok(rid)
 }
 
@@ -764,52 +1271,185 @@ async fn op_chisel_query_next(
     state: Rc<RefCell<OpState>>,
     query_stream_rid: ResourceId,
 ) -> Result<Option<ResultRow>> {
-    let (resource, cancel) = {
-        let rc: Rc<QueryStreamResource> = state.borrow().resource_table.get(query_stream_rid)?;
-        let cancel = RcRef::map(&rc, |r| &r.cancel);
-        (Rc::downgrade(&rc), cancel)
+    let traceparent = resource_traceparent(&state.borrow(), query_stream_rid);
+    let inner = async move {
+        let (resource, cancel) = {
+            let rc: Rc<QueryStreamResource> =
+                state.borrow().resource_table.get(query_stream_rid)?;
+            let cancel = RcRef::map(&rc, |r| &r.cancel);
+            (Rc::downgrade(&rc), cancel)
+        };
+        let fut = QueryNextFuture { resource };
+        let fut = fut.or_cancel(cancel);
+        if let Some(row) = fut.await? {
+            Ok(Some(row?))
+        } else {
+            Ok(None)
+        }
     };
-    let fut = QueryNextFuture { resource };
-    let fut = fut.or_cancel(cancel);
-    if let Some(row) = fut.await? {
-        Ok(Some(row?))
-    } else {
-        Ok(None)
+    telemetry::in_child_span("op_chisel_query_next", traceparent.as_deref(), inner).await
+}
+
+struct SubscriptionResource {
+    subscription: AsyncRefCell<Subscription>,
+    cancel: CancelHandle,
+}
+
+impl Resource for SubscriptionResource {
+    fn close(self: Rc<Self>) {
+        self.cancel.cancel();
     }
 }
 
+#[op]
+fn op_chisel_subscribe(
+    op_state: &mut OpState,
+    op_chain: QueryOpChain,
+    context: ChiselRequestContext,
+) -> Result<ResourceId> {
+    let _scope = crate::logging::enter(context.request_id.clone());
+    let traceparent = request_traceparent(op_state, context.handler_id);
+    let _span = telemetry::enter_child_span("op_chisel_subscribe", traceparent.as_deref());
+    let handler_id = context.handler_id;
+    let query_plan = QueryPlan::from_op_chain(
+        &RequestContext {
+            policies: current_policies(op_state),
+            ts: current_type_system(op_state),
+            api_version: context.api_version,
+            user_id: context.user_id,
+            user_roles: context.user_roles,
+            path: context.path,
+            secrets: current_secrets(op_state),
+        },
+        op_chain,
+    )?;
+    create_subscription(op_state, handler_id, query_plan)
+}
+
+#[op]
+fn op_chisel_crud_subscribe(
+    op_state: &mut OpState,
+    params: CrudQueryParams,
+    context: ChiselRequestContext,
+) -> Result<ResourceId> {
+    let _scope = crate::logging::enter(context.request_id.clone());
+    let traceparent = request_traceparent(op_state, context.handler_id);
+    let _span = telemetry::enter_child_span("op_chisel_crud_subscribe", traceparent.as_deref());
+    let handler_id = context.handler_id;
+    let query_plan = crud::query_plan_from_url(
+        &RequestContext {
+            policies: current_policies(op_state),
+            ts: current_type_system(op_state),
+            api_version: context.api_version,
+            user_id: context.user_id,
+            user_roles: context.user_roles,
+            path: context.path,
+            secrets: current_secrets(op_state),
+        },
+        &params.type_name,
+        &params.url,
+    )?;
+    create_subscription(op_state, handler_id, query_plan)
+}
+
+/// Subscribes to the results of `query_plan`, until the request `handler_id` ends. Unlike
+/// queries, subscriptions don't run in the transaction of the request.
+fn create_subscription(
+    op_state: &mut OpState,
+    handler_id: Option<u32>,
+    query_plan: QueryPlan,
+) -> Result<ResourceId> {
+    let query_engine = query_engine_arc(op_state);
+    let subscription = Subscription::new(query_engine, query_plan)?;
+    let resource = SubscriptionResource {
+        subscription: AsyncRefCell::new(subscription),
+        cancel: Default::default(),
+    };
+    let rid = op_state.resource_table.add(resource);
+    add_request_resource(op_state, handler_id, rid);
+    Ok(rid)
+}
+
+#[op]
+async fn op_chisel_subscription_next(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<SubscriptionEvent> {
+    let resource: Rc<SubscriptionResource> = state.borrow().resource_table.get(rid)?;
+    let cancel = RcRef::map(&resource, |r| &r.cancel);
+    let mut subscription = RcRef::map(&resource, |r| &r.subscription)
+        .borrow_mut()
+        .await;
+    subscription.next().or_cancel(cancel).await?
+}
+
 // Used by deno to format names in errors
 #[op]
 fn op_format_file_name(file_name: String) -> Result<String> {
     Ok(file_name)
 }
 
+/// Writes the console output of endpoints.
+#[op]
+fn op_chisel_log(method: String, message: String, request_id: Option<String>) {
+    crate::logging::console(&method, &message, request_id.as_deref());
+}
+
 #[op]
 fn op_chisel_init_worker(id: u32) {
     let mut map = GLOBAL_WORKER_CHANNELS.lock().unwrap();
     let channel = map.remove(id as usize).unwrap();
+    let isolate = WORKER_ISOLATE.with(|i| i.borrow_mut().take());
+    *channel.isolate.lock().unwrap() = isolate;
     WORKER_CHANNEL.with(|d| {
-        d.set(channel).unwrap();
+        d.set(channel)
+            .map_err(|_| ())
+            .expect("Worker is already initialized.");
+    });
+}
+
+/// Waits for the ID of a request whose handler timed out. The executor may have terminated the
+/// JavaScript that the worker was running, so this resumes the execution of the worker's isolate
+/// before any other JavaScript runs: the worker polls its ops before calling back into JavaScript.
+#[op]
+async fn op_chisel_next_abort() -> Result<u32> {
+    let (aborts, isolate) = WORKER_CHANNEL.with(|d| {
+        let channel = d.get().unwrap();
+        (channel.aborts.clone(), channel.isolate.clone())
     });
+    let id = aborts.recv().await?;
+    if let Some(isolate) = isolate.lock().unwrap().as_ref() {
+        isolate.cancel_terminate_execution();
+    }
+    Ok(id)
+}
+
+/// Ends the aborted request `id`, rolling back its transaction, if it has one. Unlike
+/// `op_chisel_rollback_transaction`, ops of the request may still hold the transaction, in which
+/// case it is rolled back once they drop it.
+#[op]
+fn op_chisel_abort_request(state: &mut OpState, id: u32) {
+    // The transaction is dropped before its lock. It is never committed, so the transactions that
+    // go next don't conflict with it.
+    drop(end_request(state, id));
 }
 
 #[op]
 async fn op_chisel_read_worker_channel(state: Rc<RefCell<OpState>>) -> Result<()> {
-    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().clone());
+    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().msgs.clone());
     let msg = receiver.recv().await.unwrap();
 
     let mut state = state.borrow_mut();
     let state = &mut state;
     match msg {
         WorkerMsg::SetMeta(meta) => state.put::<Rc<MetaService>>(Rc::new(meta)),
-        WorkerMsg::HandleRequest(_req) => unreachable!("Wrong message"),
         WorkerMsg::SetTypeSystem(type_system) => state.put(type_system),
         WorkerMsg::RemoveTypeVersion(version) => {
             state.borrow_mut::<TypeSystem>().versions.remove(&version);
         }
         WorkerMsg::SetQueryEngine(query_engine) => state.put(query_engine),
+        WorkerMsg::SetJwtConfig(config) => state.put(config),
         WorkerMsg::SetPolicies(policies) => state.put(policies),
-        WorkerMsg::MutatePolicies(func) => func(state.borrow_mut()),
         WorkerMsg::SetCurrentSecrets(secretes) => state.put(secretes),
     }
 
@@ -848,25 +1488,97 @@ extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
     }
 }
 
+/// Limits the V8 heap of every isolate to about `megabytes`. This must be called before any
+/// isolate is created.
+pub(crate) fn set_heap_limit(megabytes: usize) {
+    let flags = vec![String::new(), format!("--max-old-space-size={}", megabytes)];
+    deno_core::v8_set_flags(flags);
+}
+
 pub(crate) async fn init_deno(inspect_brk: bool) -> Result<()> {
-    let (service, init_worker) = DenoService::new(inspect_brk).await;
+    let service = DenoService::new(inspect_brk).await;
     DENO.with(|d| {
         d.set(Rc::new(RefCell::new(service)))
             .map_err(|_| ())
             .expect("Deno is already initialized.");
     });
 
-    let mut service = get();
-    let service: &mut DenoService = &mut service;
-    let runtime = &mut service.worker.js_runtime;
-    let scope = &mut runtime.handle_scope();
-    scope.set_promise_reject_callback(promise_reject_callback);
-    let undefined = v8::undefined(scope).into();
-    let id = v8::Number::new(scope, service.worker_channel_id as f64).into();
-    init_worker
-        .open(scope)
-        .call(scope, undefined, &[id])
-        .unwrap();
+    let (promise, out_of_memory) = {
+        let mut service = get();
+        let service: &mut DenoService = &mut service;
+        {
+            let runtime = &mut service.worker.js_runtime;
+            let scope = &mut runtime.handle_scope();
+            scope.set_promise_reject_callback(promise_reject_callback);
+        }
+        (service.call_init_worker(), service.out_of_memory.clone())
+    };
+    resolve_promise(promise).await?;
+
+    tokio::task::spawn_local(async move {
+        while out_of_memory.recv().await.is_ok() {
+            if let Err(e) = restart_worker().await {
+                error!("Could not restart the endpoint worker: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Replaces the worker, whose isolate ran out of memory, with a new one that gets the same state
+/// and endpoints. What the old worker was doing fails, including the request that ran out of
+/// memory.
+async fn restart_worker() -> Result<()> {
+    warn!("Endpoint worker ran out of memory, restarting it");
+    {
+        let mut service = get();
+        let service: &mut DenoService = &mut service;
+        *service.worker_isolate.lock().unwrap() = None;
+        let runtime = &mut service.worker.js_runtime;
+        let scope = &mut runtime.handle_scope();
+        let restart_worker = service.restart_worker.open(scope);
+        let undefined = v8::undefined(scope).into();
+        restart_worker.call(scope, undefined, &[]).unwrap();
+    }
+
+    // New requests wait for the worker once this waits for the gate.
+    let gate = get().worker_gate.clone();
+    let mut write = Box::pin(gate.write());
+    let _restarting = loop {
+        // Requests that wait to hand themselves to the worker fail once they do, and then let go
+        // of the gate.
+        get().drain_channel();
+        let tick = Box::pin(tokio::time::sleep(Duration::from_millis(1)));
+        match future::select(write, tick).await {
+            Either::Left((guard, _)) => break guard,
+            Either::Right((_, pending)) => write = pending,
+        }
+    };
+    get().drain_channel();
+
+    let promise = {
+        let mut service = get();
+        let service: &mut DenoService = &mut service;
+        let mut map = GLOBAL_WORKER_CHANNELS.lock().unwrap();
+        service.worker_channel_id = map.push(service.channel.clone()) as u32;
+        drop(map);
+        service.call_init_worker()
+    };
+    resolve_promise(promise).await?;
+
+    let msgs = get().worker_state.messages();
+    for msg in msgs {
+        send_to_worker(msg).await;
+    }
+    let endpoints: Vec<String> = get().endpoints.iter().cloned().collect();
+    for path in endpoints {
+        let module = format!("{}.js", path);
+        let version = get().module_loader.lock().unwrap().code_map[&module].version;
+        resolve_promise(import_endpoint(&path, version)).await?;
+        resolve_promise(call_activate_endpoint(&path)).await?;
+    }
+    crate::metrics::worker_restarted();
+    info!("Endpoint worker restarted");
     Ok(())
 }
 
@@ -1041,49 +1753,173 @@ fn current_policies(st: &OpState) -> &Policies {
     st.borrow()
 }
 
-fn is_allowed_by_policy(
+/// Checks the endpoint policies of the request. Returns the response to a request they don't allow.
+fn check_endpoint_policy(
     state: &OpState,
     api_version: &str,
-    username: Option<String>,
+    username: &Option<String>,
+    user_roles: &[String],
+    api_key_scopes: Option<&[String]>,
+    method: &str,
     path: &std::path::Path,
-) -> Result<bool> {
+) -> Result<Option<Response<Body>>> {
     let policies = current_policies(state);
-    match policies.versions.get(api_version) {
-        None => Err(anyhow!(
+    let version = match policies.versions.get(api_version) {
+        None => anyhow::bail!(
             "found a route, but no version object for {}/{}",
             api_version,
             path.display()
-        )),
-        Some(x) => Ok(x.user_authorization.is_allowed(username, path)),
+        ),
+        Some(version) => version,
+    };
+    if let Some((status, message)) =
+        version.check_endpoint(username, user_roles, api_key_scopes, method, path)
+    {
+        return Ok(Some(ApiService::error_response(
+            StatusCode::from_u16(status)?,
+            message,
+        )?));
     }
-}
-
-async fn mutate_policies_impl(func: Box<dyn FnOnce(&mut Policies) + Send>) {
-    to_worker(WorkerMsg::MutatePolicies(func)).await;
+    Ok(None)
 }
 
 pub(crate) async fn mutate_policies<F>(func: F)
 where
-    F: FnOnce(&mut Policies) + Send + 'static,
+    F: FnOnce(&mut Policies),
 {
-    mutate_policies_impl(Box::new(func)).await;
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
+    let policies = {
+        let mut service = get();
+        let policies = service
+            .worker_state
+            .policies
+            .get_or_insert_with(Default::default);
+        func(policies);
+        let policies = policies.clone();
+        service.worker_state.check_hash_secrets();
+        policies
+    };
+    send_to_worker(WorkerMsg::SetPolicies(policies)).await;
 }
 
 pub(crate) async fn set_policies(policies: Policies) {
     to_worker(WorkerMsg::SetPolicies(policies)).await;
 }
 
-fn take_current_transaction(state: &mut OpState) -> TransactionStatic {
-    state.take()
+/// What the worker keeps for a request that it is handling.
+#[derive(Default)]
+struct RequestState {
+    /// Started when the request first accesses data.
+    transaction: Option<TransactionStatic>,
+    /// Held until the transaction is committed or rolled back.
+    writer: Option<WriterLock>,
+    /// Resources of the request, which are closed when it ends.
+    resources: Vec<ResourceId>,
+    /// Trace context of the span that runs the request, which parents the spans of its ops.
+    traceparent: Option<String>,
+    /// The connection of a WebSocket handshake, until the endpoint accepts it.
+    websocket: Option<WebSocketUpgrade>,
+    /// Changes that the transaction made, which are announced once it is committed.
+    changes: Vec<Change>,
+}
+
+/// The requests that the worker is handling, by ID.
+type Requests = HashMap<u32, RequestState>;
+
+fn request_state(st: &mut OpState, id: u32) -> &mut RequestState {
+    if !st.has::<Requests>() {
+        st.put(Requests::new());
+    }
+    st.borrow_mut::<Requests>().entry(id).or_default()
+}
+
+/// Closes the resources of the request `id`, and returns its transaction, if any, for the
+/// caller to commit or roll back before releasing the transaction's lock, along with the changes
+/// that it made.
+fn end_request(
+    st: &mut OpState,
+    id: u32,
+) -> Option<(TransactionStatic, Option<WriterLock>, Vec<Change>)> {
+    let request = st.try_borrow_mut::<Requests>()?.remove(&id)?;
+    for rid in request.resources {
+        // Resources that the request closed itself are gone already.
+        st.resource_table.close(rid).ok();
+    }
+    let transaction = request.transaction?;
+    crate::metrics::transaction_ended();
+    Some((transaction, request.writer, request.changes))
+}
+
+/// Takes the transaction of the request `id`, if it has one, along with its lock and the changes
+/// that it made, and leaves the request without one but with its resources. The transaction is
+/// kept if a query of the request still reads from it.
+fn take_transaction(
+    st: &mut OpState,
+    id: u32,
+) -> Option<(TransactionStatic, Option<WriterLock>, Vec<Change>)> {
+    let request = st.try_borrow_mut::<Requests>()?.get_mut(&id)?;
+    if Arc::strong_count(request.transaction.as_ref()?) > 1 {
+        return None;
+    }
+    let transaction = request.transaction.take()?;
+    crate::metrics::transaction_ended();
+    let changes = std::mem::take(&mut request.changes);
+    Some((transaction, request.writer.take(), changes))
 }
 
-fn current_transaction(st: &OpState) -> TransactionStatic {
-    st.borrow::<TransactionStatic>().clone()
+/// The trace context of the request `handler_id`, if code that runs for a request asks for it.
+fn request_traceparent(st: &OpState, handler_id: Option<u32>) -> Option<String> {
+    handler_id.and_then(|id| st.try_borrow::<Requests>()?.get(&id)?.traceparent.clone())
 }
 
-fn set_current_transaction(st: &mut OpState, transaction: TransactionStatic) {
-    assert!(!st.has::<TransactionStatic>());
-    st.put(transaction);
+/// The trace context of the request that owns the resource `rid`.
+fn resource_traceparent(st: &OpState, rid: ResourceId) -> Option<String> {
+    let requests = st.try_borrow::<Requests>()?;
+    let request = requests.values().find(|r| r.resources.contains(&rid))?;
+    request.traceparent.clone()
+}
+
+fn current_transaction(st: &OpState, handler_id: Option<u32>) -> Result<TransactionStatic> {
+    handler_id
+        .and_then(|id| st.try_borrow::<Requests>()?.get(&id)?.transaction.clone())
+        .ok_or_else(|| anyhow!("Data can only be accessed while handling a request"))
+}
+
+fn set_current_transaction(
+    st: &mut OpState,
+    id: u32,
+    transaction: TransactionStatic,
+    writer: Option<WriterLock>,
+) -> Result<()> {
+    // A request that ended meanwhile has no state, and nothing to commit the transaction.
+    let request = st
+        .try_borrow_mut::<Requests>()
+        .and_then(|requests| requests.get_mut(&id))
+        .ok_or_else(|| anyhow!("Request {} ended", id))?;
+    anyhow::ensure!(
+        request.transaction.is_none(),
+        "Request {} already has a transaction",
+        id
+    );
+    crate::metrics::transaction_started();
+    request.transaction = Some(transaction);
+    request.writer = writer;
+    Ok(())
+}
+
+/// Has the resource `rid` closed when the request `handler_id` ends.
+fn add_request_resource(st: &mut OpState, handler_id: Option<u32>, rid: ResourceId) {
+    if let Some(id) = handler_id {
+        request_state(st, id).resources.push(rid);
+    }
+}
+
+/// Has the `changes` announced when the request `handler_id` commits its transaction.
+fn add_request_changes(st: &mut OpState, handler_id: Option<u32>, changes: Vec<Change>) {
+    if let Some(id) = handler_id {
+        request_state(st, id).changes.extend(changes);
+    }
 }
 
 fn current_secrets(st: &OpState) -> Option<&JsonObject> {
@@ -1106,6 +1942,10 @@ pub(crate) async fn set_query_engine(query_engine: Arc<QueryEngine>) {
     to_worker(WorkerMsg::SetQueryEngine(query_engine)).await;
 }
 
+pub(crate) async fn set_jwt_config(config: Arc<JwtConfig>) {
+    to_worker(WorkerMsg::SetJwtConfig(config)).await;
+}
+
 pub(crate) fn lookup_builtin_type(
     state: &OpState,
     type_name: &str,
@@ -1119,6 +1959,19 @@ pub(crate) async fn remove_type_version(version: &str) {
 }
 
 async fn to_worker(msg: WorkerMsg) {
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
+    {
+        let mut service = get();
+        service.worker_state.update(&msg);
+        if let WorkerMsg::SetPolicies(_) | WorkerMsg::SetCurrentSecrets(_) = msg {
+            service.worker_state.check_hash_secrets();
+        }
+    }
+    send_to_worker(msg).await;
+}
+
+async fn send_to_worker(msg: WorkerMsg) {
     let promise = {
         let sender = get().to_worker.clone();
         sender.send(msg).await.unwrap();
@@ -1134,7 +1987,10 @@ async fn to_worker(msg: WorkerMsg) {
             .unwrap();
         v8::Global::new(scope, promise)
     };
-    resolve_promise(promise).await.unwrap();
+    // A worker that replaces this one gets the state anyway.
+    if let Err(e) = resolve_promise(promise).await {
+        warn!("Could not update the endpoint worker: {:?}", e);
+    }
 }
 
 pub(crate) async fn set_type_system(type_system: TypeSystem) {
@@ -1146,33 +2002,289 @@ pub(crate) async fn update_secrets(secrets: JsonObject) {
 }
 
 #[op]
-async fn op_chisel_commit_transaction(state: Rc<RefCell<OpState>>) -> Result<()> {
-    let transaction = {
-        let mut state = state.borrow_mut();
-        take_current_transaction(&mut state)
+async fn op_chisel_commit_transaction(state: Rc<RefCell<OpState>>, id: u32) -> Result<()> {
+    let traceparent = request_traceparent(&state.borrow(), Some(id));
+    let inner = async move {
+        let ended = end_request(&mut state.borrow_mut(), id);
+        // Requests that didn't access data have no transaction.
+        if let Some((transaction, _writer, changes)) = ended {
+            let query_engine = query_engine_arc(&state.borrow());
+            let transaction = extract_transaction(transaction);
+            query_engine
+                .commit_transaction_with_changes(transaction, changes)
+                .await?;
+        }
+        Ok(())
     };
-    crate::datastore::QueryEngine::commit_transaction_static(transaction).await?;
-    Ok(())
+    telemetry::in_child_span(
+        "op_chisel_commit_transaction",
+        traceparent.as_deref(),
+        inner,
+    )
+    .await
+}
+
+/// Commits the transaction of the request `id` before its response body streams, for bodies that
+/// stream until the client disconnects, which would otherwise hold the transaction and its locks
+/// all along. The request keeps its resources, like its subscriptions, until it ends. Returns
+/// whether the request is left without a transaction, so that it starts another one if it
+/// accesses data again.
+#[op]
+async fn op_chisel_commit_before_stream(state: Rc<RefCell<OpState>>, id: u32) -> Result<bool> {
+    let traceparent = request_traceparent(&state.borrow(), Some(id));
+    let inner = async move {
+        let taken = take_transaction(&mut state.borrow_mut(), id);
+        let (transaction, _writer, changes) = match taken {
+            Some(taken) => taken,
+            None => return Ok(current_transaction(&state.borrow(), Some(id)).is_err()),
+        };
+        let query_engine = query_engine_arc(&state.borrow());
+        let transaction = extract_transaction(transaction);
+        query_engine
+            .commit_transaction_with_changes(transaction, changes)
+            .await?;
+        Ok(true)
+    };
+    telemetry::in_child_span(
+        "op_chisel_commit_before_stream",
+        traceparent.as_deref(),
+        inner,
+    )
+    .await
 }
 
 #[op]
-fn op_chisel_rollback_transaction(state: &mut OpState) -> Result<()> {
-    let transaction = take_current_transaction(state);
-    // Check that this is the last reference to the transaction.
-    let transaction = extract_transaction(transaction);
-    // Drop the transaction, causing it to rollback.
-    drop(transaction);
+fn op_chisel_rollback_transaction(state: &mut OpState, id: u32) -> Result<()> {
+    let traceparent = request_traceparent(state, Some(id));
+    let _span =
+        telemetry::enter_child_span("op_chisel_rollback_transaction", traceparent.as_deref());
+    if let Some((transaction, _writer, _changes)) = end_request(state, id) {
+        // Check that this is the last reference to the transaction.
+        let transaction = extract_transaction(transaction);
+        // Drop the transaction, causing it to rollback.
+        drop(transaction);
+    }
     Ok(())
 }
 
 #[op]
-async fn op_chisel_create_transaction(state: Rc<RefCell<OpState>>) -> Result<()> {
-    let qe = query_engine_arc(&state.borrow());
-    let transaction = qe.start_transaction_static().await?;
-    set_current_transaction(&mut state.borrow_mut(), transaction);
+async fn op_chisel_create_transaction(state: Rc<RefCell<OpState>>, id: u32) -> Result<()> {
+    let traceparent = request_traceparent(&state.borrow(), Some(id));
+    let inner = async move {
+        let qe = query_engine_arc(&state.borrow());
+        let writer = qe.lock_writer().await;
+        let transaction = qe.start_transaction_static().await?;
+        set_current_transaction(&mut state.borrow_mut(), id, transaction, writer)
+    };
+    telemetry::in_child_span(
+        "op_chisel_create_transaction",
+        traceparent.as_deref(),
+        inner,
+    )
+    .await
+}
+
+/// A request to upgrade its connection to a WebSocket.
+struct WebSocketUpgrade {
+    on_upgrade: OnUpgrade,
+    /// Value of the `Sec-WebSocket-Accept` header of the response that accepts the handshake.
+    accept: String,
+}
+
+/// Takes the connection of `req` if it is a WebSocket handshake, for the endpoint to accept.
+fn websocket_upgrade(req: &mut Request<hyper::Body>) -> Option<WebSocketUpgrade> {
+    let headers = req.headers();
+    let upgrade = headers.get(UPGRADE)?.to_str().ok()?;
+    let version = headers.get(SEC_WEBSOCKET_VERSION)?;
+    if req.method() != Method::GET || !upgrade.eq_ignore_ascii_case("websocket") || version != "13"
+    {
+        return None;
+    }
+    let accept = derive_accept_key(headers.get(SEC_WEBSOCKET_KEY)?.as_bytes());
+    let on_upgrade = req.extensions_mut().remove::<OnUpgrade>()?;
+    Some(WebSocketUpgrade { on_upgrade, accept })
+}
+
+/// A WebSocket handshake that the endpoint accepted, until the connection is upgraded.
+struct WebSocketUpgradeResource {
+    on_upgrade: RefCell<Option<OnUpgrade>>,
+    cancel: CancelHandle,
+}
+
+impl Resource for WebSocketUpgradeResource {
+    fn close(self: Rc<Self>) {
+        self.cancel.cancel();
+    }
+}
+
+type WebSocket = WebSocketStream<Upgraded>;
+
+struct WebSocketResource {
+    sink: AsyncRefCell<SplitSink<WebSocket, Message>>,
+    stream: AsyncRefCell<SplitStream<WebSocket>>,
+    cancel: CancelHandle,
+}
+
+impl Resource for WebSocketResource {
+    fn close(self: Rc<Self>) {
+        self.cancel.cancel();
+    }
+}
+
+#[derive(Serialize)]
+struct AcceptedWebSocket {
+    rid: ResourceId,
+    accept: String,
+}
+
+/// Accepts the WebSocket handshake of the request `id`. The WebSocket outlives the request, so
+/// its resource is not closed when the request ends.
+#[op]
+fn op_chisel_websocket_accept(state: &mut OpState, id: u32) -> Result<AcceptedWebSocket> {
+    let upgrade = state
+        .try_borrow_mut::<Requests>()
+        .and_then(|requests| requests.get_mut(&id))
+        .and_then(|request| request.websocket.take())
+        .ok_or_else(|| anyhow!("Request {} is not a WebSocket handshake", id))?;
+    let rid = state.resource_table.add(WebSocketUpgradeResource {
+        on_upgrade: RefCell::new(Some(upgrade.on_upgrade)),
+        cancel: Default::default(),
+    });
+    Ok(AcceptedWebSocket {
+        rid,
+        accept: upgrade.accept,
+    })
+}
+
+/// Waits for the connection of the accepted handshake `rid` to be upgraded, which happens once
+/// the response is sent, and returns the resource of the WebSocket.
+#[op]
+async fn op_chisel_websocket_open(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<ResourceId> {
+    let upgrade: Rc<WebSocketUpgradeResource> = state.borrow().resource_table.get(rid)?;
+    let on_upgrade = upgrade
+        .on_upgrade
+        .borrow_mut()
+        .take()
+        .ok_or_else(|| anyhow!("WebSocket {} is already open", rid))?;
+    let cancel = RcRef::map(&upgrade, |r| &r.cancel);
+    let upgraded = on_upgrade.or_cancel(cancel).await??;
+    state.borrow_mut().resource_table.close(rid).ok();
+
+    let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+    let (sink, stream) = socket.split();
+    let resource = WebSocketResource {
+        sink: AsyncRefCell::new(sink),
+        stream: AsyncRefCell::new(stream),
+        cancel: Default::default(),
+    };
+    Ok(state.borrow_mut().resource_table.add(resource))
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum WebSocketMessage {
+    Text { data: String },
+    Binary { data: ZeroCopyBuf },
+    Close { code: u16, reason: String },
+}
+
+async fn next_message(stream: &mut SplitStream<WebSocket>) -> Result<Option<WebSocketMessage>> {
+    while let Some(message) = stream.next().await {
+        let message = match message? {
+            Message::Text(data) => WebSocketMessage::Text { data },
+            Message::Binary(data) => WebSocketMessage::Binary { data: data.into() },
+            Message::Close(frame) => {
+                // 1005 stands for a close frame without a status code.
+                let (code, reason) = frame.map_or((1005, String::new()), |f| {
+                    (f.code.into(), f.reason.into_owned())
+                });
+                WebSocketMessage::Close { code, reason }
+            }
+            // The stream answers pings itself.
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+        return Ok(Some(message));
+    }
+    Ok(None)
+}
+
+/// Waits for the next message of the WebSocket `rid`. Returns `None` if the connection is lost
+/// without the closing handshake.
+#[op]
+async fn op_chisel_websocket_next(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<Option<WebSocketMessage>> {
+    let resource: Rc<WebSocketResource> = state.borrow().resource_table.get(rid)?;
+    let cancel = RcRef::map(&resource, |r| &r.cancel);
+    let mut stream = RcRef::map(&resource, |r| &r.stream).borrow_mut().await;
+    let message = next_message(&mut stream).or_cancel(cancel).await??;
+    if let Some(WebSocketMessage::Close { .. }) = message {
+        // The stream queues the answer to a closing handshake that the client started, which
+        // is sent when the sink is flushed. It fails once the connection is closed.
+        let mut sink = RcRef::map(&resource, |r| &r.sink).borrow_mut().await;
+        sink.flush().await.ok();
+    }
+    Ok(message)
+}
+
+async fn websocket_send(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    message: Message,
+) -> Result<()> {
+    let resource: Rc<WebSocketResource> = state.borrow().resource_table.get(rid)?;
+    let mut sink = RcRef::map(&resource, |r| &r.sink).borrow_mut().await;
+    sink.send(message).await?;
     Ok(())
 }
 
+#[op]
+async fn op_chisel_websocket_send_text(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    text: String,
+) -> Result<()> {
+    websocket_send(state, rid, Message::Text(text)).await
+}
+
+#[op]
+async fn op_chisel_websocket_send_binary(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    data: ZeroCopyBuf,
+) -> Result<()> {
+    websocket_send(state, rid, Message::Binary(data.to_vec())).await
+}
+
+/// Starts the closing handshake of the WebSocket `rid`. Its resource is closed once the client
+/// answers, which `op_chisel_websocket_next` returns.
+#[op]
+async fn op_chisel_websocket_close(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    code: Option<u16>,
+    reason: Option<String>,
+) -> Result<()> {
+    let frame = code.map(|code| CloseFrame {
+        code: code.into(),
+        reason: reason.unwrap_or_default().into(),
+    });
+    websocket_send(state, rid, Message::Close(frame)).await
+}
+
+/// Starts a request for a message of a WebSocket, which the message's handler runs in, with a
+/// transaction of its own. It ends when its transaction is committed or rolled back.
+#[op]
+fn op_chisel_start_message(state: &mut OpState) -> u32 {
+    let id = next_request_id();
+    request_state(state, id);
+    id
+}
+
 #[derive(Serialize)]
 struct ResponseParts {
     status: u16,
@@ -1220,19 +2332,19 @@ where
 async fn special_response(
     state: Rc<RefCell<OpState>>,
     req: &Request<hyper::Body>,
-    userid: &Option<String>,
+    principal: &Principal,
+    user_roles: &[String],
 ) -> Result<Option<Response<Body>>> {
     let req_path = req.uri().path();
-    // TODO: Make this optional, for users who want to reject some OPTIONS requests.
-    if req.method() == "OPTIONS" {
-        // Makes CORS preflights pass.
-        return Ok(Some(Response::builder().body("ok".to_string().into())?));
-    }
-    if req_path.starts_with("/__chiselstrike/auth/") {
+    let is_audit =
+        req_path == "/__chiselstrike/audit" || req_path.starts_with("/__chiselstrike/audit/");
+    if req_path.starts_with("/__chiselstrike/auth/") || is_audit {
         let auth_header = req.headers().get("ChiselAuth");
         let expected_secret = current_secrets(&state.borrow())
             .and_then(|sec| sec.get("CHISELD_AUTH_SECRET").cloned());
         match (expected_secret, auth_header) {
+            // The audit log has the data that policies hide, so it is never open.
+            (None, _) if is_audit => return Ok(Some(ApiService::forbidden("ChiselAuth")?)),
             (Some(_), None) => return Ok(Some(ApiService::forbidden("ChiselAuth")?)),
             (Some(serde_json::Value::String(s)), Some(h)) if s != *h => {
                 return Ok(Some(ApiService::forbidden("Fundamental auth")?))
@@ -1240,19 +2352,26 @@ async fn special_response(
             _ => (),
         }
     } else {
-        let username = get_username_from_id(state.clone(), userid.clone()).await;
+        let username = match &principal.username {
+            Some(username) => Some(username.clone()),
+            None => get_username_from_id(state.clone(), principal.userid.clone()).await,
+        };
         let rp = match RequestPath::try_from(req_path) {
             Ok(rp) => rp,
             Err(_) => return Ok(Some(ApiService::not_found()?)),
         };
-        let is_allowed = is_allowed_by_policy(
+        let _span = telemetry::span("policy check");
+        let denied = check_endpoint_policy(
             &state.borrow(),
             rp.api_version(),
-            username,
+            &username,
+            user_roles,
+            principal.api_key_scopes.as_deref(),
+            req.method().as_str(),
             rp.path().as_ref(),
         )?;
-        if !is_allowed {
-            return Ok(Some(ApiService::forbidden("Unauthorized user\n")?));
+        if denied.is_some() {
+            return Ok(denied);
         }
     }
     Ok(None)
@@ -1280,17 +2399,151 @@ async fn convert_response(mut res: Response<Body>) -> Result<ResponseParts> {
     })
 }
 
-pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Response<Body>> {
-    thread_local! {
-        static NEXT_REQUEST_ID: Cell<u32> = Cell::new(0);
+/// The `traceparent` of the span that runs a request, as the worker gets it.
+#[derive(Clone)]
+struct TraceParent(String);
+
+/// Header with the ID that correlates the log lines of a request.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+pub(crate) async fn run_js(path: String, mut req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let request_id = request_id(&mut req);
+    let start = Instant::now();
+    let inner = crate::logging::scoped(Some(request_id.clone()), run_js_impl(&path, req));
+    let res = telemetry::in_span("run_js", inner).await;
+    let status = match &res {
+        Ok(res) => res.status().as_u16(),
+        Err(_) => 500,
+    };
+    crate::metrics::observe_request(&path, status, start.elapsed());
+    record_heap_statistics();
+    // Errors are answered here rather than by the caller, so that they carry the ID too.
+    let mut res = match res {
+        Ok(res) => res,
+        Err(err) => ApiService::internal_error(err)?,
+    };
+    res.headers_mut()
+        .insert(REQUEST_ID_HEADER, HeaderValue::from_str(&request_id)?);
+    Ok(res)
+}
+
+/// Returns the ID that the client gave the request, or gives it a new one.
+fn request_id(req: &mut Request<hyper::Body>) -> String {
+    let given = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128);
+    if let Some(id) = given {
+        return id.to_owned();
     }
+    let id = Uuid::new_v4().to_string();
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, HeaderValue::from_str(&id).unwrap());
+    id
+}
 
-    let id = NEXT_REQUEST_ID.with(|x| {
-        let v = x.get();
-        x.set(v + 1);
-        v
-    });
+fn record_heap_statistics() {
+    let mut service = get();
+    let mut stats = v8::HeapStatistics::default();
+    service
+        .worker
+        .js_runtime
+        .v8_isolate()
+        .get_heap_statistics(&mut stats);
+    crate::metrics::set_v8_heap(
+        stats.used_heap_size(),
+        stats.total_heap_size(),
+        stats.heap_size_limit(),
+    );
+}
+
+/// How long the worker gets to abort a request before the JavaScript that it runs is terminated.
+const ABORT_GRACE: Duration = Duration::from_millis(100);
+
+/// Global of the worker with the ID of the request whose JavaScript is running, if any.
+const RUNNING_REQUEST: &str = "__chiselRunningRequest";
+
+/// What `terminate_if_running` gets: the ID of the request to abort, and where to tell the worker
+/// to resume its isolate once terminated.
+type TerminateData = (u32, Sender<u32>);
+
+/// Interrupts the worker's JavaScript to terminate it if it runs the request whose handler timed
+/// out. The JavaScript of other requests goes on: they have their own timeouts.
+extern "C" fn terminate_if_running(isolate: &mut v8::Isolate, data: *mut c_void) {
+    // Safety: abort_request leaks the box for V8 to call this once with it.
+    let (id, to_abort) = *unsafe { Box::from_raw(data as *mut TerminateData) };
+    let context = match WORKER_CONTEXT.with(|c| c.borrow().clone()) {
+        Some(context) => context,
+        None => return,
+    };
+    let running = {
+        let scope = &mut v8::HandleScope::with_context(isolate, context);
+        let key = v8::String::new(scope, RUNNING_REQUEST).unwrap();
+        let global = scope.get_current_context().global(scope);
+        match global.get(scope, key.into()) {
+            Some(running) if running.is_number() => running.number_value(scope),
+            _ => None,
+        }
+    };
+    if running == Some(id as f64) {
+        isolate.terminate_execution();
+        // The worker resumes its isolate when it receives an abort, and it may have received the
+        // first one for this request already.
+        to_abort.try_send(id).ok();
+    }
+}
+
+/// Aborts the request `id`, whose handler timed out, and waits for the worker to roll back the
+/// request's transaction. A worker that doesn't get to it soon is stuck running JavaScript,
+/// which is terminated if it's the handler's.
+async fn abort_request(id: u32) -> Result<()> {
+    let promise = {
+        let mut service = get();
+        let service: &mut DenoService = &mut service;
+        service.to_abort.try_send(id)?;
+
+        let runtime = &mut service.worker.js_runtime;
+        let scope = &mut runtime.handle_scope();
+        let abort_request = service.abort_request.open(scope);
+        let undefined = v8::undefined(scope).into();
+        let id = v8::Number::new(scope, id as f64).into();
+        let promise = abort_request.call(scope, undefined, &[id]).unwrap();
+        v8::Global::new(scope, promise)
+    };
+    let mut aborted = Box::pin(resolve_promise(promise));
+    loop {
+        if let Ok(result) = tokio::time::timeout(ABORT_GRACE, &mut aborted).await {
+            return result.map(|_| ());
+        }
+        // Whatever runs may yield to the handler later, so keep checking.
+        let isolate = get().worker_isolate.lock().unwrap().clone();
+        let isolate = isolate.ok_or(Error::NoWorker)?;
+        let data = Box::into_raw(Box::new((id, get().to_abort.clone())));
+        if !isolate.request_interrupt(terminate_if_running, data as *mut c_void) {
+            // Safety: V8 didn't take the box, as the isolate is gone.
+            drop(unsafe { Box::from_raw(data) });
+            return Err(Error::NoWorker.into());
+        }
+    }
+}
+
+/// IDs of the requests that the workers handle. The worker gives the messages of WebSockets IDs
+/// of their own, so these are allocated on whichever thread.
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(0);
+
+fn next_request_id() -> u32 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+async fn run_js_impl(path: &str, mut req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let id = next_request_id();
     let request_handler = RequestHandler { id };
+    // Timing out requests that are being debugged would only get in the way.
+    let timeout = match get().inspector {
+        Some(_) => None,
+        None => req.extensions().get::<RequestTimeout>().map(|t| t.0),
+    };
 
     {
         let mut service = get();
@@ -1302,8 +2555,15 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
         }
     }
 
-    let sender = get().to_worker.clone();
-    sender.send(WorkerMsg::HandleRequest(req)).await.unwrap();
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
+    // The worker runs the request on another thread, so its spans need the trace context
+    // handed over.
+    if let Some(traceparent) = telemetry::current_traceparent() {
+        req.extensions_mut().insert(TraceParent(traceparent));
+    }
+    let requests = get().channel.requests.clone();
+    requests.lock().unwrap().insert(id, req);
 
     let result = {
         let mut service = get();
@@ -1311,7 +2571,7 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
         let runtime = &mut service.worker.js_runtime;
         let scope = &mut runtime.handle_scope();
 
-        let path = RequestPath::try_from(path.as_ref()).unwrap();
+        let path = RequestPath::try_from(path).unwrap();
         let call_handler = service.call_handler.open(scope);
         let undefined = v8::undefined(scope).into();
         let api_version = v8::String::new(scope, path.api_version()).unwrap().into();
@@ -1322,7 +2582,20 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
             .unwrap();
         v8::Global::new(scope, result)
     };
-    let result = resolve_promise(result).await?;
+    let result = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, resolve_promise(result)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                warn!("Endpoint {} timed out after {:?}", path, timeout);
+                abort_request(id).await?;
+                return ApiService::error_response(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "Endpoint timed out\n",
+                );
+            }
+        },
+        None => resolve_promise(result).await?,
+    };
 
     let body = {
         // The rust borrow checker can track fields independently, but
@@ -1395,12 +2668,21 @@ struct StartRequest {
     method: String,
     url: String,
     userid: Option<String>,
+    username: Option<String>,
+    user_roles: Vec<String>,
+    request_id: Option<String>,
+    /// Values of the parameters of the endpoint's path template, as they are in the URL.
+    params: HashMap<String, String>,
+    /// What follows the path of the endpoint in the URL.
+    path_params: String,
 }
 
 async fn handle_request(
     state: Rc<RefCell<OpState>>,
-    userid: Option<String>,
-    req: Request<hyper::Body>,
+    id: u32,
+    principal: Principal,
+    user_roles: Vec<String>,
+    mut req: Request<hyper::Body>,
 ) -> Result<StartRequest> {
     // FIXME: this request conversion is probably simplistic. Check deno/ext/http/lib.rs
 
@@ -1415,6 +2697,19 @@ async fn handle_request(
         .unwrap();
     let url = url.to_string();
     let method = req.method();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(|id| id.to_owned());
+    let path_match = req
+        .extensions()
+        .get::<PathMatch>()
+        .cloned()
+        .unwrap_or_default();
+    if let Some(upgrade) = websocket_upgrade(&mut req) {
+        request_state(&mut state.borrow_mut(), id).websocket = Some(upgrade);
+    }
 
     let mut headers: HashMap<String, String> = HashMap::new();
     for (k, v) in req.headers().iter() {
@@ -1431,7 +2726,9 @@ async fn handle_request(
             body: RefCell::new(body),
             cancel: Default::default(),
         };
-        let rid = state.borrow_mut().resource_table.add(resource);
+        let mut state = state.borrow_mut();
+        let rid = state.resource_table.add(resource);
+        add_request_resource(&mut state, Some(id), rid);
         Some(rid)
     } else {
         None
@@ -1442,7 +2739,12 @@ async fn handle_request(
         headers,
         method,
         url,
-        userid,
+        userid: principal.userid,
+        username: principal.username,
+        user_roles,
+        request_id,
+        params: path_match.params.into_iter().collect(),
+        path_params: path_match.rest,
     })
 }
 
@@ -1452,13 +2754,75 @@ enum StartRequestRes {
     Special(ResponseParts),
 }
 
-#[op]
-async fn op_chisel_start_request(state: Rc<RefCell<OpState>>) -> Result<StartRequestRes> {
-    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().clone());
-    let req = match receiver.recv().await {
-        Ok(WorkerMsg::HandleRequest(req)) => req,
-        _ => unreachable!("Wrong message"),
-    };
+/// Who makes a request, as established by its credentials.
+#[derive(Default)]
+struct Principal {
+    userid: Option<String>,
+    /// Username given by the credentials. Otherwise, it's looked up from `userid` when needed.
+    username: Option<String>,
+    /// Roles given by the credentials, in addition to the ones granted in the database.
+    roles: Vec<String>,
+    /// Scopes of the API key of the request, if it was authenticated by one.
+    api_key_scopes: Option<Vec<String>>,
+}
+
+/// Establishes who makes the request from its API key, bearer token or ChiselUID header, in this
+/// order. Returns the body of a 401 response if the credentials aren't valid.
+///
+/// Once bearer tokens can be verified, the ChiselUID header is ignored: it's only trusted when
+/// `chiseld` is behind a frontend that authenticates the users itself.
+async fn authenticate(
+    state: Rc<RefCell<OpState>>,
+    req: &Request<hyper::Body>,
+) -> std::result::Result<Principal, String> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER) {
+        let api_key = match key.to_str() {
+            Ok(key) => get_api_key(state, key).await,
+            Err(_) => None,
+        };
+        let api_key = api_key.ok_or_else(|| "Invalid API key\n".to_string())?;
+        return Ok(Principal {
+            userid: Some(api_key.user_id),
+            api_key_scopes: Some(api_key.scopes),
+            ..Default::default()
+        });
+    }
+
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    {
+        let state = state.borrow();
+        let secret = current_secrets(&state)
+            .and_then(|sec| sec.get(JWT_SECRET))
+            .and_then(|v| v.as_str());
+        let no_config = JwtConfig::default();
+        let config = state
+            .try_borrow::<Arc<JwtConfig>>()
+            .map_or(&no_config, |c| c.as_ref());
+        // Without keys, bearer tokens are left for the endpoints to deal with.
+        if secret.is_some() || !config.jwks.is_empty() {
+            let token = match bearer {
+                Some(token) => token,
+                None => return Ok(Principal::default()),
+            };
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let claims = jwt::verify(token, secret, config, now)
+                .map_err(|e| format!("Invalid token: {}\n", e))?;
+            return Ok(Principal {
+                username: claims.verified_email().map(str::to_owned),
+                userid: Some(claims.sub),
+                roles: claims.roles,
+                api_key_scopes: None,
+            });
+        }
+    }
+
     let userid = match req.headers().get("ChiselUID").map(|v| v.to_str()) {
         Some(Ok(str)) => Some(str.to_string()),
         Some(Err(e)) => {
@@ -1470,13 +2834,77 @@ async fn op_chisel_start_request(state: Rc<RefCell<OpState>>) -> Result<StartReq
         }
         None => None,
     };
-    if let Some(resp) = special_response(state.clone(), &req, &userid).await? {
+    Ok(Principal {
+        userid,
+        ..Default::default()
+    })
+}
+
+/// Whether the policies of the API version of the request at `path` depend on the roles of the
+/// user.
+fn policies_use_roles(state: &OpState, path: &str) -> bool {
+    let rp = match RequestPath::try_from(path) {
+        Ok(rp) => rp,
+        Err(_) => return false,
+    };
+    current_policies(state)
+        .versions
+        .get(rp.api_version())
+        .map_or(false, |v| v.uses_roles())
+}
+
+#[op]
+async fn op_chisel_start_request(state: Rc<RefCell<OpState>>, id: u32) -> Result<StartRequestRes> {
+    let requests = WORKER_CHANNEL.with(|d| d.get().unwrap().requests.clone());
+    let req = requests
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or_else(|| anyhow!("Request {} is gone", id))?;
+    let traceparent = req.extensions().get::<TraceParent>().map(|t| t.0.clone());
+    let inner = start_request(state, id, req, traceparent.clone());
+    telemetry::in_child_span("op_chisel_start_request", traceparent.as_deref(), inner).await
+}
+
+async fn start_request(
+    state: Rc<RefCell<OpState>>,
+    id: u32,
+    req: Request<hyper::Body>,
+    traceparent: Option<String>,
+) -> Result<StartRequestRes> {
+    let principal = match authenticate(state.clone(), &req).await {
+        Ok(principal) => principal,
+        Err(message) => {
+            let resp = ApiService::error_response(StatusCode::UNAUTHORIZED, &message)?;
+            return Ok(StartRequestRes::Special(convert_response(resp).await?));
+        }
+    };
+    if let Some(limit) = req.extensions().get::<DeferredRateLimit>() {
+        // The API key was validated if the request was authenticated by it.
+        let api_key = principal
+            .api_key_scopes
+            .as_ref()
+            .and_then(|_| req.headers().get(API_KEY_HEADER)?.to_str().ok());
+        if let Err(wait) = limit.acquire(principal.userid.as_deref(), api_key) {
+            let resp = ApiService::too_many_requests(wait)?;
+            return Ok(StartRequestRes::Special(convert_response(resp).await?));
+        }
+    }
+    let mut user_roles = if policies_use_roles(&state.borrow(), req.uri().path()) {
+        get_user_roles(state.clone(), &principal.userid).await
+    } else {
+        vec![]
+    };
+    user_roles.extend(principal.roles.iter().cloned());
+    if let Some(resp) = special_response(state.clone(), &req, &principal, &user_roles).await? {
         let resp = convert_response(resp).await?;
         return Ok(StartRequestRes::Special(resp));
     }
 
+    // Only requests that run JS get to the ops, and end, which drops the state.
+    request_state(&mut state.borrow_mut(), id).traceparent = traceparent;
     Ok(StartRequestRes::Js(
-        handle_request(state, userid, req).await?,
+        handle_request(state, id, principal, user_roles, req).await?,
     ))
 }
 
@@ -1488,6 +2916,49 @@ fn get() -> RcMut<DenoService> {
 }
 
 pub(crate) async fn compile_endpoint(path: String, code: String) -> Result<()> {
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
+    let version = {
+        let mut service = get();
+        service.endpoints.insert(path.clone());
+        let mut handle = service.module_loader.lock().unwrap();
+        let code_map = &mut handle.code_map;
+        let mut entry = code_map
+            .entry(format!("{}.js", path))
+            .and_modify(|v| v.version += 1)
+            .or_insert(VersionedCode {
+                code: "".to_string(),
+                version: 0,
+            });
+        entry.code = code;
+        entry.version
+    };
+    resolve_promise(import_endpoint(&path, version)).await?;
+    Ok(())
+}
+
+/// Calls importEndpoint, for the worker to load `version` of the endpoint at `path`.
+fn import_endpoint(path: &str, version: u64) -> v8::Global<v8::Value> {
+    let mut service = get();
+    let service: &mut DenoService = &mut service;
+    let runtime = &mut service.worker.js_runtime;
+    let scope = &mut runtime.handle_scope();
+    let import_endpoint = service.import_endpoint.open(scope);
+    let path = RequestPath::try_from(path).unwrap();
+    let api_version = v8::String::new(scope, path.api_version()).unwrap().into();
+    let path = v8::String::new(scope, path.path()).unwrap().into();
+    let version = v8::Number::new(scope, version as f64).into();
+    let undefined = v8::undefined(scope).into();
+    let promise = import_endpoint
+        .call(scope, undefined, &[path, api_version, version])
+        .unwrap();
+    v8::Global::new(scope, promise)
+}
+
+/// Loads the populate transform module `code` under `path`.
+pub(crate) async fn compile_transform(path: &str, code: String) -> Result<()> {
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
     let promise = {
         let mut service = get();
         let service: &mut DenoService = &mut service;
@@ -1505,14 +2976,12 @@ pub(crate) async fn compile_endpoint(path: String, code: String) -> Result<()> {
 
         let runtime = &mut service.worker.js_runtime;
         let scope = &mut runtime.handle_scope();
-        let import_endpoint = service.import_endpoint.open(scope);
-        let path = RequestPath::try_from(path.as_ref()).unwrap();
-        let api_version = v8::String::new(scope, path.api_version()).unwrap().into();
-        let path = v8::String::new(scope, path.path()).unwrap().into();
+        let import_transform = service.import_transform.open(scope);
+        let path = v8::String::new(scope, path).unwrap().into();
         let version = v8::Number::new(scope, entry.version as f64).into();
         let undefined = v8::undefined(scope).into();
-        let promise = import_endpoint
-            .call(scope, undefined, &[path, api_version, version])
+        let promise = import_transform
+            .call(scope, undefined, &[path, version])
             .unwrap();
         v8::Global::new(scope, promise)
     };
@@ -1520,18 +2989,55 @@ pub(crate) async fn compile_endpoint(path: String, code: String) -> Result<()> {
     Ok(())
 }
 
-pub(crate) async fn activate_endpoint(path: &str) -> Result<()> {
+/// Runs the populate transform loaded under `path` on a `row` of type `type_name`.
+pub(crate) async fn apply_transform(
+    path: &str,
+    type_name: &str,
+    row: JsonObject,
+) -> Result<JsonObject> {
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
     let promise = {
         let mut service = get();
         let service: &mut DenoService = &mut service;
         let runtime = &mut service.worker.js_runtime;
         let scope = &mut runtime.handle_scope();
-        let activate_endpoint = service.activate_endpoint.open(scope);
+        let apply_transform = service.apply_transform.open(scope);
         let undefined = v8::undefined(scope).into();
         let path = v8::String::new(scope, path).unwrap().into();
-        let promise = activate_endpoint.call(scope, undefined, &[path]).unwrap();
+        let type_name = v8::String::new(scope, type_name).unwrap().into();
+        let row = serde_json::to_string(&row)?;
+        let row = v8::String::new(scope, &row).unwrap().into();
+        let promise = apply_transform
+            .call(scope, undefined, &[path, type_name, row])
+            .unwrap();
         v8::Global::new(scope, promise)
     };
-    resolve_promise(promise).await?;
+    let value = resolve_promise(promise).await?;
+    let row = {
+        let mut service = get();
+        let runtime = &mut service.worker.js_runtime;
+        let scope = &mut runtime.handle_scope();
+        v8::Local::new(scope, value).to_rust_string_lossy(scope)
+    };
+    serde_json::from_str(&row).context("populate transform must return an object")
+}
+
+pub(crate) async fn activate_endpoint(path: &str) -> Result<()> {
+    let gate = get().worker_gate.clone();
+    let _ready = gate.read().await;
+    resolve_promise(call_activate_endpoint(path)).await?;
     Ok(())
 }
+
+fn call_activate_endpoint(path: &str) -> v8::Global<v8::Value> {
+    let mut service = get();
+    let service: &mut DenoService = &mut service;
+    let runtime = &mut service.worker.js_runtime;
+    let scope = &mut runtime.handle_scope();
+    let activate_endpoint = service.activate_endpoint.open(scope);
+    let undefined = v8::undefined(scope).into();
+    let path = v8::String::new(scope, path).unwrap().into();
+    let promise = activate_endpoint.call(scope, undefined, &[path]).unwrap();
+    v8::Global::new(scope, promise)
+}
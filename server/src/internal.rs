@@ -9,10 +9,19 @@ use hyper::{Body, Request, Response, Server};
 use once_cell::sync::OnceCell;
 use serde_derive::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// If set, serve the web UI using this address for gRPC calls.
 static SERVE_WEBUI: OnceCell<SocketAddr> = OnceCell::new();
 
+/// Whether chiseld takes requests: once all executors serve, and until it shuts down.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Sets what `/readiness` tells, for load balancers to send traffic only to a ready chiseld.
+pub(crate) fn set_ready(ready: bool) {
+    READY.store(ready, Ordering::SeqCst);
+}
+
 fn response(body: &str, status: u16) -> Result<Response<Body>> {
     Ok(Response::builder()
         .status(status)
@@ -40,6 +49,7 @@ async fn webapply(body: Body, rpc_addr: &SocketAddr) -> Result<Response<Body>> {
             version: "dev".into(),
             version_tag: "dev".into(),
             app_name: "ChiselStrike WebUI".into(),
+            static_files: vec![],
         }))
         .await?;
     response("applied", 200)
@@ -52,8 +62,10 @@ async fn route(req: Request<Body>) -> Result<Response<Body>> {
         // FWIW, K8s does not require us to return those specific strings.
         // Anything that returns a code 200 is enough.
         ("/status", _) => response("ok", 200),
-        ("/readiness", _) => response("ready", 200),
+        ("/readiness", _) if READY.load(Ordering::SeqCst) => response("ready", 200),
+        ("/readiness", _) => response("not ready", 503),
         ("/liveness", _) => response("alive", 200),
+        ("/metrics", _) => response(&crate::metrics::gather()?, 200),
         ("/apply", Some(rpc_addr)) => webapply(req.into_body(), rpc_addr).await,
         ("/webui", Some(_)) => {
             let html = std::str::from_utf8(include_bytes!("webui.html"))?;
@@ -0,0 +1,426 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Verification of the JSON Web Tokens that clients logged in with an external identity
+//! provider send as `Authorization: Bearer` headers.
+//!
+//! HS256 tokens are verified with the `CHISELD_JWT_SECRET` secret, and RS256 and ES256 tokens
+//! with the public keys of the JWKS file given to `chiseld --jwks-file`. If `chiseld` is given
+//! the issuer and audience of the tokens, the `iss` and `aud` claims must match them.
+
+use anyhow::{Context, Result};
+use ring::hmac;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde_derive::Deserialize;
+use std::path::Path;
+
+/// Name of the secret that HS256 tokens are signed with.
+pub(crate) const JWT_SECRET: &str = "CHISELD_JWT_SECRET";
+
+/// Why a token was rejected.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub(crate) enum JwtError {
+    #[error["malformed token"]]
+    Malformed,
+    #[error["unsupported algorithm {0}"]]
+    UnsupportedAlgorithm(String),
+    #[error["no key to verify the token"]]
+    NoKey,
+    #[error["bad signature"]]
+    BadSignature,
+    #[error["token has expired"]]
+    Expired,
+    #[error["token is not valid yet"]]
+    NotYetValid,
+    #[error["token is from another issuer"]]
+    WrongIssuer,
+    #[error["token is for another audience"]]
+    WrongAudience,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// The `aud` claim, which can be one audience or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(a) => a == audience,
+            Audience::Many(list) => list.iter().any(|a| a == audience),
+        }
+    }
+}
+
+/// Claims of a verified token that identify the user.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Claims {
+    /// Becomes the user ID.
+    pub(crate) sub: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    /// Roles of the user, in addition to the ones granted in the database.
+    #[serde(default)]
+    pub(crate) roles: Vec<String>,
+    #[serde(default)]
+    exp: Option<f64>,
+    #[serde(default)]
+    nbf: Option<f64>,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    aud: Option<Audience>,
+}
+
+impl Claims {
+    /// The username that endpoint and entity policies match: the email, if the identity
+    /// provider verified it. Otherwise, anyone could claim someone else's email.
+    pub(crate) fn verified_email(&self) -> Option<&str> {
+        match self.email_verified {
+            true => self.email.as_deref(),
+            false => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PublicKey {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// An uncompressed P-256 point.
+    Ec(Vec<u8>),
+}
+
+impl PublicKey {
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Option<bool> {
+        let ok = match (self, alg) {
+            (PublicKey::Rsa { n, e }, "RS256") => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+            (PublicKey::Ec(point), "ES256") => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            _ => return None,
+        };
+        Some(ok)
+    }
+}
+
+#[derive(Deserialize)]
+struct JwkJson {
+    kty: String,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwksJson {
+    keys: Vec<JwkJson>,
+}
+
+/// Public keys of the identity provider.
+#[derive(Debug, Default)]
+pub(crate) struct Jwks {
+    keys: Vec<(Option<String>, PublicKey)>,
+}
+
+impl Jwks {
+    pub(crate) fn from_file(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read JWKS file {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("Bad JWKS file {}", path.display()))
+    }
+
+    /// Parses a JSON Web Key Set. Keys of types other than RSA and P-256 are ignored.
+    fn from_json(json: &str) -> Result<Self> {
+        let jwks: JwksJson = serde_json::from_str(json)?;
+        let mut keys = vec![];
+        for jwk in jwks.keys {
+            let field = |value: Option<String>, name: &str| -> Result<Vec<u8>> {
+                let value = value.with_context(|| format!("{} key without {}", jwk.kty, name))?;
+                decode(&value).with_context(|| format!("bad {} in {} key", name, jwk.kty))
+            };
+            let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+                ("RSA", _) => PublicKey::Rsa {
+                    n: field(jwk.n, "n")?,
+                    e: field(jwk.e, "e")?,
+                },
+                ("EC", Some("P-256")) => {
+                    let mut point = vec![4];
+                    point.extend(field(jwk.x, "x")?);
+                    point.extend(field(jwk.y, "y")?);
+                    PublicKey::Ec(point)
+                }
+                (kty, crv) => {
+                    warn!("Ignoring JWKS key of type {} and curve {:?}", kty, crv);
+                    continue;
+                }
+            };
+            keys.push((jwk.kid, key));
+        }
+        Ok(Self { keys })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Checks the signature with the keys for `alg`, restricted to the one named `kid` if given.
+    fn verify(
+        &self,
+        alg: &str,
+        kid: Option<&str>,
+        message: &[u8],
+        sig: &[u8],
+    ) -> Result<(), JwtError> {
+        let mut found = false;
+        for (key_id, key) in &self.keys {
+            if kid.is_some() && kid != key_id.as_deref() {
+                continue;
+            }
+            match key.verify(alg, message, sig) {
+                Some(true) => return Ok(()),
+                Some(false) => found = true,
+                None => {}
+            }
+        }
+        Err(if found {
+            JwtError::BadSignature
+        } else {
+            JwtError::NoKey
+        })
+    }
+}
+
+/// How `chiseld` verifies bearer tokens, besides the `CHISELD_JWT_SECRET` secret.
+#[derive(Debug, Default)]
+pub(crate) struct JwtConfig {
+    /// Public keys of RS256 and ES256 tokens.
+    pub(crate) jwks: Jwks,
+    /// Required `iss` of the tokens, if set.
+    pub(crate) issuer: Option<String>,
+    /// Audience that the `aud` of the tokens must include, if set.
+    pub(crate) audience: Option<String>,
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(s: &str) -> Result<T, JwtError> {
+    let bytes = decode(s).map_err(|_| JwtError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| JwtError::Malformed)
+}
+
+/// Verifies a token at time `now`, in seconds since the epoch, and returns its claims.
+pub(crate) fn verify(
+    token: &str,
+    secret: Option<&str>,
+    config: &JwtConfig,
+    now: f64,
+) -> Result<Claims, JwtError> {
+    let (message, sig) = token.rsplit_once('.').ok_or(JwtError::Malformed)?;
+    let (header, payload) = message.split_once('.').ok_or(JwtError::Malformed)?;
+    let header: Header = decode_json(header)?;
+    let sig = decode(sig).map_err(|_| JwtError::Malformed)?;
+    match header.alg.as_str() {
+        "HS256" => {
+            let secret = secret.ok_or(JwtError::NoKey)?;
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            hmac::verify(&key, message.as_bytes(), &sig).map_err(|_| JwtError::BadSignature)?;
+        }
+        "RS256" | "ES256" => {
+            let kid = header.kid.as_deref();
+            config
+                .jwks
+                .verify(&header.alg, kid, message.as_bytes(), &sig)?
+        }
+        alg => return Err(JwtError::UnsupportedAlgorithm(alg.to_owned())),
+    }
+    let claims: Claims = decode_json(payload)?;
+    if claims.exp.map_or(false, |exp| exp <= now) {
+        return Err(JwtError::Expired);
+    }
+    if claims.nbf.map_or(false, |nbf| nbf > now) {
+        return Err(JwtError::NotYetValid);
+    }
+    if let Some(issuer) = &config.issuer {
+        if claims.iss.as_ref() != Some(issuer) {
+            return Err(JwtError::WrongIssuer);
+        }
+    }
+    if let Some(audience) = &config.audience {
+        if !claims
+            .aud
+            .as_ref()
+            .map_or(false, |aud| aud.contains(audience))
+        {
+            return Err(JwtError::WrongAudience);
+        }
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair};
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn unsigned(header: &str, claims: &str) -> String {
+        format!(
+            "{}.{}",
+            encode(header.as_bytes()),
+            encode(claims.as_bytes())
+        )
+    }
+
+    fn hs256(claims: &str, secret: &str) -> String {
+        let message = unsigned(r#"{"alg":"HS256","typ":"JWT"}"#, claims);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let sig = hmac::sign(&key, message.as_bytes());
+        format!("{}.{}", message, encode(sig.as_ref()))
+    }
+
+    #[test]
+    fn shared_secret() {
+        let config = JwtConfig::default();
+        let claims = r#"{"sub":"u1","email":"al@example.com","email_verified":true,
+                         "roles":["admin"],"exp":200}"#;
+        let token = hs256(claims, "s3cr3t");
+        let c = verify(&token, Some("s3cr3t"), &config, 100.0).unwrap();
+        assert_eq!(c.sub, "u1");
+        assert_eq!(c.verified_email(), Some("al@example.com"));
+        assert_eq!(c.roles, vec!["admin".to_owned()]);
+
+        assert_eq!(
+            verify(&token, Some("other"), &config, 100.0).unwrap_err(),
+            JwtError::BadSignature
+        );
+        assert_eq!(
+            verify(&token, None, &config, 100.0).unwrap_err(),
+            JwtError::NoKey
+        );
+        assert_eq!(
+            verify(&token, Some("s3cr3t"), &config, 200.0).unwrap_err(),
+            JwtError::Expired
+        );
+        let token = hs256(r#"{"sub":"u1","email":"al@example.com"}"#, "s3cr3t");
+        let c = verify(&token, Some("s3cr3t"), &config, 100.0).unwrap();
+        assert_eq!(c.verified_email(), None);
+        let token = hs256(r#"{"sub":"u1","nbf":150}"#, "s3cr3t");
+        assert_eq!(
+            verify(&token, Some("s3cr3t"), &config, 100.0).unwrap_err(),
+            JwtError::NotYetValid
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let config = JwtConfig::default();
+        let check = |token: &str| verify(token, Some("s"), &config, 0.0).unwrap_err();
+        assert_eq!(check("nonsense"), JwtError::Malformed);
+        assert_eq!(check("a.b.c"), JwtError::Malformed);
+        let token = format!("{}.", unsigned(r#"{"alg":"none"}"#, r#"{"sub":"u"}"#));
+        assert_eq!(
+            check(&token),
+            JwtError::UnsupportedAlgorithm("none".to_owned())
+        );
+        let token = hs256("not json", "s");
+        assert_eq!(check(&token), JwtError::Malformed);
+    }
+
+    #[test]
+    fn public_keys() {
+        let rng = SystemRandom::new();
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).unwrap();
+        let point = pair.public_key().as_ref();
+        let jwks = Jwks::from_json(&format!(
+            r#"{{"keys":[{{"kty":"EC","crv":"P-256","kid":"k1","x":"{}","y":"{}"}},
+                         {{"kty":"oct","k":"c2VjcmV0"}}]}}"#,
+            encode(&point[1..33]),
+            encode(&point[33..])
+        ))
+        .unwrap();
+        assert!(!jwks.is_empty());
+        let config = JwtConfig {
+            jwks,
+            ..Default::default()
+        };
+
+        let sign = |header: &str| {
+            let message = unsigned(header, r#"{"sub":"u2"}"#);
+            let sig = pair.sign(&rng, message.as_bytes()).unwrap();
+            format!("{}.{}", message, encode(sig.as_ref()))
+        };
+        let token = sign(r#"{"alg":"ES256","kid":"k1"}"#);
+        assert_eq!(verify(&token, None, &config, 0.0).unwrap().sub, "u2");
+        let token = sign(r#"{"alg":"ES256"}"#);
+        assert_eq!(verify(&token, None, &config, 0.0).unwrap().sub, "u2");
+        let token = sign(r#"{"alg":"ES256","kid":"k2"}"#);
+        assert_eq!(
+            verify(&token, None, &config, 0.0).unwrap_err(),
+            JwtError::NoKey
+        );
+        let token = sign(r#"{"alg":"RS256","kid":"k1"}"#);
+        assert_eq!(
+            verify(&token, None, &config, 0.0).unwrap_err(),
+            JwtError::NoKey
+        );
+
+        assert!(Jwks::from_json(r#"{"keys":[{"kty":"RSA","e":"AQAB"}]}"#).is_err());
+    }
+
+    #[test]
+    fn issuer_and_audience() {
+        let config = JwtConfig {
+            issuer: Some("https://idp.example.com/".to_owned()),
+            audience: Some("app".to_owned()),
+            ..Default::default()
+        };
+        let check = |claims: &str| verify(&hs256(claims, "s"), Some("s"), &config, 0.0);
+        let iss = r#""iss":"https://idp.example.com/""#;
+        assert!(check(&format!(r#"{{"sub":"u",{},"aud":"app"}}"#, iss)).is_ok());
+        assert!(check(&format!(r#"{{"sub":"u",{},"aud":["x","app"]}}"#, iss)).is_ok());
+        assert_eq!(
+            check(&format!(r#"{{"sub":"u",{},"aud":"other"}}"#, iss)).unwrap_err(),
+            JwtError::WrongAudience
+        );
+        assert_eq!(
+            check(&format!(r#"{{"sub":"u",{}}}"#, iss)).unwrap_err(),
+            JwtError::WrongAudience
+        );
+        assert_eq!(
+            check(r#"{"sub":"u","iss":"https://evil.example.com/","aud":"app"}"#).unwrap_err(),
+            JwtError::WrongIssuer
+        );
+        assert_eq!(
+            check(r#"{"sub":"u","aud":"app"}"#).unwrap_err(),
+            JwtError::WrongIssuer
+        );
+    }
+}
@@ -17,18 +17,31 @@ extern crate log;
 extern crate lazy_static;
 
 pub(crate) mod api;
+pub(crate) mod audit;
 pub(crate) mod auth;
+pub(crate) mod compression;
+pub(crate) mod cors;
 pub(crate) mod datastore;
 pub(crate) mod deno;
 pub(crate) mod internal;
 pub(crate) mod introspect;
+pub(crate) mod jwt;
+pub(crate) mod listeners;
+pub mod logging;
+pub(crate) mod metrics;
 pub(crate) mod policies;
+pub(crate) mod policy_test;
+pub(crate) mod populate;
 pub(crate) mod prefix_map;
+pub(crate) mod rate_limit;
 pub(crate) mod rcmut;
 pub(crate) mod rpc;
 pub(crate) mod runtime;
 pub(crate) mod secrets;
+pub(crate) mod static_files;
 pub mod server;
+pub(crate) mod telemetry;
+pub(crate) mod tls;
 pub(crate) mod types;
 pub(crate) mod vecmap;
 
@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Listening sockets of the API server, which a restarting chiseld hands over to the process that
+//! replaces it. Connections that arrive meanwhile wait in the sockets' backlogs rather than being
+//! refused.
+
+use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Mutex;
+
+/// Environment variable with the sockets that a restarting chiseld hands over, as comma-separated
+/// `<fd>=<address>` pairs.
+const LISTEN_FDS: &str = "CHISELD_LISTEN_FDS";
+
+lazy_static! {
+    /// Sockets handed over by the previous chiseld that no API server took yet.
+    static ref INHERITED: Mutex<Vec<(SocketAddr, Socket)>> = Mutex::new(inherit());
+    /// Copies of the sockets that the API servers listen on, which outlive the servers to be
+    /// handed over.
+    static ref KEPT: Mutex<Vec<(SocketAddr, Socket)>> = Mutex::new(vec![]);
+}
+
+fn parse(fds: &str) -> Result<Vec<(RawFd, SocketAddr)>> {
+    fds.split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (fd, addr) = pair
+                .split_once('=')
+                .with_context(|| format!("bad listening socket {}", pair))?;
+            Ok((fd.parse()?, addr.parse()?))
+        })
+        .collect()
+}
+
+fn inherit() -> Vec<(SocketAddr, Socket)> {
+    let fds = match std::env::var(LISTEN_FDS) {
+        Ok(fds) => fds,
+        Err(_) => return vec![],
+    };
+    // Processes that this one starts don't get them.
+    std::env::remove_var(LISTEN_FDS);
+    match parse(&fds) {
+        Ok(fds) => fds
+            .into_iter()
+            // Safety: the previous chiseld left these open for this one, which owns them now.
+            .map(|(fd, addr)| (addr, unsafe { Socket::from_raw_fd(fd) }))
+            .collect(),
+        Err(e) => {
+            warn!("Ignoring the listening sockets of {}: {:?}", LISTEN_FDS, e);
+            vec![]
+        }
+    }
+}
+
+/// Returns a socket that listens on `addr`, either handed over by the previous chiseld or new.
+pub(crate) fn listen(addr: SocketAddr) -> Result<Socket> {
+    let inherited = {
+        let mut inherited = INHERITED.lock().unwrap();
+        let pos = inherited.iter().position(|(a, _)| *a == addr);
+        pos.map(|pos| inherited.swap_remove(pos).1)
+    };
+    let sk = match inherited {
+        Some(sk) => sk,
+        None => {
+            let domain = if addr.is_ipv6() {
+                Domain::ipv6()
+            } else {
+                Domain::ipv4()
+            };
+            let sk = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+            sk.set_reuse_port(true)?;
+            sk.bind(&SockAddr::from(addr))?;
+            sk.listen(1024)?;
+            sk
+        }
+    };
+    KEPT.lock().unwrap().push((addr, sk.try_clone()?));
+    Ok(sk)
+}
+
+/// Closes the handed over sockets that no API server took, which happens when chiseld restarts
+/// with fewer executors. Their connections would never be accepted.
+pub(crate) fn close_unclaimed() {
+    INHERITED.lock().unwrap().clear();
+}
+
+/// Has the listening sockets of the API servers survive the exec of the next chiseld, and tells
+/// it where they are.
+pub(crate) fn hand_over() -> Result<()> {
+    let kept = KEPT.lock().unwrap();
+    let mut fds = vec![];
+    for (addr, sk) in kept.iter() {
+        let fd = sk.as_raw_fd();
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+        fds.push(format!("{}={}", fd, addr));
+    }
+    std::env::set_var(LISTEN_FDS, fds.join(","));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fds() {
+        let fds = parse("3=127.0.0.1:8080,4=[::1]:8080").unwrap();
+        assert_eq!(
+            fds,
+            vec![
+                (3, "127.0.0.1:8080".parse().unwrap()),
+                (4, "[::1]:8080".parse().unwrap())
+            ]
+        );
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("3").is_err());
+        assert!(parse("x=127.0.0.1:8080").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Log lines of the server, tagged with the ID of the request that emits them.
+//!
+//! Executor threads run many requests concurrently, so the current request ID is a thread-local
+//! that is set while the futures of a request are polled, with `scoped`, or while a synchronous
+//! op runs, with `enter`.
+//!
+//! The console output of endpoints isn't part of the log: it's always written, to stdout or
+//! stderr like Deno does, but in the same format as the log lines.
+
+use anyhow::Result;
+use env_logger::Env;
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use pin_project::pin_project;
+use serde_json::json;
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug)]
+pub enum LogFormat {
+    /// `[timestamp] LEVEL request-id - message`
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target`, `requestId` and `message`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("unknown log format {:?}, expected text or json", s),
+        }
+    }
+}
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+static FORMAT: OnceCell<LogFormat> = OnceCell::new();
+
+/// Sets up the logger. The `RUST_LOG` environment variable filters the lines, and defaults to
+/// `info`.
+pub fn init(format: LogFormat) {
+    FORMAT.set(format).ok();
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .format(move |buf, record| {
+            let request_id = REQUEST_ID.with(|id| id.borrow().clone());
+            match format {
+                LogFormat::Text => {
+                    let request_id = request_id.map(|id| format!(" {}", id));
+                    writeln!(
+                        buf,
+                        "[{}] {}{} - {}",
+                        buf.timestamp(),
+                        record.level(),
+                        request_id.unwrap_or_default(),
+                        record.args()
+                    )
+                }
+                LogFormat::Json => {
+                    let mut line = json!({
+                        "timestamp": buf.timestamp().to_string(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    });
+                    if let Some(request_id) = request_id {
+                        line["requestId"] = json!(request_id);
+                    }
+                    writeln!(buf, "{}", line)
+                }
+            }
+        })
+        .filter_module("sqlx::query", LevelFilter::Warn)
+        .init();
+}
+
+/// Writes the output of a `console` method of an endpoint. `method` is the name of the method,
+/// like `log` or `error`.
+pub(crate) fn console(method: &str, message: &str, request_id: Option<&str>) {
+    let (level, to_stderr) = match method {
+        "debug" => ("DEBUG", false),
+        "warn" => ("WARN", true),
+        "error" => ("ERROR", true),
+        _ => ("INFO", false),
+    };
+    let line = match FORMAT.get().copied().unwrap_or(LogFormat::Text) {
+        LogFormat::Text => match request_id {
+            Some(id) => format!("[{}] {}", id, message),
+            None => message.to_owned(),
+        },
+        LogFormat::Json => {
+            let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+            let mut line = json!({
+                "timestamp": timestamp.to_string(),
+                "level": level,
+                "target": "endpoint",
+                "message": message,
+            });
+            if let Some(request_id) = request_id {
+                line["requestId"] = json!(request_id);
+            }
+            line.to_string()
+        }
+    };
+    // Like println!, but without panicking if the output is closed.
+    let _ = if to_stderr {
+        writeln!(std::io::stderr().lock(), "{}", line)
+    } else {
+        writeln!(std::io::stdout().lock(), "{}", line)
+    };
+}
+
+/// Restores the previous request ID when dropped.
+pub(crate) struct RequestScope {
+    previous: Option<String>,
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+/// Tags the log lines of the current thread with `request_id` until the returned scope is
+/// dropped.
+pub(crate) fn enter(request_id: Option<String>) -> RequestScope {
+    let previous = REQUEST_ID.with(|id| id.replace(request_id));
+    RequestScope { previous }
+}
+
+/// A future whose log lines are tagged with a request ID.
+#[pin_project]
+pub(crate) struct Scoped<F> {
+    request_id: Option<String>,
+    #[pin]
+    inner: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _scope = enter(this.request_id.clone());
+        this.inner.poll(cx)
+    }
+}
+
+/// Tags the log lines that `inner` emits with `request_id`.
+pub(crate) fn scoped<F: Future>(request_id: Option<String>, inner: F) -> Scoped<F> {
+    Scoped { request_id, inner }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> Option<String> {
+        REQUEST_ID.with(|id| id.borrow().clone())
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(current(), None);
+        {
+            let _outer = enter(Some("a".into()));
+            {
+                let _inner = enter(Some("b".into()));
+                assert_eq!(current().as_deref(), Some("b"));
+            }
+            assert_eq!(current().as_deref(), Some("a"));
+            let seen = futures::executor::block_on(scoped(Some("c".into()), async { current() }));
+            assert_eq!(seen.as_deref(), Some("c"));
+            assert_eq!(current().as_deref(), Some("a"));
+        }
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn formats() {
+        assert!(matches!("json".parse(), Ok(LogFormat::Json)));
+        assert!(matches!("text".parse(), Ok(LogFormat::Text)));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -6,34 +6,21 @@ extern crate log;
 use anyhow::Result;
 use chisel_server::server;
 use enclose::enclose;
-use env_logger::Env;
-use log::LevelFilter;
 use nix::unistd::execv;
 use server::DoRepeat;
 use std::env;
 use std::ffi::CString;
-use std::io::Write;
 use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut executors = vec![];
 
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "[{}] {} - {}",
-                buf.timestamp(),
-                record.level(),
-                record.args()
-            )
-        })
-        .filter_module("sqlx::query", LevelFilter::Warn)
-        .init();
+    let opt = server::Opt::from_args();
+    opt.init_logging();
 
     let args: Vec<CString> = env::args().map(|x| CString::new(x).unwrap()).collect();
-    let (tasks, shared, mut commands) = server::run_shared_state(server::Opt::from_args()).await?;
+    let (tasks, shared, mut commands) = server::run_shared_state(opt).await?;
     let exe = env::current_exe()?.into_os_string().into_string().unwrap();
 
     for id in 0..shared.executor_threads() {
@@ -56,6 +43,7 @@ async fn main() -> Result<()> {
 
     if let DoRepeat::Yes = tasks.join().await? {
         info!("Restarting");
+        server::hand_over_listeners()?;
         execv(&CString::new(exe).unwrap(), &args).unwrap();
     }
     Ok(())
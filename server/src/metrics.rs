@@ -0,0 +1,185 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Metrics of the server, served in the Prometheus text format at the `/metrics` internal route.
+//!
+//! Metrics of an executor thread are labeled with the `executor` index that the thread gets
+//! when it calls `register_executor`.
+
+use anyhow::Result;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramTimer, HistogramVec, IntCounter, IntGauge, IntGaugeVec, TextEncoder,
+};
+use sqlx::any::AnyPool;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Environment variable that carries the number of restarts over the re-execution of the server.
+const RESTARTS_ENV: &str = "CHISELD_RESTARTS";
+
+lazy_static! {
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "chiseld_http_request_duration_seconds",
+        "Time to answer requests to endpoints, up to the response headers.",
+        &["path", "status"]
+    )
+    .unwrap();
+    static ref SQL_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "chiseld_sql_query_duration_seconds",
+        "Time to run SQL queries of endpoints, up to reading their last row.",
+        &["kind"]
+    )
+    .unwrap();
+    static ref ACTIVE_TRANSACTIONS: IntGauge = register_int_gauge!(
+        "chiseld_active_transactions",
+        "Transactions of requests that are neither committed nor rolled back."
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "chiseld_db_pool_connections",
+        "Open connections of the database pool of an executor.",
+        &["executor"]
+    )
+    .unwrap();
+    static ref DB_POOL_IDLE_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "chiseld_db_pool_idle_connections",
+        "Idle connections of the database pool of an executor.",
+        &["executor"]
+    )
+    .unwrap();
+    static ref V8_HEAP_USED: IntGaugeVec = register_int_gauge_vec!(
+        "chiseld_v8_heap_used_bytes",
+        "Used V8 heap of an executor, as of its last request.",
+        &["executor"]
+    )
+    .unwrap();
+    static ref V8_HEAP_TOTAL: IntGaugeVec = register_int_gauge_vec!(
+        "chiseld_v8_heap_total_bytes",
+        "Allocated V8 heap of an executor, as of its last request.",
+        &["executor"]
+    )
+    .unwrap();
+    static ref V8_HEAP_LIMIT: IntGaugeVec = register_int_gauge_vec!(
+        "chiseld_v8_heap_limit_bytes",
+        "V8 heap size limit of an executor.",
+        &["executor"]
+    )
+    .unwrap();
+    static ref APPLIES: IntCounter =
+        register_int_counter!("chiseld_applies_total", "Successful `chisel apply` calls.").unwrap();
+    static ref APPLY_ERRORS: IntCounter = register_int_counter!(
+        "chiseld_apply_errors_total",
+        "Failed `chisel apply` calls."
+    )
+    .unwrap();
+    static ref RESTARTS: IntCounter = {
+        let counter =
+            register_int_counter!("chiseld_restarts_total", "Restarts of the server.").unwrap();
+        let restarts = std::env::var(RESTARTS_ENV).ok().and_then(|r| r.parse().ok());
+        counter.inc_by(restarts.unwrap_or(0));
+        counter
+    };
+    static ref WORKER_RESTARTS: IntCounter = register_int_counter!(
+        "chiseld_worker_restarts_total",
+        "Endpoint workers replaced because they ran out of memory."
+    )
+    .unwrap();
+    /// Database pools of the executors, sampled when the metrics are gathered.
+    static ref DB_POOLS: Mutex<Vec<(String, AnyPool)>> = Mutex::new(vec![]);
+}
+
+static NEXT_EXECUTOR: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static EXECUTOR: Cell<Option<usize>> = Cell::new(None);
+}
+
+fn executor() -> String {
+    EXECUTOR
+        .with(|e| e.get())
+        .map_or_else(|| "none".to_owned(), |e| e.to_string())
+}
+
+/// Gives the current thread the next executor index, and registers its database pool.
+pub(crate) fn register_executor(pool: AnyPool) {
+    let id = NEXT_EXECUTOR.fetch_add(1, Ordering::Relaxed);
+    EXECUTOR.with(|e| e.set(Some(id)));
+    DB_POOLS.lock().unwrap().push((id.to_string(), pool));
+}
+
+/// Registers the metrics that are exported before anything is recorded in them.
+pub(crate) fn init() {
+    lazy_static::initialize(&ACTIVE_TRANSACTIONS);
+    lazy_static::initialize(&APPLIES);
+    lazy_static::initialize(&APPLY_ERRORS);
+    lazy_static::initialize(&RESTARTS);
+    lazy_static::initialize(&WORKER_RESTARTS);
+}
+
+pub(crate) fn observe_request(path: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[path, &status.to_string()])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Starts timing an SQL query of this `kind`; the time is recorded when the timer is dropped.
+pub(crate) fn sql_timer(kind: &str) -> HistogramTimer {
+    SQL_QUERY_DURATION.with_label_values(&[kind]).start_timer()
+}
+
+pub(crate) fn transaction_started() {
+    ACTIVE_TRANSACTIONS.inc();
+}
+
+pub(crate) fn transaction_ended() {
+    ACTIVE_TRANSACTIONS.dec();
+}
+
+/// Records the heap statistics of the current executor's isolate.
+pub(crate) fn set_v8_heap(used: usize, total: usize, limit: usize) {
+    let executor = executor();
+    V8_HEAP_USED
+        .with_label_values(&[&executor])
+        .set(used as i64);
+    V8_HEAP_TOTAL
+        .with_label_values(&[&executor])
+        .set(total as i64);
+    V8_HEAP_LIMIT
+        .with_label_values(&[&executor])
+        .set(limit as i64);
+}
+
+pub(crate) fn apply_done(ok: bool) {
+    if ok {
+        APPLIES.inc();
+    } else {
+        APPLY_ERRORS.inc();
+    }
+}
+
+/// Counts a restart, which the re-executed server picks up from the environment.
+pub(crate) fn restarting() {
+    RESTARTS.inc();
+    std::env::set_var(RESTARTS_ENV, RESTARTS.get().to_string());
+}
+
+pub(crate) fn worker_restarted() {
+    WORKER_RESTARTS.inc();
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub(crate) fn gather() -> Result<String> {
+    for (executor, pool) in DB_POOLS.lock().unwrap().iter() {
+        DB_POOL_CONNECTIONS
+            .with_label_values(&[executor])
+            .set(pool.size() as i64);
+        DB_POOL_IDLE_CONNECTIONS
+            .with_label_values(&[executor])
+            .set(pool.num_idle() as i64);
+    }
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
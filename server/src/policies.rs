@@ -1,20 +1,113 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::cors::Cors;
+use crate::datastore::expr::Expr;
+use crate::datastore::filter::{bind_filter, parse_filter};
 use crate::prefix_map::PrefixMap;
-use crate::types::ObjectType;
-use anyhow::Result;
+use crate::rate_limit::{RateLimit, RateLimits};
+use crate::types::{ObjectType, Type};
+use crate::JsonObject;
+use anyhow::{Context, Result};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use yaml_rust::YamlLoader;
+use std::sync::Mutex;
+use std::time::Duration;
+use yaml_rust::{Yaml, YamlLoader};
 
 /// Different kinds of policies.
 #[derive(Clone)]
 pub(crate) enum Kind {
     /// How this policy transforms values read from storage.
-    Transform(fn(Value) -> Value),
+    Transform(Transform),
     /// Field is of AuthUser type and must match the user currently logged in.
     MatchLogin,
+    /// Field can be set when its entity is created, but never changed afterwards.
+    ReadOnly,
+}
+
+/// Built-in transformations of values read from storage.
+#[derive(Clone, Debug)]
+pub(crate) enum Transform {
+    /// Replaces the value with a placeholder of the same type.
+    Anonymize,
+    /// Replaces a string with its SHA-256 hash, salted with the secret of this name.
+    Hash { secret: String },
+    /// Masks the local part of an email address, keeping its first character.
+    MaskEmail,
+    /// Keeps at most this many characters of a string.
+    Truncate(usize),
+    /// Replaces the value with null.
+    Null,
+}
+
+/// A transformation applied to the values of one field.
+#[derive(Clone, Debug)]
+pub(crate) struct FieldTransform {
+    transform: Transform,
+    /// Type of the field.
+    type_: Type,
+    /// Salt for `Transform::Hash`, looked up in the secrets when the policies are applied.
+    salt: Option<String>,
+}
+
+fn hash_salt<'a>(secret: &str, secrets: Option<&'a JsonObject>) -> Option<&'a str> {
+    secrets.and_then(|s| s.get(secret)).and_then(|v| v.as_str())
+}
+
+lazy_static! {
+    /// Secrets of hash transforms that were found missing, so each is warned about once.
+    static ref MISSING_HASH_SECRETS: Mutex<HashSet<String>> = Default::default();
+}
+
+impl FieldTransform {
+    fn new(transform: &Transform, type_: &Type, secrets: Option<&JsonObject>) -> Self {
+        let salt = match transform {
+            Transform::Hash { secret } => {
+                // A missing secret is warned about when the policies are loaded.
+                hash_salt(secret, secrets).map(|s| s.to_owned())
+            }
+            _ => None,
+        };
+        Self {
+            transform: transform.clone(),
+            type_: type_.clone(),
+            salt,
+        }
+    }
+
+    /// Transforms a value of this field. Transformations that only make sense
+    /// for strings anonymize values of other types.
+    pub(crate) fn apply(&self, value: Value) -> Value {
+        if value.is_null() {
+            return value;
+        }
+        let transformed = match (&self.transform, value.as_str()) {
+            (Transform::Null, _) => Some(Value::Null),
+            (Transform::Hash { .. }, Some(s)) => {
+                self.salt.as_ref().map(|salt| json!(hash(salt, s)))
+            }
+            (Transform::MaskEmail, Some(s)) => s
+                .split_once('@')
+                .map(|(local, domain)| json!(mask_email(local, domain))),
+            (Transform::Truncate(len), Some(s)) => {
+                Some(json!(s.chars().take(*len).collect::<String>()))
+            }
+            _ => None,
+        };
+        transformed.unwrap_or_else(|| anonymize(&self.type_))
+    }
+}
+
+/// Errors from requests that policies don't allow. Endpoints answer these with 403.
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum PolicyError {
+    #[error["{0} of {1} is not allowed by policy"]]
+    WriteDenied(&'static str, String),
+    #[error["update is not allowed by policy, or it changes a read-only field or an entity that \
+             can't be read"]]
+    UpdateRejected,
 }
 
 #[derive(Clone)]
@@ -23,6 +116,17 @@ pub(crate) struct Policy {
 
     /// This policy doesn't apply when the request URI matches.
     pub(crate) except_uri: regex::Regex,
+
+    /// This policy doesn't apply to users that have any of these roles.
+    pub(crate) except_roles: HashSet<String>,
+}
+
+impl Policy {
+    /// Does this policy apply to a request from a user with `user_roles` at `current_path`?
+    fn applies(&self, current_path: &str, user_roles: &[String]) -> bool {
+        !self.except_uri.is_match(current_path)
+            && !user_roles.iter().any(|r| self.except_roles.contains(r))
+    }
 }
 
 /// Maps labels to their applicable policies.
@@ -31,46 +135,393 @@ pub(crate) type LabelPolicies = HashMap<String, Policy>;
 #[derive(Clone, Default, Debug)]
 pub(crate) struct FieldPolicies {
     /// Maps a field name to the transformation we apply to that field's values.
-    pub(crate) transforms: HashMap<String, fn(Value) -> Value>,
+    pub(crate) transforms: HashMap<String, FieldTransform>,
     /// Names of fields that must equal the currently logged-in user.
     pub(crate) match_login: HashSet<String>,
     /// ID of the currently logged-in user.
     pub(crate) current_userid: Option<String>,
 }
 
+/// What the current request may write into an entity type.
+#[derive(Clone, Debug)]
+pub(crate) struct WritePermissions {
+    pub(crate) create: bool,
+    pub(crate) update: bool,
+    pub(crate) delete: bool,
+    /// Names of fields that can't be changed once the entity is created.
+    pub(crate) readonly: HashSet<String>,
+    /// Permissions for the entities nested in this one, keyed by field name.
+    pub(crate) nested: HashMap<String, WritePermissions>,
+    /// Query for the IDs of the entities that the request may read, if policies hide some.
+    /// Only those can be updated.
+    pub(crate) readable: Option<String>,
+}
+
+impl Default for WritePermissions {
+    /// Allows everything.
+    fn default() -> Self {
+        Self {
+            create: true,
+            update: true,
+            delete: true,
+            readonly: HashSet::default(),
+            nested: HashMap::default(),
+            readable: None,
+        }
+    }
+}
+
+/// Who may call an endpoint. The default allows everyone.
+#[derive(Clone, Debug, Default)]
+struct Access {
+    /// Everyone may call it, even anonymous users.
+    anonymous: bool,
+    /// Usernames that may call it. Anonymous users can't, if this is set.
+    users: Option<regex::Regex>,
+    /// Roles that may call it. Anonymous users can't, if this is set.
+    roles: Option<HashSet<String>>,
+    /// API keys used to call it must have one of these scopes, if this is set.
+    scopes: Option<HashSet<String>>,
+}
+
+impl Access {
+    /// Parses `anonymous`, `users`, `roles` and `scopes`, or returns `None` if `rule` has none of
+    /// them.
+    fn from_yaml(rule: &Yaml, path: &str) -> Result<Option<Self>> {
+        let keys = ["anonymous", "users", "roles", "scopes"];
+        if keys.iter().all(|k| rule[*k].is_badvalue()) {
+            return Ok(None);
+        }
+        let anonymous = match &rule["anonymous"] {
+            Yaml::BadValue => false,
+            Yaml::Boolean(b) => *b,
+            x => anyhow::bail!(
+                "couldn't parse yaml: bad anonymous {:?} for path {}",
+                x,
+                path
+            ),
+        };
+        let users = match rule["users"].as_str() {
+            Some(users) => Some(regex::Regex::new(users)?),
+            None => None,
+        };
+        let set = |name: &str| -> Result<Option<HashSet<String>>> {
+            match &rule[name] {
+                Yaml::BadValue => Ok(None),
+                items => Ok(Some(
+                    yaml_strings(items)
+                        .with_context(|| {
+                            format!("couldn't parse yaml: bad {} for path {}", name, path)
+                        })?
+                        .into_iter()
+                        .collect(),
+                )),
+            }
+        };
+        Ok(Some(Self {
+            anonymous,
+            users,
+            roles: set("roles")?,
+            scopes: set("scopes")?,
+        }))
+    }
+
+    fn allows(
+        &self,
+        username: &Option<String>,
+        user_roles: &[String],
+        api_key_scopes: Option<&[String]>,
+    ) -> bool {
+        if self.anonymous {
+            return true;
+        }
+        let user_ok = match (&self.users, username) {
+            (None, _) => true,
+            (Some(_), None) => false, // Must be logged in if a regex is specified.
+            (Some(users), Some(username)) => users.is_match(username),
+        };
+        let role_ok = match &self.roles {
+            None => true,
+            Some(roles) => user_roles.iter().any(|r| roles.contains(r)),
+        };
+        let scope_ok = match (&self.scopes, api_key_scopes) {
+            (Some(scopes), Some(key_scopes)) => key_scopes.iter().any(|s| scopes.contains(s)),
+            _ => true, // Only requests authenticated by an API key have scopes.
+        };
+        user_ok && role_ok && scope_ok
+    }
+}
+
+/// Who may call an endpoint with some HTTP method, and how to answer those who may not.
+#[derive(Clone, Debug)]
+pub(crate) struct MethodRule {
+    access: Access,
+    /// Status of the response to a request that isn't allowed.
+    status: u16,
+    /// Body of the response to a request that isn't allowed.
+    message: String,
+}
+
+impl Default for MethodRule {
+    /// Allows everyone.
+    fn default() -> Self {
+        Self {
+            access: Access::default(),
+            status: 403,
+            message: "Unauthorized user\n".to_owned(),
+        }
+    }
+}
+
+impl MethodRule {
+    /// Parses `status` and `message`, which default to the ones of `parent`, along with the
+    /// access keys. If `rule` has no access keys, it gets the access of `parent` when
+    /// `inherit_access` is set, and allows everyone otherwise.
+    fn from_yaml(
+        rule: &Yaml,
+        path: &str,
+        parent: &MethodRule,
+        inherit_access: bool,
+    ) -> Result<Self> {
+        let access = match Access::from_yaml(rule, path)? {
+            Some(access) => access,
+            None if inherit_access => parent.access.clone(),
+            None => Access::default(),
+        };
+        let status = match &rule["status"] {
+            Yaml::BadValue => parent.status,
+            Yaml::Integer(s) if (400..600).contains(s) => *s as u16,
+            x => anyhow::bail!("couldn't parse yaml: bad status {:?} for path {}", x, path),
+        };
+        let message = match rule["message"].as_str() {
+            Some(m) => m.to_owned(),
+            None => parent.message.clone(),
+        };
+        Ok(Self {
+            access,
+            status,
+            message,
+        })
+    }
+}
+
+/// Access rules for the endpoints under a path.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EndpointRule {
+    /// Applies to the methods that aren't in `methods`.
+    default: MethodRule,
+    /// Maps upper-case HTTP methods to their rules.
+    methods: HashMap<String, MethodRule>,
+}
+
+impl EndpointRule {
+    /// Parses an item of the `endpoints` section. Its keys, other than `path`, form the default
+    /// rule, and `methods` maps HTTP methods to the rules that replace it for them.
+    ///
+    /// What the item leaves unset comes from `parent`, the rule of the longest enclosing path:
+    /// `status` and `message`, and, if the item has none of the access keys, the access of the
+    /// default rule along with the rules of the methods that the item doesn't list.
+    pub(crate) fn from_yaml(endpoint: &Yaml, path: &str, parent: &EndpointRule) -> Result<Self> {
+        let inherit = Access::from_yaml(endpoint, path)?.is_none();
+        let default = MethodRule::from_yaml(endpoint, path, &parent.default, true)?;
+        let mut methods = if inherit {
+            parent.methods.clone()
+        } else {
+            HashMap::new()
+        };
+        match &endpoint["methods"] {
+            Yaml::BadValue => {}
+            Yaml::Hash(rules) => {
+                for (method, rule) in rules {
+                    let method = method.as_str().ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "couldn't parse yaml: bad method {:?} for path {}",
+                            method,
+                            path
+                        )
+                    })?;
+                    let rule = MethodRule::from_yaml(rule, path, &default, false)?;
+                    methods.insert(method.to_uppercase(), rule);
+                }
+            }
+            x => anyhow::bail!("couldn't parse yaml: bad methods {:?} for path {}", x, path),
+        }
+        Ok(Self { default, methods })
+    }
+
+    fn rule(&self, method: &str) -> &MethodRule {
+        self.methods.get(method).unwrap_or(&self.default)
+    }
+
+    fn uses_roles(&self) -> bool {
+        self.default.access.roles.is_some()
+            || self.methods.values().any(|m| m.access.roles.is_some())
+    }
+}
+
 #[derive(Clone, Default, Debug)]
 pub(crate) struct UserAuthorization {
-    /// A user is authorized to access a path if the username matches the regex for the longest path prefix present
-    /// here.
-    paths: PrefixMap<regex::Regex>,
+    /// A user is authorized to access a path if they satisfy the rule for the request's method under the longest
+    /// path prefix present here.
+    paths: PrefixMap<EndpointRule>,
 }
 
 impl UserAuthorization {
-    /// Is this username allowed to execute the endpoint at this path?
-    pub fn is_allowed(&self, username: Option<String>, path: &Path) -> bool {
+    /// Checks whether this user may call the endpoint at this path with this method.  `api_key_scopes` are the
+    /// scopes of the API key of the request, if it was authenticated by one.  If not allowed, returns the status
+    /// and body to answer the request with.
+    pub fn check(
+        &self,
+        username: &Option<String>,
+        user_roles: &[String],
+        api_key_scopes: Option<&[String]>,
+        method: &str,
+        path: &Path,
+    ) -> Option<(u16, &str)> {
+        let rule = self.paths.longest_prefix(path)?.1.rule(method);
+        if rule.access.allows(username, user_roles, api_key_scopes) {
+            None
+        } else {
+            Some((rule.status, &rule.message))
+        }
+    }
+
+    /// Whether any rule depends on the roles of the user.
+    fn uses_roles(&self) -> bool {
+        self.paths.iter().any(|(_, rule)| rule.uses_roles())
+    }
+
+    /// Adds the rules for the endpoints under this path.  Longer paths override existing prefixes.  Error if this
+    /// same path has already been added.
+    pub fn add(&mut self, path: &str, rule: EndpointRule) -> Result<()> {
+        if self.paths.insert(path.into(), rule).is_some() {
+            anyhow::bail!("Repeated path in user authorization: {:?}", path);
+        }
+        Ok(())
+    }
+}
+
+/// Maps roles to the HTTP methods they may use, `None` meaning all of them.
+type RoleMethods = HashMap<String, Option<HashSet<String>>>;
+
+#[derive(Clone, Default, Debug)]
+pub(crate) struct RoleAuthorization {
+    /// A user is authorized to access a path if they have one of the roles listed for the longest path prefix
+    /// present here, and that role allows the HTTP method.
+    paths: PrefixMap<RoleMethods>,
+}
+
+impl RoleAuthorization {
+    /// Is a user with these roles allowed to call the endpoint at this path with this method?
+    pub fn is_allowed(&self, user_roles: &[String], method: &str, path: &Path) -> bool {
         match self.paths.longest_prefix(path) {
             None => true,
-            Some((_, u)) => match username {
-                None => false, // Must be logged in if path specified a regex.
-                Some(username) => u.is_match(&username),
-            },
+            Some((_, roles)) => user_roles.iter().any(|r| match roles.get(r) {
+                None => false,
+                Some(None) => true,
+                Some(Some(methods)) => methods.contains(method),
+            }),
         }
     }
 
-    /// Authorizes users matching a regex to execute any endpoint under this path.  Longer paths override existing
-    /// prefixes.  Error if this same path has already been added.
-    pub fn add(&mut self, path: &str, users: regex::Regex) -> Result<()> {
-        if self.paths.insert(path.into(), users).is_some() {
-            anyhow::bail!("Repeated path in user authorization: {:?}", path);
+    fn is_empty(&self) -> bool {
+        self.paths.iter().next().is_none()
+    }
+
+    /// Authorizes users with this role to call any endpoint under this path with the given methods, or all methods
+    /// if `methods` is `None`.  Error if this role was already added for the same path.
+    pub fn add(&mut self, path: &str, role: &str, methods: Option<HashSet<String>>) -> Result<()> {
+        let roles = self.paths.get_or_insert_default(path.into());
+        if roles.insert(role.to_owned(), methods).is_some() {
+            anyhow::bail!("Repeated path {:?} for role {}", path, role);
         }
         Ok(())
     }
 }
 
+/// Restricts who can create, update or delete an entity.
+#[derive(Clone, Debug)]
+pub(crate) struct WriteRule {
+    /// If false, nobody can do it.
+    allow: bool,
+    /// Usernames that can do it. Anonymous users can't, if this is set.
+    users: Option<regex::Regex>,
+    /// Request paths from which it can be done.
+    paths: Option<regex::Regex>,
+}
+
+impl WriteRule {
+    /// Parses either `allow`, `deny`, or a map with `users` and/or `paths` regexes.
+    fn from_yaml(rule: &Yaml, entity: &str, op: &str) -> Result<Option<Self>> {
+        let bad_rule =
+            || anyhow::anyhow!("couldn't parse yaml: bad {} rule for entity {}", op, entity);
+        let rule = match rule {
+            Yaml::BadValue => return Ok(None),
+            Yaml::String(s) if s == "allow" || s == "deny" => Self {
+                allow: s == "allow",
+                users: None,
+                paths: None,
+            },
+            Yaml::Hash(_) => {
+                let regex = |key: &str| -> Result<Option<regex::Regex>> {
+                    match &rule[key] {
+                        Yaml::BadValue => Ok(None),
+                        Yaml::String(s) => Ok(Some(regex::Regex::new(s)?)),
+                        _ => Err(bad_rule()),
+                    }
+                };
+                Self {
+                    allow: true,
+                    users: regex("users")?,
+                    paths: regex("paths")?,
+                }
+            }
+            _ => return Err(bad_rule()),
+        };
+        Ok(Some(rule))
+    }
+
+    fn allows(&self, username: &Option<String>, path: &str) -> bool {
+        let user_ok = match (&self.users, username) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(users), Some(username)) => users.is_match(username),
+        };
+        let path_ok = self
+            .paths
+            .as_ref()
+            .map_or(true, |paths| paths.is_match(path));
+        self.allow && user_ok && path_ok
+    }
+}
+
+/// Row-level rules for an entity.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct EntityPolicy {
+    /// Only the rows matching this filter can be read, or deleted.
+    pub(crate) read: Option<Expr>,
+    pub(crate) create: Option<WriteRule>,
+    pub(crate) update: Option<WriteRule>,
+    pub(crate) delete: Option<WriteRule>,
+    /// Whether changes to the entity are recorded in the audit log.
+    pub(crate) audit: bool,
+}
+
 #[derive(Clone, Default)]
 pub(crate) struct VersionPolicy {
     pub(crate) labels: LabelPolicies,
     pub(crate) user_authorization: UserAuthorization,
+    pub(crate) role_authorization: RoleAuthorization,
+    /// Rate limits of the endpoints, by path prefix.
+    pub(crate) rate_limits: RateLimits,
+    /// Cross-origin requests allowed to the endpoints, if they are restricted.
+    pub(crate) cors: Option<Cors>,
+    /// How long the endpoints under a path may take to respond, overriding the server's default.
+    pub(crate) timeouts: PrefixMap<Duration>,
+    /// Maps entity names to their row-level policies.
+    pub(crate) entities: HashMap<String, EntityPolicy>,
+    /// Changes to entities with fields of these labels are recorded in the audit log.
+    pub(crate) audited_labels: HashSet<String>,
 }
 
 #[derive(Clone, Default)]
@@ -79,6 +530,26 @@ pub(crate) struct Policies {
 }
 
 impl Policies {
+    /// Warns about the hash transforms whose secrets are missing from `secrets`, which anonymize
+    /// values instead. Each executor loads the policies, but a secret is only warned about the
+    /// first time it is found missing.
+    pub(crate) fn check_hash_secrets(&self, secrets: Option<&JsonObject>) {
+        let mut missing = MISSING_HASH_SECRETS.lock().unwrap();
+        let labels = self.versions.values().flat_map(|v| v.labels.values());
+        for label in labels {
+            if let Kind::Transform(Transform::Hash { secret }) = &label.kind {
+                if hash_salt(secret, secrets).is_some() {
+                    missing.remove(secret);
+                } else if missing.insert(secret.clone()) {
+                    warn!(
+                        "secret {} for hash transform is missing, anonymizing instead",
+                        secret
+                    );
+                }
+            }
+        }
+    }
+
     pub(crate) fn add_from_yaml<K: ToString, Y: AsRef<str>>(
         &mut self,
         version: K,
@@ -89,11 +560,13 @@ impl Policies {
         Ok(())
     }
 
-    /// For field of type `ty` creates field policies.
+    /// For field of type `ty` creates field policies. `secrets` provide the salts of hash transforms.
     pub(crate) fn make_field_policies(
         &self,
         user_id: &Option<String>,
+        user_roles: &[String],
         current_path: &str,
+        secrets: Option<&JsonObject>,
         ty: &ObjectType,
     ) -> FieldPolicies {
         let mut field_policies = FieldPolicies {
@@ -105,14 +578,16 @@ impl Policies {
             for fld in ty.user_fields() {
                 for lbl in &fld.labels {
                     if let Some(p) = version.labels.get(lbl) {
-                        if !p.except_uri.is_match(current_path) {
-                            match p.kind {
-                                Kind::Transform(f) => {
-                                    field_policies.transforms.insert(fld.name.clone(), f);
+                        if p.applies(current_path, user_roles) {
+                            match &p.kind {
+                                Kind::Transform(t) => {
+                                    let t = FieldTransform::new(t, &fld.type_, secrets);
+                                    field_policies.transforms.insert(fld.name.clone(), t);
                                 }
                                 Kind::MatchLogin => {
                                     field_policies.match_login.insert(fld.name.clone());
                                 }
+                                Kind::ReadOnly => {}
                             }
                         }
                     }
@@ -121,9 +596,108 @@ impl Policies {
         }
         field_policies
     }
+
+    /// Returns the filter that rows of type `ty` must satisfy to be visible to `user_id`.
+    ///
+    /// `entity` is the expression addressing the filtered entity in the query.
+    pub(crate) fn make_read_filter(
+        &self,
+        user_id: &Option<String>,
+        ty: &ObjectType,
+        entity: &Expr,
+    ) -> Option<Expr> {
+        let read = self
+            .versions
+            .get(&ty.api_version)?
+            .entities
+            .get(ty.name())?
+            .read
+            .as_ref()?;
+        Some(bind_filter(read, entity, user_id))
+    }
+
+    /// Computes what `username`, with `user_roles`, at `current_path`, may write into entities
+    /// of type `ty` and the entities nested in it.
+    pub(crate) fn make_write_permissions(
+        &self,
+        username: &Option<String>,
+        user_roles: &[String],
+        current_path: &str,
+        ty: &ObjectType,
+    ) -> WritePermissions {
+        let mut permissions = WritePermissions::default();
+        if let Some(version) = self.versions.get(&ty.api_version) {
+            if let Some(entity) = version.entities.get(ty.name()) {
+                let allows = |rule: &Option<WriteRule>| {
+                    rule.as_ref()
+                        .map_or(true, |rule| rule.allows(username, current_path))
+                };
+                permissions.create = allows(&entity.create);
+                permissions.update = allows(&entity.update);
+                permissions.delete = allows(&entity.delete);
+            }
+            for fld in ty.user_fields() {
+                for lbl in &fld.labels {
+                    if let Some(p) = version.labels.get(lbl) {
+                        if matches!(p.kind, Kind::ReadOnly) && p.applies(current_path, user_roles) {
+                            permissions.readonly.insert(fld.name.clone());
+                        }
+                    }
+                }
+            }
+        }
+        for fld in ty.user_fields() {
+            if let Type::Object(nested) = &fld.type_ {
+                if !nested.is_auth() {
+                    let nested =
+                        self.make_write_permissions(username, user_roles, current_path, nested);
+                    permissions.nested.insert(fld.name.clone(), nested);
+                }
+            }
+        }
+        permissions
+    }
 }
 
 impl VersionPolicy {
+    /// Whether these policies depend on the roles of the user, which cost a query to look up.
+    pub(crate) fn uses_roles(&self) -> bool {
+        !self.role_authorization.is_empty()
+            || self.user_authorization.uses_roles()
+            || self.labels.values().any(|l| !l.except_roles.is_empty())
+    }
+
+    /// Whether changes to entities of type `ty` are recorded in the audit log.
+    pub(crate) fn is_audited(&self, ty: &ObjectType) -> bool {
+        self.entities.get(ty.name()).map_or(false, |e| e.audit)
+            || ty
+                .user_fields()
+                .any(|f| f.labels.iter().any(|l| self.audited_labels.contains(l)))
+    }
+
+    /// Checks whether this user may call the endpoint at this path with this method, by both the
+    /// user and the role authorization. If not allowed, returns the status and body to answer the
+    /// request with.
+    pub(crate) fn check_endpoint(
+        &self,
+        username: &Option<String>,
+        user_roles: &[String],
+        api_key_scopes: Option<&[String]>,
+        method: &str,
+        path: &Path,
+    ) -> Option<(u16, &str)> {
+        if let Some(denied) =
+            self.user_authorization
+                .check(username, user_roles, api_key_scopes, method, path)
+        {
+            return Some(denied);
+        }
+        if !self.role_authorization.is_allowed(user_roles, method, path) {
+            return Some((403, "Unauthorized user\n"));
+        }
+        None
+    }
+
     pub(crate) fn from_yaml<S: AsRef<str>>(config: S) -> Result<Self> {
         let mut policies = Self::default();
         let mut labels = vec![];
@@ -139,42 +713,158 @@ impl VersionPolicy {
                 debug!("Applying policy for label {:?}", name);
                 let pattern = label["except_uri"].as_str().unwrap_or("^$"); // ^$ never matches; each path has at least a '/' in it.
 
-                match label["transform"].as_str() {
-                    Some("anonymize") => {
-                        policies.labels.insert(
-                            name.to_owned(),
-                            Policy {
-                                kind: Kind::Transform(crate::policies::anonymize),
-                                except_uri: regex::Regex::new(pattern)?,
-                            },
-                        );
+                if yaml_bool(&label["audit"], "audit", &format!("label {}", name))? {
+                    policies.audited_labels.insert(name.to_owned());
+                }
+
+                let kind = match label["transform"].as_str() {
+                    Some("anonymize") => Kind::Transform(Transform::Anonymize),
+                    Some("hash") => {
+                        let secret = label["secret"].as_str().ok_or_else(|| {
+                            anyhow::anyhow!("hash transform for label {} needs a secret", name)
+                        })?;
+                        Kind::Transform(Transform::Hash {
+                            secret: secret.to_owned(),
+                        })
                     }
-                    Some("match_login") => {
-                        policies.labels.insert(
-                            name.to_owned(),
-                            Policy {
-                                kind: Kind::MatchLogin,
-                                except_uri: regex::Regex::new(pattern)?,
-                            },
-                        );
+                    Some("mask_email") => Kind::Transform(Transform::MaskEmail),
+                    Some("truncate") => {
+                        let length = label["length"]
+                            .as_i64()
+                            .and_then(|l| usize::try_from(l).ok())
+                            .ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "truncate transform for label {} needs a non-negative length",
+                                    name
+                                )
+                            })?;
+                        Kind::Transform(Transform::Truncate(length))
                     }
+                    Some("null") => Kind::Transform(Transform::Null),
+                    Some("match_login") => Kind::MatchLogin,
+                    Some("readonly") => Kind::ReadOnly,
                     Some(x) => {
                         anyhow::bail!("unknown transform: {} for label {}", x, name);
                     }
-                    None => {}
+                    None => continue,
                 };
+                let except_roles = yaml_strings(&label["except_roles"]).with_context(|| {
+                    format!("couldn't parse yaml: bad except_roles for label {}", name)
+                })?;
+                policies.labels.insert(
+                    name.to_owned(),
+                    Policy {
+                        kind,
+                        except_uri: regex::Regex::new(pattern)?,
+                        except_roles: except_roles.into_iter().collect(),
+                    },
+                );
+            }
+            let mut endpoints: Vec<_> = config["endpoints"]
+                .as_vec()
+                .get_or_insert(&[].into())
+                .iter()
+                .filter_map(|endpoint| Some((endpoint["path"].as_str()?, endpoint)))
+                .collect();
+            // Enclosing paths come first, so the items under them can inherit their rules.
+            endpoints.sort_by_key(|(path, _)| Path::new(path).components().count());
+            for (path, endpoint) in endpoints {
+                let rule = {
+                    let parent = policies
+                        .user_authorization
+                        .paths
+                        .longest_prefix(Path::new(path));
+                    let parent = parent.map(|(_, rule)| rule).cloned().unwrap_or_default();
+                    EndpointRule::from_yaml(endpoint, path, &parent)?
+                };
+                policies.user_authorization.add(path, rule)?;
+            }
+            for role in config["roles"].as_vec().get_or_insert(&[].into()).iter() {
+                let name = role["name"].as_str().ok_or_else(|| {
+                    anyhow::anyhow!("couldn't parse yaml: role without a name: {:?}", role)
+                })?;
+                let paths = yaml_strings(&role["paths"])
+                    .with_context(|| format!("couldn't parse yaml: bad paths for role {}", name))?;
+                let methods = match &role["methods"] {
+                    Yaml::BadValue => None,
+                    methods => Some(
+                        yaml_strings(methods)
+                            .with_context(|| {
+                                format!("couldn't parse yaml: bad methods for role {}", name)
+                            })?
+                            .into_iter()
+                            .map(|m| m.to_uppercase())
+                            .collect::<HashSet<_>>(),
+                    ),
+                };
+                for path in paths {
+                    policies
+                        .role_authorization
+                        .add(&path, name, methods.clone())?;
+                }
+            }
+            if !config["cors"].is_badvalue() {
+                policies.cors = Some(Cors::from_yaml(&config["cors"])?);
             }
-            for endpoint in config["endpoints"]
+            for limit in config["rate_limits"]
                 .as_vec()
                 .get_or_insert(&[].into())
                 .iter()
             {
-                if let Some(path) = endpoint["path"].as_str() {
-                    if let Some(users) = endpoint["users"].as_str() {
-                        policies
-                            .user_authorization
-                            .add(path, regex::Regex::new(users)?)?;
-                    }
+                let path = limit["path"].as_str().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "couldn't parse yaml: rate limit without a path: {:?}",
+                        limit
+                    )
+                })?;
+                let limit = RateLimit::from_yaml(limit, path)?;
+                if policies.rate_limits.insert(path.into(), limit).is_some() {
+                    anyhow::bail!("Repeated path in rate limits: {:?}", path);
+                }
+            }
+            for timeout in config["timeouts"].as_vec().get_or_insert(&[].into()).iter() {
+                let path = timeout["path"].as_str().ok_or_else(|| {
+                    anyhow::anyhow!("couldn't parse yaml: timeout without a path: {:?}", timeout)
+                })?;
+                let seconds = match &timeout["seconds"] {
+                    Yaml::Integer(s) => Some(*s as f64),
+                    s => s.as_f64(),
+                };
+                let seconds = seconds.filter(|s| *s > 0.0).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "couldn't parse yaml: bad seconds {:?} in timeout for path {}",
+                        timeout["seconds"],
+                        path
+                    )
+                })?;
+                let timeout = Duration::from_secs_f64(seconds);
+                if policies.timeouts.insert(path.into(), timeout).is_some() {
+                    anyhow::bail!("Repeated path in timeouts: {:?}", path);
+                }
+            }
+            for entity in config["entities"].as_vec().get_or_insert(&[].into()).iter() {
+                let name = entity["name"].as_str().ok_or_else(|| {
+                    anyhow::anyhow!("couldn't parse yaml: entity without a name: {:?}", entity)
+                })?;
+                let read = match entity["read"].as_str() {
+                    Some(read) => Some(parse_filter(read).with_context(|| {
+                        format!("couldn't parse read filter of entity {}", name)
+                    })?),
+                    None => None,
+                };
+                let entity_policy = EntityPolicy {
+                    read,
+                    create: WriteRule::from_yaml(&entity["create"], name, "create")?,
+                    update: WriteRule::from_yaml(&entity["update"], name, "update")?,
+                    delete: WriteRule::from_yaml(&entity["delete"], name, "delete")?,
+                    audit: yaml_bool(&entity["audit"], "audit", &format!("entity {}", name))?,
+                };
+                if policies
+                    .entities
+                    .insert(name.to_owned(), entity_policy)
+                    .is_some()
+                {
+                    anyhow::bail!("Repeated entity in policies: {:?}", name);
                 }
             }
         }
@@ -182,7 +872,316 @@ impl VersionPolicy {
     }
 }
 
-pub(crate) fn anonymize(_: Value) -> Value {
-    // TODO: use type-specific anonymization.
-    json!("xxxxx")
+/// Parses an optional boolean, `false` by default. `what` names the item it belongs to in errors.
+fn yaml_bool(yaml: &Yaml, key: &str, what: &str) -> Result<bool> {
+    match yaml {
+        Yaml::BadValue => Ok(false),
+        Yaml::Boolean(b) => Ok(*b),
+        x => anyhow::bail!("couldn't parse yaml: bad {} {:?} for {}", key, x, what),
+    }
+}
+
+/// Parses a string or a list of strings. A missing value is an empty list.
+pub(crate) fn yaml_strings(yaml: &Yaml) -> Result<Vec<String>> {
+    match yaml {
+        Yaml::BadValue => Ok(vec![]),
+        Yaml::String(s) => Ok(vec![s.clone()]),
+        Yaml::Array(items) => items
+            .iter()
+            .map(|item| {
+                item.as_str()
+                    .map(|s| s.to_owned())
+                    .ok_or_else(|| anyhow::anyhow!("expected a string, got {:?}", item))
+            })
+            .collect(),
+        _ => anyhow::bail!("expected a string or a list of strings, got {:?}", yaml),
+    }
+}
+
+/// Placeholder of the given type that reveals nothing about the original value.
+pub(crate) fn anonymize(type_: &Type) -> Value {
+    match type_ {
+        Type::String | Type::Id => json!("xxxxx"),
+        Type::Float => json!(0),
+        Type::Boolean => json!(false),
+        Type::Object(_) => json!({}),
+    }
+}
+
+fn hash(salt: &str, s: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(s.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn mask_email(local: &str, domain: &str) -> String {
+    let mut masked: String = local.chars().take(1).collect();
+    masked.push_str("***@");
+    masked.push_str(domain);
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(t: Transform, type_: Type, secrets: Option<&JsonObject>) -> FieldTransform {
+        FieldTransform::new(&t, &type_, secrets)
+    }
+
+    #[test]
+    fn anonymize_by_type() {
+        let anon = |type_, value| transform(Transform::Anonymize, type_, None).apply(value);
+        assert_eq!(anon(Type::String, json!("secret")), json!("xxxxx"));
+        assert_eq!(anon(Type::Float, json!(42.5)), json!(0));
+        assert_eq!(anon(Type::Boolean, json!(true)), json!(false));
+        assert_eq!(anon(Type::String, Value::Null), Value::Null);
+    }
+
+    #[test]
+    fn string_transforms() {
+        let mut secrets = JsonObject::default();
+        secrets.insert("salt".to_owned(), json!("pepper"));
+        let hash = Transform::Hash {
+            secret: "salt".to_owned(),
+        };
+        assert_eq!(
+            transform(hash.clone(), Type::String, Some(&secrets)).apply(json!("123-45-6789")),
+            json!("db0c794283a81a650b113847473f398c7fde98aa7f6ce9c6d3298559c4210d2c")
+        );
+        assert_eq!(
+            transform(hash.clone(), Type::String, None).apply(json!("123-45-6789")),
+            json!("xxxxx")
+        );
+        assert_eq!(
+            transform(hash, Type::Float, Some(&secrets)).apply(json!(7)),
+            json!(0)
+        );
+
+        let mask = transform(Transform::MaskEmail, Type::String, None);
+        assert_eq!(
+            mask.apply(json!("jill@example.com")),
+            json!("j***@example.com")
+        );
+        assert_eq!(mask.apply(json!("jill")), json!("xxxxx"));
+
+        let truncate = transform(Transform::Truncate(3), Type::String, None);
+        assert_eq!(truncate.apply(json!("héllo")), json!("hél"));
+        assert_eq!(truncate.apply(json!("hi")), json!("hi"));
+
+        let null = transform(Transform::Null, Type::Boolean, None);
+        assert_eq!(null.apply(json!(true)), Value::Null);
+    }
+
+    #[test]
+    fn parse_transforms() {
+        let policy = VersionPolicy::from_yaml(
+            "labels:\n  - name: a\n    transform: truncate\n    length: 5\n  - name: b\n    transform: hash\n    secret: salt\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            policy.labels["a"].kind,
+            Kind::Transform(Transform::Truncate(5))
+        ));
+        assert!(
+            matches!(&policy.labels["b"].kind, Kind::Transform(Transform::Hash { secret }) if secret == "salt")
+        );
+        assert!(
+            VersionPolicy::from_yaml("labels:\n  - name: a\n    transform: truncate\n").is_err()
+        );
+        assert!(VersionPolicy::from_yaml("labels:\n  - name: a\n    transform: hash\n").is_err());
+        assert!(VersionPolicy::from_yaml("labels:\n  - name: a\n    transform: bogus\n").is_err());
+    }
+
+    #[test]
+    fn roles() {
+        let policy = VersionPolicy::from_yaml(
+            r#"
+labels:
+  - name: pii
+    transform: anonymize
+    except_roles: [admin, auditor]
+roles:
+  - name: admin
+    paths: [/admin, /reports]
+  - name: analyst
+    paths: /reports
+    methods: [get]
+"#,
+        )
+        .unwrap();
+        let pii = &policy.labels["pii"];
+        assert!(pii.applies("/find", &[]));
+        assert!(pii.applies("/find", &["analyst".to_owned()]));
+        assert!(!pii.applies("/find", &["auditor".to_owned()]));
+
+        let roles = &policy.role_authorization;
+        let admin = ["admin".to_owned()];
+        let analyst = ["analyst".to_owned()];
+        assert!(roles.is_allowed(&[], "GET", Path::new("/find")));
+        assert!(!roles.is_allowed(&[], "GET", Path::new("/admin/users")));
+        assert!(roles.is_allowed(&admin, "DELETE", Path::new("/admin/users")));
+        assert!(!roles.is_allowed(&analyst, "GET", Path::new("/admin")));
+        assert!(roles.is_allowed(&analyst, "GET", Path::new("/reports")));
+        assert!(!roles.is_allowed(&analyst, "POST", Path::new("/reports")));
+        assert!(roles.is_allowed(&admin, "POST", Path::new("/reports")));
+
+        assert!(policy.uses_roles());
+        let uses_roles = |yaml: &str| VersionPolicy::from_yaml(yaml).unwrap().uses_roles();
+        assert!(uses_roles(
+            "labels:\n  - name: pii\n    transform: anonymize\n    except_roles: [admin]\n"
+        ));
+        assert!(uses_roles(
+            "endpoints:\n  - path: /x\n    methods:\n      POST:\n        roles: [admin]\n"
+        ));
+        assert!(!uses_roles(
+            "labels:\n  - name: pii\n    transform: anonymize\nendpoints:\n  - path: /x\n    users: ^a$\n"
+        ));
+
+        assert!(VersionPolicy::from_yaml(
+            "roles:\n  - name: a\n    paths: [/x]\n  - name: a\n    paths: [/x]\n"
+        )
+        .is_err());
+        assert!(VersionPolicy::from_yaml("roles:\n  - paths: [/x]\n").is_err());
+    }
+
+    #[test]
+    fn endpoint_methods() {
+        let policy = VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /comments
+    users: .*
+    status: 401
+    message: please log in
+    methods:
+      get:
+        anonymous: true
+      DELETE:
+        roles: [admin]
+        status: 404
+  - path: /comments/drafts
+    message: drafts are private
+    methods:
+      GET:
+        users: ^al$
+"#,
+        )
+        .unwrap();
+        let auth = &policy.user_authorization;
+        let check = |username: Option<&str>, roles: &[&str], method: &str, path: &str| {
+            let roles: Vec<String> = roles.iter().map(|r| r.to_string()).collect();
+            auth.check(
+                &username.map(|u| u.to_owned()),
+                &roles,
+                None,
+                method,
+                Path::new(path),
+            )
+            .map(|(status, message)| (status, message.to_owned()))
+        };
+        assert_eq!(check(None, &[], "GET", "/comments"), None);
+        assert_eq!(
+            check(None, &[], "POST", "/comments"),
+            Some((401, "please log in".to_owned()))
+        );
+        assert_eq!(check(Some("bo"), &[], "POST", "/comments"), None);
+        assert_eq!(
+            check(Some("bo"), &[], "DELETE", "/comments"),
+            Some((404, "please log in".to_owned()))
+        );
+        assert_eq!(check(Some("bo"), &["admin"], "DELETE", "/comments"), None);
+        assert_eq!(check(Some("al"), &[], "GET", "/comments/drafts"), None);
+        assert_eq!(
+            check(Some("bo"), &[], "GET", "/comments/drafts"),
+            Some((401, "drafts are private".to_owned()))
+        );
+        // /comments/drafts inherits what it leaves unset from /comments.
+        assert_eq!(
+            check(None, &[], "POST", "/comments/drafts"),
+            Some((401, "drafts are private".to_owned()))
+        );
+        assert_eq!(check(Some("bo"), &[], "POST", "/comments/drafts"), None);
+        assert_eq!(
+            check(Some("bo"), &[], "DELETE", "/comments/drafts"),
+            Some((404, "please log in".to_owned()))
+        );
+        assert_eq!(check(None, &[], "POST", "/other"), None);
+
+        assert!(VersionPolicy::from_yaml("endpoints:\n  - path: /x\n    status: 200\n").is_err());
+        assert!(
+            VersionPolicy::from_yaml("endpoints:\n  - path: /x\n    anonymous: maybe\n").is_err()
+        );
+        assert!(
+            VersionPolicy::from_yaml("endpoints:\n  - path: /x\n    methods: [GET]\n").is_err()
+        );
+    }
+
+    #[test]
+    fn endpoint_scopes() {
+        let policy = VersionPolicy::from_yaml(
+            "endpoints:\n  - path: /reports\n    users: .*\n    scopes: [read, admin]\n",
+        )
+        .unwrap();
+        let auth = &policy.user_authorization;
+        let user = Some("bot@example.com".to_owned());
+        let check = |scopes: Option<&[&str]>| {
+            let scopes: Option<Vec<String>> =
+                scopes.map(|s| s.iter().map(|s| s.to_string()).collect());
+            auth.check(&user, &[], scopes.as_deref(), "GET", Path::new("/reports"))
+                .is_none()
+        };
+        assert!(check(None));
+        assert!(check(Some(&["read"])));
+        assert!(check(Some(&["write", "admin"])));
+        assert!(!check(Some(&["write"])));
+        assert!(!check(Some(&[])));
+        assert!(auth
+            .check(
+                &None,
+                &[],
+                Some(&["read".to_owned()]),
+                "GET",
+                Path::new("/reports")
+            )
+            .is_some());
+    }
+
+    #[test]
+    fn audit() {
+        use crate::datastore::query::tests::{make_field, make_object};
+        let yaml = "entities:\n  - name: Account\n    audit: true\n\
+                    labels:\n  - name: financial\n    audit: true\n";
+        let policy = VersionPolicy::from_yaml(yaml).unwrap();
+        let mut amount = make_field("amount", Type::Float);
+        amount.labels.push("financial".to_owned());
+        assert!(policy.is_audited(&make_object("Account", vec![])));
+        assert!(policy.is_audited(&make_object("Payment", vec![amount])));
+        let text = make_field("text", Type::String);
+        assert!(!policy.is_audited(&make_object("Note", vec![text])));
+        let yaml = "entities:\n  - name: A\n    audit: maybe\n";
+        assert!(VersionPolicy::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn timeouts() {
+        let policy = VersionPolicy::from_yaml(
+            "timeouts:\n  - path: /reports\n    seconds: 30\n  - path: /quick\n    seconds: 0.5\n",
+        )
+        .unwrap();
+        let timeout = |path| {
+            policy
+                .timeouts
+                .longest_prefix(Path::new(path))
+                .map(|t| *t.1)
+        };
+        assert_eq!(timeout("/reports/daily"), Some(Duration::from_secs(30)));
+        assert_eq!(timeout("/quick"), Some(Duration::from_millis(500)));
+        assert_eq!(timeout("/other"), None);
+        assert!(VersionPolicy::from_yaml("timeouts:\n  - path: /x\n    seconds: 0\n").is_err());
+        assert!(VersionPolicy::from_yaml("timeouts:\n  - seconds: 3\n").is_err());
+        let repeated = "timeouts:\n  - path: /x\n    seconds: 1\n  - path: /x\n    seconds: 2\n";
+        assert!(VersionPolicy::from_yaml(repeated).is_err());
+    }
 }
@@ -0,0 +1,311 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Checks of policies against test cases, behind `chisel policy test`.
+//!
+//! Each case of a test file describes a request, and optionally a row of an entity that the
+//! request reads. The case is run through the same policy code that serves requests, but against
+//! the policies and types sent along with the cases, so nothing that is applied is affected.
+//! The outcome is compared with the case's expectations, if it has any.
+
+use crate::chisel::AddTypeRequest;
+use crate::policies::{yaml_strings, Policies};
+use crate::types::{AuthOrNot, Field, NewField, NewObject, ObjectType, Type, TypeSystem};
+use crate::JsonObject;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Version that the types and policies under test are put in.
+const TEST_VERSION: &str = "test";
+
+/// Outcome of a test case.
+pub(crate) struct CaseResult {
+    pub(crate) name: String,
+    /// Status and body of the response to a request that isn't allowed.
+    pub(crate) denied: Option<(u16, String)>,
+    /// Fields of the row that label policies transform, sorted.
+    pub(crate) transformed: Vec<String>,
+    /// The row as the request would read it.
+    pub(crate) row: Option<Value>,
+    /// Expectations of the case that weren't met.
+    pub(crate) failures: Vec<String>,
+}
+
+/// Runs the cases of `cases_yaml` against the policies of `policy_yaml`, for entities of `types`.
+pub(crate) fn run(
+    policy_yaml: &str,
+    types: &[AddTypeRequest],
+    cases_yaml: &str,
+) -> Result<Vec<CaseResult>> {
+    let mut policies = Policies::default();
+    policies.add_from_yaml(TEST_VERSION, policy_yaml)?;
+    let types = make_types(types)?;
+
+    let docs = YamlLoader::load_from_str(cases_yaml)?;
+    let mut results = vec![];
+    for doc in &docs {
+        let secrets = match &doc["secrets"] {
+            Yaml::BadValue => None,
+            secrets => Some(yaml_object(secrets, "secrets")?),
+        };
+        let cases = match &doc["cases"] {
+            Yaml::BadValue => continue,
+            Yaml::Array(cases) => cases,
+            x => anyhow::bail!("couldn't parse yaml: bad cases {:?}", x),
+        };
+        for (i, case) in cases.iter().enumerate() {
+            let name = case["name"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or_else(|| format!("case {}", i + 1));
+            let result = run_case(&policies, &types, secrets.as_ref(), case, name.clone())
+                .with_context(|| format!("in test case `{}`", name))?;
+            results.push(result);
+        }
+    }
+    Ok(results)
+}
+
+/// Builds the object types of `defs`, which may only refer to the types that precede them.
+fn make_types(defs: &[AddTypeRequest]) -> Result<HashMap<String, Arc<ObjectType>>> {
+    let ts = TypeSystem::default();
+    let mut types = HashMap::<String, Arc<ObjectType>>::new();
+    for def in defs {
+        let mut fields = vec![];
+        for field in &def.field_defs {
+            let ty = match ts.lookup_builtin_type(&field.field_type) {
+                Ok(ty) => ty,
+                Err(_) => match types.get(&field.field_type) {
+                    Some(ty) => Type::Object(ty.clone()),
+                    None => anyhow::bail!(
+                        "field type `{}` is neither a built-in nor a custom type",
+                        &field.field_type
+                    ),
+                },
+            };
+            fields.push(Field::new(
+                NewField::new(&field.name, ty, TEST_VERSION)?,
+                field.labels.clone(),
+                None,
+                false,
+                false,
+            ));
+        }
+        let ty = ObjectType::new(
+            NewObject::new(&def.name, TEST_VERSION),
+            fields,
+            AuthOrNot::IsNotAuth,
+        )?;
+        types.insert(def.name.clone(), Arc::new(ty));
+    }
+    Ok(types)
+}
+
+fn run_case(
+    policies: &Policies,
+    types: &HashMap<String, Arc<ObjectType>>,
+    secrets: Option<&JsonObject>,
+    case: &Yaml,
+    name: String,
+) -> Result<CaseResult> {
+    let string = |key: &str| -> Result<Option<String>> {
+        match &case[key] {
+            Yaml::BadValue => Ok(None),
+            Yaml::String(s) => Ok(Some(s.clone())),
+            x => anyhow::bail!("couldn't parse yaml: bad {} {:?}", key, x),
+        }
+    };
+    let path = string("path")?.context("couldn't parse yaml: missing path")?;
+    let method = string("method")?.unwrap_or_else(|| "GET".to_owned());
+    let user = string("user")?;
+    // Policies that match the logged-in user compare the user ID, not the username.
+    let user_id = string("user_id")?;
+    let roles = yaml_strings(&case["roles"]).context("couldn't parse yaml: bad roles")?;
+    let scopes = match &case["scopes"] {
+        Yaml::BadValue => None,
+        scopes => Some(yaml_strings(scopes).context("couldn't parse yaml: bad scopes")?),
+    };
+
+    let version = &policies.versions[TEST_VERSION];
+    let denied = version
+        .check_endpoint(
+            &user,
+            &roles,
+            scopes.as_deref(),
+            &method.to_uppercase(),
+            Path::new(&path),
+        )
+        .map(|(status, message)| (status, message.to_owned()));
+
+    let mut transformed = vec![];
+    let mut row = None;
+    if let Some(entity) = string("entity")? {
+        let ty = types
+            .get(&entity)
+            .with_context(|| format!("no entity named {}", entity))?;
+        let field_policies = policies.make_field_policies(&user_id, &roles, &path, secrets, ty);
+        transformed = field_policies.transforms.keys().cloned().collect();
+        transformed.sort();
+        if !matches!(case["row"], Yaml::BadValue) {
+            let mut values = yaml_object(&case["row"], "row")?;
+            for (field, transform) in &field_policies.transforms {
+                if let Some(value) = values.get_mut(field) {
+                    *value = transform.apply(value.take());
+                }
+            }
+            row = Some(Value::Object(values));
+        }
+    }
+
+    let mut failures = vec![];
+    let expect = &case["expect"];
+    match &expect["allowed"] {
+        Yaml::BadValue => {}
+        Yaml::Boolean(allowed) => {
+            if *allowed != denied.is_none() {
+                failures.push(format!(
+                    "expected {}",
+                    if *allowed { "allowed" } else { "denied" }
+                ));
+            }
+        }
+        x => anyhow::bail!("couldn't parse yaml: bad allowed {:?}", x),
+    }
+    if !matches!(expect["transformed"], Yaml::BadValue) {
+        let mut expected =
+            yaml_strings(&expect["transformed"]).context("couldn't parse yaml: bad transformed")?;
+        expected.sort();
+        if expected != transformed {
+            failures.push(format!("expected transformed [{}]", expected.join(", ")));
+        }
+    }
+    Ok(CaseResult {
+        name,
+        denied,
+        transformed,
+        row,
+        failures,
+    })
+}
+
+fn yaml_object(yaml: &Yaml, what: &str) -> Result<JsonObject> {
+    let hash = match yaml {
+        Yaml::Hash(hash) => hash,
+        x => anyhow::bail!("couldn't parse yaml: {} must be a map, got {:?}", what, x),
+    };
+    let mut object = JsonObject::new();
+    for (key, value) in hash {
+        let key = key
+            .as_str()
+            .with_context(|| format!("couldn't parse yaml: bad key {:?} in {}", key, what))?;
+        let value = match value {
+            Yaml::Real(_) => Value::from(value.as_f64().unwrap()),
+            Yaml::Integer(i) => Value::from(*i),
+            Yaml::String(s) => Value::from(s.clone()),
+            Yaml::Boolean(b) => Value::from(*b),
+            Yaml::Null => Value::Null,
+            x => anyhow::bail!(
+                "couldn't parse yaml: bad value {:?} of {} in {}",
+                x,
+                key,
+                what
+            ),
+        };
+        object.insert(key.to_owned(), value);
+    }
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chisel::FieldDefinition;
+    use serde_json::json;
+
+    fn types() -> Vec<AddTypeRequest> {
+        let field = |name: &str, labels: &[&str]| FieldDefinition {
+            name: name.to_owned(),
+            field_type: "string".to_owned(),
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            ..Default::default()
+        };
+        vec![AddTypeRequest {
+            name: "Person".to_owned(),
+            field_defs: vec![field("name", &[]), field("email", &["pii"])],
+        }]
+    }
+
+    const POLICY: &str = r#"
+labels:
+  - name: pii
+    transform: anonymize
+    except_uri: /admin
+endpoints:
+  - path: /people
+    users: ^.*@example.com$
+"#;
+
+    #[test]
+    fn cases() {
+        let results = run(
+            POLICY,
+            &types(),
+            r#"
+cases:
+  - name: member reads people
+    path: /people
+    user: al@example.com
+    entity: Person
+    row: {name: Al, email: al@example.com}
+    expect:
+      allowed: true
+      transformed: [email]
+  - name: anonymous reads people
+    path: /people
+    expect:
+      allowed: true
+  - path: /admin/people
+    entity: Person
+    expect:
+      transformed: [email]
+"#,
+        )
+        .unwrap();
+        assert_eq!(results.len(), 3);
+
+        assert!(results[0].failures.is_empty());
+        assert_eq!(results[0].transformed, vec!["email".to_owned()]);
+        assert_eq!(
+            results[0].row,
+            Some(json!({"name": "Al", "email": "xxxxx"}))
+        );
+
+        assert_eq!(results[1].name, "anonymous reads people");
+        assert_eq!(
+            results[1].denied,
+            Some((403, "Unauthorized user\n".to_owned()))
+        );
+        assert_eq!(results[1].failures, vec!["expected allowed".to_owned()]);
+
+        assert_eq!(results[2].name, "case 3");
+        assert!(results[2].transformed.is_empty());
+        assert_eq!(
+            results[2].failures,
+            vec!["expected transformed [email]".to_owned()]
+        );
+    }
+
+    #[test]
+    fn bad_cases() {
+        let check = |cases: &str| run(POLICY, &types(), cases).is_err();
+        assert!(check("cases:\n  - method: GET\n"));
+        assert!(check("cases:\n  - path: /people\n    entity: Nobody\n"));
+        assert!(check(
+            "cases:\n  - path: /people\n    expect:\n      allowed: maybe\n"
+        ));
+        assert!(!check("cases:\n  - path: /people\n"));
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+use crate::types::ObjectType;
+use crate::JsonObject;
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Path under which the user-provided transform module is loaded into the runtime.
+pub(crate) const TRANSFORM_PATH: &str = "/__chiselstrike/populate_transform";
+
+/// Describes how rows of a single type are carried over from the source version.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TypeMapping {
+    /// Name of the source type, if it differs from the target type's name.
+    pub(crate) from: Option<String>,
+    /// Maps a target field name to the source field its value is read from.
+    pub(crate) fields: HashMap<String, String>,
+    /// Values for target fields that have no counterpart in the source type.
+    pub(crate) defaults: JsonObject,
+}
+
+impl TypeMapping {
+    /// Name of the source field that feeds the target field `name`.
+    pub(crate) fn source_field<'a>(&'a self, name: &'a str) -> &'a str {
+        self.fields.get(name).map(|s| s.as_str()).unwrap_or(name)
+    }
+
+    /// Builds a row of type `target` out of a `row` read from the source type.
+    ///
+    /// Target fields that are neither in the source row nor in the defaults are left out, so they get
+    /// the field's own default value (or stay empty, if optional) on insertion.
+    pub(crate) fn map_row(&self, row: &JsonObject, target: &ObjectType) -> JsonObject {
+        let mut ret = JsonObject::default();
+        for field in target.all_fields() {
+            let value = row
+                .get(self.source_field(&field.name))
+                .or_else(|| self.defaults.get(&field.name));
+            if let Some(value) = value {
+                ret.insert(field.name.clone(), value.clone());
+            }
+        }
+        ret
+    }
+}
+
+/// User-provided description of how to populate a version from another, keyed by target type name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PopulateMapping {
+    types: HashMap<String, TypeMapping>,
+}
+
+impl PopulateMapping {
+    /// Parses a mapping like:
+    ///
+    /// ```yaml
+    /// types:
+    ///   - name: Person
+    ///     from: Human
+    ///     fields:
+    ///       fullName: name
+    ///     defaults:
+    ///       age: 0
+    /// ```
+    pub(crate) fn from_yaml<S: AsRef<str>>(config: S) -> Result<Self> {
+        let mut mapping = Self::default();
+
+        let docs = YamlLoader::load_from_str(config.as_ref())?;
+        for config in docs.iter() {
+            for ty in config["types"].as_vec().get_or_insert(&[].into()).iter() {
+                let name = ty["name"].as_str().ok_or_else(|| {
+                    anyhow::anyhow!("couldn't parse yaml: type mapping without a name: {:?}", ty)
+                })?;
+                let mut type_mapping = TypeMapping {
+                    from: ty["from"].as_str().map(|x| x.to_owned()),
+                    ..Default::default()
+                };
+                if let Some(fields) = as_hash(&ty["fields"], name, "fields")? {
+                    for (to, from) in fields.iter() {
+                        match (to.as_str(), from.as_str()) {
+                            (Some(to), Some(from)) => {
+                                type_mapping.fields.insert(to.to_owned(), from.to_owned());
+                            }
+                            _ => anyhow::bail!(
+                                "couldn't parse yaml: field mapping of type {} must map names to names",
+                                name
+                            ),
+                        }
+                    }
+                }
+                if let Some(defaults) = as_hash(&ty["defaults"], name, "defaults")? {
+                    for (field, value) in defaults.iter() {
+                        let field = field.as_str().ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "couldn't parse yaml: default of type {} without a field name",
+                                name
+                            )
+                        })?;
+                        type_mapping
+                            .defaults
+                            .insert(field.to_owned(), yaml_to_json(value)?);
+                    }
+                }
+                if mapping
+                    .types
+                    .insert(name.to_owned(), type_mapping)
+                    .is_some()
+                {
+                    anyhow::bail!("Repeated type in populate mapping: {:?}", name);
+                }
+            }
+        }
+        Ok(mapping)
+    }
+
+    /// Returns the mapping for the target type `name`, which is the identity if none was given.
+    pub(crate) fn get(&self, name: &str) -> TypeMapping {
+        self.types.get(name).cloned().unwrap_or_default()
+    }
+}
+
+/// Returns the map under a type mapping's `key`, or None if the key is absent.
+fn as_hash<'a>(y: &'a Yaml, name: &str, key: &str) -> Result<Option<&'a Hash>> {
+    match y {
+        Yaml::BadValue => Ok(None),
+        Yaml::Hash(h) => Ok(Some(h)),
+        _ => anyhow::bail!(
+            "couldn't parse yaml: {} of type {} must be a map",
+            key,
+            name
+        ),
+    }
+}
+
+fn yaml_to_json(y: &Yaml) -> Result<Value> {
+    Ok(match y {
+        Yaml::Real(_) => Value::from(y.as_f64().unwrap()),
+        Yaml::Integer(i) => Value::from(*i),
+        Yaml::String(s) => Value::from(s.clone()),
+        Yaml::Boolean(b) => Value::from(*b),
+        Yaml::Null => Value::Null,
+        x => anyhow::bail!("unsupported default value in populate mapping: {:?}", x),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PopulateMapping;
+    use serde_json::json;
+
+    #[test]
+    fn parse() {
+        let mapping = PopulateMapping::from_yaml(
+            r#"
+types:
+  - name: Person
+    from: Human
+    fields:
+      fullName: name
+    defaults:
+      age: 3
+      human: true
+      nickname: Bob
+"#,
+        )
+        .unwrap();
+        let person = mapping.get("Person");
+        assert_eq!(person.from.as_deref(), Some("Human"));
+        assert_eq!(person.source_field("fullName"), "name");
+        assert_eq!(person.source_field("age"), "age");
+        assert_eq!(person.defaults["age"], json!(3));
+        assert_eq!(person.defaults["human"], json!(true));
+        assert_eq!(person.defaults["nickname"], json!("Bob"));
+
+        let other = mapping.get("Other");
+        assert!(other.from.is_none());
+        assert_eq!(other.source_field("name"), "name");
+    }
+
+    #[test]
+    fn repeated_type() {
+        let err = PopulateMapping::from_yaml(
+            r#"
+types:
+  - name: Person
+  - name: Person
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Repeated type in populate mapping: \"Person\""
+        );
+    }
+
+    #[test]
+    fn bad_fields() {
+        assert!(PopulateMapping::from_yaml(
+            r#"
+types:
+  - name: Person
+    fields:
+      - name
+"#,
+        )
+        .is_err());
+        assert!(PopulateMapping::from_yaml(
+            r#"
+types:
+  - fields:
+      a: b
+"#,
+        )
+        .is_err());
+    }
+}
@@ -2,21 +2,108 @@
 
 use std::collections::BTreeMap;
 use std::ops::Bound;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Components, Path, PathBuf};
 
 #[derive(Clone, Debug)]
 pub(crate) struct PrefixMap<T> {
     map: BTreeMap<PathBuf, T>,
+    /// Whether some key is a template, so that lookups can't just compare prefixes.
+    has_templates: bool,
 }
 
 impl<T> Default for PrefixMap<T> {
     fn default() -> Self {
         Self {
             map: Default::default(),
+            has_templates: false,
         }
     }
 }
 
+/// What a key matched of a path: the values of its parameters, and the rest of the path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PathMatch {
+    pub(crate) params: Vec<(String, String)>,
+    pub(crate) rest: String,
+}
+
+/// A component of a key. `[name]` matches any one component of a path, and `[...name]` all the
+/// rest of it.
+enum Segment<'a> {
+    Literal(Component<'a>),
+    Param(&'a str),
+    CatchAll(&'a str),
+}
+
+impl<'a> Segment<'a> {
+    fn of(component: Component<'a>) -> Self {
+        let name = match component {
+            Component::Normal(s) => s
+                .to_str()
+                .and_then(|s| s.strip_prefix('['))
+                .and_then(|s| s.strip_suffix(']')),
+            _ => None,
+        };
+        match name {
+            Some(name) => match name.strip_prefix("...") {
+                Some(name) => Segment::CatchAll(name),
+                None => Segment::Param(name),
+            },
+            None => Segment::Literal(component),
+        }
+    }
+}
+
+/// Whether `key` has parameters.
+pub(crate) fn is_template(key: &Path) -> bool {
+    key.components()
+        .any(|c| !matches!(Segment::of(c), Segment::Literal(_)))
+}
+
+fn join(components: Components) -> String {
+    components
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Matches `template` against the start of `path`. Matches are ranked by how specific their
+/// components are, literals before parameters before catch-alls, and longer ones first.
+fn match_template(template: &Path, path: &Path) -> Option<(Vec<u8>, PathMatch)> {
+    let mut components = path.components();
+    let mut rank = vec![];
+    let mut params = vec![];
+    for t in template.components() {
+        match Segment::of(t) {
+            Segment::Literal(t) => {
+                if components.next()? != t {
+                    return None;
+                }
+                rank.push(2);
+            }
+            Segment::Param(name) => match components.next()? {
+                Component::Normal(value) => {
+                    params.push((name.to_string(), value.to_string_lossy().into_owned()));
+                    rank.push(1);
+                }
+                _ => return None,
+            },
+            Segment::CatchAll(name) => {
+                let value = join(components);
+                if value.is_empty() {
+                    return None;
+                }
+                params.push((name.to_string(), value));
+                rank.push(0);
+                let rest = String::new();
+                return Some((rank, PathMatch { params, rest }));
+            }
+        }
+    }
+    let rest = join(components);
+    Some((rank, PathMatch { params, rest }))
+}
+
 impl<T> PrefixMap<T> {
     /// Returns the longest map entry whose key is a prefix of path, if one exists.
     pub(crate) fn longest_prefix(&self, path: &Path) -> Option<(&Path, &T)> {
@@ -30,22 +117,50 @@ impl<T> PrefixMap<T> {
         None
     }
 
+    /// Returns the map entry whose key best matches the start of path, either as a prefix or as
+    /// a template with parameters, and what it matched.
+    pub(crate) fn find(&self, path: &Path) -> Option<(&Path, &T, PathMatch)> {
+        if !self.has_templates {
+            return self.longest_prefix(path).map(|(k, v)| {
+                let rest = join(path.strip_prefix(k).unwrap().components());
+                let params = vec![];
+                (k, v, PathMatch { params, rest })
+            });
+        }
+        self.map
+            .iter()
+            .filter_map(|(k, v)| match_template(k, path).map(|(rank, m)| (rank, k, v, m)))
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, k, v, m)| (k.as_path(), v, m))
+    }
+
     pub(crate) fn iter(&self) -> impl Iterator<Item = (&Path, &T)> {
         self.map.iter().map(|(k, v)| (k.as_path(), v))
     }
 
     pub(crate) fn insert(&mut self, k: PathBuf, v: T) -> Option<T> {
+        self.has_templates |= is_template(&k);
         self.map.insert(k, v)
     }
 
+    /// Returns the value for exactly this key, inserting the default value if there is none.
+    pub(crate) fn get_or_insert_default(&mut self, k: PathBuf) -> &mut T
+    where
+        T: Default,
+    {
+        self.has_templates |= is_template(&k);
+        self.map.entry(k).or_default()
+    }
+
     pub(crate) fn remove_prefix(&mut self, prefix: &Path) {
-        self.map.retain(|k, _| !k.starts_with(prefix))
+        self.map.retain(|k, _| !k.starts_with(prefix));
+        self.has_templates = self.map.keys().any(|k| is_template(k));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::PrefixMap;
+    use super::{PathMatch, PrefixMap};
     use std::collections::BTreeMap;
     use std::path::{Path, PathBuf};
 
@@ -55,7 +170,10 @@ mod tests {
 
     fn fixture() -> PrefixMap<String> {
         let map = BTreeMap::from([entry("/a/b/c"), entry("/a/b"), entry("/a/bb/c")]);
-        PrefixMap { map }
+        PrefixMap {
+            map,
+            has_templates: false,
+        }
     }
 
     fn lp<'t>(path: &str, tree: &'t PrefixMap<String>) -> Option<(&'t Path, &'t String)> {
@@ -95,4 +213,94 @@ mod tests {
         assert_longest_prefix!(tt, "/a/bb/c/d", "/a/bb/c");
         assert_longest_prefix!(tt, "/a/b/d", "/a/b");
     }
+
+    fn templates() -> PrefixMap<String> {
+        let mut tt = PrefixMap::default();
+        for path in [
+            "/dev/posts",
+            "/dev/posts/[id]",
+            "/dev/posts/new",
+            "/dev/posts/[id]/comments/[n]",
+            "/dev/files/[...path]",
+            "/dev/files/readme",
+        ] {
+            let (k, v) = entry(path);
+            tt.insert(k, v);
+        }
+        tt
+    }
+
+    /// The value of the entry that a path matched, with the parameters and the rest.
+    type Found = (String, Vec<(String, String)>, String);
+
+    fn find(path: &str, tree: &PrefixMap<String>) -> Option<Found> {
+        tree.find(path.as_ref()).map(|(_, v, m)| {
+            let params = m.params.into_iter().collect();
+            (v.clone(), params, m.rest)
+        })
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn find_without_templates() {
+        let tt = fixture();
+        let m = tt.find("/a/b/c/d//e/".as_ref()).unwrap();
+        assert_eq!(m.0, Path::new("/a/b/c"));
+        let rest = "d/e".to_string();
+        let params = vec![];
+        assert_eq!(m.2, PathMatch { params, rest });
+        assert!(tt.find("/a/c".as_ref()).is_none());
+    }
+
+    #[test]
+    fn find_templates() {
+        let tt = templates();
+        let found = |v: &str, p: &[(&str, &str)], rest: &str| {
+            Some((v.to_string(), params(p), rest.to_string()))
+        };
+        assert_eq!(find("/dev/posts", &tt), found("/dev/posts", &[], ""));
+        assert_eq!(
+            find("/dev/posts/42", &tt),
+            found("/dev/posts/[id]", &[("id", "42")], "")
+        );
+        assert_eq!(
+            find("/dev/posts/new", &tt),
+            found("/dev/posts/new", &[], "")
+        );
+        assert_eq!(
+            find("/dev/posts/42/comments/7/x", &tt),
+            found(
+                "/dev/posts/[id]/comments/[n]",
+                &[("id", "42"), ("n", "7")],
+                "x"
+            )
+        );
+        assert_eq!(
+            find("/dev/posts/42/likes", &tt),
+            found("/dev/posts/[id]", &[("id", "42")], "likes")
+        );
+        assert_eq!(
+            find("/dev/files/a/b", &tt),
+            found("/dev/files/[...path]", &[("path", "a/b")], "")
+        );
+        assert_eq!(
+            find("/dev/files/readme/more", &tt),
+            found("/dev/files/readme", &[], "more")
+        );
+        assert_eq!(find("/dev/files", &tt), None);
+    }
+
+    #[test]
+    fn remove_templates() {
+        let mut tt = templates();
+        tt.remove_prefix("/dev".as_ref());
+        assert!(!tt.has_templates);
+        assert_eq!(find("/dev/posts/42", &tt), None);
+    }
 }
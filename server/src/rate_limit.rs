@@ -0,0 +1,266 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Token-bucket rate limiting of API requests.
+//!
+//! Policies define the limits per path prefix. Each client of a limited path
+//! has a bucket of tokens that refills at a constant rate; a request takes a
+//! token, and is answered with 429 when the bucket is empty. The buckets live
+//! in a [`RateLimiter`] shared by all executor threads, so a client can't
+//! escape its limit by having its requests spread among them.
+//!
+//! Limits keyed by remote address are checked before the request is routed. Limits keyed by
+//! user or API key are checked by the executor, once the credentials of the request are
+//! validated, so that clients can't get a fresh bucket by making up an identity.
+
+use crate::prefix_map::PrefixMap;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use yaml_rust::Yaml;
+
+/// Header carrying the API key of machine clients.
+pub(crate) const API_KEY_HEADER: &str = "ChiselApiKey";
+
+/// How often the buckets that are full again are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What identifies the clients that get separate buckets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ClientKey {
+    /// The logged-in user. Anonymous requests are keyed by remote address.
+    User,
+    /// The API key of the request. Requests without one are keyed by remote address.
+    ApiKey,
+    /// The remote address of the request.
+    Ip,
+}
+
+/// Rate limit of the endpoints under a path.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimit {
+    key: ClientKey,
+    /// Maximum number of tokens in a bucket, which is the largest burst of requests allowed.
+    burst: f64,
+    /// Tokens added to a bucket per second.
+    rate: f64,
+}
+
+impl RateLimit {
+    /// Parses an item of the `rate_limits` section: `requests` per `period` seconds (1 by
+    /// default), bursts of up to `burst` requests (`requests` by default), for each client
+    /// identified by `key` (`user`, `api_key` or `ip`, the default).
+    pub(crate) fn from_yaml(limit: &Yaml, path: &str) -> Result<Self> {
+        let positive = |name: &str, default: Option<i64>| match (&limit[name], default) {
+            (Yaml::BadValue, Some(d)) => Ok(d),
+            (Yaml::Integer(n), _) if *n > 0 => Ok(*n),
+            (x, _) => Err(anyhow::anyhow!(
+                "couldn't parse yaml: bad {} {:?} in rate limit for path {}",
+                name,
+                x,
+                path
+            )),
+        };
+        let requests = positive("requests", None)?;
+        let period = positive("period", Some(1))?;
+        let burst = positive("burst", Some(requests))?;
+        let key = match limit["key"].as_str() {
+            None | Some("ip") => ClientKey::Ip,
+            Some("user") => ClientKey::User,
+            Some("api_key") => ClientKey::ApiKey,
+            Some(x) => anyhow::bail!(
+                "couldn't parse yaml: unknown key {} in rate limit for path {}",
+                x,
+                path
+            ),
+        };
+        Ok(Self {
+            key,
+            burst: burst as f64,
+            rate: requests as f64 / period as f64,
+        })
+    }
+
+    pub(crate) fn key(&self) -> ClientKey {
+        self.key
+    }
+}
+
+/// Rate limits keyed by path prefix.
+pub(crate) type RateLimits = PrefixMap<RateLimit>;
+
+/// Identifies the client of a request, as described by `key`. `user_id` and `api_key` must have
+/// been validated.
+pub(crate) fn client_id(
+    key: ClientKey,
+    user_id: Option<&str>,
+    api_key: Option<&str>,
+    remote_addr: &SocketAddr,
+) -> String {
+    match (key, user_id, api_key) {
+        (ClientKey::User, Some(user_id), _) => format!("user:{}", user_id),
+        (ClientKey::ApiKey, _, Some(api_key)) => format!("key:{}", api_key),
+        _ => format!("ip:{}", remote_addr.ip()),
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again, if no more tokens are taken.
+    full_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_name: HashMap<String, Bucket>,
+    /// When to drop the buckets that are full again.
+    next_sweep: Option<Instant>,
+}
+
+/// Token buckets of all clients, shared by the executor threads.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket named `bucket`, which follows `limit`. If the bucket is
+    /// empty, returns how long it takes to have a token again.
+    pub(crate) fn acquire(
+        &self,
+        bucket: String,
+        limit: &RateLimit,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        // A full bucket is the same as no bucket, so dropping them doesn't change any limit.
+        match buckets.next_sweep {
+            Some(sweep) if sweep > now => {}
+            _ => {
+                buckets.by_name.retain(|_, b| b.full_at > now);
+                buckets.next_sweep = Some(now + SWEEP_INTERVAL);
+            }
+        }
+        let b = buckets.by_name.entry(bucket).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+            full_at: now,
+        });
+        let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+        b.tokens = (b.tokens + elapsed * limit.rate).min(limit.burst);
+        b.updated = now;
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            b.full_at = now + Duration::from_secs_f64((limit.burst - b.tokens) / limit.rate);
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - b.tokens) / limit.rate))
+        }
+    }
+}
+
+/// A limit keyed by user or API key, left for the executor to check once it has authenticated
+/// the request. The router attaches it to the request as an extension.
+#[derive(Clone, Debug)]
+pub(crate) struct DeferredRateLimit {
+    pub(crate) limiter: Arc<RateLimiter>,
+    pub(crate) limit: RateLimit,
+    /// Name of the bucket, without the client.
+    pub(crate) prefix: String,
+    pub(crate) remote_addr: SocketAddr,
+}
+
+impl DeferredRateLimit {
+    /// Takes a token from the bucket of the client identified by the validated `user_id` or
+    /// `api_key`.
+    pub(crate) fn acquire(
+        &self,
+        user_id: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Result<(), Duration> {
+        let client = client_id(self.limit.key, user_id, api_key, &self.remote_addr);
+        let bucket = format!("{}:{}", self.prefix, client);
+        self.limiter.acquire(bucket, &self.limit, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn limit(yaml: &str) -> Result<RateLimit> {
+        let docs = YamlLoader::load_from_str(yaml).unwrap();
+        RateLimit::from_yaml(&docs[0], "/x")
+    }
+
+    #[test]
+    fn parse() {
+        let l = limit("requests: 10\nperiod: 60\nburst: 3\nkey: user").unwrap();
+        assert_eq!(l.key(), ClientKey::User);
+        assert_eq!(l.burst, 3.0);
+        assert!((l.rate - 1.0 / 6.0).abs() < 1e-9);
+        let l = limit("requests: 5").unwrap();
+        assert_eq!(l.key(), ClientKey::Ip);
+        assert_eq!(l.burst, 5.0);
+        assert_eq!(l.rate, 5.0);
+        assert!(limit("period: 5").is_err());
+        assert!(limit("requests: 0").is_err());
+        assert!(limit("requests: 1\nkey: cookie").is_err());
+    }
+
+    #[test]
+    fn buckets() {
+        let l = limit("requests: 1\nperiod: 2\nburst: 2").unwrap();
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        let acquire = |name: &str, secs: f64| {
+            limiter.acquire(name.into(), &l, start + Duration::from_secs_f64(secs))
+        };
+        assert!(acquire("a", 0.0).is_ok());
+        assert!(acquire("a", 0.0).is_ok());
+        assert_eq!(acquire("a", 0.0), Err(Duration::from_secs(2)));
+        assert!(acquire("b", 0.0).is_ok());
+        assert_eq!(acquire("a", 1.0), Err(Duration::from_secs(1)));
+        assert!(acquire("a", 2.0).is_ok());
+        assert!(acquire("a", 2.0).is_err());
+    }
+
+    #[test]
+    fn sweep() {
+        let l = limit("requests: 1\nburst: 1").unwrap();
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let count = || limiter.buckets.lock().unwrap().by_name.len();
+        assert!(limiter.acquire("a".into(), &l, at(0)).is_ok());
+        assert!(limiter.acquire("b".into(), &l, at(30)).is_ok());
+        assert_eq!(count(), 2);
+        // "a" is full again, but the buckets aren't swept before the interval is over.
+        assert!(limiter.acquire("c".into(), &l, at(59)).is_ok());
+        assert_eq!(count(), 3);
+        assert!(limiter.acquire("c".into(), &l, at(60)).is_ok());
+        assert_eq!(count(), 1);
+    }
+
+    #[test]
+    fn clients() {
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        assert_eq!(client_id(ClientKey::User, Some("u"), None, &addr), "user:u");
+        assert_eq!(
+            client_id(ClientKey::User, None, Some("k"), &addr),
+            "ip:10.0.0.1"
+        );
+        assert_eq!(
+            client_id(ClientKey::ApiKey, Some("u"), Some("k"), &addr),
+            "key:k"
+        );
+        assert_eq!(
+            client_id(ClientKey::Ip, Some("u"), Some("k"), &addr),
+            "ip:10.0.0.1"
+        );
+    }
+}
@@ -8,10 +8,13 @@ use crate::deno::mutate_policies;
 use crate::deno::remove_type_version;
 use crate::deno::set_type_system;
 use crate::policies::{Policies, VersionPolicy};
-use crate::prefix_map::PrefixMap;
+use crate::populate::{PopulateMapping, TRANSFORM_PATH};
+use crate::prefix_map::{is_template, PrefixMap};
 use crate::runtime;
 use crate::server::CommandTrait;
 use crate::server::CoordinatorChannel;
+use crate::static_files::{self, StaticFile};
+use crate::tls::Tls;
 use crate::types::AuthOrNot::IsNotAuth;
 use crate::types::{Field, NewField, NewObject, ObjectType, Type, TypeSystem, TypeSystemError};
 use anyhow::{Context, Result};
@@ -19,13 +22,14 @@ use async_lock::Mutex;
 use chisel::chisel_rpc_server::{ChiselRpc, ChiselRpcServer};
 use chisel::{
     ChiselApplyRequest, ChiselApplyResponse, ChiselDeleteRequest, ChiselDeleteResponse,
-    DescribeRequest, DescribeResponse, PopulateRequest, PopulateResponse, RestartRequest,
-    RestartResponse, StatusRequest, StatusResponse,
+    DescribeRequest, DescribeResponse, PolicyTestCase, PolicyTestRequest, PolicyTestResponse,
+    PopulateRequest, PopulateResponse, RestartRequest, RestartResponse, StatusRequest,
+    StatusResponse,
 };
 use futures::FutureExt;
 use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use tonic::{transport::Server, Request, Response, Status};
 
@@ -55,6 +59,7 @@ pub(crate) struct GlobalRpcState {
     meta: MetaService,
     query_engine: Arc<QueryEngine>,
     routes: PrefixMap<String>, // For globally keeping track of routes
+    static_files: PrefixMap<Arc<StaticFile>>,
     commands: Vec<CoordinatorChannel>,
     policies: Policies,
     versions: BTreeSet<String>,
@@ -68,13 +73,18 @@ impl GlobalRpcState {
     ) -> Result<Self> {
         let type_system = meta.load_type_system().await?;
         let routes = meta.load_endpoints().await?;
+        let static_files = meta.load_static_files().await?;
         let policies = meta.load_policies().await?;
 
         let mut versions = BTreeSet::new();
         for v in type_system.versions.keys() {
             versions.insert(v.to_owned());
         }
-        for (p, _) in routes.iter() {
+        let paths = routes
+            .iter()
+            .map(|(p, _)| p)
+            .chain(static_files.iter().map(|(p, _)| p));
+        for p in paths {
             let rp = RequestPath::try_from(p.to_str().unwrap()).unwrap();
             versions.insert(rp.api_version().to_owned());
         }
@@ -85,6 +95,7 @@ impl GlobalRpcState {
             query_engine: Arc::new(query_engine),
             commands,
             routes,
+            static_files,
             policies,
             versions,
         })
@@ -155,6 +166,8 @@ impl RpcService {
 
         let prefix: PathBuf = format!("/{}/", api_version).into();
         state.routes.remove_prefix(&prefix);
+        state.static_files.remove_prefix(&prefix);
+        state.meta.persist_static_files(&state.static_files).await?;
         state.type_system.versions.remove(&api_version);
         state.policies.versions.remove(&api_version);
 
@@ -163,13 +176,15 @@ impl RpcService {
         let cmd = send_command!({
             remove_type_version(&version).await;
 
+            let pol_version = version.clone();
             mutate_policies(move |policies| {
-                policies.versions.remove(&version);
+                policies.versions.remove(&pol_version);
             })
             .await;
 
             let runtime = runtime::get();
             runtime.api.remove_routes(&prefix);
+            runtime.api.remove_policy(&version);
             Ok(())
         });
         state.send_command(cmd).await?;
@@ -188,12 +203,34 @@ impl RpcService {
         let to = request.to_version.clone();
         let from = request.from_version.clone();
 
+        let mapping =
+            PopulateMapping::from_yaml(&request.mapping).context("parsing the populate mapping")?;
+
         let state = self.state.lock().await;
 
-        state
-            .type_system
-            .populate_types(state.query_engine.clone(), &to, &from)
-            .await?;
+        if request.transform.is_empty() {
+            state
+                .type_system
+                .populate_types(state.query_engine.clone(), &to, &from, &mapping, None)
+                .await?;
+        } else {
+            // The transform is JavaScript, so the population has to run in one of the
+            // executors. Any of them will do.
+            let type_system = state.type_system.clone();
+            let query_engine = state.query_engine.clone();
+            let code = request.transform;
+            let cmd = send_command!({
+                deno::compile_transform(TRANSFORM_PATH, code).await?;
+                type_system
+                    .populate_types(query_engine, &to, &from, &mapping, Some(TRANSFORM_PATH))
+                    .await
+            });
+            let executor = state
+                .commands
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("no executor available to run the transform"))?;
+            executor.send(cmd).await?;
+        }
 
         let response = chisel::PopulateResponse {
             msg: "OK".to_string(),
@@ -222,6 +259,29 @@ impl RpcService {
             endpoint_routes.push((path, endpoint.code));
         }
 
+        let mut static_routes = vec![];
+        for file in apply_request.static_files {
+            let relative = Path::new(&file.path);
+            anyhow::ensure!(
+                relative
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+                    && !is_template(relative),
+                "bad static file path {}",
+                file.path
+            );
+            let path = format!("/{}/{}", api_version, file.path);
+            for route in static_files::route_paths(Path::new(&path)) {
+                anyhow::ensure!(
+                    !endpoint_routes.iter().any(|(p, _)| Path::new(p) == route),
+                    "{} is both an endpoint and a static file",
+                    route.display()
+                );
+            }
+            let file = StaticFile::new(Path::new(&path), file.content);
+            static_routes.push((path, Arc::new(file)));
+        }
+
         // Do this before any permanent changes to any of the databases. Otherwise
         // we end up with bad code commited to the meta database and will fail to load
         // chiseld next time, as it tries to replenish the endpoints
@@ -416,10 +476,21 @@ or
 
         state.meta.persist_endpoints(&state.routes).await?;
 
+        state.static_files.remove_prefix(&prefix);
+        for (path, file) in &static_routes {
+            state.static_files.insert(path.into(), file.clone());
+        }
+
+        state.meta.persist_static_files(&state.static_files).await?;
+
         let endpoints = endpoint_routes.clone();
+        let files = static_routes.clone();
         let types_global = state.type_system.clone();
 
-        if !endpoints.is_empty() || types_global.get_version(&api_version).is_ok() {
+        if !endpoints.is_empty()
+            || !files.is_empty()
+            || types_global.get_version(&api_version).is_ok()
+        {
             state.versions.insert(api_version.clone());
         }
 
@@ -427,6 +498,7 @@ or
             {
                 set_type_system(types_global.clone()).await;
                 let pol_version = api_version.clone();
+                runtime::get().api.set_policy(&api_version, &policy);
                 mutate_policies(move |policies| {
                     policies.versions.insert(pol_version, policy);
                 })
@@ -442,6 +514,9 @@ or
                     });
                     runtime.api.add_route(path.into(), func);
                 }
+                for (path, file) in &files {
+                    static_files::add_routes(&runtime.api, Path::new(path), file.clone());
+                }
                 runtime.api.update_api_info(&api_version, api_info);
             }
             for (path, _) in endpoints {
@@ -457,6 +532,7 @@ or
             types: type_names_user_order,
             endpoints: endpoint_routes.iter().map(|x| x.0.clone()).collect(),
             labels,
+            static_files: static_routes.into_iter().map(|x| x.0).collect(),
         }))
     }
 }
@@ -479,9 +555,9 @@ impl ChiselRpc for RpcService {
         &self,
         request: Request<ChiselApplyRequest>,
     ) -> Result<Response<ChiselApplyResponse>, Status> {
-        self.apply_aux(request)
-            .await
-            .map_err(|e| Status::internal(format!("{:?}", e)))
+        let res = self.apply_aux(request).await;
+        crate::metrics::apply_done(res.is_ok());
+        res.map_err(|e| Status::internal(format!("{:?}", e)))
     }
 
     /// Delete a version of ChiselStrike
@@ -573,20 +649,52 @@ impl ChiselRpc for RpcService {
         let ok = nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).is_ok();
         Ok(Response::new(RestartResponse { ok }))
     }
+
+    /// Runs policy test cases against the policies and types of the request, which need not be
+    /// applied.
+    async fn policy_test(
+        &self,
+        request: Request<PolicyTestRequest>,
+    ) -> Result<Response<PolicyTestResponse>, Status> {
+        let request = request.into_inner();
+        let results =
+            crate::policy_test::run(&request.policy_config, &request.types, &request.cases)
+                .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        let cases = results
+            .into_iter()
+            .map(|r| {
+                let (status, message) = r.denied.clone().unwrap_or_default();
+                PolicyTestCase {
+                    name: r.name,
+                    denied: r.denied.is_some(),
+                    status: status as u32,
+                    message,
+                    transformed: r.transformed,
+                    row: r.row.map(|row| row.to_string()),
+                    failures: r.failures,
+                }
+            })
+            .collect();
+        Ok(Response::new(PolicyTestResponse { cases }))
+    }
 }
 
 pub(crate) fn spawn(
     rpc: RpcService,
     addr: SocketAddr,
+    tls: Option<Arc<Tls>>,
     start_wait: impl core::future::Future<Output = ()> + Send + 'static,
     shutdown: impl core::future::Future<Output = ()> + Send + 'static,
 ) -> tokio::task::JoinHandle<Result<()>> {
     tokio::task::spawn(async move {
         start_wait.await;
 
+        let listener = std::net::TcpListener::bind(addr)?;
+        let acceptor = tls.map(|tls| tls.acceptor(&["h2"]));
+        let incoming = crate::tls::incoming(listener, acceptor)?;
         let ret = Server::builder()
             .add_service(ChiselRpcServer::new(rpc))
-            .serve_with_shutdown(addr, shutdown)
+            .serve_with_incoming_shutdown(incoming, shutdown)
             .await;
         debug!("Tonic shutdown");
         ret?;
@@ -1,19 +1,24 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::ApiService;
-use crate::datastore::{DbConnection, MetaService, QueryEngine};
+use crate::datastore::{changes, DbConnection, Kind, MetaService, QueryEngine};
 use crate::deno;
 use crate::deno::init_deno;
+use crate::deno::set_jwt_config;
 use crate::deno::set_meta;
 use crate::deno::set_policies;
 use crate::deno::set_query_engine;
 use crate::deno::set_type_system;
 use crate::deno::update_secrets;
 use crate::deno::{activate_endpoint, compile_endpoint};
+use crate::jwt::{Jwks, JwtConfig};
+use crate::logging::LogFormat;
+use crate::rate_limit::RateLimiter;
 use crate::rpc::{GlobalRpcState, RpcService};
 use crate::runtime;
 use crate::runtime::Runtime;
 use crate::secrets::get_secrets;
+use crate::tls::Tls;
 use crate::JsonObject;
 use anyhow::Result;
 use async_lock::Mutex;
@@ -63,6 +68,55 @@ pub struct Opt {
     /// If on, serve a web UI on an internal route.
     #[structopt(long)]
     webui: bool,
+    /// JWKS file with the public keys that verify RS256 and ES256 bearer tokens.
+    #[structopt(long)]
+    jwks_file: Option<PathBuf>,
+    /// Issuer that bearer tokens must have in their `iss` claim.
+    #[structopt(long)]
+    jwt_issuer: Option<String>,
+    /// Audience that bearer tokens must include in their `aud` claim.
+    #[structopt(long)]
+    jwt_audience: Option<String>,
+    /// Format of the log lines: text or json.
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
+    /// OTLP (gRPC) endpoint of an OpenTelemetry collector to export request spans to.
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+    /// Seconds that endpoints may take to respond before they are aborted with a 504. Policies
+    /// can set other timeouts for some paths.
+    #[structopt(long, parse(try_from_str = parse_seconds))]
+    request_timeout: Option<Duration>,
+    /// Megabytes of V8 heap that the JavaScript of each executor may use. An endpoint that runs
+    /// out of it is answered with a 500 and its worker is restarted.
+    #[structopt(long)]
+    v8_heap_limit_mb: Option<usize>,
+    /// Seconds that requests in flight get to finish when chiseld shuts down or restarts.
+    #[structopt(long, default_value = "30", parse(try_from_str = parse_seconds))]
+    shutdown_grace_period: Duration,
+    /// PEM file with the certificate chain that the API and RPC servers present, which makes them
+    /// serve HTTPS. It is reloaded when it changes.
+    #[structopt(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the --tls-cert certificate.
+    #[structopt(long)]
+    tls_key: Option<PathBuf>,
+    /// PEM file with the CAs that client certificates must be signed by. Clients without one are
+    /// refused.
+    #[structopt(long)]
+    tls_client_ca: Option<PathBuf>,
+}
+
+fn parse_seconds(s: &str) -> Result<Duration> {
+    let seconds: f64 = s.parse()?;
+    anyhow::ensure!(seconds > 0.0, "expected a positive number of seconds");
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+impl Opt {
+    pub fn init_logging(&self) {
+        crate::logging::init(self.log_format);
+    }
 }
 
 /// Whether an action should be repeated.
@@ -91,6 +145,16 @@ pub struct SharedState {
     executor_threads: usize,
     db: DbConnection,
     nr_connections: usize,
+    /// Rate limiting buckets, shared by all executors.
+    rate_limiter: Arc<RateLimiter>,
+    /// How to verify bearer tokens.
+    jwt_config: Arc<JwtConfig>,
+    /// Timeout of the endpoints that policies don't give one.
+    request_timeout: Option<Duration>,
+    /// How long requests in flight get to finish on shutdown.
+    shutdown_grace_period: Duration,
+    /// Certificate of the API servers, if they serve HTTPS.
+    tls: Option<Arc<Tls>>,
 }
 
 impl SharedState {
@@ -107,7 +171,9 @@ pub struct SharedTasks {
 impl SharedTasks {
     pub async fn join(self) -> Result<DoRepeat> {
         self.rpc_task.await??;
-        self.sig_task.await?
+        let repeat = self.sig_task.await?;
+        crate::telemetry::shutdown().await?;
+        repeat
     }
 }
 
@@ -144,15 +210,19 @@ async fn run(state: SharedState, mut cmd: ExecutorChannel) -> Result<()> {
     let ts = meta.load_type_system().await?;
 
     let routes = meta.load_endpoints().await?;
+    let static_files = meta.load_static_files().await?;
     let policies = meta.load_policies().await?;
     let api_info = meta.load_api_info().await?;
 
-    let mut api_service = ApiService::new(api_info);
+    let mut api_service =
+        ApiService::new(api_info, state.rate_limiter.clone(), state.request_timeout);
     crate::auth::init(&mut api_service).await?;
+    crate::audit::init(&mut api_service).await?;
     crate::introspect::init(&api_service);
 
     let query_engine =
         Arc::new(QueryEngine::local_connection(&state.db, state.nr_connections).await?);
+    crate::metrics::register_executor(query_engine.pool().clone());
     ts.create_builtin_backing_tables(query_engine.as_ref())
         .await?;
     let api_service = Rc::new(api_service);
@@ -166,12 +236,19 @@ async fn run(state: SharedState, mut cmd: ExecutorChannel) -> Result<()> {
     runtime::set(rt);
     set_type_system(ts).await;
     set_query_engine(query_engine).await;
+    set_jwt_config(state.jwt_config.clone()).await;
+    for (version, policy) in &policies.versions {
+        api_service.set_policy(version, policy);
+    }
     set_policies(policies).await;
     set_meta(meta).await;
 
     for (path, code) in routes.iter() {
         add_endpoint(path.to_str().unwrap(), code.to_string(), &api_service).await?;
     }
+    for (path, file) in static_files.iter() {
+        crate::static_files::add_routes(&api_service, path, file.clone());
+    }
 
     let command_task = tokio::task::spawn_local(async move {
         while let Some(item) = cmd.rx.next().await {
@@ -183,17 +260,31 @@ async fn run(state: SharedState, mut cmd: ExecutorChannel) -> Result<()> {
     let api_tasks = crate::api::spawn(
         api_service,
         state.api_listen_addr.clone(),
+        state.tls.clone(),
         state.signal_rx.clone(),
     )?;
     state.readiness_tx.send(()).await?;
 
+    let scheme = if state.tls.is_some() { "https" } else { "http" };
     info!(
-        "ChiselStrike is ready 🚀 - URL: http://{} ",
-        state.api_listen_addr
+        "ChiselStrike is ready 🚀 - URL: {}://{} ",
+        scheme, state.api_listen_addr
     );
 
-    for api_task in api_tasks {
-        api_task.await??;
+    // The API servers stop taking connections on shutdown, and wait for the requests in flight,
+    // and so for their transactions, to end.
+    state.signal_rx.recv().await.ok();
+    let drained = futures::future::join_all(api_tasks);
+    match tokio::time::timeout(state.shutdown_grace_period, drained).await {
+        Ok(results) => {
+            for res in results {
+                res??;
+            }
+        }
+        Err(_) => warn!(
+            "Shutting down with requests still in flight after {:?}",
+            state.shutdown_grace_period
+        ),
     }
     command_task.await?;
     deno::shutdown();
@@ -242,6 +333,34 @@ fn find_legacy_sqlite_dbs(opt: &Opt) -> Vec<PathBuf> {
 pub async fn run_shared_state(
     opt: Opt,
 ) -> Result<(SharedTasks, SharedState, Vec<ExecutorChannel>)> {
+    let jwt_config = JwtConfig {
+        jwks: match &opt.jwks_file {
+            Some(path) => Jwks::from_file(path)?,
+            None => Jwks::default(),
+        },
+        issuer: opt.jwt_issuer.clone(),
+        audience: opt.jwt_audience.clone(),
+    };
+    let tls = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(Tls::load(
+            cert,
+            key,
+            opt.tls_client_ca.as_deref(),
+        )?)),
+        (None, None) => {
+            anyhow::ensure!(
+                opt.tls_client_ca.is_none(),
+                "--tls-client-ca needs --tls-cert and --tls-key"
+            );
+            None
+        }
+        _ => anyhow::bail!("--tls-cert and --tls-key must be given together"),
+    };
+    crate::metrics::init();
+    crate::telemetry::init(opt.otlp_endpoint.as_deref())?;
+    if let Some(megabytes) = opt.v8_heap_limit_mb {
+        deno::set_heap_limit(megabytes);
+    }
     let db_conn = DbConnection::connect(&opt.db_uri, opt.nr_connections).await?;
     let meta = MetaService::local_connection(&db_conn, opt.nr_connections).await?;
 
@@ -253,6 +372,16 @@ pub async fn run_shared_state(
 
     let query_engine = QueryEngine::local_connection(&db_conn, opt.nr_connections).await?;
 
+    // Other processes may change the data of Postgres too, so all of the changes come from it.
+    if let Kind::Postgres = db_conn.kind {
+        let listener = changes::listen(db_conn.conn_uri.clone(), db_conn.changes.clone());
+        tokio::task::spawn(async move {
+            if let Err(e) = listener.await {
+                warn!("Not listening for changes to the data: {:?}", e);
+            }
+        });
+    }
+
     meta.create_schema().await?;
 
     let mut commands = vec![];
@@ -286,9 +415,15 @@ pub async fn run_shared_state(
         let res = futures::select! {
             _ = sigterm.recv().fuse() => { debug!("Got SIGTERM"); DoRepeat::No },
             _ = sigint.recv().fuse() => { debug!("Got SIGINT"); DoRepeat::No },
-            _ = sighup.recv().fuse() => { debug!("Got SIGHUP"); DoRepeat::Yes },
+            _ = sighup.recv().fuse() => {
+                debug!("Got SIGHUP");
+                crate::metrics::restarting();
+                DoRepeat::Yes
+            },
         };
         debug!("Got signal");
+        // Load balancers stop sending requests while the ones in flight finish.
+        crate::internal::set_ready(false);
         signal_tx.send(()).await?;
         Ok(res)
     });
@@ -352,6 +487,11 @@ pub async fn run_shared_state(
         }
     });
 
+    // Renewed certificates are picked up without a restart.
+    if let Some(tls) = &tls {
+        tokio::task::spawn(crate::tls::watch(tls.clone(), signal_rx.clone()));
+    }
+
     // rpc server should start listening only when all threads start
     let (readiness_tx, readiness_rx) = async_channel::bounded(opt.executor_threads);
 
@@ -359,6 +499,8 @@ pub async fn run_shared_state(
         for _id in 0..opt.executor_threads {
             readiness_rx.recv().await.unwrap();
         }
+        crate::listeners::close_unclaimed();
+        crate::internal::set_ready(true);
     };
 
     let rpc_rx = signal_rx.clone();
@@ -366,7 +508,7 @@ pub async fn run_shared_state(
         rpc_rx.recv().await.ok();
     };
 
-    let rpc_task = crate::rpc::spawn(rpc, opt.rpc_listen_addr, start_wait, shutdown);
+    let rpc_task = crate::rpc::spawn(rpc, opt.rpc_listen_addr, tls.clone(), start_wait, shutdown);
     debug!("RPC is ready. URL: {}", opt.rpc_listen_addr);
 
     crate::internal::init(
@@ -387,12 +529,23 @@ pub async fn run_shared_state(
         executor_threads: opt.executor_threads,
         db: db_conn,
         nr_connections: opt.nr_connections,
+        rate_limiter: Default::default(),
+        jwt_config: Arc::new(jwt_config),
+        request_timeout: opt.request_timeout,
+        shutdown_grace_period: opt.shutdown_grace_period,
+        tls,
     };
 
     let tasks = SharedTasks { rpc_task, sig_task };
     Ok((tasks, state, commands))
 }
 
+/// Has the listening sockets of the API servers survive the exec of the chiseld that replaces this
+/// one on restart.
+pub fn hand_over_listeners() -> Result<()> {
+    crate::listeners::hand_over()
+}
+
 pub async fn run_on_new_localset(state: SharedState, command: ExecutorChannel) -> Result<()> {
     let local = tokio::task::LocalSet::new();
     local.run_until(run(state, command)).await
@@ -0,0 +1,313 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Static files of the project, like the bundle of its frontend, which `chisel apply` uploads
+//! along with the endpoints.
+//!
+//! They are served by the API server itself, without going through the JS worker. Responses
+//! carry the file's content type and an ETag, so that clients can revalidate what they cached,
+//! and clients can ask for part of a file with a `Range` header.
+
+use crate::api::{ApiService, Body};
+use crate::prefix_map::PathMatch;
+use anyhow::Result;
+use futures::FutureExt;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Content types by file extension. Files with other extensions are sent as
+/// `application/octet-stream`.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "application/javascript"),
+    ("mjs", "application/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+];
+
+/// Caches may keep the files, but must check with the server that they are current before using
+/// them, as their names don't change when their content does.
+const CACHE_POLICY: &str = "public, no-cache";
+
+/// The file that directories are served as.
+const INDEX_FILE: &str = "index.html";
+
+pub(crate) struct StaticFile {
+    pub(crate) content: Box<[u8]>,
+    content_type: &'static str,
+    etag: String,
+}
+
+impl StaticFile {
+    pub(crate) fn new(path: &Path, content: Vec<u8>) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let content_type = CONTENT_TYPES
+            .iter()
+            .find(|(ext, _)| Some(*ext) == extension.as_deref())
+            .map_or("application/octet-stream", |(_, content_type)| content_type);
+        let digest = Sha256::digest(&content);
+        let etag = format!(
+            "\"{}\"",
+            base64::encode_config(&digest[..16], base64::URL_SAFE_NO_PAD)
+        );
+        StaticFile {
+            content: content.into_boxed_slice(),
+            content_type,
+            etag,
+        }
+    }
+
+    fn response(&self, req: &Request<hyper::Body>) -> Result<Response<Body>> {
+        let builder = Response::builder()
+            .header(CONTENT_TYPE, self.content_type)
+            .header(ETAG, &self.etag)
+            .header(CACHE_CONTROL, CACHE_POLICY)
+            .header(ACCEPT_RANGES, "bytes");
+        if let Some(tags) = req.headers().get(IF_NONE_MATCH) {
+            if etag_matches(tags, &self.etag) {
+                return Ok(builder
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::default())?);
+            }
+        }
+        let len = self.content.len();
+        // A range is only served if the client has the version of the file that it asked for part
+        // of, so when `If-Range` doesn't match, the whole file is sent instead.
+        let if_range = req.headers().get(IF_RANGE);
+        let range = match req.headers().get(RANGE) {
+            Some(range) if if_range.map_or(true, |tag| tag == self.etag.as_str()) => {
+                range.to_str().ok().and_then(|r| parse_range(r, len))
+            }
+            _ => None,
+        };
+        let (builder, body) = match range {
+            None => (builder.status(StatusCode::OK), &self.content[..]),
+            Some(Ok((start, end))) => (
+                builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len)),
+                &self.content[start..=end],
+            ),
+            Some(Err(())) => {
+                return Ok(builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(Body::default())?);
+            }
+        };
+        let builder = builder.header(CONTENT_LENGTH, body.len());
+        let body = if req.method() == Method::HEAD {
+            Body::Const(None)
+        } else {
+            Body::Const(Some(body.into()))
+        };
+        Ok(builder.body(body)?)
+    }
+}
+
+/// Whether the `If-None-Match` header `tags` has `etag`. Tags are compared weakly, as the
+/// compression of the response makes its tag a weak one.
+fn etag_matches(tags: &HeaderValue, etag: &str) -> bool {
+    let tags = match tags.to_str() {
+        Ok(tags) => tags,
+        Err(_) => return false,
+    };
+    tags.split(',').map(|t| t.trim()).any(|t| {
+        let t = t.strip_prefix("W/").unwrap_or(t);
+        t == "*" || t == etag
+    })
+}
+
+/// Parses a `Range` header for a file of `len` bytes, returning the first and last byte it asks
+/// for, or an error if none of it is in the file. Returns `None` for headers that should be
+/// ignored, like malformed ones or those with more than one range, so that the whole file is sent.
+fn parse_range(range: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // A suffix, like `-500` for the last 500 bytes.
+        let suffix: usize = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: usize = start.parse().ok()?;
+        let end = match end {
+            "" => usize::MAX,
+            end => end.parse().ok()?,
+        };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end.min(len - 1))
+    };
+    Some(Ok(range))
+}
+
+async fn serve(file: Arc<StaticFile>, req: Request<hyper::Body>) -> Result<Response<Body>> {
+    // Routes match any path that they are a prefix of, but files have nothing below them.
+    let exact = req
+        .extensions()
+        .get::<PathMatch>()
+        .map_or(true, |m| m.rest.is_empty());
+    if !exact {
+        return ApiService::not_found();
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, "GET, HEAD")
+            .body(Body::default())?);
+    }
+    file.response(&req)
+}
+
+/// Returns the paths that the file of `path` is served at, which is also its directory for an
+/// index file, except for the root directory of the version, which is where its introspection
+/// is served.
+pub(crate) fn route_paths(path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![path.to_path_buf()];
+    if path.file_name().map_or(false, |name| name == INDEX_FILE) {
+        if let Some(dir) = path.parent() {
+            if dir.components().count() > 2 {
+                paths.push(dir.to_path_buf());
+            }
+        }
+    }
+    paths
+}
+
+/// Adds the routes that serve the file of `path`.
+pub(crate) fn add_routes(api: &ApiService, path: &Path, file: Arc<StaticFile>) {
+    for path in route_paths(path) {
+        let file = file.clone();
+        let func = Arc::new(move |req| serve(file.clone(), req).boxed_local());
+        api.add_route(path, func);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &str) -> StaticFile {
+        StaticFile::new(Path::new("/dev/app.js"), content.as_bytes().to_vec())
+    }
+
+    fn get(file: &StaticFile, headers: &[(&'static str, &str)]) -> Response<Body> {
+        let mut req = Request::get("/dev/app.js");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        file.response(&req.body(hyper::Body::empty()).unwrap())
+            .unwrap()
+    }
+
+    fn body(response: Response<Body>) -> String {
+        match response.into_body() {
+            Body::Const(Some(data)) => String::from_utf8(data.into()).unwrap(),
+            _ => panic!("expected a constant body"),
+        }
+    }
+
+    #[test]
+    fn content_types() {
+        let content_type = |path| StaticFile::new(Path::new(path), vec![]).content_type;
+        assert_eq!(content_type("/dev/index.HTML"), "text/html; charset=utf-8");
+        assert_eq!(content_type("/dev/logo.svg"), "image/svg+xml");
+        assert_eq!(content_type("/dev/data"), "application/octet-stream");
+    }
+
+    #[test]
+    fn revalidation() {
+        let f = file("console.log('hello')");
+        let response = get(&f, &[]);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], CACHE_POLICY);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        let response = get(&f, &[("if-none-match", &etag)]);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let weak = format!("\"other\", W/{}", etag);
+        let response = get(&f, &[("if-none-match", &weak)]);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let changed = file("console.log('bye')");
+        let response = get(&changed, &[("if-none-match", &etag)]);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn ranges() {
+        let f = file("0123456789");
+        let response = get(&f, &[("range", "bytes=2-4")]);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(body(response), "234");
+        assert_eq!(body(get(&f, &[("range", "bytes=7-")])), "789");
+        assert_eq!(body(get(&f, &[("range", "bytes=-3")])), "789");
+        assert_eq!(body(get(&f, &[("range", "bytes=8-100")])), "89");
+
+        let response = get(&f, &[("range", "bytes=10-")]);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = get(&f, &[("range", "bytes=0-1,4-5")]);
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get(&f, &[("range", "bytes=2-4"), ("if-range", "\"old\"")]);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response), "0123456789");
+    }
+
+    #[test]
+    fn index_routes() {
+        let paths = |path| route_paths(Path::new(path));
+        assert_eq!(
+            paths("/dev/docs/index.html"),
+            vec![
+                PathBuf::from("/dev/docs/index.html"),
+                PathBuf::from("/dev/docs")
+            ]
+        );
+        assert_eq!(
+            paths("/dev/index.html"),
+            vec![PathBuf::from("/dev/index.html")]
+        );
+    }
+}
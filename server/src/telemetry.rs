@@ -0,0 +1,191 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! OpenTelemetry spans of the request lifecycle, exported over OTLP when the server is given a
+//! collector endpoint.
+//!
+//! A request continues the trace of its W3C `traceparent` header, if any. The span of the
+//! request is made current while its futures are polled, so the spans of the route lookup and
+//! JS execution nest under it. The ops of a request run on the thread of the endpoint worker,
+//! which doesn't share that context, so the request is handed over with the `traceparent` of
+//! its JS execution span, and op spans are explicitly made children of it. The SQL statements
+//! of an op nest under the op span.
+
+use anyhow::Result;
+use hyper::HeaderMap;
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::trace::{FutureExt, SpanKind, TraceContextExt, Tracer, WithContext};
+use opentelemetry::{Context, ContextGuard, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use std::future::Future;
+
+const TRACER: &str = "chiseld";
+
+/// Sets up the propagation of trace contexts and, given a collector `endpoint`, the export of
+/// spans to it. Without an endpoint, spans are not recorded.
+pub(crate) fn init(endpoint: Option<&str>) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    if let Some(endpoint) = endpoint {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                trace::config()
+                    .with_resource(Resource::new(vec![KeyValue::new("service.name", TRACER)])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        info!("Exporting spans to {}", endpoint);
+    }
+    Ok(())
+}
+
+/// Flushes the spans that are not exported yet.
+pub(crate) async fn shutdown() -> Result<()> {
+    tokio::task::spawn_blocking(global::shutdown_tracer_provider).await?;
+    Ok(())
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Returns the trace context that the caller sent in the `headers` of a request.
+fn remote_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// The W3C `traceparent` of the current span, for another thread to continue its trace.
+pub(crate) fn current_traceparent() -> Option<String> {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Context::current(), &mut carrier)
+    });
+    carrier.remove("traceparent")
+}
+
+/// Returns a context with the span of `traceparent` as the parent of new spans, or an empty one.
+fn traceparent_context(traceparent: Option<&str>) -> Context {
+    let traceparent = match traceparent {
+        Some(traceparent) => traceparent,
+        None => return Context::new(),
+    };
+    let carrier = HashMap::from([("traceparent".to_owned(), traceparent.to_owned())]);
+    global::get_text_map_propagator(|propagator| propagator.extract(&carrier))
+}
+
+/// Starts a span that is a child of `traceparent`, and returns a context where it is current.
+fn child_context(name: &'static str, traceparent: Option<&str>) -> Context {
+    let parent = traceparent_context(traceparent);
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_parent_context(parent.clone())
+        .start(&tracer);
+    parent.with_span(span)
+}
+
+/// Starts the span of a request with these `headers`, and returns a context where it is
+/// current. The span ends when the last clone of the context is dropped.
+pub(crate) fn request_context(
+    name: String,
+    headers: &HeaderMap,
+    attributes: Vec<KeyValue>,
+) -> Context {
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_parent_context(remote_context(headers))
+        .with_attributes(attributes)
+        .start(&tracer);
+    Context::current_with_span(span)
+}
+
+/// Starts a span that is a child of the current one and ends when dropped.
+pub(crate) fn span(name: &'static str) -> BoxedSpan {
+    global::tracer(TRACER).start(name)
+}
+
+/// Starts a span for an SQL `statement` of this `kind`, that ends when dropped.
+pub(crate) fn sql_span(kind: &'static str, statement: &str) -> BoxedSpan {
+    let tracer = global::tracer(TRACER);
+    tracer
+        .span_builder(format!("SQL {}", kind))
+        .with_kind(SpanKind::Client)
+        .with_attributes(vec![
+            KeyValue::new("db.operation", kind),
+            KeyValue::new("db.statement", statement.to_owned()),
+        ])
+        .start(&tracer)
+}
+
+/// Runs `inner` in a span that is a child of the current one, making it current while `inner`
+/// is polled.
+pub(crate) fn in_span<F: Future>(name: &'static str, inner: F) -> WithContext<F> {
+    inner.with_context(Context::current_with_span(span(name)))
+}
+
+/// Runs `inner` in a span that is a child of `traceparent`, making it current while `inner` is
+/// polled.
+pub(crate) fn in_child_span<F: Future>(
+    name: &'static str,
+    traceparent: Option<&str>,
+    inner: F,
+) -> WithContext<F> {
+    inner.with_context(child_context(name, traceparent))
+}
+
+/// Starts a span that is a child of `traceparent`, and makes it current on this thread until
+/// the returned guard is dropped, which also ends it.
+pub(crate) fn enter_child_span(name: &'static str, traceparent: Option<&str>) -> ContextGuard {
+    child_context(name, traceparent).attach()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn traceparent() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut headers = HeaderMap::new();
+        let cx = remote_context(&headers);
+        assert!(!cx.span().span_context().is_valid());
+
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        );
+        let cx = remote_context(&headers);
+        let parent = cx.span().span_context().clone();
+        assert!(parent.is_remote());
+        assert_eq!(
+            parent.trace_id().to_hex(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(parent.span_id().to_hex(), "b7ad6b7169203331");
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let cx = traceparent_context(Some(traceparent));
+        assert_eq!(cx.span().span_context(), &parent);
+        assert!(!traceparent_context(None).span().span_context().is_valid());
+        let _guard = cx.attach();
+        assert_eq!(current_traceparent().as_deref(), Some(traceparent));
+    }
+}
@@ -0,0 +1,310 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! TLS termination of the API and RPC servers.
+//!
+//! The servers present the certificate of the files given to `chiseld --tls-cert` and
+//! `--tls-key`, which are reloaded when they change, so that renewed certificates are used
+//! without restarting. With `--tls-client-ca`, clients must present a certificate signed by one
+//! of the CAs of that file.
+
+use anyhow::{anyhow, Context, Result};
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use pin_project::pin_project;
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::sleep;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+
+/// How long clients get to complete the handshake before they are disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many handshakes a server does at once. Further connections wait to be accepted.
+const MAX_HANDSHAKES: usize = 128;
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// The certificate that the servers present, as last loaded from its files.
+struct Certificate {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// TLS configuration shared by the servers.
+pub(crate) struct Tls {
+    certificate: Arc<Certificate>,
+    /// CAs that client certificates must be signed by, if clients must have one.
+    client_roots: Option<RootCertStore>,
+}
+
+impl Tls {
+    pub(crate) fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let current = load_certified_key(cert, key)?;
+        let client_roots = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for der in load_certs(path)? {
+                    roots
+                        .add(&rustls::Certificate(der))
+                        .with_context(|| format!("bad CA certificate in {}", path.display()))?;
+                }
+                Some(roots)
+            }
+            None => None,
+        };
+        Ok(Tls {
+            certificate: Arc::new(Certificate {
+                cert: cert.to_path_buf(),
+                key: key.to_path_buf(),
+                current: RwLock::new(Arc::new(current)),
+            }),
+            client_roots,
+        })
+    }
+
+    /// Returns an acceptor for a server that speaks the given ALPN protocols.
+    pub(crate) fn acceptor(&self, alpn: &[&str]) -> TlsAcceptor {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_roots {
+            Some(roots) => {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self.certificate.clone());
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Arc::new(config).into()
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        Some((
+            modified(&self.certificate.cert)?,
+            modified(&self.certificate.key)?,
+        ))
+    }
+
+    fn reload(&self) -> Result<()> {
+        let certificate = &self.certificate;
+        let current = load_certified_key(&certificate.cert, &certificate.key)?;
+        *certificate.current.write().unwrap() = Arc::new(current);
+        Ok(())
+    }
+}
+
+/// Reloads the certificate whenever its files change, until `shutdown` receives. If the new
+/// files can't be loaded, which happens when only one of them was replaced yet, the servers keep
+/// presenting the old certificate.
+pub(crate) async fn watch(tls: Arc<Tls>, shutdown: async_channel::Receiver<()>) {
+    let mut last_modified = tls.modified();
+    let mut last_try_was_failure = false;
+    loop {
+        futures::select! {
+            _ = sleep(RELOAD_INTERVAL).fuse() => {},
+            _ = shutdown.recv().fuse() => {
+                break;
+            }
+        };
+
+        let modified = tls.modified();
+        if modified == last_modified {
+            continue;
+        }
+        match tls.reload() {
+            Ok(()) => {
+                info!("Reloaded the TLS certificate");
+                last_modified = modified;
+                last_try_was_failure = false;
+            }
+            Err(e) => {
+                if !last_try_was_failure {
+                    warn!("Could not reload the TLS certificate: {:?}", e);
+                }
+                last_try_was_failure = true;
+            }
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("could not read certificates from {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificate in {}", path.display());
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("could not read private key from {}", path.display()))?;
+        match item {
+            Some(Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der)) => {
+                return Ok(PrivateKey(der))
+            }
+            Some(_) => {}
+            None => anyhow::bail!("no private key in {}", path.display()),
+        }
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let certs = load_certs(cert)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = sign::any_supported_type(&load_key(key)?)
+        .map_err(|_| anyhow!("unsupported private key in {}", key.display()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// A connection to one of the servers, which is encrypted if the server has a certificate.
+#[pin_project(project = ConnProj)]
+pub(crate) enum Conn {
+    Plain(#[pin] AddrStream),
+    Tls(#[pin] Box<TlsStream<AddrStream>>),
+}
+
+impl Conn {
+    pub(crate) fn remote_addr(&self) -> SocketAddr {
+        match self {
+            Conn::Plain(s) => s.remote_addr(),
+            Conn::Tls(s) => s.get_ref().0.remote_addr(),
+        }
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.project() {
+            ConnProj::Plain(s) => s.poll_read(cx, buf),
+            ConnProj::Tls(s) => s.poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.project() {
+            ConnProj::Plain(s) => s.poll_write(cx, buf),
+            ConnProj::Tls(s) => s.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            ConnProj::Plain(s) => s.poll_flush(cx),
+            ConnProj::Tls(s) => s.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            ConnProj::Plain(s) => s.poll_shutdown(cx),
+            ConnProj::Tls(s) => s.poll_shutdown(cx),
+        }
+    }
+}
+
+impl Connected for Conn {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            Conn::Plain(s) => s.connect_info(),
+            Conn::Tls(s) => s.get_ref().0.connect_info(),
+        }
+    }
+}
+
+/// Accepts the connections of `listener`, doing the handshake first if there is an `acceptor`.
+/// Clients that fail the handshake are disconnected without the server seeing them.
+pub(crate) fn incoming(
+    listener: std::net::TcpListener,
+    acceptor: Option<TlsAcceptor>,
+) -> Result<impl Stream<Item = io::Result<Conn>> + Send> {
+    listener.set_nonblocking(true)?;
+    let mut addr_incoming =
+        AddrIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?)?;
+    let accepted = futures::stream::poll_fn(move |cx| Pin::new(&mut addr_incoming).poll_accept(cx));
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => return Ok(accepted.map_ok(Conn::Plain).left_stream()),
+    };
+    let handshakes = accepted
+        .map(move |conn| {
+            let acceptor = acceptor.clone();
+            async move {
+                let conn = conn?;
+                let remote_addr = conn.remote_addr();
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+                    .map_err(|e| {
+                        io::Error::new(e.kind(), format!("handshake with {}: {}", remote_addr, e))
+                    })
+            }
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|conn| async move {
+            match conn {
+                Ok(conn) => Some(Ok(Conn::Tls(Box::new(conn)))),
+                Err(e) => {
+                    debug!("TLS {}", e);
+                    None
+                }
+            }
+        });
+    Ok(handshakes.right_stream())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn rejects_files_without_certificate_or_key() {
+        let mut empty = tempfile::NamedTempFile::new().unwrap();
+        writeln!(empty, "not a PEM file").unwrap();
+        let err = Tls::load(empty.path(), empty.path(), None).err().unwrap();
+        assert!(err.to_string().starts_with("no certificate in"));
+        let err = load_key(empty.path()).unwrap_err();
+        assert!(err.to_string().starts_with("no private key in"));
+        let missing = Path::new("/nonexistent/cert.pem");
+        let err = Tls::load(missing, missing, None).err().unwrap();
+        assert!(err.to_string().starts_with("could not open"));
+    }
+}
@@ -1,8 +1,13 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
-use crate::auth::{AUTH_ACCOUNT_NAME, AUTH_SESSION_NAME, AUTH_TOKEN_NAME, AUTH_USER_NAME};
+use crate::audit::AUDIT_RECORD_NAME;
+use crate::auth::{
+    AUTH_ACCOUNT_NAME, AUTH_API_KEY_NAME, AUTH_SESSION_NAME, AUTH_TOKEN_NAME, AUTH_USER_NAME,
+    AUTH_USER_ROLE_NAME,
+};
 use crate::datastore::query::QueryPlan;
 use crate::datastore::QueryEngine;
+use crate::populate::{PopulateMapping, TypeMapping};
 use crate::types::AuthOrNot::IsAuth;
 use anyhow::Context;
 use derive_new::new;
@@ -81,7 +86,7 @@ fn optional_string_field(name: &str) -> Field {
     f
 }
 
-fn optional_number_field(name: &str) -> Field {
+fn number_field(name: &str) -> Field {
     Field {
         id: None,
         name: name.into(),
@@ -89,12 +94,18 @@ fn optional_number_field(name: &str) -> Field {
         labels: vec![],
         default: None,
         effective_default: None,
-        is_optional: true,
+        is_optional: false,
         api_version: "__chiselstrike".into(),
         is_unique: false,
     }
 }
 
+fn optional_number_field(name: &str) -> Field {
+    let mut f = number_field(name);
+    f.is_optional = true;
+    f
+}
+
 impl Default for TypeSystem {
     fn default() -> Self {
         let mut ts = Self {
@@ -155,6 +166,39 @@ impl Default for TypeSystem {
             "auth_account",
             IsAuth,
         );
+        ts.add_builtin_object_type(
+            AUTH_USER_ROLE_NAME,
+            vec![string_field("userId"), string_field("role")],
+            "auth_user_role",
+            IsAuth,
+        );
+        ts.add_builtin_object_type(
+            AUTH_API_KEY_NAME,
+            vec![
+                string_field("keyHash"),
+                string_field("userId"),
+                optional_string_field("scopes"),
+                optional_number_field("expiresAt"),
+            ],
+            "auth_api_key",
+            IsAuth,
+        );
+        ts.add_builtin_object_type(
+            AUDIT_RECORD_NAME,
+            vec![
+                number_field("timestamp"),
+                optional_string_field("userId"),
+                string_field("path"),
+                string_field("apiVersion"),
+                string_field("entity"),
+                string_field("entityId"),
+                string_field("action"),
+                optional_string_field("before"),
+                optional_string_field("after"),
+            ],
+            "audit_record",
+            IsAuth,
+        );
 
         ts
     }
@@ -394,11 +438,18 @@ impl TypeSystem {
         }
     }
 
+    /// Copies the rows of every type in `api_version_from` into its counterpart in `api_version_to`.
+    ///
+    /// `mapping` says which source type and fields feed each target type. If `transform` is given,
+    /// it names a module loaded into the runtime whose default export is applied to every row after
+    /// the mapping, so this must then run in an executor thread.
     pub(crate) async fn populate_types<T: AsRef<str>, F: AsRef<str>>(
         &self,
         engine: Arc<QueryEngine>,
         api_version_to: T,
         api_version_from: F,
+        mapping: &PopulateMapping,
+        transform: Option<&str>,
     ) -> anyhow::Result<()> {
         let to = match self.versions.get(api_version_to.as_ref()) {
             Some(x) => Ok(x),
@@ -414,11 +465,13 @@ impl TypeSystem {
             )),
         }?;
 
-        for (ty_name, ty_obj) in from.custom_types.iter() {
-            if let Some(ty_obj_to) = to.custom_types.get(ty_name) {
+        for (ty_name, ty_obj_to) in to.custom_types.iter() {
+            let type_mapping = mapping.get(ty_name);
+            let from_name = type_mapping.from.as_deref().unwrap_or(ty_name);
+            if let Some(ty_obj) = from.custom_types.get(from_name) {
                 // Either the TO type is a safe replacement of FROM, of we need to have a lens
                 ty_obj_to
-                    .check_if_safe_to_populate(ty_obj)
+                    .check_if_safe_to_populate(ty_obj, &type_mapping, transform.is_some())
                     .with_context(|| {
                         format!(
                             "Not possible to evolve type {} ({} -> {})",
@@ -436,6 +489,14 @@ impl TypeSystem {
                     // FIXME: basic rate limit?
                     let row = row
                         .with_context(|| format!("population can't proceed as reading from the underlying database for type {} failed", ty_obj_to.name))?;
+                    let mut row = type_mapping.map_row(&row, ty_obj_to);
+                    if let Some(transform) = transform {
+                        row = crate::deno::apply_transform(transform, ty_name, row)
+                            .await
+                            .with_context(|| {
+                                format!("transforming a row of type {} failed", ty_name)
+                            })?;
+                    }
                     engine.add_row_shallow(ty_obj_to, &row).await?;
                 }
                 drop(row_streams);
@@ -715,10 +776,15 @@ impl ObjectType {
         format!("{}.{}", self.api_version, self.name)
     }
 
-    fn check_if_safe_to_populate(&self, source_type: &ObjectType) -> anyhow::Result<()> {
+    fn check_if_safe_to_populate(
+        &self,
+        source_type: &ObjectType,
+        mapping: &TypeMapping,
+        has_transform: bool,
+    ) -> anyhow::Result<()> {
         let source_map: FieldMap<'_> = source_type.into();
         let to_map: FieldMap<'_> = self.into();
-        to_map.check_populate_from(&source_map)
+        to_map.check_populate_from(&source_map, mapping, has_transform)
     }
 
     pub(crate) fn is_auth(&self) -> bool {
@@ -753,23 +819,46 @@ impl<'a> From<&'a ObjectType> for FieldMap<'a> {
 impl<'a> FieldMap<'a> {
     /// Similar to is_safe_replacement_for, but will be able to work across backing tables. Useful
     /// when evolving versions
-    fn check_populate_from(&self, source_type: &Self) -> anyhow::Result<()> {
+    fn check_populate_from(
+        &self,
+        source_type: &Self,
+        mapping: &TypeMapping,
+        has_transform: bool,
+    ) -> anyhow::Result<()> {
+        for name in mapping.defaults.keys() {
+            anyhow::ensure!(
+                self.map.contains_key(name.as_str()),
+                "Mapping has a default for field {}, which doesn't exist in the target type",
+                name
+            );
+        }
+        for source_name in mapping.fields.values() {
+            anyhow::ensure!(
+                source_type.map.contains_key(source_name.as_str()),
+                "Mapping reads from field {}, which doesn't exist in the source type",
+                source_name
+            );
+        }
         // to -> from, always ok to remove fields, so only loop over self.
         //
         // Adding fields: Ok, if there is a default value or lens
         //
         // Fields in common: Ok if the type is the same, or if there is a lens
         for (name, field) in self.map.iter() {
-            if let Some(existing) = source_type.map.get(name) {
+            if let Some(existing) = source_type.map.get(mapping.source_field(name)) {
+                // The transform may convert the value, so we can only check its output on insertion.
                 anyhow::ensure!(
-                    existing.type_.name() == field.type_.name(),
-                    "Type name mismatch on field {} ({} -> {}). We don't support that yet, but that's coming soon! 🙏",
+                    has_transform || existing.type_.name() == field.type_.name(),
+                    "Type name mismatch on field {} ({} -> {}). Consider converting it with a populate transform",
                     name, existing.type_.name(), field.type_.name()
                 );
             } else {
                 anyhow::ensure!(
-                    field.default.is_none(),
-                    "Adding field {} without a default, which is not supported yet",
+                    field.default.is_some()
+                        || field.is_optional
+                        || mapping.defaults.contains_key(*name)
+                        || has_transform,
+                    "Adding field {} without a default. Consider adding a default for it to the populate mapping",
                     name
                 );
             }